@@ -0,0 +1,170 @@
+//! Append-only file persistence
+//!
+//! Every write command is appended to the file in its RESP form. A rewrite
+//! (BGREWRITEAOF) replaces the log with an RDB preamble holding the current
+//! dataset, followed by whatever was appended while the rewrite was running.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// When to fsync the append-only file, set with `appendfsync`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every write command
+    Always,
+    /// Once a second, from a background task
+    EverySec,
+    /// Whenever the OS decides to flush
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn parse(s: &str) -> Option<FsyncPolicy> {
+        match s.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+}
+
+pub struct AofWriter {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    /// Writes accepted since the last fsync
+    unsynced: bool,
+    /// Commands appended while a rewrite is in progress; they're copied onto the
+    /// end of the rewritten file before it replaces the current one.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl AofWriter {
+    pub fn append(&mut self, command: &[u8]) -> io::Result<()> {
+        self.file.write_all(command)?;
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(command);
+        }
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data(),
+            _ => {
+                self.unsynced = true;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    /// Starts buffering appends for a rewrite. The caller takes its snapshot of the
+    /// dataset while still holding this writer, so nothing falls between the two.
+    pub fn start_rewrite(&mut self) {
+        self.rewrite_buffer = Some(Vec::new());
+    }
+
+    /// Replaces the log with `base` plus everything appended since `start_rewrite`.
+    pub fn finish_rewrite(&mut self, base: &[u8]) -> io::Result<()> {
+        let buffered = self.rewrite_buffer.take().unwrap_or_default();
+        let tmp_path = self
+            .path
+            .with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let write = || -> io::Result<File> {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(base)?;
+            tmp.write_all(&buffered)?;
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &self.path)?;
+            OpenOptions::new().append(true).open(&self.path)
+        };
+        match write() {
+            Ok(file) => {
+                self.file = file;
+                self.unsynced = false;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+    }
+}
+
+pub struct Aof {
+    writer: Mutex<AofWriter>,
+}
+
+impl Aof {
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Aof {
+            writer: Mutex::new(AofWriter {
+                file,
+                path: path.to_path_buf(),
+                policy,
+                unsynced: false,
+                rewrite_buffer: None,
+            }),
+        })
+    }
+
+    /// Locks the log for appending. Holding the guard while a command runs keeps
+    /// the command and its log entry atomic with respect to rewrites.
+    pub fn lock(&self) -> MutexGuard<'_, AofWriter> {
+        self.writer.lock().unwrap()
+    }
+
+    /// Called once a second for `appendfsync everysec`
+    pub fn fsync_if_needed(&self) -> io::Result<()> {
+        let mut writer = self.lock();
+        if writer.policy == FsyncPolicy::EverySec && writer.unsynced {
+            writer.file.sync_data()?;
+            writer.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+/// Cuts a log with a partially written last command back to its last complete one.
+pub fn truncate(path: &Path, valid_len: u64) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.set_len(valid_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_keeps_concurrent_appends() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let aof = Aof::open(&path, FsyncPolicy::Always).unwrap();
+        aof.lock().append(b"old\r\n").unwrap();
+        aof.lock().start_rewrite();
+        aof.lock().append(b"during\r\n").unwrap();
+        aof.lock().finish_rewrite(b"base\r\n").unwrap();
+        aof.lock().append(b"after\r\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"base\r\nduring\r\nafter\r\n");
+
+        truncate(&path, 6).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"base\r\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! The replication backlog: the latest writes, kept for replicas to read at their
+//! own pace
+//!
+//! Writes are numbered by the replication offset of their first byte. Each replica
+//! reads on from the offset it got to, taking everything written since in one go.
+//! Once the backlog is full the oldest writes are dropped, and a replica that
+//! hadn't read them yet can only catch up with a full resync.
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Bytes kept by default, as in Redis' repl-backlog-size
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// A replica fell so far behind that writes it hadn't read were dropped
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("replica fell behind the replication backlog")]
+pub struct Lagged;
+
+#[derive(Debug)]
+pub struct Backlog {
+    size: usize,
+    writes: Mutex<Writes>,
+    /// The offset just past the newest write, watched by replicas waiting for more
+    end: watch::Sender<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Writes {
+    /// Each write with its offset, oldest first
+    kept: VecDeque<(u64, Bytes)>,
+    bytes: usize,
+    /// A transaction's writes, sent together when it's done
+    held: Option<BytesMut>,
+}
+
+impl Backlog {
+    /// A backlog keeping about `size` bytes of writes
+    pub fn new(size: usize) -> Self {
+        Backlog {
+            size,
+            writes: Mutex::default(),
+            end: watch::Sender::new(0),
+        }
+    }
+
+    /// Sends a write to replicas, or holds it back while a transaction is open
+    pub fn send(&self, write: &[u8]) {
+        let mut writes = self.writes.lock().unwrap();
+        match writes.held.as_mut() {
+            Some(held) => held.extend_from_slice(write),
+            None => self.append(&mut writes, Bytes::copy_from_slice(write)),
+        }
+    }
+
+    /// Holds back the writes that follow until `release`, so replicas never read
+    /// part of a transaction
+    pub fn hold(&self) {
+        self.writes.lock().unwrap().held.get_or_insert_with(BytesMut::new);
+    }
+
+    /// Sends the writes held back since `hold` as one
+    pub fn release(&self) {
+        let mut writes = self.writes.lock().unwrap();
+        if let Some(held) = writes.held.take().filter(|held| !held.is_empty()) {
+            self.append(&mut writes, held.freeze());
+        }
+    }
+
+    /// The offset just past the newest write
+    pub fn offset(&self) -> u64 {
+        *self.end.borrow()
+    }
+
+    /// A reader starting at the next write
+    pub fn subscribe(self: &Arc<Self>) -> BacklogReader {
+        // Locked so no write lands between reading the offset and watching it
+        let _writes = self.writes.lock().unwrap();
+        let end = self.end.subscribe();
+        let offset = *end.borrow();
+        BacklogReader {
+            backlog: Arc::clone(self),
+            offset,
+            end,
+        }
+    }
+
+    fn append(&self, writes: &mut Writes, write: Bytes) {
+        let offset = self.offset();
+        let end = offset + write.len() as u64;
+        writes.bytes += write.len();
+        writes.kept.push_back((offset, write));
+        // The newest write stays even when it's bigger than the backlog
+        while writes.bytes > self.size && writes.kept.len() > 1 {
+            if let Some((_, oldest)) = writes.kept.pop_front() {
+                writes.bytes -= oldest.len();
+            }
+        }
+        self.end.send_replace(end);
+    }
+
+    /// Everything written from `offset` on, or None if nothing was
+    fn read_from(&self, offset: u64) -> Result<Option<Bytes>, Lagged> {
+        let writes = self.writes.lock().unwrap();
+        if offset == self.offset() {
+            return Ok(None);
+        }
+        // Readers only stop between writes, so a kept one starts at `offset`
+        let first = writes
+            .kept
+            .binary_search_by_key(&offset, |(start, _)| *start)
+            .map_err(|_| Lagged)?;
+        let mut unread = writes.kept.range(first..).map(|(_, write)| write);
+        if writes.kept.len() - first == 1 {
+            return Ok(unread.next().cloned());
+        }
+        let mut read = BytesMut::with_capacity(writes.bytes);
+        unread.for_each(|write| read.extend_from_slice(write));
+        Ok(Some(read.freeze()))
+    }
+}
+
+/// One replica's place in the backlog
+#[derive(Debug)]
+pub struct BacklogReader {
+    backlog: Arc<Backlog>,
+    offset: u64,
+    end: watch::Receiver<u64>,
+}
+
+impl BacklogReader {
+    /// Everything written since the last read, waiting for a write if there's
+    /// none yet
+    pub async fn recv(&mut self) -> Result<Bytes, Lagged> {
+        loop {
+            self.end.borrow_and_update();
+            if let Some(read) = self.backlog.read_from(self.offset)? {
+                self.offset += read.len() as u64;
+                return Ok(read);
+            }
+            // The backlog can't be dropped while this holds it, so this only
+            // returns once there's a write
+            let _ = self.end.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_backlog() {
+        let backlog = Arc::new(Backlog::new(8));
+        backlog.send(b"before");
+        let mut reader = backlog.subscribe();
+        backlog.send(b"one");
+        backlog.send(b"two");
+        assert_eq!(reader.recv().await, Ok(Bytes::from("onetwo")));
+
+        // A transaction is read whole, however long
+        backlog.hold();
+        backlog.send(b"MULTI");
+        backlog.send(b"in between");
+        backlog.send(b"EXEC");
+        assert_eq!(backlog.offset(), 12);
+        backlog.release();
+        assert_eq!(reader.recv().await, Ok(Bytes::from("MULTIin betweenEXEC")));
+        assert_eq!(backlog.offset(), 31);
+
+        // A reader waits for the next write
+        let waiting = tokio::spawn(async move { reader.recv().await });
+        tokio::task::yield_now().await;
+        backlog.send(b"three");
+        assert_eq!(waiting.await.unwrap(), Ok(Bytes::from("three")));
+
+        // Once writes it hadn't read are dropped, a reader can't go on
+        let mut reader = backlog.subscribe();
+        backlog.send(b"four");
+        backlog.send(b"five");
+        backlog.send(b"six");
+        assert_eq!(reader.recv().await, Err(Lagged));
+    }
+}
@@ -0,0 +1,144 @@
+//! Clients parked in blocking commands such as BLPOP
+//!
+//! A blocked client registers the keys it waits on and a channel for its reply.
+//! Writes that may satisfy it mark the key ready; once the write is done, the
+//! server serves the waiters of each ready key oldest first.
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::oneshot;
+
+use crate::parser::BlockingOp;
+use crate::server::RedisValue;
+
+#[derive(Debug)]
+pub struct Waiter {
+    pub keys: Vec<Bytes>,
+    pub op: BlockingOp,
+    reply: oneshot::Sender<RedisValue>,
+}
+
+impl Waiter {
+    /// Whether the client gave up, e.g. by disconnecting
+    pub fn is_gone(&self) -> bool {
+        self.reply.is_closed()
+    }
+
+    /// Hands the client its reply. It's only lost if the client gave up in the
+    /// meantime, which callers rule out with `is_gone` while holding the lock.
+    pub fn wake(self, reply: RedisValue) {
+        let _ = self.reply.send(reply);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BlockedClients {
+    waiters: HashMap<u64, Waiter>,
+    /// Ids of the clients waiting on each key, in the order they blocked
+    by_key: HashMap<Bytes, VecDeque<u64>>,
+    /// Keys with waiters that got new data since the last `take_ready`
+    ready: Vec<Bytes>,
+    next_id: u64,
+}
+
+impl BlockedClients {
+    /// Parks a client on `keys`. Returns its id and where its reply will arrive.
+    pub fn block(&mut self, keys: Vec<Bytes>, op: BlockingOp) -> (u64, oneshot::Receiver<RedisValue>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in &keys {
+            self.by_key.entry(key.clone()).or_default().push_back(id);
+        }
+        let (reply, rx) = oneshot::channel();
+        self.waiters.insert(id, Waiter { keys, op, reply });
+        (id, rx)
+    }
+
+    /// Removes a waiter that timed out or went away. Returns false if it was
+    /// already served, in which case its reply is waiting in its channel.
+    pub fn unblock(&mut self, id: u64) -> bool {
+        self.take(id).is_some()
+    }
+
+    /// Notes that `key` may now satisfy a waiter
+    pub fn signal_ready(&mut self, key: &Bytes) {
+        if self.by_key.contains_key(key) && !self.ready.contains(key) {
+            self.ready.push(key.clone());
+        }
+    }
+
+    pub fn take_ready(&mut self) -> Vec<Bytes> {
+        std::mem::take(&mut self.ready)
+    }
+
+    /// The longest waiting client on `key`
+    pub fn first(&self, key: &[u8]) -> Option<&Waiter> {
+        let id = self.by_key.get(key)?.front()?;
+        self.waiters.get(id)
+    }
+
+    /// Takes the longest waiting client on `key` out of every queue it's in
+    pub fn pop_first(&mut self, key: &[u8]) -> Option<Waiter> {
+        let id = *self.by_key.get(key)?.front()?;
+        self.take(id)
+    }
+
+    /// Ids of the clients waiting on `key`, longest waiting first
+    pub fn waiting(&self, key: &[u8]) -> Vec<u64> {
+        self.by_key.get(key).map_or_else(Vec::new, |queue| queue.iter().copied().collect())
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Waiter> {
+        self.waiters.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    /// Takes a client out of every queue it's in
+    pub fn take(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.by_key.get_mut(key) {
+                queue.retain(|waiting| *waiting != id);
+                if queue.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ListEnd;
+
+    #[test]
+    fn test_waiters_are_served_in_order() {
+        let mut blocked = BlockedClients::default();
+        let pop = BlockingOp::Pop(ListEnd::Left);
+        let (first, _rx1) = blocked.block(vec![Bytes::from("a"), Bytes::from("b")], pop.clone());
+        let (second, mut rx2) = blocked.block(vec![Bytes::from("b")], pop.clone());
+        blocked.signal_ready(&Bytes::from("b"));
+        blocked.signal_ready(&Bytes::from("b"));
+        blocked.signal_ready(&Bytes::from("nobody"));
+        assert_eq!(blocked.take_ready(), vec![Bytes::from("b")]);
+
+        let waiter = blocked.pop_first(b"b").unwrap();
+        assert_eq!(waiter.keys.len(), 2);
+        // Serving a client removes it from the other keys it waited on too
+        assert!(blocked.first(b"a").is_none());
+        assert!(!blocked.unblock(first));
+
+        blocked.pop_first(b"b").unwrap().wake(RedisValue::Int(1));
+        assert_eq!(rx2.try_recv(), Ok(RedisValue::Int(1)));
+        assert!(!blocked.unblock(second));
+        assert!(blocked.is_empty());
+    }
+}
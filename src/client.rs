@@ -0,0 +1,77 @@
+use bytes::Bytes;
+use std::collections::BTreeSet;
+
+use crate::parser::Command;
+use crate::pubsub::Messages;
+use crate::server::{Protocol, RedisValue};
+
+/// State that lives for as long as a single client connection
+#[derive(Debug, Default)]
+pub struct ClientState {
+    pub id: u64,
+    pub name: Option<Bytes>,
+    pub protocol: Protocol,
+    /// Set on a replica's connection to its master. Commands arriving on it are
+    /// replicated writes, so apart from REPLCONF GETACK they get no replies.
+    pub is_master_link: bool,
+    /// Channels the client is subscribed to
+    pub channels: BTreeSet<Bytes>,
+    /// Patterns the client is subscribed to
+    pub patterns: BTreeSet<Bytes>,
+    /// Shard channels the client is subscribed to, kept apart from the others
+    pub shard_channels: BTreeSet<Bytes>,
+    /// Where published messages arrive, once the client has subscribed
+    pub messages: Option<Messages>,
+    /// Commands queued since MULTI; None outside a transaction
+    pub transaction: Option<Transaction>,
+    /// Keys under WATCH, with the version each had when it was watched
+    pub watched: Vec<(Bytes, u64)>,
+    /// Set once AUTH succeeds. Only matters when the server has a password.
+    pub authenticated: bool,
+}
+
+/// What MULTI has queued for EXEC to run
+#[derive(Debug, Default)]
+pub struct Transaction {
+    /// Each command along with the frame it arrived in, which is what gets
+    /// propagated
+    pub queued: Vec<(Command, Bytes)>,
+    /// Set when a command couldn't be queued; EXEC then discards the lot
+    pub aborted: bool,
+}
+
+impl ClientState {
+    pub fn new(id: u64) -> ClientState {
+        ClientState {
+            id,
+            ..Default::default()
+        }
+    }
+
+    pub fn master_link(id: u64) -> ClientState {
+        ClientState {
+            id,
+            is_master_link: true,
+            ..Default::default()
+        }
+    }
+
+    /// How many channels and patterns the client is subscribed to
+    pub fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Whether the client is subscribed to anything, shard channels included
+    pub fn is_subscribed(&self) -> bool {
+        self.subscriptions() > 0 || !self.shard_channels.is_empty()
+    }
+
+    /// The next message published to the client. Never resolves for a client
+    /// that hasn't subscribed.
+    pub async fn next_message(&mut self) -> Option<RedisValue> {
+        match self.messages.as_mut() {
+            Some(messages) => messages.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+
+use crate::blocking::BlockedClients;
+use crate::hash::Hash;
+use crate::scan::ScanOrder;
+use crate::server::{random_u64, unix_time_ms};
+use crate::set::Set;
+use crate::stream::Stream;
+use crate::zset::ZSet;
+
+/// Keys RANDOMKEY picks before settling for the first live one
+const RANDOM_KEY_TRIES: usize = 100;
+
+/// A value stored under a key
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(Hash),
+    Set(Set),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// Name of the type, as reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+/// A stored value and its deadline as unix time in milliseconds, if it has one.
+/// Wall clock deadlines stay meaningful in snapshots and on other servers.
+pub type Entry = (Value, Option<u64>);
+
+/// The keyspace. Keys with a deadline are also kept in a list so the active expire
+/// cycle can walk them without scanning every key.
+#[derive(Debug, Default)]
+pub struct Db {
+    entries: HashMap<Bytes, Entry>,
+    /// The order SCAN visits keys in
+    order: ScanOrder,
+    volatile: Vec<Bytes>,
+    /// Position of each key in `volatile`
+    volatile_index: HashMap<Bytes, usize>,
+    /// Where the next active expire cycle resumes in `volatile`
+    expire_cursor: usize,
+    /// Keys removed because their deadline passed, lazily or actively
+    pub expired_keys: u64,
+    /// Clients waiting in BLPOP and friends, kept under the same lock as the data
+    /// so a push and the wake-up it causes can't be interleaved with other writes
+    pub blocked: BlockedClients,
+    /// Keys under WATCH: how many clients watch each, and how many times it
+    /// changed since the first of them did
+    watched: HashMap<Bytes, (usize, u64)>,
+}
+
+impl Db {
+    /// Looks up a key, deleting it first if its deadline has passed.
+    pub fn get(&mut self, key: &[u8]) -> Option<&Entry> {
+        if self.is_expired(key, unix_time_ms()) {
+            self.remove(key);
+            self.expired_keys += 1;
+            return None;
+        }
+        self.entries.get(key)
+    }
+
+    /// Like `get`, for changing the value in place. A caller that empties a
+    /// collection is expected to remove the key.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        if self.is_expired(key, unix_time_ms()) {
+            self.remove(key);
+            self.expired_keys += 1;
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: Bytes, value: Value, expiration: Option<u64>) {
+        self.touch(&key);
+        if matches!(value, Value::List(_) | Value::ZSet(_) | Value::Stream(_)) {
+            self.blocked.signal_ready(&key);
+        }
+        self.track(&key, expiration.is_some());
+        if !self.entries.contains_key(&key) {
+            self.order.insert(&key);
+        }
+        self.entries.insert(key, (value, expiration));
+    }
+
+    /// Changes the deadline of an existing key. Returns false if there's no such key.
+    pub fn set_expiration(&mut self, key: &[u8], expiration: Option<u64>) -> bool {
+        let key = match self.entries.get_key_value(key) {
+            Some((key, _)) => key.clone(),
+            None => return false,
+        };
+        self.track(&key, expiration.is_some());
+        self.touch(&key);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.1 = expiration;
+        }
+        true
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.untrack(key);
+        let removed = self.entries.remove(key);
+        if removed.is_some() {
+            self.order.remove(key);
+            self.touch(key);
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        for (_, version) in self.watched.values_mut() {
+            *version += 1;
+        }
+        self.entries.clear();
+        self.order.clear();
+        self.volatile.clear();
+        self.volatile_index.clear();
+        self.expire_cursor = 0;
+    }
+
+    /// Every entry, including ones that expired but haven't been removed yet
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Entry)> {
+        self.entries.iter()
+    }
+
+    /// Entries whose deadline hasn't passed at `now`
+    pub fn iter_live(&self, now_ms: u64) -> impl Iterator<Item = (&Bytes, &Entry)> {
+        self.entries
+            .iter()
+            .filter(move |(_, (_, expiration))| expiration.is_none_or(|e| e > now_ms))
+    }
+
+    /// Number of keys, including ones that expired but haven't been removed yet
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// One step of a SCAN iteration: up to about `count` entries, less any whose
+    /// deadline passed at `now`, and the cursor to continue from
+    pub fn scan(&self, cursor: u64, count: usize, now_ms: u64) -> (u64, Vec<(&Bytes, &Entry)>) {
+        let (next, keys) = self.order.scan(cursor, count);
+        let entries = keys
+            .into_iter()
+            .map(|key| (key, &self.entries[key]))
+            .filter(|(_, (_, expiration))| expiration.is_none_or(|e| e > now_ms))
+            .collect();
+        (next, entries)
+    }
+
+    /// A key picked about at random from those whose deadline hasn't passed at `now`
+    pub fn random_key(&self, now_ms: u64) -> Option<&Bytes> {
+        // Like Redis, stop picking at random after a while if most keys expired
+        for _ in 0..RANDOM_KEY_TRIES {
+            match self.order.at_or_after(random_u64()) {
+                Some(key) if !self.is_expired(key, now_ms) => return Some(key),
+                Some(_) => continue,
+                None => return None,
+            }
+        }
+        self.iter_live(now_ms).map(|(key, _)| key).next()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of keys with a deadline
+    pub fn volatile_len(&self) -> usize {
+        self.volatile.len()
+    }
+
+    /// Checks up to `count` keys with deadlines, continuing from where the last call
+    /// stopped, and deletes the expired ones. Returns (checked, deleted).
+    pub fn expire_step(&mut self, count: usize, now_ms: u64) -> (usize, usize) {
+        let mut checked = 0;
+        let mut deleted = 0;
+        while checked < count && !self.volatile.is_empty() {
+            if self.expire_cursor >= self.volatile.len() {
+                self.expire_cursor = 0;
+            }
+            let key = self.volatile[self.expire_cursor].clone();
+            checked += 1;
+            if self.is_expired(&key, now_ms) {
+                // The last key is swapped into the cursor's slot, so it's checked next
+                self.remove(&key);
+                self.expired_keys += 1;
+                deleted += 1;
+            } else {
+                self.expire_cursor += 1;
+            }
+        }
+        (checked, deleted)
+    }
+
+    /// Starts watching `key` for changes, returning its current version
+    pub fn watch(&mut self, key: &Bytes) -> u64 {
+        // A key that already expired is removed now, rather than counting as a
+        // change later
+        self.get(key);
+        let (watchers, version) = self.watched.entry(key.clone()).or_default();
+        *watchers += 1;
+        *version
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some((watchers, _)) = self.watched.get_mut(key) {
+            *watchers -= 1;
+            if *watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// The version of a watched key, which changes whenever the key does
+    pub fn version(&self, key: &[u8]) -> u64 {
+        self.watched.get(key).map_or(0, |(_, version)| *version)
+    }
+
+    /// Records that `key` changed. Writes that change a value in place call
+    /// this themselves; adding, removing and expiring keys does it here.
+    pub fn touch(&mut self, key: &[u8]) {
+        if let Some((_, version)) = self.watched.get_mut(key) {
+            *version += 1;
+        }
+    }
+
+    pub fn contains(&mut self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn is_expired(&self, key: &[u8], now_ms: u64) -> bool {
+        matches!(self.entries.get(key), Some((_, Some(expiration))) if *expiration <= now_ms)
+    }
+
+    /// Adds or removes `key` from the keys the active expire cycle walks
+    fn track(&mut self, key: &Bytes, volatile: bool) {
+        if !volatile {
+            self.untrack(key);
+        } else if !self.volatile_index.contains_key(key) {
+            self.volatile_index.insert(key.clone(), self.volatile.len());
+            self.volatile.push(key.clone());
+        }
+    }
+
+    fn untrack(&mut self, key: &[u8]) {
+        if let Some(index) = self.volatile_index.remove(key) {
+            self.volatile.swap_remove(index);
+            if let Some(moved) = self.volatile.get(index) {
+                self.volatile_index.insert(moved.clone(), index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire_step() {
+        let mut db = Db::default();
+        let past = unix_time_ms();
+        let future = past + 60_000;
+        for i in 0..10 {
+            let deadline = if i % 2 == 0 { past } else { future };
+            db.insert(Bytes::from(format!("k{}", i)), Value::String(Bytes::from(i.to_string())), Some(deadline));
+        }
+        db.insert(Bytes::from("forever"), Value::String(Bytes::new()), None);
+        assert_eq!(db.volatile_len(), 10);
+
+        let now = past + 1;
+        assert_eq!(db.expire_step(4, now).0, 4);
+        let mut deleted = 0;
+        for _ in 0..5 {
+            deleted += db.expire_step(4, now).1;
+        }
+        assert_eq!(db.len(), 6);
+        assert_eq!(db.volatile_len(), 5);
+        assert!(deleted <= 5);
+        assert_eq!(db.expired_keys, 5);
+
+        // Overwriting without a deadline stops tracking the key
+        db.insert(Bytes::from("k1"), Value::String(Bytes::new()), None);
+        assert_eq!(db.volatile_len(), 4);
+        assert!(db.get(b"k1").is_some());
+        assert!(db.set_expiration(b"k1", Some(future)));
+        assert!(!db.set_expiration(b"missing", Some(future)));
+        assert_eq!(db.volatile_len(), 5);
+    }
+
+    #[test]
+    fn test_watched_versions() {
+        let mut db = Db::default();
+        let key = Bytes::from("k");
+        assert_eq!(db.watch(&key), 0);
+        db.insert(key.clone(), Value::String(Bytes::from("v")), None);
+        db.set_expiration(&key, Some(unix_time_ms() + 60_000));
+        assert_eq!(db.version(&key), 2);
+        // Removing a missing key changes nothing
+        db.remove(b"other");
+        db.remove(&key);
+        db.remove(&key);
+        assert_eq!(db.version(&key), 3);
+        db.clear();
+        assert_eq!(db.version(&key), 4);
+
+        // The count only goes while someone's watching
+        assert_eq!(db.watch(&key), 4);
+        db.unwatch(&key);
+        assert_eq!(db.version(&key), 4);
+        db.unwatch(&key);
+        db.touch(&key);
+        assert_eq!(db.version(&key), 0);
+    }
+
+    #[test]
+    fn test_scan_and_random_key() {
+        let mut db = Db::default();
+        let now = unix_time_ms();
+        for i in 0..20 {
+            db.insert(Bytes::from(format!("k{}", i)), Value::String(Bytes::new()), None);
+        }
+        db.insert(Bytes::from("expired"), Value::String(Bytes::new()), Some(now - 1));
+        db.insert(Bytes::from("k0"), Value::String(Bytes::from("again")), None);
+        db.remove(b"k1");
+        // Expired keys still count until removed, but aren't returned
+        assert_eq!(db.len(), 20);
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = db.scan(cursor, 3, now);
+            seen.extend(batch.into_iter().map(|(key, _)| key.clone()));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(seen.len(), 19);
+        assert!(!seen.contains(&Bytes::from("expired")));
+        assert!(!seen.contains(&Bytes::from("k1")));
+
+        for _ in 0..10 {
+            let key = db.random_key(now).unwrap();
+            assert!(key != "expired" && key != "k1");
+        }
+        db.clear();
+        assert!(db.random_key(now).is_none());
+        assert_eq!(db.scan(0, 10, now), (0, Vec::new()));
+    }
+}
@@ -0,0 +1,155 @@
+/// Redis-style glob matching, as used by KEYS, SCAN MATCH and CONFIG GET
+///
+/// Supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+    let mut p = 0;
+    let mut s = 0;
+    // Where to resume after the most recent `*` if the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let mut matched = None;
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    // Collapse runs of stars
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((p, s));
+                    continue;
+                }
+                b'?' => matched = Some(p + 1),
+                b'[' => {
+                    if let Some((is_match, next)) = match_class(pattern, p, string[s], nocase) {
+                        if is_match {
+                            matched = Some(next);
+                        }
+                    } else if eq(b'[', string[s]) {
+                        // Unterminated class: treat `[` literally
+                        matched = Some(p + 1);
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if eq(pattern[p + 1], string[s]) {
+                        matched = Some(p + 2);
+                    }
+                }
+                c => {
+                    if eq(c, string[s]) {
+                        matched = Some(p + 1);
+                    }
+                }
+            }
+        }
+        match matched {
+            Some(next) => {
+                p = next;
+                s += 1;
+            }
+            None => match backtrack {
+                Some((star_p, star_s)) => {
+                    p = star_p;
+                    s = star_s + 1;
+                    backtrack = Some((star_p, star_s + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+/// Matches `c` against the character class starting at `pattern[start] == '['`.
+/// Returns whether it matched and the index just past the closing `]`, or `None`
+/// if the class is never closed.
+fn match_class(pattern: &[u8], start: usize, c: u8, nocase: bool) -> Option<(bool, usize)> {
+    let fold = |b: u8| if nocase { b.to_ascii_lowercase() } else { b };
+    let c = fold(c);
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut found = false;
+    loop {
+        let b = *pattern.get(p)?;
+        if b == b']' {
+            break;
+        }
+        if b == b'\\' && p + 1 < pattern.len() {
+            if fold(pattern[p + 1]) == c {
+                found = true;
+            }
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (mut lo, mut hi) = (fold(b), fold(pattern[p + 2]));
+            if lo > hi {
+                std::mem::swap(&mut lo, &mut hi);
+            }
+            if lo <= c && c <= hi {
+                found = true;
+            }
+            p += 3;
+        } else {
+            if fold(b) == c {
+                found = true;
+            }
+            p += 1;
+        }
+    }
+    Some((found != negate, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "anything", true),
+            ("*", "", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello world", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("user:*:name", "user:42:name", true),
+            ("user:*:name", "user:42:email", false),
+            ("*a*b", "xxaxxbxb", true),
+            ("[abc", "[abc", true),
+        ];
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes(), false),
+                *expected,
+                "{} ~ {}",
+                pattern,
+                string
+            );
+        }
+        assert!(glob_match(b"HELLO*", b"hello world", true));
+        assert!(!glob_match(b"HELLO*", b"hello world", false));
+    }
+}
@@ -0,0 +1,69 @@
+//! The hash type: fields and their values, along with the order HSCAN visits
+//! the fields in
+use bytes::Bytes;
+use std::collections::HashMap;
+
+use crate::scan::ScanOrder;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Hash {
+    fields: HashMap<Bytes, Bytes>,
+    order: ScanOrder,
+}
+
+impl Hash {
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        self.fields.get(field)
+    }
+
+    pub fn contains_key(&self, field: &[u8]) -> bool {
+        self.fields.contains_key(field)
+    }
+
+    /// Sets a field, returning the value it had
+    pub fn insert(&mut self, field: Bytes, value: Bytes) -> Option<Bytes> {
+        if !self.fields.contains_key(&field) {
+            self.order.insert(&field);
+        }
+        self.fields.insert(field, value)
+    }
+
+    /// Removes a field, returning its value
+    pub fn remove(&mut self, field: &[u8]) -> Option<Bytes> {
+        let removed = self.fields.remove(field);
+        if removed.is_some() {
+            self.order.remove(field);
+        }
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields.iter()
+    }
+
+    /// One step of an HSCAN iteration: up to about `count` fields with their
+    /// values, and the cursor to continue from
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&Bytes, &Bytes)>) {
+        let (next, fields) = self.order.scan(cursor, count);
+        let pairs = fields.into_iter().map(|field| (field, &self.fields[field])).collect();
+        (next, pairs)
+    }
+}
+
+impl FromIterator<(Bytes, Bytes)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Bytes)>>(pairs: I) -> Self {
+        let mut hash = Hash::default();
+        for (field, value) in pairs {
+            hash.insert(field, value);
+        }
+        hash
+    }
+}
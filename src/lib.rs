@@ -1,82 +1,23 @@
-use std::{
-    sync::{mpsc, Arc, Mutex},
-    thread,
-};
-
-use std::error::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-mod server;
-use server::{RedisServer, RedisValue};
-
 #[macro_use]
 pub mod macros;
 
-mod parser;
-mod log;
-use  log::Logger;
-
-
-
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
-}
-
-type Job = Box<dyn FnOnce() + Send + 'static>;
-
-#[derive(Debug, Clone)]
-pub enum PoolCreationError {
-    ZeroSize,
-}
-
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-
-        ThreadPool { workers, sender }
-    }
-    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
-        match size {
-            0 => Err(PoolCreationError::ZeroSize),
-            _ => Ok(ThreadPool::new(size)),
-        }
-    }
-
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-
-        self.sender
-            .send(job)
-            .expect("failed to send job to worker pool");
-    }
-}
-
-pub struct Worker {
-    id: usize,
-    thread: thread::JoinHandle<()>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-
-            println!("Worker {id} got a job; executing.");
-
-            job();
-        });
-
-        Worker { id, thread }
-    }
-}
+pub mod aof;
+pub mod backlog;
+pub mod blocking;
+pub mod client;
+pub mod cluster;
+pub mod db;
+pub mod glob;
+pub mod hash;
+pub mod log;
+pub mod lua;
+pub mod parser;
+pub mod pubsub;
+pub mod rdb;
+pub mod scan;
+pub mod scripting;
+pub mod server;
+pub mod set;
+pub mod sha1;
+pub mod stream;
+pub mod zset;
@@ -5,6 +5,12 @@ pub struct Logger {
     pub kv: HashMap<String, String>,
 }
 
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Logger {
     pub fn new() -> Self {
         Logger {
@@ -0,0 +1,210 @@
+//! A Lua 5.1 interpreter for the scripts clients send with EVAL. Scripts compile
+//! to a syntax tree that can be cached and shared between connections; the values
+//! a script creates only live as long as its run.
+use bytes::Bytes;
+use std::sync::Arc;
+
+mod ast;
+mod interpreter;
+mod json;
+mod lexer;
+mod parser;
+mod pattern;
+mod stdlib;
+mod value;
+
+pub use ast::FunctionProto;
+pub use interpreter::Runtime;
+pub use value::{Table, TableRef, Value};
+
+#[derive(Debug, Clone)]
+pub enum LuaError {
+    /// An error raised by the script or the library, which pcall can catch
+    Error(Value),
+    /// The run was stopped from outside, by the time limit or SCRIPT KILL
+    Interrupted(String),
+}
+
+/// What a running script can reach of the server
+pub trait Host {
+    /// Runs a command for redis.call and redis.pcall. An error reply comes back
+    /// as a table with an `err` field.
+    fn call(&mut self, args: Vec<Bytes>) -> Value;
+
+    /// Writes a line for redis.log at one of the LOG_* levels
+    fn log(&mut self, level: i64, message: &str);
+
+    /// Called every so often while the script runs; an error stops it
+    fn check(&mut self) -> Result<(), LuaError>;
+}
+
+/// Compiles a chunk. Errors name it as Lua does, e.g. `user_script:1: ...`.
+pub fn compile(chunk: &str, source: &[u8]) -> Result<Arc<FunctionProto>, String> {
+    parser::parse(chunk, source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every command with its arguments joined by spaces
+    struct Echo {
+        calls: usize,
+    }
+
+    impl Host for Echo {
+        fn call(&mut self, args: Vec<Bytes>) -> Value {
+            self.calls += 1;
+            let joined = args.iter().map(|arg| String::from_utf8_lossy(arg)).collect::<Vec<_>>().join(" ");
+            if joined.starts_with("fail") {
+                let mut table = Table::default();
+                table.set_str("err", Value::str("ERR failed"));
+                return Value::table(table);
+            }
+            Value::str(&joined)
+        }
+
+        fn log(&mut self, _: i64, _: &str) {}
+
+        fn check(&mut self) -> Result<(), LuaError> {
+            if self.calls > 100 {
+                return Err(LuaError::Interrupted("too long".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    fn run(source: &str) -> Result<Vec<Value>, LuaError> {
+        let proto = compile("user_script", source.as_bytes()).map_err(|e| LuaError::Error(Value::str(&e)))?;
+        let mut host = Echo { calls: 0 };
+        let mut runtime = Runtime::new("user_script", &mut host);
+        runtime.run(&proto)
+    }
+
+    fn eval(source: &str) -> Value {
+        run(source).unwrap().into_iter().next().unwrap_or_default()
+    }
+
+    fn error(source: &str) -> String {
+        match run(source) {
+            Err(LuaError::Error(Value::String(message))) => String::from_utf8_lossy(&message).into_owned(),
+            other => panic!("expected an error from {:?}, got {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(eval("return 1 + 2 * 3 ^ 2"), Value::Number(19.0));
+        assert_eq!(eval("return 7 % -3"), Value::Number(-2.0));
+        assert_eq!(eval("return '10' + 5"), Value::Number(15.0));
+        assert_eq!(eval("return 1 .. 2 .. 'x'"), Value::str("12x"));
+        assert_eq!(eval("return 10 / 4"), Value::Number(2.5));
+        assert_eq!(eval("return 'a' < 'b' and not nil"), Value::Boolean(true));
+        assert_eq!(eval("return nil or false or 'x'"), Value::str("x"));
+        assert_eq!(eval("return #'hello' + #{1, 2, 3}"), Value::Number(8.0));
+        assert_eq!(eval("return -2 ^ 2"), Value::Number(-4.0));
+        assert_eq!(eval("return tostring(1e15) .. ' ' .. 0.1"), Value::str("1e+15 0.1"));
+    }
+
+    #[test]
+    fn test_statements() {
+        let source = r#"
+            local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+            local total = 0
+            for i = 10, 1, -2 do total = total + i end
+            local t = {a = 1, b = 2, 'x', 'y'}
+            local keys = 0
+            for k, v in pairs(t) do keys = keys + 1 end
+            local n = 0
+            repeat n = n + 1 until n >= 3
+            while true do n = n + 1 if n > 5 then break end end
+            return fib(15), total, keys, n, select('#', 1, nil, 3)
+        "#;
+        let values = run(source).unwrap();
+        let numbers = [610.0, 30.0, 4.0, 6.0, 3.0].map(Value::Number);
+        assert_eq!(values, numbers.to_vec());
+
+        // Each iteration gets its own variable to capture
+        let source = r#"
+            local fs = {}
+            for i = 1, 3 do fs[i] = function() return i end end
+            local function counter() local c = 0 return function() c = c + 1 return c end end
+            local next_id = counter()
+            next_id() next_id()
+            return fs[1]() + fs[3](), next_id()
+        "#;
+        assert_eq!(run(source).unwrap(), vec![Value::Number(4.0), Value::Number(3.0)]);
+
+        let source = "local a, b, c = (function(...) return ... end)(1, 2) return c, b, {...}";
+        assert_eq!(run(source).unwrap()[..2], [Value::Nil, Value::Number(2.0)]);
+    }
+
+    #[test]
+    fn test_libraries() {
+        assert_eq!(eval("return string.format('%5.2f|%-3d|%x|%s|%q', 3.14159, 7, 255, 'a', 'b\"')"), Value::str(" 3.14|7  |ff|a|\"b\\\"\""));
+        assert_eq!(eval("return ('a,b,,c'):gsub(',', ';')"), Value::str("a;b;;c"));
+        assert_eq!(eval("return string.gsub('hello world', '(%w+)', '<%1>')"), Value::str("<hello> <world>"));
+        assert_eq!(eval("local s = '' for w in string.gmatch('one two', '%a+') do s = s .. w:upper() end return s"), Value::str("ONETWO"));
+        assert_eq!(eval("return select(2, string.find('key:42', ':(%d+)'))"), Value::Number(6.0));
+        assert_eq!(eval("return string.match('key:42', ':(%d+)') + 1"), Value::Number(43.0));
+        assert_eq!(eval("return string.sub('hello', -3, -2) .. string.rep('ab', 2) .. string.byte('A')"), Value::str("llabab65"));
+        assert_eq!(eval("local t = {3, 1, 2} table.sort(t) return table.concat(t, ',')"), Value::str("1,2,3"));
+        assert_eq!(eval("local t = {3, 1, 2} table.sort(t, function(a, b) return a > b end) return table.concat(t)"), Value::str("321"));
+        assert_eq!(eval("local t = {1, 2} table.insert(t, 1, 0) table.remove(t) return table.concat(t)"), Value::str("01"));
+        assert_eq!(eval("return cjson.encode({1, 'a', {x = true}})"), Value::str("[1,\"a\",{\"x\":true}]"));
+        assert_eq!(eval("return cjson.decode('{\"a\":[1,2,{\"b\":null}]}').a[2]"), Value::Number(2.0));
+        assert_eq!(eval("return bit.band(0xff, 0x0f) + bit.lshift(1, 4)"), Value::Number(31.0));
+        assert_eq!(eval("return math.max(1, 5, 3) + math.floor(2.7)"), Value::Number(7.0));
+        assert_eq!(eval("return redis.sha1hex('')"), Value::str("da39a3ee5e6b4b0d3255bfef95601890afd80709"));
+        assert_eq!(eval("return tonumber('ff', 16) + tonumber('0x10')"), Value::Number(271.0));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("local x = nil\nreturn x.y"), "user_script:2: attempt to index local 'x' (a nil value)");
+        assert_eq!(error("return nosuch"), "user_script:1: Script attempted to access nonexistent global variable 'nosuch'");
+        assert_eq!(error("x = 1"), "user_script:1: Attempt to modify a readonly table");
+        assert_eq!(error("string.foo = 1"), "user_script:1: Attempt to modify a readonly table");
+        assert_eq!(error("return 1 + {}"), "user_script:1: attempt to perform arithmetic on a table value");
+        assert_eq!(error("error('boom')"), "user_script:1: boom");
+        assert_eq!(error("error('boom', 0)"), "boom");
+        assert_eq!(error("local t = {} t.f()"), "user_script:1: attempt to call field 'f' (a nil value)");
+        assert_eq!(error("return 1 < 'x'"), "user_script:1: attempt to compare number with string");
+        assert_eq!(error("string.rep()"), "user_script:1: bad argument #1 to 'rep' (string expected, got no value)");
+        assert_eq!(error("return 1 +"), "user_script:1: unexpected symbol near '<eof>'");
+        assert_eq!(error("if true then"), "user_script:1: 'end' expected near '<eof>'");
+        assert_eq!(error("for i = 1, 2 do\nlocal x = 1"), "user_script:2: 'end' expected (to close 'for' at line 1) near '<eof>'");
+        assert_eq!(error("return 'abc"), "user_script:1: unfinished string near ''abc'");
+
+        // Scripts run on a thread with room for deep recursion
+        let overflow = std::thread::Builder::new()
+            .stack_size(256 << 20)
+            .spawn(|| error("local function f() return f() + 1 end return f()"))
+            .unwrap();
+        assert_eq!(overflow.join().unwrap(), "user_script:1: stack overflow");
+
+        let caught = run("local ok, err = pcall(error, {code = 1}) return ok, err.code").unwrap();
+        assert_eq!(caught, vec![Value::Boolean(false), Value::Number(1.0)]);
+        assert_eq!(eval("return select(2, pcall(error, 'x'))"), Value::str("user_script:1: x"));
+    }
+
+    #[test]
+    fn test_host_calls() {
+        assert_eq!(eval("return redis.call('get', 'key', 1.5)"), Value::str("get key 1.5"));
+
+        // redis.call raises error replies, redis.pcall returns them
+        let raised = run("local ok, err = pcall(redis.call, 'fail') return ok, err.err").unwrap();
+        assert_eq!(raised, vec![Value::Boolean(false), Value::str("ERR failed")]);
+        assert_eq!(eval("return redis.pcall('fail').err"), Value::str("ERR failed"));
+        assert_eq!(
+            eval("return redis.pcall('get', {}).err"),
+            Value::str("ERR Lua redis lib command arguments must be strings or integers")
+        );
+
+        // The host can stop a script, and pcall doesn't catch that
+        match run("while true do pcall(redis.call, 'ping') end") {
+            Err(LuaError::Interrupted(message)) => assert_eq!(message, "too long"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! The syntax tree the parser builds. Variables are already resolved: locals to
+//! slots in their function's frame, captured ones to upvalues, the rest to globals.
+use bytes::Bytes;
+use std::sync::Arc;
+
+/// A function body, or the whole chunk, which is a function taking `...`
+#[derive(Debug)]
+pub struct FunctionProto {
+    /// How many parameters there are; they take the first slots
+    pub params: usize,
+    pub is_vararg: bool,
+    /// Names of the local slots, for error messages
+    pub locals: Vec<String>,
+    /// Where each captured variable comes from in the enclosing function
+    pub upvalues: Vec<Upvalue>,
+    pub upvalue_names: Vec<String>,
+    pub body: Block,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upvalue {
+    /// A local of the enclosing function
+    Local(usize),
+    /// A variable the enclosing function captured itself
+    Upvalue(usize),
+}
+
+pub type Block = Vec<Stat>;
+
+#[derive(Debug)]
+pub struct Stat {
+    pub line: u32,
+    pub kind: StatKind,
+}
+
+#[derive(Debug)]
+pub enum StatKind {
+    Local {
+        slots: Vec<usize>,
+        values: Vec<Expr>,
+    },
+    LocalFunction {
+        slot: usize,
+        function: Arc<FunctionProto>,
+    },
+    Assign {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+    },
+    Call(Expr),
+    Do(Block),
+    While {
+        condition: Expr,
+        body: Block,
+    },
+    Repeat {
+        body: Block,
+        condition: Expr,
+    },
+    If {
+        branches: Vec<(Expr, Block)>,
+        otherwise: Option<Block>,
+    },
+    NumericFor {
+        slot: usize,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    GenericFor {
+        slots: Vec<usize>,
+        values: Vec<Expr>,
+        body: Block,
+    },
+    Return(Vec<Expr>),
+    Break,
+}
+
+#[derive(Debug)]
+pub enum Expr {
+    Nil,
+    True,
+    False,
+    Number(f64),
+    String(Bytes),
+    VarArg,
+    Function(Arc<FunctionProto>),
+    Table(Vec<Field>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Local(usize),
+    Upvalue(usize),
+    Global(Bytes),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    /// `object:name(args)`
+    Method(Box<Expr>, Bytes, Vec<Expr>),
+    /// An expression in parentheses, which is cut down to a single value
+    Paren(Box<Expr>),
+}
+
+impl Expr {
+    /// Whether the expression can produce several values, when last in a list
+    pub fn is_multi(&self) -> bool {
+        matches!(self, Expr::Call(..) | Expr::Method(..) | Expr::VarArg)
+    }
+}
+
+#[derive(Debug)]
+pub enum Field {
+    /// A value for the next array position
+    Positional(Expr),
+    Keyed(Expr, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+}
@@ -0,0 +1,661 @@
+//! Runs the syntax tree. Every local lives in its own cell, so closures share
+//! the variables they capture with the function that declared them.
+use bytes::{Bytes, BytesMut};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use super::ast::{BinOp, Block, Expr, Field, FunctionProto, StatKind, UnOp, Upvalue};
+use super::value::{self, new_table, Cell, Closure, Function, Returns, Table, TableRef, Value};
+use super::{stdlib, Host, LuaError};
+
+/// How deeply functions may call each other before it's a stack overflow
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// How many statements and loop iterations run between calls to `Host::check`
+const CHECK_INTERVAL: u32 = 1000;
+
+/// How many `__index` and `__newindex` tables are followed before giving up
+const MAX_META_CHAIN: usize = 100;
+
+/// How a block finished
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+}
+
+/// A running call of a Lua function
+struct Frame {
+    slots: Vec<Cell>,
+    varargs: Vec<Value>,
+    closure: Rc<Closure>,
+}
+
+/// Where an assignment stores its value
+enum Place {
+    Local(usize),
+    Upvalue(usize),
+    Index(Value, Value),
+}
+
+fn cell(value: Value) -> Cell {
+    Rc::new(RefCell::new(value))
+}
+
+/// A handler in the metatable of `table`, or nil
+pub(super) fn metamethod(table: &Table, name: &str) -> Value {
+    table.metatable.as_ref().map_or(Value::Nil, |metatable| metatable.borrow().get_str(name))
+}
+
+/// The first of a list of values, or nil
+pub(super) fn first(values: Vec<Value>) -> Value {
+    values.into_iter().next().unwrap_or_default()
+}
+
+/// The state of one script run: its globals and the host it calls back into
+pub struct Runtime<'h> {
+    pub(super) globals: TableRef,
+    /// The string library, where methods called on strings are found
+    pub(super) strings: TableRef,
+    host: &'h mut dyn Host,
+    chunk: String,
+    ticks: u32,
+    depth: usize,
+    /// The line of the statement running, for error messages
+    line: u32,
+}
+
+impl Drop for Runtime<'_> {
+    fn drop(&mut self) {
+        value::sweep();
+    }
+}
+
+impl<'h> Runtime<'h> {
+    /// A runtime with the standard libraries loaded. Errors are reported as
+    /// coming from `chunk`.
+    pub fn new(chunk: &str, host: &'h mut dyn Host) -> Runtime<'h> {
+        let mut runtime = Runtime {
+            globals: new_table(Table::default()),
+            strings: new_table(Table::default()),
+            host,
+            chunk: chunk.to_string(),
+            ticks: 0,
+            depth: 0,
+            line: 0,
+        };
+        stdlib::open(&mut runtime);
+        runtime.globals.borrow_mut().readonly = true;
+        runtime
+    }
+
+    pub fn host(&mut self) -> &mut dyn Host {
+        &mut *self.host
+    }
+
+    /// The line the script was at when it last stopped or failed
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn global(&self, name: &str) -> Value {
+        self.globals.borrow().get_str(name)
+    }
+
+    /// Sets a global, even though scripts themselves can't
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().set_str(name, value);
+    }
+
+    /// Runs a compiled chunk
+    pub fn run(&mut self, proto: &Arc<FunctionProto>) -> Returns {
+        let chunk = Value::Function(Function::Lua(Rc::new(Closure {
+            proto: proto.clone(),
+            upvalues: Vec::new(),
+        })));
+        self.call(&chunk, Vec::new())
+    }
+
+    /// An error whose message says where in the script it happened
+    pub fn error(&self, message: impl Display) -> LuaError {
+        let message = format!("{}:{}: {}", self.chunk, self.line, message);
+        LuaError::Error(Value::String(Bytes::from(message)))
+    }
+
+    pub(super) fn set_line(&mut self, line: u32) {
+        self.line = line;
+    }
+
+    /// Counts a step of work, checking in with the host every so often
+    pub(super) fn tick(&mut self) -> Result<(), LuaError> {
+        self.ticks += 1;
+        if self.ticks >= CHECK_INTERVAL {
+            self.ticks = 0;
+            self.host.check()?;
+        }
+        Ok(())
+    }
+
+    pub fn call(&mut self, function: &Value, args: Vec<Value>) -> Returns {
+        match function {
+            Value::Function(Function::Lua(closure)) => self.call_lua(closure.clone(), args),
+            Value::Function(Function::Native(native)) => {
+                let native = native.clone();
+                (native.call)(self, args)
+            }
+            Value::Table(table) => {
+                let handler = metamethod(&table.borrow(), "__call");
+                if matches!(handler, Value::Nil) {
+                    return Err(self.error("attempt to call a table value"));
+                }
+                let mut all = Vec::with_capacity(args.len() + 1);
+                all.push(function.clone());
+                all.extend(args);
+                self.call(&handler, all)
+            }
+            _ => Err(self.error(format!("attempt to call a {} value", function.type_name()))),
+        }
+    }
+
+    fn call_lua(&mut self, closure: Rc<Closure>, mut args: Vec<Value>) -> Returns {
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(self.error("stack overflow"));
+        }
+        let proto = closure.proto.clone();
+        let varargs = if proto.is_vararg && args.len() > proto.params {
+            args.split_off(proto.params)
+        } else {
+            Vec::new()
+        };
+        args.resize(proto.params, Value::Nil);
+        let mut slots: Vec<Cell> = args.into_iter().map(cell).collect();
+        slots.extend((proto.params..proto.locals.len()).map(|_| cell(Value::Nil)));
+        let mut frame = Frame {
+            slots,
+            varargs,
+            closure,
+        };
+        let line = self.line;
+        self.depth += 1;
+        let flow = self.exec_block(&mut frame, &proto.body);
+        self.depth -= 1;
+        // On failure the line stays where the error was raised
+        let flow = flow?;
+        self.line = line;
+        match flow {
+            Flow::Return(values) => Ok(values),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// `object[key]`, following `__index`
+    pub fn index(&mut self, object: &Value, key: &Value) -> Result<Value, LuaError> {
+        let mut object = object.clone();
+        for _ in 0..MAX_META_CHAIN {
+            let handler = match &object {
+                Value::Table(table) => {
+                    let table = table.borrow();
+                    let value = table.get(key);
+                    if !matches!(value, Value::Nil) {
+                        return Ok(value);
+                    }
+                    match metamethod(&table, "__index") {
+                        Value::Nil => return Ok(Value::Nil),
+                        handler => handler,
+                    }
+                }
+                Value::String(_) => return Ok(self.strings.borrow().get(key)),
+                _ => return Err(self.error(format!("attempt to index a {} value", object.type_name()))),
+            };
+            if let Value::Function(_) = handler {
+                return Ok(first(self.call(&handler, vec![object, key.clone()])?));
+            }
+            object = handler;
+        }
+        Err(self.error("loop in gettable"))
+    }
+
+    /// `object[key] = value`, following `__newindex`
+    pub fn set_index(&mut self, object: &Value, key: Value, value: Value) -> Result<(), LuaError> {
+        let mut object = object.clone();
+        for _ in 0..MAX_META_CHAIN {
+            let handler = match &object {
+                Value::Table(table) => {
+                    let mut table = table.borrow_mut();
+                    if table.readonly {
+                        return Err(self.error("Attempt to modify a readonly table"));
+                    }
+                    let handler = match table.get(&key) {
+                        Value::Nil => metamethod(&table, "__newindex"),
+                        _ => Value::Nil,
+                    };
+                    if matches!(handler, Value::Nil) {
+                        return table.set(key, value).map_err(|message| self.error(message));
+                    }
+                    handler
+                }
+                _ => return Err(self.error(format!("attempt to index a {} value", object.type_name()))),
+            };
+            if let Value::Function(_) = handler {
+                self.call(&handler, vec![object, key, value])?;
+                return Ok(());
+            }
+            object = handler;
+        }
+        Err(self.error("loop in settable"))
+    }
+
+    /// The `<` operator
+    pub(super) fn less_than(&self, a: &Value, b: &Value) -> Result<bool, LuaError> {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) => Ok(x < y),
+            (Value::String(x), Value::String(y)) => Ok(x < y),
+            _ => Err(self.compare_error(a, b)),
+        }
+    }
+
+    /// The `<=` operator
+    fn less_equal(&self, a: &Value, b: &Value) -> Result<bool, LuaError> {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) => Ok(x <= y),
+            (Value::String(x), Value::String(y)) => Ok(x <= y),
+            _ => Err(self.compare_error(a, b)),
+        }
+    }
+
+    fn compare_error(&self, a: &Value, b: &Value) -> LuaError {
+        if a.type_name() == b.type_name() {
+            self.error(format!("attempt to compare two {} values", a.type_name()))
+        } else {
+            self.error(format!("attempt to compare {} with {}", a.type_name(), b.type_name()))
+        }
+    }
+
+    fn exec_block(&mut self, frame: &mut Frame, block: &Block) -> Result<Flow, LuaError> {
+        for stat in block {
+            self.line = stat.line;
+            self.tick()?;
+            match &stat.kind {
+                StatKind::Local { slots, values } => {
+                    let mut values = self.eval_all(frame, values)?;
+                    values.resize(slots.len(), Value::Nil);
+                    // A fresh cell each time, so closures made in a loop each get their own
+                    for (slot, value) in slots.iter().zip(values) {
+                        frame.slots[*slot] = cell(value);
+                    }
+                }
+                StatKind::LocalFunction { slot, function } => {
+                    frame.slots[*slot] = cell(Value::Nil);
+                    let closure = self.closure(frame, function);
+                    *frame.slots[*slot].borrow_mut() = closure;
+                }
+                StatKind::Assign { targets, values } => self.assign(frame, targets, values)?,
+                StatKind::Call(call) => {
+                    self.eval_multi(frame, call)?;
+                }
+                StatKind::Do(body) => match self.exec_block(frame, body)? {
+                    Flow::Normal => {}
+                    flow => return Ok(flow),
+                },
+                StatKind::While { condition, body } => loop {
+                    self.tick()?;
+                    if !self.eval(frame, condition)?.truthy() {
+                        break;
+                    }
+                    match self.exec_block(frame, body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                },
+                StatKind::Repeat { body, condition } => loop {
+                    self.tick()?;
+                    match self.exec_block(frame, body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                    if self.eval(frame, condition)?.truthy() {
+                        break;
+                    }
+                },
+                StatKind::If { branches, otherwise } => {
+                    let mut taken = otherwise.as_ref();
+                    for (condition, body) in branches {
+                        if self.eval(frame, condition)?.truthy() {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    if let Some(body) = taken {
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            flow => return Ok(flow),
+                        }
+                    }
+                }
+                StatKind::NumericFor {
+                    slot,
+                    start,
+                    limit,
+                    step,
+                    body,
+                } => {
+                    let start = self.eval(frame, start)?.to_number();
+                    let start = start.ok_or_else(|| self.error("'for' initial value must be a number"))?;
+                    let limit = self.eval(frame, limit)?.to_number();
+                    let limit = limit.ok_or_else(|| self.error("'for' limit must be a number"))?;
+                    let step = match step {
+                        Some(step) => self.eval(frame, step)?.to_number(),
+                        None => Some(1.0),
+                    };
+                    let step = step.ok_or_else(|| self.error("'for' step must be a number"))?;
+                    let mut i = start;
+                    while if step > 0.0 { i <= limit } else { i >= limit } {
+                        self.tick()?;
+                        frame.slots[*slot] = cell(Value::Number(i));
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            Flow::Break => break,
+                            flow => return Ok(flow),
+                        }
+                        i += step;
+                    }
+                }
+                StatKind::GenericFor { slots, values, body } => {
+                    let mut values = self.eval_all(frame, values)?.into_iter();
+                    let iterator = values.next().unwrap_or_default();
+                    let state = values.next().unwrap_or_default();
+                    let mut control = values.next().unwrap_or_default();
+                    loop {
+                        self.tick()?;
+                        let mut results = self.call(&iterator, vec![state.clone(), control.clone()])?;
+                        results.resize(slots.len(), Value::Nil);
+                        if matches!(results[0], Value::Nil) {
+                            break;
+                        }
+                        control = results[0].clone();
+                        for (slot, value) in slots.iter().zip(results) {
+                            frame.slots[*slot] = cell(value);
+                        }
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            Flow::Break => break,
+                            flow => return Ok(flow),
+                        }
+                    }
+                }
+                StatKind::Return(values) => return Ok(Flow::Return(self.eval_all(frame, values)?)),
+                StatKind::Break => return Ok(Flow::Break),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn assign(&mut self, frame: &mut Frame, targets: &[Expr], values: &[Expr]) -> Result<(), LuaError> {
+        // Tables and keys on the left are evaluated before the values
+        let mut places = Vec::with_capacity(targets.len());
+        for target in targets {
+            places.push(match target {
+                Expr::Local(slot) => Place::Local(*slot),
+                Expr::Upvalue(i) => Place::Upvalue(*i),
+                Expr::Global(name) => Place::Index(Value::Table(self.globals.clone()), Value::String(name.clone())),
+                Expr::Index(object, key) => {
+                    let table = self.eval(frame, object)?;
+                    if !matches!(table, Value::Table(_)) {
+                        return Err(self.error(format!("attempt to index {}", self.describe(frame, object, &table))));
+                    }
+                    Place::Index(table, self.eval(frame, key)?)
+                }
+                _ => return Err(self.error("cannot assign to this expression")),
+            });
+        }
+        let mut values = self.eval_all(frame, values)?;
+        values.resize(places.len(), Value::Nil);
+        for (place, value) in places.into_iter().zip(values) {
+            match place {
+                Place::Local(slot) => *frame.slots[slot].borrow_mut() = value,
+                Place::Upvalue(i) => *frame.closure.upvalues[i].borrow_mut() = value,
+                Place::Index(table, key) => self.set_index(&table, key, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates a list of expressions, expanding the last if it has several values
+    fn eval_all(&mut self, frame: &Frame, exprs: &[Expr]) -> Returns {
+        let mut values = Vec::with_capacity(exprs.len());
+        for (i, expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() && expr.is_multi() {
+                values.extend(self.eval_multi(frame, expr)?);
+            } else {
+                values.push(self.eval(frame, expr)?);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Evaluates an expression keeping all its values
+    fn eval_multi(&mut self, frame: &Frame, expr: &Expr) -> Returns {
+        match expr {
+            Expr::Call(callee, args) => {
+                let function = self.eval(frame, callee)?;
+                let args = self.eval_all(frame, args)?;
+                if !self.callable(&function) {
+                    return Err(self.error(format!("attempt to call {}", self.describe(frame, callee, &function))));
+                }
+                self.call(&function, args)
+            }
+            Expr::Method(object, name, args) => {
+                let object = self.eval(frame, object)?;
+                let key = Value::String(name.clone());
+                let function = match &object {
+                    Value::Table(_) | Value::String(_) => self.index(&object, &key)?,
+                    _ => {
+                        return Err(self.error(format!(
+                            "attempt to index a {} value",
+                            object.type_name()
+                        )))
+                    }
+                };
+                if !self.callable(&function) {
+                    return Err(self.error(format!(
+                        "attempt to call method '{}' (a {} value)",
+                        String::from_utf8_lossy(name),
+                        function.type_name()
+                    )));
+                }
+                let mut all = vec![object];
+                all.extend(self.eval_all(frame, args)?);
+                self.call(&function, all)
+            }
+            Expr::VarArg => Ok(frame.varargs.clone()),
+            _ => Ok(vec![self.eval(frame, expr)?]),
+        }
+    }
+
+    fn eval(&mut self, frame: &Frame, expr: &Expr) -> Result<Value, LuaError> {
+        Ok(match expr {
+            Expr::Nil => Value::Nil,
+            Expr::True => Value::Boolean(true),
+            Expr::False => Value::Boolean(false),
+            Expr::Number(n) => Value::Number(*n),
+            Expr::String(s) => Value::String(s.clone()),
+            Expr::VarArg => frame.varargs.first().cloned().unwrap_or_default(),
+            Expr::Function(proto) => self.closure(frame, proto),
+            Expr::Table(fields) => self.table(frame, fields)?,
+            Expr::Binary(op, left, right) => {
+                let a = self.eval(frame, left)?;
+                let b = self.eval(frame, right)?;
+                self.binary(frame, *op, a, b, left, right)?
+            }
+            Expr::Unary(op, operand) => {
+                let value = self.eval(frame, operand)?;
+                match op {
+                    UnOp::Not => Value::Boolean(!value.truthy()),
+                    UnOp::Neg => match value.to_number() {
+                        Some(n) => Value::Number(-n),
+                        None => {
+                            let what = self.describe(frame, operand, &value);
+                            return Err(self.error(format!("attempt to perform arithmetic on {}", what)));
+                        }
+                    },
+                    UnOp::Len => match &value {
+                        Value::String(s) => Value::Number(s.len() as f64),
+                        Value::Table(table) => Value::Number(table.borrow().len() as f64),
+                        _ => {
+                            let what = self.describe(frame, operand, &value);
+                            return Err(self.error(format!("attempt to get length of {}", what)));
+                        }
+                    },
+                }
+            }
+            Expr::And(left, right) => {
+                let value = self.eval(frame, left)?;
+                if value.truthy() {
+                    self.eval(frame, right)?
+                } else {
+                    value
+                }
+            }
+            Expr::Or(left, right) => {
+                let value = self.eval(frame, left)?;
+                if value.truthy() {
+                    value
+                } else {
+                    self.eval(frame, right)?
+                }
+            }
+            Expr::Local(slot) => frame.slots[*slot].borrow().clone(),
+            Expr::Upvalue(i) => frame.closure.upvalues[*i].borrow().clone(),
+            Expr::Global(name) => {
+                let key = Value::String(name.clone());
+                let value = self.globals.borrow().get(&key);
+                match value {
+                    Value::Nil => self.index(&Value::Table(self.globals.clone()), &key)?,
+                    value => value,
+                }
+            }
+            Expr::Index(object, key) => {
+                let table = self.eval(frame, object)?;
+                let key = self.eval(frame, key)?;
+                if !matches!(table, Value::Table(_) | Value::String(_)) {
+                    return Err(self.error(format!("attempt to index {}", self.describe(frame, object, &table))));
+                }
+                self.index(&table, &key)?
+            }
+            Expr::Call(..) | Expr::Method(..) => first(self.eval_multi(frame, expr)?),
+            Expr::Paren(inner) => self.eval(frame, inner)?,
+        })
+    }
+
+    fn binary(&mut self, frame: &Frame, op: BinOp, a: Value, b: Value, left: &Expr, right: &Expr) -> Result<Value, LuaError> {
+        let (x, y) = match op {
+            BinOp::Eq => return Ok(Value::Boolean(a.raw_equals(&b))),
+            BinOp::Ne => return Ok(Value::Boolean(!a.raw_equals(&b))),
+            BinOp::Lt => return Ok(Value::Boolean(self.less_than(&a, &b)?)),
+            BinOp::Le => return Ok(Value::Boolean(self.less_equal(&a, &b)?)),
+            BinOp::Gt => return Ok(Value::Boolean(self.less_than(&b, &a)?)),
+            BinOp::Ge => return Ok(Value::Boolean(self.less_equal(&b, &a)?)),
+            BinOp::Concat => {
+                return match (a.to_bytes(), b.to_bytes()) {
+                    (Some(x), Some(y)) => {
+                        let mut joined = BytesMut::with_capacity(x.len() + y.len());
+                        joined.extend_from_slice(&x);
+                        joined.extend_from_slice(&y);
+                        Ok(Value::String(joined.freeze()))
+                    }
+                    (None, _) => Err(self.error(format!("attempt to concatenate {}", self.describe(frame, left, &a)))),
+                    _ => Err(self.error(format!("attempt to concatenate {}", self.describe(frame, right, &b)))),
+                };
+            }
+            _ => match (a.to_number(), b.to_number()) {
+                (Some(x), Some(y)) => (x, y),
+                (None, _) => {
+                    let what = self.describe(frame, left, &a);
+                    return Err(self.error(format!("attempt to perform arithmetic on {}", what)));
+                }
+                _ => {
+                    let what = self.describe(frame, right, &b);
+                    return Err(self.error(format!("attempt to perform arithmetic on {}", what)));
+                }
+            },
+        };
+        Ok(Value::Number(match op {
+            BinOp::Add => x + y,
+            BinOp::Sub => x - y,
+            BinOp::Mul => x * y,
+            BinOp::Div => x / y,
+            BinOp::Mod => x - (x / y).floor() * y,
+            _ => x.powf(y),
+        }))
+    }
+
+    /// Names the variable an expression reads for error messages, like Lua's
+    /// `global 'x' (a nil value)`
+    fn describe(&self, frame: &Frame, expr: &Expr, value: &Value) -> String {
+        let kind = value.type_name();
+        let (scope, name) = match expr {
+            Expr::Global(name) => ("global", String::from_utf8_lossy(name).into_owned()),
+            Expr::Local(slot) => ("local", frame.closure.proto.locals[*slot].clone()),
+            Expr::Upvalue(i) => ("upvalue", frame.closure.proto.upvalue_names[*i].clone()),
+            Expr::Index(_, key) => match key.as_ref() {
+                Expr::String(name) => ("field", String::from_utf8_lossy(name).into_owned()),
+                _ => return format!("a {} value", kind),
+            },
+            _ => return format!("a {} value", kind),
+        };
+        format!("{} '{}' (a {} value)", scope, name, kind)
+    }
+
+    fn callable(&self, value: &Value) -> bool {
+        match value {
+            Value::Function(_) => true,
+            Value::Table(table) => !matches!(metamethod(&table.borrow(), "__call"), Value::Nil),
+            _ => false,
+        }
+    }
+
+    fn closure(&self, frame: &Frame, proto: &Arc<FunctionProto>) -> Value {
+        let upvalues = proto
+            .upvalues
+            .iter()
+            .map(|upvalue| match upvalue {
+                Upvalue::Local(slot) => value::track_cell(&frame.slots[*slot]),
+                Upvalue::Upvalue(i) => frame.closure.upvalues[*i].clone(),
+            })
+            .collect();
+        Value::Function(Function::Lua(Rc::new(Closure {
+            proto: proto.clone(),
+            upvalues,
+        })))
+    }
+
+    fn table(&mut self, frame: &Frame, fields: &[Field]) -> Result<Value, LuaError> {
+        let mut table = Table::default();
+        let mut n = 0;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                Field::Positional(expr) if i + 1 == fields.len() && expr.is_multi() => {
+                    for value in self.eval_multi(frame, expr)? {
+                        n += 1;
+                        let _ = table.set(Value::Number(n as f64), value);
+                    }
+                }
+                Field::Positional(expr) => {
+                    n += 1;
+                    let value = self.eval(frame, expr)?;
+                    let _ = table.set(Value::Number(n as f64), value);
+                }
+                Field::Keyed(key, value) => {
+                    let key = self.eval(frame, key)?;
+                    let value = self.eval(frame, value)?;
+                    table.set(key, value).map_err(|message| self.error(message))?;
+                }
+            }
+        }
+        Ok(Value::table(table))
+    }
+}
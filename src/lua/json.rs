@@ -0,0 +1,292 @@
+//! cjson.encode and cjson.decode
+use bytes::Bytes;
+
+use super::value::{format_g, Table, Value};
+
+/// How deeply tables may nest when encoding, and arrays and objects when decoding
+const MAX_DEPTH: usize = 1000;
+
+pub fn encode(value: &Value) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    encode_value(value, &mut out, 0)?;
+    Ok(out)
+}
+
+fn encode_value(value: &Value, out: &mut Vec<u8>, depth: usize) -> Result<(), String> {
+    match value {
+        Value::Nil => out.extend_from_slice(b"null"),
+        Value::Boolean(b) => out.extend_from_slice(if *b { b"true" } else { b"false" }),
+        Value::Number(n) => {
+            if !n.is_finite() {
+                return Err("Cannot serialise number: must not be NaN or Inf".to_string());
+            }
+            out.extend_from_slice(format_g(*n, 14, false).as_bytes());
+        }
+        Value::String(s) => encode_string(s, out),
+        Value::Table(table) => {
+            if depth >= MAX_DEPTH {
+                return Err(format!("Cannot serialise, excessive nesting ({})", depth + 1));
+            }
+            let table = table.borrow();
+            match array_length(&table) {
+                Some(len) => {
+                    out.push(b'[');
+                    for i in 1..=len {
+                        if i > 1 {
+                            out.push(b',');
+                        }
+                        encode_value(&table.get(&Value::Number(i as f64)), out, depth + 1)?;
+                    }
+                    out.push(b']');
+                }
+                None => {
+                    out.push(b'{');
+                    let mut key = Value::Nil;
+                    let mut first = true;
+                    while let Ok(Some((k, v))) = table.next(&key) {
+                        if !first {
+                            out.push(b',');
+                        }
+                        first = false;
+                        match &k {
+                            Value::String(s) => encode_string(s, out),
+                            Value::Number(n) => encode_string(format_g(*n, 14, false).as_bytes(), out),
+                            _ => return Err("Cannot serialise table: table key must be a number or string".to_string()),
+                        }
+                        out.push(b':');
+                        encode_value(&v, out, depth + 1)?;
+                        key = k;
+                    }
+                    out.push(b'}');
+                }
+            }
+        }
+        Value::Function(_) => return Err("Cannot serialise function: type not supported".to_string()),
+    }
+    Ok(())
+}
+
+/// The length of a table whose keys are all positive integers, to encode as a
+/// JSON array. Empty tables are objects, and so are very sparse arrays.
+fn array_length(table: &Table) -> Option<usize> {
+    let mut key = Value::Nil;
+    let mut count = 0;
+    let mut max = 0;
+    while let Ok(Some((k, _))) = table.next(&key) {
+        match k {
+            Value::Number(n) if n >= 1.0 && n.fract() == 0.0 => max = max.max(n as usize),
+            _ => return None,
+        }
+        count += 1;
+        key = k;
+    }
+    (count > 0 && (max <= 10 || max <= count * 2)).then_some(max)
+}
+
+fn encode_string(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for &c in s {
+        match c {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'/' => out.extend_from_slice(b"\\/"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            0x08 => out.extend_from_slice(b"\\b"),
+            0x0c => out.extend_from_slice(b"\\f"),
+            c if c < 0x20 || c == 0x7f => out.extend_from_slice(format!("\\u{:04x}", c).as_bytes()),
+            c => out.push(c),
+        }
+    }
+    out.push(b'"');
+}
+
+pub fn decode(json: &[u8]) -> Result<Value, String> {
+    let mut decoder = Decoder { json, pos: 0 };
+    let value = decoder.value(0)?;
+    decoder.skip_whitespace();
+    if decoder.pos < json.len() {
+        return Err(decoder.error("the end"));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    json: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn error(&self, expected: &str) -> String {
+        let found = match self.json.get(self.pos) {
+            None => "T_END".to_string(),
+            Some(_) => "invalid token".to_string(),
+        };
+        format!("Expected {} but found {} at character {}", expected, found, self.pos + 1)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.json.get(self.pos).is_some_and(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth >= MAX_DEPTH {
+            return Err(format!("Found too many nested data structures ({}) at character {}", depth + 1, self.pos + 1));
+        }
+        self.skip_whitespace();
+        match self.json.get(self.pos) {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut table = Table::default();
+                self.skip_whitespace();
+                if self.json.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Value::table(table));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.json.get(self.pos) != Some(&b'"') {
+                        return Err(self.error("object key string"));
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.json.get(self.pos) != Some(&b':') {
+                        return Err(self.error("colon"));
+                    }
+                    self.pos += 1;
+                    let value = self.value(depth + 1)?;
+                    let _ = table.set(Value::String(key), value);
+                    self.skip_whitespace();
+                    match self.json.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Value::table(table));
+                        }
+                        _ => return Err(self.error("comma or object end")),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut table = Table::default();
+                self.skip_whitespace();
+                if self.json.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Value::table(table));
+                }
+                let mut n = 0;
+                loop {
+                    let value = self.value(depth + 1)?;
+                    n += 1;
+                    let _ = table.set(Value::Number(n as f64), value);
+                    self.skip_whitespace();
+                    match self.json.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Value::table(table));
+                        }
+                        _ => return Err(self.error("comma or array end")),
+                    }
+                }
+            }
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while self
+                    .json
+                    .get(self.pos)
+                    .is_some_and(|c| c.is_ascii_digit() || matches!(c, b'-' | b'+' | b'.' | b'e' | b'E'))
+                {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.json[start..self.pos]).unwrap_or_default();
+                match text.parse::<f64>() {
+                    Ok(n) => Ok(Value::Number(n)),
+                    Err(_) => {
+                        self.pos = start;
+                        Err(self.error("value"))
+                    }
+                }
+            }
+            _ => {
+                for (word, value) in [
+                    (&b"true"[..], Value::Boolean(true)),
+                    (b"false", Value::Boolean(false)),
+                    (b"null", Value::Nil),
+                ] {
+                    if self.json[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("value"))
+            }
+        }
+    }
+
+    /// A string from its opening quote
+    fn string(&mut self) -> Result<Bytes, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = match self.json.get(self.pos) {
+                Some(c) => *c,
+                None => {
+                    self.pos = start;
+                    return Err(self.error("string end"));
+                }
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(Bytes::from(out)),
+                b'\\' => {
+                    let escaped = self.json.get(self.pos).copied().unwrap_or(0);
+                    self.pos += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => out.push(escaped),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair spells one character outside the BMP
+                            if (0xd800..0xdc00).contains(&code) && self.json[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if (0xdc00..0xe000).contains(&low) {
+                                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                }
+                            }
+                            let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => {
+                            self.pos -= 2;
+                            return Err(self.error("valid escape"));
+                        }
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.json.get(self.pos..self.pos + 4).and_then(|d| std::str::from_utf8(d).ok());
+        match digits.and_then(|d| u32::from_str_radix(d, 16).ok()) {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => Err(self.error("valid unicode escape")),
+        }
+    }
+}
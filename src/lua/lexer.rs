@@ -0,0 +1,351 @@
+//! Splits Lua source into tokens
+use super::value::parse_number;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Name(String),
+    String(Vec<u8>),
+    Number(f64),
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Hash,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+    Assign,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Semicolon,
+    Colon,
+    Comma,
+    Dot,
+    Concat,
+    Ellipsis,
+    Eof,
+}
+
+/// A token with where it was and how it was written, for error messages
+#[derive(Debug, Clone)]
+pub struct Lexeme {
+    pub token: Token,
+    pub line: u32,
+    pub text: String,
+}
+
+fn keyword(name: &str) -> Option<Token> {
+    Some(match name {
+        "and" => Token::And,
+        "break" => Token::Break,
+        "do" => Token::Do,
+        "else" => Token::Else,
+        "elseif" => Token::Elseif,
+        "end" => Token::End,
+        "false" => Token::False,
+        "for" => Token::For,
+        "function" => Token::Function,
+        "if" => Token::If,
+        "in" => Token::In,
+        "local" => Token::Local,
+        "nil" => Token::Nil,
+        "not" => Token::Not,
+        "or" => Token::Or,
+        "repeat" => Token::Repeat,
+        "return" => Token::Return,
+        "then" => Token::Then,
+        "true" => Token::True,
+        "until" => Token::Until,
+        "while" => Token::While,
+        _ => return None,
+    })
+}
+
+struct Lexer<'a> {
+    source: &'a [u8],
+    pos: usize,
+    line: u32,
+    chunk: &'a str,
+}
+
+/// Tokenizes a whole chunk. Errors read like Lua's, e.g.
+/// `user_script:1: unfinished string near '"abc'`.
+pub fn tokenize(chunk: &str, source: &[u8]) -> Result<Vec<Lexeme>, String> {
+    let mut lexer = Lexer {
+        source,
+        pos: 0,
+        line: 1,
+        chunk,
+    };
+    let mut lexemes = Vec::new();
+    loop {
+        let lexeme = lexer.next()?;
+        let done = lexeme.token == Token::Eof;
+        lexemes.push(lexeme);
+        if done {
+            return Ok(lexemes);
+        }
+    }
+}
+
+impl Lexer<'_> {
+    fn peek(&self, offset: usize) -> u8 {
+        self.source.get(self.pos + offset).copied().unwrap_or(0)
+    }
+
+    fn error(&self, message: &str, near: &[u8]) -> String {
+        format!(
+            "{}:{}: {} near '{}'",
+            self.chunk,
+            self.line,
+            message,
+            String::from_utf8_lossy(near)
+        )
+    }
+
+    fn newline(&mut self) {
+        // \r\n and \n\r count once
+        let first = self.peek(0);
+        self.pos += 1;
+        if matches!(self.peek(0), b'\n' | b'\r') && self.peek(0) != first {
+            self.pos += 1;
+        }
+        self.line += 1;
+    }
+
+    fn next(&mut self) -> Result<Lexeme, String> {
+        loop {
+            match self.peek(0) {
+                b'\n' | b'\r' => self.newline(),
+                b' ' | b'\t' | 0x0b | 0x0c => self.pos += 1,
+                b'-' if self.peek(1) == b'-' => self.comment()?,
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        let line = self.line;
+        let token = self.token()?;
+        let text = match &token {
+            Token::Eof => "<eof>".to_string(),
+            _ => String::from_utf8_lossy(&self.source[start..self.pos]).into_owned(),
+        };
+        Ok(Lexeme { token, line, text })
+    }
+
+    fn comment(&mut self) -> Result<(), String> {
+        self.pos += 2;
+        if self.peek(0) == b'[' {
+            if let Some(level) = self.long_bracket_level() {
+                self.long_string(level, "unfinished long comment")?;
+                return Ok(());
+            }
+        }
+        while self.pos < self.source.len() && !matches!(self.peek(0), b'\n' | b'\r') {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// At a `[`, the number of `=` in an opening long bracket like `[==[`, or
+    /// None if it isn't one
+    fn long_bracket_level(&self) -> Option<usize> {
+        let mut level = 0;
+        while self.peek(1 + level) == b'=' {
+            level += 1;
+        }
+        (self.peek(1 + level) == b'[').then_some(level)
+    }
+
+    /// Reads a long string or comment from its opening bracket
+    fn long_string(&mut self, level: usize, unfinished: &str) -> Result<Vec<u8>, String> {
+        let start = self.pos;
+        self.pos += level + 2;
+        // A newline straight after the opening bracket isn't part of the string
+        if matches!(self.peek(0), b'\n' | b'\r') {
+            self.newline();
+        }
+        let mut contents = Vec::new();
+        loop {
+            match self.peek(0) {
+                _ if self.pos >= self.source.len() => {
+                    return Err(self.error(unfinished, &self.source[start..self.pos.min(start + 20)]))
+                }
+                b']' if (1..=level).all(|i| self.peek(i) == b'=') && self.peek(level + 1) == b']' => {
+                    self.pos += level + 2;
+                    return Ok(contents);
+                }
+                b'\n' | b'\r' => {
+                    self.newline();
+                    contents.push(b'\n');
+                }
+                c => {
+                    contents.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<Token, String> {
+        let c = self.peek(0);
+        if self.pos >= self.source.len() {
+            return Ok(Token::Eof);
+        }
+        if c.is_ascii_alphabetic() || c == b'_' {
+            let start = self.pos;
+            while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+                self.pos += 1;
+            }
+            let name = String::from_utf8_lossy(&self.source[start..self.pos]).into_owned();
+            return Ok(keyword(&name).unwrap_or(Token::Name(name)));
+        }
+        if c.is_ascii_digit() || c == b'.' && self.peek(1).is_ascii_digit() {
+            return self.number();
+        }
+        let (token, len) = match (c, self.peek(1), self.peek(2)) {
+            (b'"' | b'\'', _, _) => return self.string(c),
+            (b'[', _, _) if self.long_bracket_level().is_some() => {
+                let level = self.long_bracket_level().unwrap_or(0);
+                return self.long_string(level, "unfinished long string").map(Token::String);
+            }
+            (b'.', b'.', b'.') => (Token::Ellipsis, 3),
+            (b'.', b'.', _) => (Token::Concat, 2),
+            (b'=', b'=', _) => (Token::Eq, 2),
+            (b'~', b'=', _) => (Token::Ne, 2),
+            (b'<', b'=', _) => (Token::Le, 2),
+            (b'>', b'=', _) => (Token::Ge, 2),
+            (b'.', _, _) => (Token::Dot, 1),
+            (b'=', _, _) => (Token::Assign, 1),
+            (b'<', _, _) => (Token::Lt, 1),
+            (b'>', _, _) => (Token::Gt, 1),
+            (b'+', _, _) => (Token::Plus, 1),
+            (b'-', _, _) => (Token::Minus, 1),
+            (b'*', _, _) => (Token::Star, 1),
+            (b'/', _, _) => (Token::Slash, 1),
+            (b'%', _, _) => (Token::Percent, 1),
+            (b'^', _, _) => (Token::Caret, 1),
+            (b'#', _, _) => (Token::Hash, 1),
+            (b'(', _, _) => (Token::LParen, 1),
+            (b')', _, _) => (Token::RParen, 1),
+            (b'{', _, _) => (Token::LBrace, 1),
+            (b'}', _, _) => (Token::RBrace, 1),
+            (b'[', _, _) => (Token::LBracket, 1),
+            (b']', _, _) => (Token::RBracket, 1),
+            (b';', _, _) => (Token::Semicolon, 1),
+            (b':', _, _) => (Token::Colon, 1),
+            (b',', _, _) => (Token::Comma, 1),
+            _ => return Err(self.error("unexpected symbol", &[c])),
+        };
+        self.pos += len;
+        Ok(token)
+    }
+
+    fn number(&mut self) -> Result<Token, String> {
+        let start = self.pos;
+        // Like Lua, take everything that could belong to a number, then check it
+        loop {
+            let c = self.peek(0);
+            if matches!(c, b'e' | b'E') && matches!(self.peek(1), b'+' | b'-') && !self.is_hex(start) {
+                self.pos += 2;
+            } else if c.is_ascii_alphanumeric() || c == b'.' || c == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text = &self.source[start..self.pos];
+        match parse_number(text) {
+            Some(n) => Ok(Token::Number(n)),
+            None => Err(self.error("malformed number", text)),
+        }
+    }
+
+    fn is_hex(&self, start: usize) -> bool {
+        self.source[start..].starts_with(b"0x") || self.source[start..].starts_with(b"0X")
+    }
+
+    fn string(&mut self, quote: u8) -> Result<Token, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut contents = Vec::new();
+        loop {
+            let c = self.peek(0);
+            if self.pos >= self.source.len() || c == b'\n' || c == b'\r' {
+                return Err(self.error("unfinished string", &self.source[start..self.pos]));
+            }
+            self.pos += 1;
+            match c {
+                _ if c == quote => return Ok(Token::String(contents)),
+                b'\\' => {
+                    let escaped = self.peek(0);
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => contents.push(b'\n'),
+                        b't' => contents.push(b'\t'),
+                        b'r' => contents.push(b'\r'),
+                        b'a' => contents.push(0x07),
+                        b'b' => contents.push(0x08),
+                        b'f' => contents.push(0x0c),
+                        b'v' => contents.push(0x0b),
+                        b'\\' | b'"' | b'\'' => contents.push(escaped),
+                        b'\n' | b'\r' => {
+                            self.pos -= 1;
+                            self.newline();
+                            contents.push(b'\n');
+                        }
+                        b'0'..=b'9' => {
+                            let mut value = (escaped - b'0') as u32;
+                            for _ in 0..2 {
+                                if !self.peek(0).is_ascii_digit() {
+                                    break;
+                                }
+                                value = value * 10 + (self.peek(0) - b'0') as u32;
+                                self.pos += 1;
+                            }
+                            if value > 255 {
+                                return Err(self.error("escape sequence too large", &self.source[start..self.pos]));
+                            }
+                            contents.push(value as u8);
+                        }
+                        _ if self.pos > self.source.len() => {
+                            return Err(self.error("unfinished string", &self.source[start..]))
+                        }
+                        // Lua 5.1 takes any other escaped character as itself
+                        other => contents.push(other),
+                    }
+                }
+                c => contents.push(c),
+            }
+        }
+    }
+}
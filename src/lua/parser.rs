@@ -0,0 +1,654 @@
+//! Builds the syntax tree from tokens. Names are resolved as they're read: to a
+//! local slot, to a variable captured from an enclosing function, or to a global.
+use bytes::Bytes;
+use std::sync::Arc;
+
+use super::ast::{BinOp, Block, Expr, Field, FunctionProto, Stat, StatKind, UnOp, Upvalue};
+use super::lexer::{tokenize, Lexeme, Token};
+
+/// How deeply expressions and blocks may nest
+const MAX_DEPTH: usize = 200;
+
+/// The precedence of unary operators, between `..` and `^`
+const UNARY_PRIORITY: u8 = 8;
+
+/// Compiles a chunk into the function that runs it. `chunk` names it in errors.
+pub fn parse(chunk: &str, source: &[u8]) -> Result<Arc<FunctionProto>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(chunk, source)?,
+        pos: 0,
+        chunk,
+        functions: vec![FunctionState::new(true)],
+        depth: 0,
+    };
+    let body = parser.block()?;
+    parser.expect(Token::Eof, "'<eof>' expected")?;
+    let function = parser.functions.pop().unwrap_or_else(|| FunctionState::new(true));
+    Ok(Arc::new(function.finish(0, body, 0)))
+}
+
+/// What the parser knows about a function whose body it's reading
+struct FunctionState {
+    is_vararg: bool,
+    /// Locals in scope, innermost last, with their slots
+    active: Vec<(String, usize)>,
+    /// How many locals were in scope when each open block started
+    blocks: Vec<usize>,
+    /// The name of each slot
+    locals: Vec<String>,
+    upvalues: Vec<Upvalue>,
+    upvalue_names: Vec<String>,
+}
+
+impl FunctionState {
+    fn new(is_vararg: bool) -> FunctionState {
+        FunctionState {
+            is_vararg,
+            active: Vec::new(),
+            blocks: Vec::new(),
+            locals: Vec::new(),
+            upvalues: Vec::new(),
+            upvalue_names: Vec::new(),
+        }
+    }
+
+    fn finish(self, params: usize, body: Block, line: u32) -> FunctionProto {
+        FunctionProto {
+            params,
+            is_vararg: self.is_vararg,
+            locals: self.locals,
+            upvalues: self.upvalues,
+            upvalue_names: self.upvalue_names,
+            body,
+            line,
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Lexeme>,
+    pos: usize,
+    chunk: &'a str,
+    /// The function being read, innermost last
+    functions: Vec<FunctionState>,
+    depth: usize,
+}
+
+fn block_follow(token: &Token) -> bool {
+    matches!(token, Token::Else | Token::Elseif | Token::End | Token::Until | Token::Eof)
+}
+
+fn unary_op(token: &Token) -> Option<UnOp> {
+    match token {
+        Token::Minus => Some(UnOp::Neg),
+        Token::Not => Some(UnOp::Not),
+        Token::Hash => Some(UnOp::Len),
+        _ => None,
+    }
+}
+
+/// A binary operator with its left and right precedence; None stands for `and`
+/// and `or`, which short-circuit
+fn binary_op(token: &Token) -> Option<(Option<BinOp>, bool, u8, u8)> {
+    let (op, left, right) = match token {
+        Token::Or => return Some((None, false, 1, 1)),
+        Token::And => return Some((None, true, 2, 2)),
+        Token::Eq => (BinOp::Eq, 3, 3),
+        Token::Ne => (BinOp::Ne, 3, 3),
+        Token::Lt => (BinOp::Lt, 3, 3),
+        Token::Le => (BinOp::Le, 3, 3),
+        Token::Gt => (BinOp::Gt, 3, 3),
+        Token::Ge => (BinOp::Ge, 3, 3),
+        Token::Concat => (BinOp::Concat, 5, 4),
+        Token::Plus => (BinOp::Add, 6, 6),
+        Token::Minus => (BinOp::Sub, 6, 6),
+        Token::Star => (BinOp::Mul, 7, 7),
+        Token::Slash => (BinOp::Div, 7, 7),
+        Token::Percent => (BinOp::Mod, 7, 7),
+        Token::Caret => (BinOp::Pow, 10, 9),
+        _ => return None,
+    };
+    Some((Some(op), false, left, right))
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn peek_ahead(&self) -> &Token {
+        &self.tokens[(self.pos + 1).min(self.tokens.len() - 1)].token
+    }
+
+    fn line(&self) -> u32 {
+        self.tokens[self.pos].line
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].token.clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn accept(&mut self, token: Token) -> bool {
+        if *self.peek() == token {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    fn error(&self, message: &str) -> String {
+        let lexeme = &self.tokens[self.pos];
+        format!("{}:{}: {} near '{}'", self.chunk, lexeme.line, message, lexeme.text)
+    }
+
+    fn expect(&mut self, token: Token, message: &str) -> Result<(), String> {
+        if self.accept(token) {
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    /// Expects the keyword closing a construct that started at `line`
+    fn expect_closing(&mut self, token: Token, what: &str, opener: &str, line: u32) -> Result<(), String> {
+        if line == self.line() {
+            self.expect(token, &format!("'{}' expected", what))
+        } else {
+            self.expect(
+                token,
+                &format!("'{}' expected (to close '{}' at line {})", what, opener, line),
+            )
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek().clone() {
+            Token::Name(name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.error("<name> expected")),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error("chunk has too many syntax levels"));
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn function(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("no function being parsed")
+    }
+
+    fn open_scope(&mut self) {
+        let function = self.function();
+        let active = function.active.len();
+        function.blocks.push(active);
+    }
+
+    fn close_scope(&mut self) {
+        let function = self.function();
+        let active = function.blocks.pop().unwrap_or(0);
+        function.active.truncate(active);
+    }
+
+    /// Brings a new local into scope, returning its slot
+    fn declare(&mut self, name: String) -> usize {
+        let function = self.function();
+        let slot = function.locals.len();
+        function.locals.push(name.clone());
+        function.active.push((name, slot));
+        slot
+    }
+
+    fn resolve(&mut self, name: &str) -> Expr {
+        match self.resolve_in(self.functions.len() - 1, name) {
+            Some(Upvalue::Local(slot)) => Expr::Local(slot),
+            Some(Upvalue::Upvalue(i)) => Expr::Upvalue(i),
+            None => Expr::Global(Bytes::copy_from_slice(name.as_bytes())),
+        }
+    }
+
+    /// Finds `name` in the function at `level`, capturing it from enclosing
+    /// functions as needed. None for a global.
+    fn resolve_in(&mut self, level: usize, name: &str) -> Option<Upvalue> {
+        let function = &self.functions[level];
+        if let Some((_, slot)) = function.active.iter().rev().find(|(local, _)| local == name) {
+            return Some(Upvalue::Local(*slot));
+        }
+        if let Some(i) = function.upvalue_names.iter().position(|upvalue| upvalue == name) {
+            return Some(Upvalue::Upvalue(i));
+        }
+        if level == 0 {
+            return None;
+        }
+        let source = self.resolve_in(level - 1, name)?;
+        let function = &mut self.functions[level];
+        function.upvalues.push(source);
+        function.upvalue_names.push(name.to_string());
+        Some(Upvalue::Upvalue(function.upvalues.len() - 1))
+    }
+
+    fn block(&mut self) -> Result<Block, String> {
+        self.open_scope();
+        let block = self.statements();
+        self.close_scope();
+        block
+    }
+
+    fn statements(&mut self) -> Result<Block, String> {
+        self.enter()?;
+        let mut stats = Vec::new();
+        while !block_follow(self.peek()) {
+            let line = self.line();
+            if self.accept(Token::Return) {
+                let values = if block_follow(self.peek()) || *self.peek() == Token::Semicolon {
+                    Vec::new()
+                } else {
+                    self.expr_list()?
+                };
+                self.accept(Token::Semicolon);
+                stats.push(Stat {
+                    line,
+                    kind: StatKind::Return(values),
+                });
+                // Nothing may follow a return in its block
+                break;
+            }
+            let kind = self.statement()?;
+            stats.push(Stat { line, kind });
+            self.accept(Token::Semicolon);
+        }
+        self.leave();
+        Ok(stats)
+    }
+
+    fn statement(&mut self) -> Result<StatKind, String> {
+        let line = self.line();
+        match self.peek() {
+            Token::If => self.if_statement(line),
+            Token::While => {
+                self.advance();
+                let condition = self.expr()?;
+                self.expect(Token::Do, "'do' expected")?;
+                let body = self.block()?;
+                self.expect_closing(Token::End, "end", "while", line)?;
+                Ok(StatKind::While { condition, body })
+            }
+            Token::Do => {
+                self.advance();
+                let body = self.block()?;
+                self.expect_closing(Token::End, "end", "do", line)?;
+                Ok(StatKind::Do(body))
+            }
+            Token::For => self.for_statement(line),
+            Token::Repeat => {
+                self.advance();
+                // The condition can see the body's locals
+                self.open_scope();
+                let body = self.statements()?;
+                self.expect_closing(Token::Until, "until", "repeat", line)?;
+                let condition = self.expr()?;
+                self.close_scope();
+                Ok(StatKind::Repeat { body, condition })
+            }
+            Token::Function => {
+                self.advance();
+                let name = self.name()?;
+                let mut target = self.resolve(&name);
+                let mut method = false;
+                loop {
+                    match self.peek() {
+                        Token::Dot => {
+                            self.advance();
+                            let key = Expr::String(Bytes::from(self.name()?));
+                            target = Expr::Index(Box::new(target), Box::new(key));
+                        }
+                        Token::Colon => {
+                            self.advance();
+                            let key = Expr::String(Bytes::from(self.name()?));
+                            target = Expr::Index(Box::new(target), Box::new(key));
+                            method = true;
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                let function = self.function_body(method, line)?;
+                Ok(StatKind::Assign {
+                    targets: vec![target],
+                    values: vec![Expr::Function(function)],
+                })
+            }
+            Token::Local => {
+                self.advance();
+                if self.accept(Token::Function) {
+                    let name = self.name()?;
+                    // Declared first, so the function can call itself
+                    let slot = self.declare(name);
+                    let function = self.function_body(false, line)?;
+                    return Ok(StatKind::LocalFunction { slot, function });
+                }
+                let mut names = vec![self.name()?];
+                while self.accept(Token::Comma) {
+                    names.push(self.name()?);
+                }
+                let values = if self.accept(Token::Assign) { self.expr_list()? } else { Vec::new() };
+                let slots = names.into_iter().map(|name| self.declare(name)).collect();
+                Ok(StatKind::Local { slots, values })
+            }
+            Token::Break => {
+                self.advance();
+                Ok(StatKind::Break)
+            }
+            _ => self.expr_statement(),
+        }
+    }
+
+    fn if_statement(&mut self, line: u32) -> Result<StatKind, String> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        self.advance();
+        loop {
+            let condition = self.expr()?;
+            self.expect(Token::Then, "'then' expected")?;
+            branches.push((condition, self.block()?));
+            match self.peek() {
+                Token::Elseif => {
+                    self.advance();
+                }
+                Token::Else => {
+                    self.advance();
+                    otherwise = Some(self.block()?);
+                    self.expect_closing(Token::End, "end", "if", line)?;
+                    break;
+                }
+                _ => {
+                    self.expect_closing(Token::End, "end", "if", line)?;
+                    break;
+                }
+            }
+        }
+        Ok(StatKind::If { branches, otherwise })
+    }
+
+    fn for_statement(&mut self, line: u32) -> Result<StatKind, String> {
+        self.advance();
+        let first = self.name()?;
+        if self.accept(Token::Assign) {
+            let start = self.expr()?;
+            self.expect(Token::Comma, "',' expected")?;
+            let limit = self.expr()?;
+            let step = if self.accept(Token::Comma) { Some(self.expr()?) } else { None };
+            self.expect(Token::Do, "'do' expected")?;
+            self.open_scope();
+            let slot = self.declare(first);
+            let body = self.block();
+            self.close_scope();
+            self.expect_closing(Token::End, "end", "for", line)?;
+            return Ok(StatKind::NumericFor {
+                slot,
+                start,
+                limit,
+                step,
+                body: body?,
+            });
+        }
+        let mut names = vec![first];
+        while self.accept(Token::Comma) {
+            names.push(self.name()?);
+        }
+        if *self.peek() != Token::In {
+            return Err(self.error("'=' or 'in' expected"));
+        }
+        self.advance();
+        let values = self.expr_list()?;
+        self.expect(Token::Do, "'do' expected")?;
+        self.open_scope();
+        let slots = names.into_iter().map(|name| self.declare(name)).collect();
+        let body = self.block();
+        self.close_scope();
+        self.expect_closing(Token::End, "end", "for", line)?;
+        Ok(StatKind::GenericFor {
+            slots,
+            values,
+            body: body?,
+        })
+    }
+
+    fn expr_statement(&mut self) -> Result<StatKind, String> {
+        let first = self.suffixed_expr()?;
+        if matches!(self.peek(), Token::Assign | Token::Comma) {
+            let mut targets = vec![first];
+            while self.accept(Token::Comma) {
+                targets.push(self.suffixed_expr()?);
+            }
+            if targets
+                .iter()
+                .any(|target| !matches!(target, Expr::Local(_) | Expr::Upvalue(_) | Expr::Global(_) | Expr::Index(..)))
+            {
+                return Err(self.error("syntax error"));
+            }
+            self.expect(Token::Assign, "'=' expected")?;
+            let values = self.expr_list()?;
+            return Ok(StatKind::Assign { targets, values });
+        }
+        match first {
+            call @ (Expr::Call(..) | Expr::Method(..)) => Ok(StatKind::Call(call)),
+            _ => Err(self.error("syntax error")),
+        }
+    }
+
+    /// Parameters and body of a function; a method gets `self` first
+    fn function_body(&mut self, method: bool, line: u32) -> Result<Arc<FunctionProto>, String> {
+        self.functions.push(FunctionState::new(false));
+        let mut params = 0;
+        if method {
+            self.declare("self".to_string());
+            params += 1;
+        }
+        self.expect(Token::LParen, "'(' expected")?;
+        if *self.peek() != Token::RParen {
+            loop {
+                match self.peek().clone() {
+                    Token::Name(name) => {
+                        self.advance();
+                        self.declare(name);
+                        params += 1;
+                    }
+                    Token::Ellipsis => {
+                        self.advance();
+                        self.function().is_vararg = true;
+                        break;
+                    }
+                    _ => return Err(self.error("<name> expected")),
+                }
+                if !self.accept(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RParen, "')' expected")?;
+        let body = self.block();
+        let closed = body.and_then(|body| {
+            self.expect_closing(Token::End, "end", "function", line)?;
+            Ok(body)
+        });
+        let function = self.functions.pop().expect("function being parsed");
+        Ok(Arc::new(function.finish(params, closed?, line)))
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, String> {
+        let mut exprs = vec![self.expr()?];
+        while self.accept(Token::Comma) {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.subexpr(0)
+    }
+
+    /// An expression whose binary operators bind tighter than `limit`
+    fn subexpr(&mut self, limit: u8) -> Result<Expr, String> {
+        self.enter()?;
+        let mut left = match unary_op(self.peek()) {
+            Some(op) => {
+                self.advance();
+                match (op, self.subexpr(UNARY_PRIORITY)?) {
+                    (UnOp::Neg, Expr::Number(n)) => Expr::Number(-n),
+                    (op, operand) => Expr::Unary(op, Box::new(operand)),
+                }
+            }
+            None => self.simple_expr()?,
+        };
+        while let Some((op, and, left_priority, right_priority)) = binary_op(self.peek()) {
+            if left_priority <= limit {
+                break;
+            }
+            self.advance();
+            let right = self.subexpr(right_priority)?;
+            left = match op {
+                Some(op) => Expr::Binary(op, Box::new(left), Box::new(right)),
+                None if and => Expr::And(Box::new(left), Box::new(right)),
+                None => Expr::Or(Box::new(left), Box::new(right)),
+            };
+        }
+        self.leave();
+        Ok(left)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, String> {
+        let expr = match self.peek().clone() {
+            Token::Number(n) => Expr::Number(n),
+            Token::String(s) => Expr::String(Bytes::from(s)),
+            Token::Nil => Expr::Nil,
+            Token::True => Expr::True,
+            Token::False => Expr::False,
+            Token::Ellipsis => {
+                if !self.function().is_vararg {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                Expr::VarArg
+            }
+            Token::LBrace => return self.table(),
+            Token::Function => {
+                let line = self.line();
+                self.advance();
+                return Ok(Expr::Function(self.function_body(false, line)?));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    fn primary_expr(&mut self) -> Result<Expr, String> {
+        match self.peek().clone() {
+            Token::Name(name) => {
+                self.advance();
+                Ok(self.resolve(&name))
+            }
+            Token::LParen => {
+                let line = self.line();
+                self.advance();
+                let expr = self.expr()?;
+                self.expect_closing(Token::RParen, ")", "(", line)?;
+                Ok(Expr::Paren(Box::new(expr)))
+            }
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary_expr()?;
+        loop {
+            expr = match self.peek() {
+                Token::Dot => {
+                    self.advance();
+                    let key = Expr::String(Bytes::from(self.name()?));
+                    Expr::Index(Box::new(expr), Box::new(key))
+                }
+                Token::LBracket => {
+                    self.advance();
+                    let key = self.expr()?;
+                    self.expect(Token::RBracket, "']' expected")?;
+                    Expr::Index(Box::new(expr), Box::new(key))
+                }
+                Token::Colon => {
+                    self.advance();
+                    let name = Bytes::from(self.name()?);
+                    let args = self.call_args()?;
+                    Expr::Method(Box::new(expr), name, args)
+                }
+                Token::LParen | Token::String(_) | Token::LBrace => {
+                    let args = self.call_args()?;
+                    Expr::Call(Box::new(expr), args)
+                }
+                _ => return Ok(expr),
+            };
+        }
+    }
+
+    fn call_args(&mut self) -> Result<Vec<Expr>, String> {
+        match self.peek().clone() {
+            Token::String(s) => {
+                self.advance();
+                Ok(vec![Expr::String(Bytes::from(s))])
+            }
+            Token::LBrace => Ok(vec![self.table()?]),
+            Token::LParen => {
+                let line = self.line();
+                self.advance();
+                if self.accept(Token::RParen) {
+                    return Ok(Vec::new());
+                }
+                let args = self.expr_list()?;
+                self.expect_closing(Token::RParen, ")", "(", line)?;
+                Ok(args)
+            }
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+
+    fn table(&mut self) -> Result<Expr, String> {
+        let line = self.line();
+        self.expect(Token::LBrace, "'{' expected")?;
+        let mut fields = Vec::new();
+        while *self.peek() != Token::RBrace {
+            let field = match (self.peek().clone(), self.peek_ahead()) {
+                (Token::Name(name), Token::Assign) => {
+                    self.advance();
+                    self.advance();
+                    Field::Keyed(Expr::String(Bytes::from(name)), self.expr()?)
+                }
+                (Token::LBracket, _) => {
+                    self.advance();
+                    let key = self.expr()?;
+                    self.expect(Token::RBracket, "']' expected")?;
+                    self.expect(Token::Assign, "'=' expected")?;
+                    Field::Keyed(key, self.expr()?)
+                }
+                _ => Field::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !self.accept(Token::Comma) && !self.accept(Token::Semicolon) {
+                break;
+            }
+        }
+        self.expect_closing(Token::RBrace, "}", "{", line)?;
+        Ok(Expr::Table(fields))
+    }
+}
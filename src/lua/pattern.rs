@@ -0,0 +1,381 @@
+//! Lua patterns, as used by string.find, match, gmatch and gsub. This follows
+//! the matcher in Lua's own string library, backtracking included.
+
+const ESCAPE: u8 = b'%';
+const MAX_CAPTURES: usize = 32;
+
+/// Characters that make a pattern more than a plain substring
+pub const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureLen {
+    Unfinished,
+    /// A `()` capture, which records a position
+    Position,
+    Closed(usize),
+}
+
+/// A captured value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Captured {
+    /// The bytes from one offset to another
+    Span(usize, usize),
+    /// The offset `()` matched at
+    Position(usize),
+}
+
+/// Matches one pattern against one subject
+pub struct Matcher<'a> {
+    src: &'a [u8],
+    pattern: &'a [u8],
+    captures: Vec<(usize, CaptureLen)>,
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(src: &'a [u8], pattern: &'a [u8]) -> Matcher<'a> {
+        Matcher {
+            src,
+            pattern,
+            captures: Vec::new(),
+        }
+    }
+
+    /// Tries to match the pattern from its offset `p` at subject offset `s`,
+    /// returning where the match ends
+    pub fn match_at(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        self.captures.clear();
+        self.do_match(s, p)
+    }
+
+    /// The captures of the last match from `s` to `e`. A pattern without any
+    /// captures gives the whole match when `whole` is set.
+    pub fn captures(&self, s: usize, e: usize, whole: bool) -> Result<Vec<Captured>, String> {
+        if self.captures.is_empty() {
+            return Ok(if whole { vec![Captured::Span(s, e)] } else { Vec::new() });
+        }
+        (0..self.captures.len()).map(|i| self.capture(i, s, e)).collect()
+    }
+
+    /// Capture `i`, where capture 0 of a pattern without any is the whole match
+    pub fn capture(&self, i: usize, s: usize, e: usize) -> Result<Captured, String> {
+        match self.captures.get(i) {
+            None if i == 0 => Ok(Captured::Span(s, e)),
+            None => Err("invalid capture index".to_string()),
+            Some((_, CaptureLen::Unfinished)) => Err("unfinished capture".to_string()),
+            Some((start, CaptureLen::Position)) => Ok(Captured::Position(*start)),
+            Some((start, CaptureLen::Closed(len))) => Ok(Captured::Span(*start, start + len)),
+        }
+    }
+
+    fn pat(&self, p: usize) -> u8 {
+        self.pattern.get(p).copied().unwrap_or(0)
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        loop {
+            if p >= self.pattern.len() {
+                return Ok(Some(s));
+            }
+            match self.pattern[p] {
+                b'(' => {
+                    return if self.pat(p + 1) == b')' {
+                        self.start_capture(s, p + 2, CaptureLen::Position)
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unfinished)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pattern.len() => {
+                    return Ok((s == self.src.len()).then_some(s));
+                }
+                ESCAPE if self.pat(p + 1) == b'b' => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                ESCAPE if self.pat(p + 1) == b'f' => {
+                    p += 2;
+                    if self.pat(p) != b'[' {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let end = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if self.match_bracket_class(previous, p, end - 1) || !self.match_bracket_class(current, p, end - 1)
+                    {
+                        return Ok(None);
+                    }
+                    p = end;
+                    continue;
+                }
+                ESCAPE if self.pat(p + 1).is_ascii_digit() => match self.match_capture(s, self.pat(p + 1))? {
+                    Some(end) => {
+                        s = end;
+                        p += 2;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                _ => {}
+            }
+            let end = self.class_end(p)?;
+            let matched = s < self.src.len() && self.single_match(self.src[s], p, end);
+            match self.pat(end) {
+                b'?' => {
+                    if matched {
+                        if let Some(result) = self.do_match(s + 1, end + 1)? {
+                            return Ok(Some(result));
+                        }
+                    }
+                    p = end + 1;
+                }
+                b'*' => return self.max_expand(s, p, end),
+                b'+' => return if matched { self.max_expand(s + 1, p, end) } else { Ok(None) },
+                b'-' => return self.min_expand(s, p, end),
+                _ => {
+                    if !matched {
+                        return Ok(None);
+                    }
+                    s += 1;
+                    p = end;
+                }
+            }
+        }
+    }
+
+    /// Where the single-character class starting at `p` ends
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pat(p);
+        p += 1;
+        match c {
+            ESCAPE => {
+                if p >= self.pattern.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if self.pat(p) == b'^' {
+                    p += 1;
+                }
+                // The first character is part of the set even if it's a ]
+                loop {
+                    if p >= self.pattern.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = self.pattern[p];
+                    p += 1;
+                    if c == ESCAPE && p < self.pattern.len() {
+                        p += 1;
+                    }
+                    if self.pat(p) == b']' {
+                        return Ok(p + 1);
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    fn single_match(&self, c: u8, p: usize, end: usize) -> bool {
+        match self.pattern[p] {
+            b'.' => true,
+            ESCAPE => match_class(c, self.pat(p + 1)),
+            b'[' => self.match_bracket_class(c, p, end - 1),
+            literal => literal == c,
+        }
+    }
+
+    /// Whether `c` is in the set from the `[` at `p` to the `]` at `end`
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut found = true;
+        if self.pat(p + 1) == b'^' {
+            found = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pattern[p] == ESCAPE {
+                p += 1;
+                if match_class(c, self.pat(p)) {
+                    return found;
+                }
+            } else if self.pat(p + 1) == b'-' && p + 2 < end {
+                if self.pattern[p] <= c && c <= self.pattern[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.pattern[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while s + count < self.src.len() && self.single_match(self.src[s + count], p, end) {
+            count += 1;
+        }
+        // Back off one at a time until the rest of the pattern matches
+        loop {
+            if let Some(result) = self.do_match(s + count, end + 1)? {
+                return Ok(Some(result));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(result) = self.do_match(s, end + 1)? {
+                return Ok(Some(result));
+            }
+            if s < self.src.len() && self.single_match(self.src[s], p, end) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, len: CaptureLen) -> Result<Option<usize>, String> {
+        if self.captures.len() >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.captures.push((s, len));
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let open = self
+            .captures
+            .iter()
+            .rposition(|(_, len)| *len == CaptureLen::Unfinished)
+            .ok_or_else(|| "invalid pattern capture".to_string())?;
+        self.captures[open].1 = CaptureLen::Closed(s - self.captures[open].0);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[open].1 = CaptureLen::Unfinished;
+        }
+        Ok(result)
+    }
+
+    /// `%1` to `%9`: the same bytes as an earlier capture
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        let (start, len) = match digit.checked_sub(b'1').and_then(|i| self.captures.get(i as usize)) {
+            Some((start, CaptureLen::Closed(len))) => (*start, *len),
+            _ => return Err("invalid capture index".to_string()),
+        };
+        let captured = &self.src[start..start + len];
+        Ok(self.src[s..].starts_with(captured).then_some(s + len))
+    }
+
+    /// `%bxy`: a balanced run from an x to its matching y
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pattern.len() {
+            return Err("unbalanced pattern".to_string());
+        }
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Whether `c` is in a class like `%a`; an upper case letter negates it
+fn match_class(c: u8, class: u8) -> bool {
+    let matched = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c.is_ascii_whitespace() || c == 0x0b,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matched
+    } else {
+        matched
+    }
+}
+
+/// The first match at or after `init`, as string.find and string.match look for it
+pub fn find(src: &[u8], pattern: &[u8], init: usize) -> Result<Option<(usize, usize, Vec<Captured>)>, String> {
+    let anchored = pattern.first() == Some(&b'^');
+    let start = anchored as usize;
+    let mut matcher = Matcher::new(src, pattern);
+    let mut s = init;
+    loop {
+        if let Some(e) = matcher.match_at(s, start)? {
+            return Ok(Some((s, e, matcher.captures(s, e, false)?)));
+        }
+        s += 1;
+        if anchored || s > src.len() {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matched(src: &str, pattern: &str) -> Option<String> {
+        let (s, e, _) = find(src.as_bytes(), pattern.as_bytes(), 0).unwrap()?;
+        Some(src[s..e].to_string())
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert_eq!(matched("hello world", "o w"), Some("o w".to_string()));
+        assert_eq!(matched("hello world", "^world"), None);
+        assert_eq!(matched("hello world", "%a+$"), Some("world".to_string()));
+        assert_eq!(matched("key:123:x", "%d+"), Some("123".to_string()));
+        assert_eq!(matched("<a><b>", "<.->"), Some("<a>".to_string()));
+        assert_eq!(matched("<a><b>", "<.*>"), Some("<a><b>".to_string()));
+        assert_eq!(matched("f(a(b)c)d", "%b()"), Some("(a(b)c)".to_string()));
+        assert_eq!(matched("THE (quick) fox", "%f[%a]%a+"), Some("THE".to_string()));
+        assert_eq!(matched("a-b_c", "[%w_]+$"), Some("b_c".to_string()));
+        assert_eq!(matched("x]y", "[]]"), Some("]".to_string()));
+        assert_eq!(matched("abcabc", "(abc)%1"), Some("abcabc".to_string()));
+
+        let (_, _, captures) = find(b"k=v", b"(%w+)=()(%w+)", 0).unwrap().unwrap();
+        assert_eq!(
+            captures,
+            vec![Captured::Span(0, 1), Captured::Position(2), Captured::Span(2, 3)]
+        );
+
+        assert_eq!(find(b"a", b"[a", 0), Err("malformed pattern (missing ']')".to_string()));
+        assert_eq!(find(b"a", b"a%", 0), Err("malformed pattern (ends with '%')".to_string()));
+        assert_eq!(find(b"a", b"a)", 0), Err("invalid pattern capture".to_string()));
+    }
+}
@@ -0,0 +1,1220 @@
+//! The libraries scripts can use: Lua's base, string, table and math libraries,
+//! cjson and bit as Redis ships them, and the `redis` table
+use bytes::Bytes;
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use super::interpreter::{first, metamethod, Runtime};
+use super::json;
+use super::pattern::{self, Captured, Matcher, SPECIALS};
+use super::value::{format_g, format_number, new_table, Returns, Table, TableRef, Value};
+use super::LuaError;
+use crate::sha1::sha1_hex;
+
+type Builtin = fn(&mut Runtime, Vec<Value>) -> Returns;
+
+/// The most values unpack will return
+const MAX_UNPACK: i64 = 8000;
+
+/// The longest string string.rep will build
+const MAX_STRING: usize = 512 * 1024 * 1024;
+
+const BASE: &[(&str, Builtin)] = &[
+    ("assert", assert),
+    ("collectgarbage", collectgarbage),
+    ("error", error),
+    ("getmetatable", getmetatable),
+    ("ipairs", ipairs),
+    ("next", next),
+    ("pairs", pairs),
+    ("pcall", pcall),
+    ("rawequal", rawequal),
+    ("rawget", rawget),
+    ("rawset", rawset),
+    ("select", select),
+    ("setmetatable", setmetatable),
+    ("tonumber", tonumber),
+    ("tostring", tostring),
+    ("type", type_of),
+    ("unpack", unpack),
+    ("xpcall", xpcall),
+];
+
+const STRING: &[(&str, Builtin)] = &[
+    ("byte", string_byte),
+    ("char", string_char),
+    ("find", string_find),
+    ("format", string_format),
+    ("gmatch", string_gmatch),
+    ("gsub", string_gsub),
+    ("len", string_len),
+    ("lower", string_lower),
+    ("match", string_match),
+    ("rep", string_rep),
+    ("reverse", string_reverse),
+    ("sub", string_sub),
+    ("upper", string_upper),
+];
+
+const TABLE: &[(&str, Builtin)] = &[
+    ("concat", table_concat),
+    ("getn", table_getn),
+    ("insert", table_insert),
+    ("maxn", table_maxn),
+    ("remove", table_remove),
+    ("sort", table_sort),
+];
+
+const MATH: &[(&str, Builtin)] = &[
+    ("abs", |rt, args| math1(rt, args, "abs", f64::abs)),
+    ("acos", |rt, args| math1(rt, args, "acos", f64::acos)),
+    ("asin", |rt, args| math1(rt, args, "asin", f64::asin)),
+    ("atan", |rt, args| math1(rt, args, "atan", f64::atan)),
+    ("atan2", |rt, args| math2(rt, args, "atan2", f64::atan2)),
+    ("ceil", |rt, args| math1(rt, args, "ceil", f64::ceil)),
+    ("cos", |rt, args| math1(rt, args, "cos", f64::cos)),
+    ("deg", |rt, args| math1(rt, args, "deg", f64::to_degrees)),
+    ("exp", |rt, args| math1(rt, args, "exp", f64::exp)),
+    ("floor", |rt, args| math1(rt, args, "floor", f64::floor)),
+    ("fmod", |rt, args| math2(rt, args, "fmod", |x, y| x % y)),
+    ("log", |rt, args| math1(rt, args, "log", f64::ln)),
+    ("log10", |rt, args| math1(rt, args, "log10", f64::log10)),
+    ("max", math_max),
+    ("min", math_min),
+    ("modf", math_modf),
+    ("pow", |rt, args| math2(rt, args, "pow", f64::powf)),
+    ("rad", |rt, args| math1(rt, args, "rad", f64::to_radians)),
+    ("sin", |rt, args| math1(rt, args, "sin", f64::sin)),
+    ("sqrt", |rt, args| math1(rt, args, "sqrt", f64::sqrt)),
+    ("tan", |rt, args| math1(rt, args, "tan", f64::tan)),
+];
+
+const BIT: &[(&str, Builtin)] = &[
+    ("arshift", |rt, args| bit2(rt, args, "arshift", |x, n| x >> (n & 31))),
+    ("band", |rt, args| bits(rt, args, "band", |x, y| x & y)),
+    ("bnot", |rt, args| bit1(rt, args, "bnot", |x| !x)),
+    ("bor", |rt, args| bits(rt, args, "bor", |x, y| x | y)),
+    ("bswap", |rt, args| bit1(rt, args, "bswap", i32::swap_bytes)),
+    ("bxor", |rt, args| bits(rt, args, "bxor", |x, y| x ^ y)),
+    ("lshift", |rt, args| bit2(rt, args, "lshift", |x, n| x.wrapping_shl(n as u32))),
+    ("rol", |rt, args| bit2(rt, args, "rol", |x, n| x.rotate_left(n as u32 & 31))),
+    ("ror", |rt, args| bit2(rt, args, "ror", |x, n| x.rotate_right(n as u32 & 31))),
+    ("rshift", |rt, args| bit2(rt, args, "rshift", |x, n| ((x as u32) >> (n & 31)) as i32)),
+    ("tobit", |rt, args| bit1(rt, args, "tobit", |x| x)),
+    ("tohex", bit_tohex),
+];
+
+const CJSON: &[(&str, Builtin)] = &[("decode", cjson_decode), ("encode", cjson_encode)];
+
+const REDIS: &[(&str, Builtin)] = &[
+    ("call", |rt, args| redis_call(rt, args, true)),
+    ("error_reply", |rt, args| redis_reply(rt, args, "err")),
+    ("log", redis_log),
+    ("pcall", |rt, args| redis_call(rt, args, false)),
+    ("replicate_commands", |_, _| Ok(vec![Value::Boolean(true)])),
+    ("sha1hex", redis_sha1hex),
+    ("status_reply", |rt, args| redis_reply(rt, args, "ok")),
+];
+
+/// Loads the libraries into a new runtime's globals
+pub fn open(rt: &mut Runtime) {
+    for (name, function) in BASE {
+        rt.set_global(name, Value::native(name, *function));
+    }
+    rt.set_global("_VERSION", Value::str("Lua 5.1"));
+
+    {
+        let mut strings = rt.strings.borrow_mut();
+        for (name, function) in STRING {
+            strings.set_str(name, Value::native(name, *function));
+        }
+        strings.readonly = true;
+    }
+    rt.set_global("string", Value::Table(rt.strings.clone()));
+    rt.set_global("table", library(TABLE, |_| {}));
+    rt.set_global(
+        "math",
+        library(MATH, |math| {
+            math.set_str("huge", Value::Number(f64::INFINITY));
+            math.set_str("pi", Value::Number(std::f64::consts::PI));
+            // The same sequence for every run, so scripts are deterministic
+            let state = Rc::new(RefCell::new(Rand48::new(0)));
+            let random = state.clone();
+            math.set_str("random", Value::native("random", move |rt, args| math_random(rt, args, &random)));
+            math.set_str(
+                "randomseed",
+                Value::native("randomseed", move |rt, args| {
+                    let seed = check_number(rt, &args, 0, "randomseed")?;
+                    *state.borrow_mut() = Rand48::new(seed as i64);
+                    Ok(Vec::new())
+                }),
+            );
+        }),
+    );
+    rt.set_global("bit", library(BIT, |_| {}));
+    rt.set_global("cjson", library(CJSON, |_| {}));
+    rt.set_global(
+        "redis",
+        library(REDIS, |redis| {
+            for (i, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"].iter().enumerate() {
+                redis.set_str(level, Value::Number(i as f64));
+            }
+        }),
+    );
+
+    // Reading a global that isn't set is an error, as Redis makes it to catch typos
+    let globals = rt.globals.clone();
+    rt.set_global("_G", Value::Table(globals.clone()));
+    let mut metatable = Table::default();
+    metatable.set_str(
+        "__index",
+        Value::native("__index", |rt, args| {
+            let name = arg(&args, 1).to_bytes().unwrap_or_default();
+            Err(rt.error(format!(
+                "Script attempted to access nonexistent global variable '{}'",
+                String::from_utf8_lossy(&name)
+            )))
+        }),
+    );
+    globals.borrow_mut().metatable = Some(new_table(metatable));
+}
+
+/// A library table holding `functions`, with whatever else `extra` adds
+fn library(functions: &[(&'static str, Builtin)], extra: impl FnOnce(&mut Table)) -> Value {
+    let mut table = Table::default();
+    for (name, function) in functions {
+        table.set_str(name, Value::native(name, *function));
+    }
+    extra(&mut table);
+    table.readonly = true;
+    Value::table(table)
+}
+
+fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or_default()
+}
+
+fn bad_argument(rt: &Runtime, i: usize, name: &str, message: impl Display) -> LuaError {
+    rt.error(format!("bad argument #{} to '{}' ({})", i + 1, name, message))
+}
+
+fn type_error(rt: &Runtime, args: &[Value], i: usize, name: &str, expected: &str) -> LuaError {
+    let got = args.get(i).map_or("no value", Value::type_name);
+    bad_argument(rt, i, name, format!("{} expected, got {}", expected, got))
+}
+
+fn check_any(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<Value, LuaError> {
+    args.get(i)
+        .cloned()
+        .ok_or_else(|| bad_argument(rt, i, name, "value expected"))
+}
+
+fn check_table(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<TableRef, LuaError> {
+    match args.get(i) {
+        Some(Value::Table(table)) => Ok(table.clone()),
+        _ => Err(type_error(rt, args, i, name, "table")),
+    }
+}
+
+/// A table the caller is about to change
+fn check_writable(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<TableRef, LuaError> {
+    let table = check_table(rt, args, i, name)?;
+    if table.borrow().readonly {
+        return Err(rt.error("Attempt to modify a readonly table"));
+    }
+    Ok(table)
+}
+
+fn check_number(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<f64, LuaError> {
+    args.get(i)
+        .and_then(Value::to_number)
+        .ok_or_else(|| type_error(rt, args, i, name, "number"))
+}
+
+fn check_integer(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<i64, LuaError> {
+    check_number(rt, args, i, name).map(|n| n as i64)
+}
+
+fn opt_integer(rt: &Runtime, args: &[Value], i: usize, name: &str, default: i64) -> Result<i64, LuaError> {
+    match args.get(i) {
+        None | Some(Value::Nil) => Ok(default),
+        Some(_) => check_integer(rt, args, i, name),
+    }
+}
+
+fn check_bytes(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<Bytes, LuaError> {
+    args.get(i)
+        .and_then(Value::to_bytes)
+        .ok_or_else(|| type_error(rt, args, i, name, "string"))
+}
+
+/// `tostring`, honouring `__tostring`
+pub fn tostring_value(rt: &mut Runtime, value: &Value) -> Result<Bytes, LuaError> {
+    if let Value::Table(table) = value {
+        let handler = metamethod(&table.borrow(), "__tostring");
+        if !matches!(handler, Value::Nil) {
+            return first(rt.call(&handler, vec![value.clone()])?)
+                .to_bytes()
+                .ok_or_else(|| rt.error("'__tostring' must return a string"));
+        }
+    }
+    Ok(match value {
+        Value::Nil => Bytes::from_static(b"nil"),
+        Value::Boolean(true) => Bytes::from_static(b"true"),
+        Value::Boolean(false) => Bytes::from_static(b"false"),
+        Value::Number(_) | Value::String(_) => value.to_bytes().unwrap_or_default(),
+        Value::Table(_) | Value::Function(_) => Bytes::from(format!("{:?}", value)),
+    })
+}
+
+fn assert(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    if check_any(rt, &args, 0, "assert")?.truthy() {
+        return Ok(args);
+    }
+    match args.get(1).and_then(Value::to_bytes) {
+        Some(message) => Err(rt.error(String::from_utf8_lossy(&message))),
+        None => Err(rt.error("assertion failed!")),
+    }
+}
+
+fn collectgarbage(_: &mut Runtime, _: Vec<Value>) -> Returns {
+    Ok(vec![Value::Number(0.0)])
+}
+
+fn error(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let level = opt_integer(rt, &args, 1, "error", 1)?;
+    let message = arg(&args, 0);
+    Err(match message {
+        // Messages get the position they were raised from, unless level is 0
+        Value::String(_) | Value::Number(_) if level > 0 => {
+            rt.error(String::from_utf8_lossy(&message.to_bytes().unwrap_or_default()))
+        }
+        message => LuaError::Error(message),
+    })
+}
+
+fn getmetatable(_: &mut Runtime, args: Vec<Value>) -> Returns {
+    let metatable = match args.first() {
+        Some(Value::Table(table)) => table.borrow().metatable.clone(),
+        _ => None,
+    };
+    Ok(vec![match metatable {
+        Some(metatable) => match metatable.borrow().get_str("__metatable") {
+            Value::Nil => Value::Table(metatable.clone()),
+            protected => protected,
+        },
+        None => Value::Nil,
+    }])
+}
+
+fn ipairs(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "ipairs")?;
+    let iterator = Value::native("ipairs_iterator", |rt, args| {
+        let table = check_table(rt, &args, 0, "ipairs")?;
+        let i = check_number(rt, &args, 1, "ipairs")? + 1.0;
+        let value = table.borrow().get(&Value::Number(i));
+        Ok(match value {
+            Value::Nil => vec![Value::Nil],
+            value => vec![Value::Number(i), value],
+        })
+    });
+    Ok(vec![iterator, Value::Table(table), Value::Number(0.0)])
+}
+
+fn next(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "next")?;
+    let entry = table.borrow().next(&arg(&args, 1));
+    match entry {
+        Ok(Some((key, value))) => Ok(vec![key, value]),
+        Ok(None) => Ok(vec![Value::Nil]),
+        Err(()) => Err(rt.error("invalid key to 'next'")),
+    }
+}
+
+fn pairs(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "pairs")?;
+    Ok(vec![Value::native("next", next), Value::Table(table), Value::Nil])
+}
+
+fn pcall(rt: &mut Runtime, mut args: Vec<Value>) -> Returns {
+    let function = check_any(rt, &args, 0, "pcall")?;
+    args.remove(0);
+    let line = rt.line();
+    match rt.call(&function, args) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(LuaError::Error(error)) => {
+            rt.set_line(line);
+            Ok(vec![Value::Boolean(false), error])
+        }
+        // Running out of time isn't something the script gets to handle
+        Err(interrupted) => Err(interrupted),
+    }
+}
+
+fn xpcall(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let function = arg(&args, 0);
+    let handler = check_any(rt, &args, 1, "xpcall")?;
+    let line = rt.line();
+    match rt.call(&function, Vec::new()) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(LuaError::Error(error)) => {
+            let handled = first(rt.call(&handler, vec![error])?);
+            rt.set_line(line);
+            Ok(vec![Value::Boolean(false), handled])
+        }
+        Err(interrupted) => Err(interrupted),
+    }
+}
+
+fn rawequal(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let a = check_any(rt, &args, 0, "rawequal")?;
+    let b = check_any(rt, &args, 1, "rawequal")?;
+    Ok(vec![Value::Boolean(a.raw_equals(&b))])
+}
+
+fn rawget(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "rawget")?;
+    let value = table.borrow().get(&arg(&args, 1));
+    Ok(vec![value])
+}
+
+fn rawset(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_writable(rt, &args, 0, "rawset")?;
+    let result = table.borrow_mut().set(arg(&args, 1), arg(&args, 2));
+    result.map_err(|message| rt.error(message))?;
+    Ok(vec![Value::Table(table)])
+}
+
+fn select(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let count = args.len() as i64 - 1;
+    if let Some(Value::String(s)) = args.first() {
+        if &s[..] == b"#" {
+            return Ok(vec![Value::Number(count as f64)]);
+        }
+    }
+    let n = check_integer(rt, &args, 0, "select")?;
+    let skip = match n {
+        n if n < 0 => count + n,
+        0 => -1,
+        n => n - 1,
+    };
+    if skip < 0 {
+        return Err(bad_argument(rt, 0, "select", "index out of range"));
+    }
+    Ok(args.into_iter().skip(1 + skip as usize).collect())
+}
+
+fn setmetatable(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_writable(rt, &args, 0, "setmetatable")?;
+    let metatable = match args.get(1) {
+        Some(Value::Table(metatable)) => Some(metatable.clone()),
+        Some(Value::Nil) => None,
+        _ => return Err(bad_argument(rt, 1, "setmetatable", "nil or table expected")),
+    };
+    let protected = match &table.borrow().metatable {
+        Some(current) => !matches!(current.borrow().get_str("__metatable"), Value::Nil),
+        None => false,
+    };
+    if protected {
+        return Err(rt.error("cannot change a protected metatable"));
+    }
+    table.borrow_mut().metatable = metatable;
+    Ok(vec![Value::Table(table)])
+}
+
+fn tonumber(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let value = check_any(rt, &args, 0, "tonumber")?;
+    let base = opt_integer(rt, &args, 1, "tonumber", 10)?;
+    if base == 10 {
+        return Ok(vec![value.to_number().map_or(Value::Nil, Value::Number)]);
+    }
+    if !(2..=36).contains(&base) {
+        return Err(bad_argument(rt, 1, "tonumber", "base out of range"));
+    }
+    let digits = check_bytes(rt, &args, 0, "tonumber")?;
+    let digits = String::from_utf8_lossy(&digits);
+    let parsed = i64::from_str_radix(digits.trim(), base as u32).ok();
+    Ok(vec![parsed.map_or(Value::Nil, |n| Value::Number(n as f64))])
+}
+
+fn tostring(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let value = check_any(rt, &args, 0, "tostring")?;
+    Ok(vec![Value::String(tostring_value(rt, &value)?)])
+}
+
+fn type_of(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let value = check_any(rt, &args, 0, "type")?;
+    Ok(vec![Value::str(value.type_name())])
+}
+
+fn unpack(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "unpack")?;
+    let table = table.borrow();
+    let start = opt_integer(rt, &args, 1, "unpack", 1)?;
+    let end = opt_integer(rt, &args, 2, "unpack", table.len() as i64)?;
+    if start > end {
+        return Ok(Vec::new());
+    }
+    if end - start >= MAX_UNPACK {
+        return Err(rt.error("too many results to unpack"));
+    }
+    Ok((start..=end).map(|i| table.get(&Value::Number(i as f64))).collect())
+}
+
+/// A position in a string counted from 1, where negative ones count from the end
+fn position(pos: i64, len: usize) -> i64 {
+    if pos < 0 {
+        (len as i64 + pos + 1).max(0)
+    } else {
+        pos
+    }
+}
+
+fn string_byte(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "byte")?;
+    let start = position(opt_integer(rt, &args, 1, "byte", 1)?, s.len()).max(1);
+    let end = position(opt_integer(rt, &args, 2, "byte", start)?, s.len()).min(s.len() as i64);
+    Ok((start..=end).map(|i| Value::Number(s[i as usize - 1] as f64)).collect())
+}
+
+fn string_char(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let mut s = Vec::with_capacity(args.len());
+    for i in 0..args.len() {
+        let c = check_integer(rt, &args, i, "char")?;
+        if !(0..=255).contains(&c) {
+            return Err(bad_argument(rt, i, "char", "invalid value"));
+        }
+        s.push(c as u8);
+    }
+    Ok(vec![Value::String(Bytes::from(s))])
+}
+
+fn captured(s: &Bytes, capture: Captured) -> Value {
+    match capture {
+        Captured::Span(start, end) => Value::String(s.slice(start..end)),
+        Captured::Position(at) => Value::Number((at + 1) as f64),
+    }
+}
+
+/// string.find, or string.match when `find` isn't set
+fn find(rt: &mut Runtime, args: Vec<Value>, find: bool) -> Returns {
+    let name = if find { "find" } else { "match" };
+    let s = check_bytes(rt, &args, 0, name)?;
+    let p = check_bytes(rt, &args, 1, name)?;
+    let init = (position(opt_integer(rt, &args, 2, name, 1)?, s.len()) - 1).clamp(0, s.len() as i64) as usize;
+    if find && (arg(&args, 3).truthy() || !p.iter().any(|c| SPECIALS.contains(c))) {
+        let found = if p.is_empty() {
+            Some(init)
+        } else {
+            s[init..].windows(p.len()).position(|window| window == &p[..]).map(|i| init + i)
+        };
+        return Ok(match found {
+            Some(start) => vec![Value::Number((start + 1) as f64), Value::Number((start + p.len()) as f64)],
+            None => vec![Value::Nil],
+        });
+    }
+    match pattern::find(&s, &p, init).map_err(|message| rt.error(message))? {
+        Some((start, end, captures)) => {
+            let mut values = Vec::new();
+            if find {
+                values.push(Value::Number((start + 1) as f64));
+                values.push(Value::Number(end as f64));
+            } else if captures.is_empty() {
+                values.push(Value::String(s.slice(start..end)));
+            }
+            values.extend(captures.into_iter().map(|capture| captured(&s, capture)));
+            Ok(values)
+        }
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+fn string_find(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    find(rt, args, true)
+}
+
+fn string_match(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    find(rt, args, false)
+}
+
+fn string_gmatch(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "gmatch")?;
+    let p = check_bytes(rt, &args, 1, "gmatch")?;
+    let next = Rc::new(RefCell::new(0));
+    let iterator = Value::native("gmatch_iterator", move |rt, _| {
+        let mut matcher = Matcher::new(&s, &p);
+        let mut start = *next.borrow();
+        while start <= s.len() {
+            rt.tick()?;
+            if let Some(end) = matcher.match_at(start, 0).map_err(|message| rt.error(message))? {
+                // An empty match moves on a character, so the loop ends
+                *next.borrow_mut() = if end == start { end + 1 } else { end };
+                let captures = matcher.captures(start, end, true).map_err(|message| rt.error(message))?;
+                return Ok(captures.into_iter().map(|capture| captured(&s, capture)).collect());
+            }
+            start += 1;
+        }
+        *next.borrow_mut() = start;
+        Ok(vec![Value::Nil])
+    });
+    Ok(vec![iterator])
+}
+
+fn string_gsub(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "gsub")?;
+    let p = check_bytes(rt, &args, 1, "gsub")?;
+    let replacement = arg(&args, 2);
+    if !matches!(
+        replacement,
+        Value::Number(_) | Value::String(_) | Value::Table(_) | Value::Function(_)
+    ) {
+        return Err(type_error(rt, &args, 2, "gsub", "string/function/table"));
+    }
+    let limit = match args.get(3) {
+        None | Some(Value::Nil) => i64::MAX,
+        Some(_) => check_integer(rt, &args, 3, "gsub")?,
+    };
+    let anchored = p.first() == Some(&b'^');
+    let mut matcher = Matcher::new(&s, &p);
+    let mut out = Vec::with_capacity(s.len());
+    let mut start = 0;
+    let mut count = 0;
+    while count < limit {
+        rt.tick()?;
+        let end = matcher.match_at(start, anchored as usize).map_err(|message| rt.error(message))?;
+        if let Some(end) = end {
+            count += 1;
+            substitute(rt, &matcher, &s, start, end, &replacement, &mut out)?;
+        }
+        match end {
+            Some(end) if end > start => start = end,
+            _ if start < s.len() => {
+                out.push(s[start]);
+                start += 1;
+            }
+            _ => break,
+        }
+        if anchored {
+            break;
+        }
+    }
+    out.extend_from_slice(&s[start..]);
+    Ok(vec![Value::String(Bytes::from(out)), Value::Number(count as f64)])
+}
+
+/// Appends the replacement for a match to `out`
+fn substitute(
+    rt: &mut Runtime,
+    matcher: &Matcher,
+    s: &Bytes,
+    start: usize,
+    end: usize,
+    replacement: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), LuaError> {
+    let capture = |rt: &Runtime, i: usize| {
+        matcher
+            .capture(i, start, end)
+            .map(|capture| captured(s, capture))
+            .map_err(|message| rt.error(message))
+    };
+    let value = match replacement {
+        Value::Table(_) => {
+            let key = capture(rt, 0)?;
+            rt.index(replacement, &key)?
+        }
+        Value::Function(_) => {
+            let captures = matcher
+                .captures(start, end, true)
+                .map_err(|message| rt.error(message))?;
+            let captures = captures.into_iter().map(|capture| captured(s, capture)).collect();
+            first(rt.call(replacement, captures)?)
+        }
+        _ => {
+            let template = replacement.to_bytes().unwrap_or_default();
+            let mut i = 0;
+            while i < template.len() {
+                let c = template[i];
+                i += 1;
+                if c != b'%' || i == template.len() {
+                    out.push(c);
+                    continue;
+                }
+                let escaped = template[i];
+                i += 1;
+                match escaped {
+                    b'0' => out.extend_from_slice(&s[start..end]),
+                    b'1'..=b'9' => {
+                        let value = capture(rt, (escaped - b'1') as usize)?;
+                        out.extend_from_slice(&value.to_bytes().unwrap_or_default());
+                    }
+                    other => out.push(other),
+                }
+            }
+            return Ok(());
+        }
+    };
+    match value {
+        // nil or false keeps what was matched
+        Value::Nil | Value::Boolean(false) => out.extend_from_slice(&s[start..end]),
+        value => match value.to_bytes() {
+            Some(bytes) => out.extend_from_slice(&bytes),
+            None => return Err(rt.error(format!("invalid replacement value (a {})", value.type_name()))),
+        },
+    }
+    Ok(())
+}
+
+fn string_len(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "len")?;
+    Ok(vec![Value::Number(s.len() as f64)])
+}
+
+fn string_lower(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "lower")?;
+    Ok(vec![Value::String(Bytes::from(s.to_ascii_lowercase()))])
+}
+
+fn string_upper(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "upper")?;
+    Ok(vec![Value::String(Bytes::from(s.to_ascii_uppercase()))])
+}
+
+fn string_rep(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "rep")?;
+    let n = check_integer(rt, &args, 1, "rep")?.max(0) as usize;
+    if s.len().saturating_mul(n) > MAX_STRING {
+        return Err(rt.error("resulting string too large"));
+    }
+    Ok(vec![Value::String(Bytes::from(s.repeat(n)))])
+}
+
+fn string_reverse(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "reverse")?;
+    Ok(vec![Value::String(s.iter().rev().copied().collect())])
+}
+
+fn string_sub(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let s = check_bytes(rt, &args, 0, "sub")?;
+    let start = position(check_integer(rt, &args, 1, "sub")?, s.len()).max(1);
+    let end = position(opt_integer(rt, &args, 2, "sub", -1)?, s.len()).min(s.len() as i64);
+    Ok(vec![Value::String(if start <= end {
+        s.slice(start as usize - 1..end as usize)
+    } else {
+        Bytes::new()
+    })])
+}
+
+/// A conversion in string.format, like `%-5.2f`
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    fn pad(&self, sign: &str, body: &[u8], numeric: bool, out: &mut Vec<u8>) {
+        let fill = self.width.saturating_sub(sign.len() + body.len());
+        if self.left {
+            out.extend_from_slice(sign.as_bytes());
+            out.extend_from_slice(body);
+            out.resize(out.len() + fill, b' ');
+        } else if self.zero && numeric {
+            out.extend_from_slice(sign.as_bytes());
+            out.resize(out.len() + fill, b'0');
+            out.extend_from_slice(body);
+        } else {
+            out.resize(out.len() + fill, b' ');
+            out.extend_from_slice(sign.as_bytes());
+            out.extend_from_slice(body);
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        match (negative, self.plus, self.space) {
+            (true, _, _) => "-",
+            (false, true, _) => "+",
+            (false, false, true) => " ",
+            _ => "",
+        }
+    }
+}
+
+/// `%e` as C formats it, with at least two exponent digits
+fn format_exponent(n: f64, precision: usize) -> String {
+    let formatted = format!("{:.*e}", precision, n);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    format!("{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+fn string_format(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let format = check_bytes(rt, &args, 0, "format")?;
+    let mut out = Vec::with_capacity(format.len());
+    let mut next_arg = 1;
+    let mut i = 0;
+    while i < format.len() {
+        let c = format[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if format.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let mut spec = Spec::default();
+        let flags_start = i;
+        while let Some(flag) = format.get(i).filter(|c| b"-+ #0".contains(c)) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                _ => spec.zero = true,
+            }
+            i += 1;
+        }
+        if i - flags_start > 5 {
+            return Err(rt.error("invalid format (repeated flags)"));
+        }
+        let digits = |i: &mut usize| {
+            let mut n = 0;
+            let mut count = 0;
+            while let Some(d) = format.get(*i).filter(|c| c.is_ascii_digit()) {
+                n = n * 10 + (d - b'0') as usize;
+                count += 1;
+                *i += 1;
+            }
+            (n, count)
+        };
+        let (width, width_digits) = digits(&mut i);
+        spec.width = width;
+        let mut precision_digits = 0;
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            let (precision, count) = digits(&mut i);
+            spec.precision = Some(precision);
+            precision_digits = count;
+        }
+        if width_digits > 2 || precision_digits > 2 {
+            return Err(rt.error("invalid format (width or precision too long)"));
+        }
+        let conversion = format.get(i).copied().unwrap_or(0);
+        i += 1;
+        let n = next_arg;
+        next_arg += 1;
+        match conversion {
+            b'c' => {
+                let c = check_integer(rt, &args, n, "format")?;
+                spec.pad("", &[c as u8], false, &mut out);
+            }
+            b'd' | b'i' | b'u' => {
+                let value = check_number(rt, &args, n, "format")? as i64;
+                let mut body = value.unsigned_abs().to_string();
+                if let Some(precision) = spec.precision {
+                    if precision == 0 && value == 0 {
+                        body.clear();
+                    }
+                    body = format!("{:0>width$}", body, width = precision);
+                    spec.zero = false;
+                }
+                spec.pad(spec.sign(value < 0), body.as_bytes(), true, &mut out);
+            }
+            b'o' | b'x' | b'X' => {
+                let value = check_number(rt, &args, n, "format")? as i64 as u64;
+                let mut body = match conversion {
+                    b'o' => format!("{:o}", value),
+                    b'x' => format!("{:x}", value),
+                    _ => format!("{:X}", value),
+                };
+                if let Some(precision) = spec.precision {
+                    body = format!("{:0>width$}", body, width = precision);
+                    spec.zero = false;
+                }
+                let prefix = match conversion {
+                    _ if !spec.alternate || value == 0 => "",
+                    b'o' => "0",
+                    b'x' => "0x",
+                    _ => "0X",
+                };
+                spec.pad(prefix, body.as_bytes(), true, &mut out);
+            }
+            b'e' | b'E' | b'f' | b'g' | b'G' => {
+                let value = check_number(rt, &args, n, "format")?;
+                let precision = spec.precision.unwrap_or(6);
+                let mut body = if !value.is_finite() {
+                    format_number(value.abs())
+                } else {
+                    match conversion {
+                        b'e' | b'E' => format_exponent(value.abs(), precision),
+                        b'f' => format!("{:.*}", precision, value.abs()),
+                        _ => format_g(value.abs(), precision, spec.alternate),
+                    }
+                };
+                if conversion.is_ascii_uppercase() {
+                    body = body.to_ascii_uppercase();
+                }
+                let numeric = value.is_finite();
+                spec.pad(spec.sign(value.is_sign_negative() && !value.is_nan()), body.as_bytes(), numeric, &mut out);
+            }
+            b'q' => {
+                let s = check_bytes(rt, &args, n, "format")?;
+                out.push(b'"');
+                for &c in s.iter() {
+                    match c {
+                        b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', c]),
+                        b'\r' => out.extend_from_slice(b"\\r"),
+                        0 => out.extend_from_slice(b"\\000"),
+                        c => out.push(c),
+                    }
+                }
+                out.push(b'"');
+            }
+            b's' => {
+                let value = check_any(rt, &args, n, "format")?;
+                let s = tostring_value(rt, &value)?;
+                let s = match spec.precision {
+                    Some(precision) => &s[..precision.min(s.len())],
+                    None => &s[..],
+                };
+                spec.pad("", s, false, &mut out);
+            }
+            c => {
+                return Err(rt.error(format!(
+                    "invalid option '%{}' to 'format'",
+                    String::from_utf8_lossy(&[c])
+                )))
+            }
+        }
+    }
+    Ok(vec![Value::String(Bytes::from(out))])
+}
+
+fn table_concat(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "concat")?;
+    let separator = match args.get(1) {
+        None | Some(Value::Nil) => Bytes::new(),
+        Some(_) => check_bytes(rt, &args, 1, "concat")?,
+    };
+    let table = table.borrow();
+    let start = opt_integer(rt, &args, 2, "concat", 1)?;
+    let end = opt_integer(rt, &args, 3, "concat", table.len() as i64)?;
+    let mut out = Vec::new();
+    for i in start..=end {
+        match table.get(&Value::Number(i as f64)).to_bytes() {
+            Some(s) => out.extend_from_slice(&s),
+            None => return Err(rt.error(format!("invalid value (at index {}) in table for 'concat'", i))),
+        }
+        if i < end {
+            out.extend_from_slice(&separator);
+        }
+    }
+    Ok(vec![Value::String(Bytes::from(out))])
+}
+
+fn table_getn(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "getn")?;
+    let len = table.borrow().len();
+    Ok(vec![Value::Number(len as f64)])
+}
+
+fn table_maxn(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_table(rt, &args, 0, "maxn")?;
+    let table = table.borrow();
+    let mut max = 0.0f64;
+    let mut key = Value::Nil;
+    while let Ok(Some((k, _))) = table.next(&key) {
+        if let Value::Number(n) = k {
+            max = max.max(n);
+        }
+        key = k;
+    }
+    Ok(vec![Value::Number(max)])
+}
+
+fn table_insert(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_writable(rt, &args, 0, "insert")?;
+    let len = table.borrow().len() as i64;
+    match args.len() {
+        2 => table.borrow_mut().push(arg(&args, 1)),
+        3 => {
+            let position = check_integer(rt, &args, 1, "insert")?;
+            if (1..=len + 1).contains(&position) {
+                table.borrow_mut().insert(position as usize - 1, arg(&args, 2));
+            } else {
+                let result = table.borrow_mut().set(Value::Number(position as f64), arg(&args, 2));
+                result.map_err(|message| rt.error(message))?;
+            }
+        }
+        _ => return Err(rt.error("wrong number of arguments to 'insert'")),
+    }
+    Ok(Vec::new())
+}
+
+fn table_remove(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_writable(rt, &args, 0, "remove")?;
+    let len = table.borrow().len() as i64;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let position = opt_integer(rt, &args, 1, "remove", len)?;
+    if !(1..=len).contains(&position) {
+        return Ok(vec![Value::Nil]);
+    }
+    let value = table.borrow_mut().remove(position as usize - 1);
+    Ok(vec![value])
+}
+
+fn table_sort(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let table = check_writable(rt, &args, 0, "sort")?;
+    let comparator = match args.get(1) {
+        None | Some(Value::Nil) => None,
+        Some(Value::Function(_)) => Some(arg(&args, 1)),
+        Some(_) => return Err(type_error(rt, &args, 1, "sort", "function")),
+    };
+    let values = {
+        let table = table.borrow();
+        (1..=table.len()).map(|i| table.get(&Value::Number(i as f64))).collect()
+    };
+    let mut less = |rt: &mut Runtime, a: &Value, b: &Value| match &comparator {
+        Some(comparator) => Ok(first(rt.call(comparator, vec![a.clone(), b.clone()])?).truthy()),
+        None => rt.less_than(a, b),
+    };
+    let sorted = merge_sort(rt, values, &mut less)?;
+    let mut table = table.borrow_mut();
+    for (i, value) in sorted.into_iter().enumerate() {
+        let _ = table.set(Value::Number((i + 1) as f64), value);
+    }
+    Ok(Vec::new())
+}
+
+/// A merge sort, as a comparison written in Lua may fail or be inconsistent
+fn merge_sort<F>(rt: &mut Runtime, mut values: Vec<Value>, less: &mut F) -> Result<Vec<Value>, LuaError>
+where
+    F: FnMut(&mut Runtime, &Value, &Value) -> Result<bool, LuaError>,
+{
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let left = merge_sort(rt, values, less)?;
+    let right = merge_sort(rt, right, less)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        rt.tick()?;
+        if less(rt, b, a)? {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+fn math1(rt: &mut Runtime, args: Vec<Value>, name: &str, f: fn(f64) -> f64) -> Returns {
+    let x = check_number(rt, &args, 0, name)?;
+    Ok(vec![Value::Number(f(x))])
+}
+
+fn math2(rt: &mut Runtime, args: Vec<Value>, name: &str, f: fn(f64, f64) -> f64) -> Returns {
+    let x = check_number(rt, &args, 0, name)?;
+    let y = check_number(rt, &args, 1, name)?;
+    Ok(vec![Value::Number(f(x, y))])
+}
+
+fn math_max(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let mut max = check_number(rt, &args, 0, "max")?;
+    for i in 1..args.len() {
+        max = max.max(check_number(rt, &args, i, "max")?);
+    }
+    Ok(vec![Value::Number(max)])
+}
+
+fn math_min(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let mut min = check_number(rt, &args, 0, "min")?;
+    for i in 1..args.len() {
+        min = min.min(check_number(rt, &args, i, "min")?);
+    }
+    Ok(vec![Value::Number(min)])
+}
+
+fn math_modf(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let x = check_number(rt, &args, 0, "modf")?;
+    Ok(vec![Value::Number(x.trunc()), Value::Number(x.fract())])
+}
+
+/// The lrand48 generator Redis gives scripts
+struct Rand48(u64);
+
+impl Rand48 {
+    fn new(seed: i64) -> Rand48 {
+        Rand48(((seed as u64 & 0xffff_ffff) << 16) | 0x330e)
+    }
+
+    /// A number from 0 to 2^31 - 1
+    fn next(&mut self) -> u32 {
+        self.0 = (self.0.wrapping_mul(0x5_deec_e66d).wrapping_add(0xb)) & ((1 << 48) - 1);
+        (self.0 >> 17) as u32
+    }
+}
+
+fn math_random(rt: &mut Runtime, args: Vec<Value>, state: &RefCell<Rand48>) -> Returns {
+    const MAX: u32 = i32::MAX as u32;
+    let r = (state.borrow_mut().next() % MAX) as f64 / MAX as f64;
+    let value = match args.len() {
+        0 => r,
+        1 => {
+            let upper = check_integer(rt, &args, 0, "random")?;
+            if upper < 1 {
+                return Err(bad_argument(rt, 0, "random", "interval is empty"));
+            }
+            (r * upper as f64).floor() + 1.0
+        }
+        2 => {
+            let lower = check_integer(rt, &args, 0, "random")?;
+            let upper = check_integer(rt, &args, 1, "random")?;
+            if lower > upper {
+                return Err(bad_argument(rt, 1, "random", "interval is empty"));
+            }
+            (r * (upper - lower + 1) as f64).floor() + lower as f64
+        }
+        _ => return Err(rt.error("wrong number of arguments")),
+    };
+    Ok(vec![Value::Number(value)])
+}
+
+/// A number as the 32-bit integer the bit library works on
+fn check_bit(rt: &Runtime, args: &[Value], i: usize, name: &str) -> Result<i32, LuaError> {
+    let n = check_number(rt, args, i, name)?.round();
+    Ok(n.rem_euclid(4294967296.0) as u32 as i32)
+}
+
+fn bit1(rt: &mut Runtime, args: Vec<Value>, name: &str, f: fn(i32) -> i32) -> Returns {
+    let x = check_bit(rt, &args, 0, name)?;
+    Ok(vec![Value::Number(f(x) as f64)])
+}
+
+fn bit2(rt: &mut Runtime, args: Vec<Value>, name: &str, f: fn(i32, i32) -> i32) -> Returns {
+    let x = check_bit(rt, &args, 0, name)?;
+    let n = check_bit(rt, &args, 1, name)?;
+    Ok(vec![Value::Number(f(x, n) as f64)])
+}
+
+fn bits(rt: &mut Runtime, args: Vec<Value>, name: &str, f: fn(i32, i32) -> i32) -> Returns {
+    let mut x = check_bit(rt, &args, 0, name)?;
+    for i in 1..args.len() {
+        x = f(x, check_bit(rt, &args, i, name)?);
+    }
+    Ok(vec![Value::Number(x as f64)])
+}
+
+fn bit_tohex(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let x = check_bit(rt, &args, 0, "tohex")? as u32;
+    let n = opt_integer(rt, &args, 1, "tohex", 8)?;
+    let digits = n.unsigned_abs().clamp(1, 8) as usize;
+    let hex = if n < 0 { format!("{:08X}", x) } else { format!("{:08x}", x) };
+    Ok(vec![Value::str(&hex[8 - digits..])])
+}
+
+fn cjson_encode(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let value = check_any(rt, &args, 0, "encode")?;
+    let json = json::encode(&value).map_err(|message| rt.error(message))?;
+    Ok(vec![Value::String(Bytes::from(json))])
+}
+
+fn cjson_decode(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    let json = check_bytes(rt, &args, 0, "decode")?;
+    let value = json::decode(&json).map_err(|message| rt.error(message))?;
+    Ok(vec![value])
+}
+
+/// A table like `{err = message}`, as error replies are given to scripts
+pub fn error_table(message: &str) -> Value {
+    let mut table = Table::default();
+    table.set_str("err", Value::str(message));
+    Value::table(table)
+}
+
+fn redis_call(rt: &mut Runtime, args: Vec<Value>, raise: bool) -> Returns {
+    let mut command = Vec::with_capacity(args.len());
+    for arg in &args {
+        match arg {
+            Value::String(_) | Value::Number(_) => command.extend(arg.to_bytes()),
+            _ => command.clear(),
+        }
+    }
+    let reply = if args.is_empty() {
+        error_table("ERR Please specify at least one argument for this redis lib call")
+    } else if command.len() < args.len() {
+        error_table("ERR Lua redis lib command arguments must be strings or integers")
+    } else {
+        rt.host().call(command)
+    };
+    if let Value::Table(table) = &reply {
+        if raise && matches!(table.borrow().get_str("err"), Value::String(_)) {
+            return Err(LuaError::Error(reply.clone()));
+        }
+    }
+    Ok(vec![reply])
+}
+
+/// redis.error_reply and redis.status_reply, which wrap a string in a table
+/// under `field`
+fn redis_reply(rt: &mut Runtime, args: Vec<Value>, field: &str) -> Returns {
+    let message = match args.as_slice() {
+        [Value::String(message)] => message.clone(),
+        _ => return Err(rt.error("wrong number or type of arguments")),
+    };
+    let mut table = Table::default();
+    table.set_str(field, Value::String(message));
+    Ok(vec![Value::table(table)])
+}
+
+fn redis_log(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    if args.len() < 2 {
+        return Err(rt.error("redis.log() requires two arguments or more."));
+    }
+    let level = match &args[0] {
+        Value::Number(level) => *level as i64,
+        _ => return Err(rt.error("First argument must be a number (log level).")),
+    };
+    if !(0..=3).contains(&level) {
+        return Err(rt.error("Invalid debug level."));
+    }
+    let mut message = Vec::new();
+    for (i, part) in args.iter().enumerate().skip(1) {
+        if i > 1 {
+            message.push(b' ');
+        }
+        message.extend_from_slice(&part.to_bytes().unwrap_or_default());
+    }
+    rt.host().log(level, &String::from_utf8_lossy(&message));
+    Ok(Vec::new())
+}
+
+fn redis_sha1hex(rt: &mut Runtime, args: Vec<Value>) -> Returns {
+    if args.len() != 1 {
+        return Err(rt.error("wrong number of arguments"));
+    }
+    let s = check_bytes(rt, &args, 0, "sha1hex")?;
+    Ok(vec![Value::str(&sha1_hex(&s))])
+}
@@ -0,0 +1,530 @@
+//! Lua values, and the tables they're kept in
+use bytes::Bytes;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+
+use super::ast::FunctionProto;
+use super::interpreter::Runtime;
+use super::LuaError;
+
+pub type TableRef = Rc<RefCell<Table>>;
+
+/// A local variable, shared with the closures that capture it
+pub type Cell = Rc<RefCell<Value>>;
+
+/// What a function returns, or the error it raised
+pub type Returns = Result<Vec<Value>, LuaError>;
+
+#[derive(Clone, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    /// Lua strings are byte strings, as Redis ones are
+    String(Bytes),
+    Table(TableRef),
+    Function(Function),
+}
+
+#[derive(Clone)]
+pub enum Function {
+    Lua(Rc<Closure>),
+    Native(Rc<Native>),
+}
+
+/// A function defined in Lua, with the variables it captured
+pub struct Closure {
+    pub proto: Arc<FunctionProto>,
+    pub upvalues: Vec<Cell>,
+}
+
+/// A function implemented in Rust
+pub struct Native {
+    pub name: &'static str,
+    #[allow(clippy::type_complexity)]
+    pub call: Box<dyn Fn(&mut Runtime, Vec<Value>) -> Returns>,
+}
+
+impl Value {
+    pub fn str(s: &str) -> Value {
+        Value::String(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    pub fn native(name: &'static str, call: impl Fn(&mut Runtime, Vec<Value>) -> Returns + 'static) -> Value {
+        Value::Function(Function::Native(Rc::new(Native {
+            name,
+            call: Box::new(call),
+        })))
+    }
+
+    pub fn table(table: Table) -> Value {
+        Value::Table(new_table(table))
+    }
+
+    /// Everything but nil and false is true
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+        }
+    }
+
+    /// The value as a number, converting strings that look like one
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::String(s) => parse_number(s),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, converting numbers
+    pub fn to_bytes(&self) -> Option<Bytes> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(Bytes::from(format_number(*n))),
+            _ => None,
+        }
+    }
+
+    /// Equality without metamethods: tables and functions are only equal to
+    /// themselves
+    pub fn raw_equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => a.ptr_eq(b),
+            _ => false,
+        }
+    }
+}
+
+impl Function {
+    fn ptr_eq(&self, other: &Function) -> bool {
+        match (self, other) {
+            (Function::Lua(a), Function::Lua(b)) => Rc::ptr_eq(a, b),
+            (Function::Native(a), Function::Native(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Function::Lua(closure) => Rc::as_ptr(closure) as *const u8 as usize,
+            Function::Native(native) => Rc::as_ptr(native) as *const u8 as usize,
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::String(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            Value::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+            Value::Function(function) => write!(f, "function: 0x{:x}", function.address()),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.raw_equals(other)
+    }
+}
+
+/// Tables and captured variables, which are what reference cycles go through.
+/// Values are reference counted, so cycles have to be broken when a run ends.
+#[derive(Default)]
+struct Heap {
+    tables: Vec<Weak<RefCell<Table>>>,
+    cells: Vec<Weak<RefCell<Value>>>,
+    /// How many were live when dead entries were last dropped
+    live: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+}
+
+impl Heap {
+    /// Forgets what's been freed once the lists have doubled
+    fn prune(&mut self) {
+        if self.tables.len() + self.cells.len() > 2 * self.live + 1024 {
+            self.tables.retain(|table| table.strong_count() > 0);
+            self.cells.retain(|cell| cell.strong_count() > 0);
+            self.live = self.tables.len() + self.cells.len();
+        }
+    }
+}
+
+pub fn new_table(table: Table) -> TableRef {
+    let table = Rc::new(RefCell::new(table));
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.tables.push(Rc::downgrade(&table));
+        heap.prune();
+    });
+    table
+}
+
+/// Notes a local that a closure is capturing
+pub fn track_cell(cell: &Cell) -> Cell {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.cells.push(Rc::downgrade(cell));
+        heap.prune();
+    });
+    cell.clone()
+}
+
+/// Empties every table and captured variable still alive on this thread,
+/// freeing any cycles among them
+pub fn sweep() {
+    let heap = HEAP.with(|heap| std::mem::take(&mut *heap.borrow_mut()));
+    for table in heap.tables.iter().filter_map(Weak::upgrade) {
+        let contents = std::mem::take(&mut *table.borrow_mut());
+        drop(contents);
+    }
+    for cell in heap.cells.iter().filter_map(Weak::upgrade) {
+        let value = std::mem::take(&mut *cell.borrow_mut());
+        drop(value);
+    }
+}
+
+/// Formats a number as Lua does, with up to 14 significant digits
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if n == n.trunc() && n.abs() < 1e14 {
+        return format!("{}", n as i64);
+    }
+    format_g(n, 14, false)
+}
+
+/// C's `%.<precision>g`, or `%#.<precision>g` with `keep_zeros`
+pub fn format_g(n: f64, precision: usize, keep_zeros: bool) -> String {
+    if !n.is_finite() {
+        return format_number(n);
+    }
+    let precision = precision.max(1);
+    if n == 0.0 {
+        let zeros = if keep_zeros { format!(".{}", "0".repeat(precision - 1)) } else { String::new() };
+        let sign = if n.is_sign_negative() { "-" } else { "" };
+        return format!("{}0{}", sign, zeros.trim_end_matches('.'));
+    }
+    // The exponent after rounding to the requested digits decides the style
+    let scientific = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let trim = |s: String| {
+        if keep_zeros || !s.contains('.') {
+            s
+        } else {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        }
+    };
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa.to_string()), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent).max(0) as usize;
+        trim(format!("{:.*}", decimals, n))
+    }
+}
+
+/// Reads a number the way Lua converts strings: decimal or hex, with blanks
+/// around it
+pub fn parse_number(s: &[u8]) -> Option<f64> {
+    let s = std::str::from_utf8(s).ok()?.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let n = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        hex.bytes().fold(0.0, |n, b| n * 16.0 + (b as char).to_digit(16).unwrap() as f64)
+    } else {
+        let valid = !digits.is_empty()
+            && digits.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'))
+            && digits.bytes().next().is_some_and(|b| b.is_ascii_digit() || b == b'.');
+        if !valid {
+            return None;
+        }
+        digits.parse::<f64>().ok()?
+    };
+    Some(if negative { -n } else { n })
+}
+
+/// A table key: any value but nil and NaN
+#[derive(Clone)]
+pub struct Key(Value);
+
+impl Key {
+    pub fn new(value: Value) -> Option<Key> {
+        match value {
+            Value::Nil => None,
+            Value::Number(n) if n.is_nan() => None,
+            // -0 and 0 are the same key
+            Value::Number(0.0) => Some(Key(Value::Number(0.0))),
+            value => Some(Key(value)),
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.0.raw_equals(&other.0)
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            Value::Nil => 0.hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::Number(n) => n.to_bits().hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::Function(f) => f.address().hash(state),
+        }
+    }
+}
+
+/// A Lua table. Keys 1 to n live in an array; the rest keep the order they were
+/// added in, so traversal is repeatable.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    entries: Vec<(Key, Value)>,
+    index: HashMap<Key, usize>,
+    /// Entries set to nil, which stay in place so a traversal can go on
+    removed: usize,
+    pub metatable: Option<TableRef>,
+    /// Set for the globals and the libraries, which scripts can't change
+    pub readonly: bool,
+}
+
+impl Table {
+    /// A table holding `values` at 1, 2, ...
+    pub fn from_array(values: Vec<Value>) -> Table {
+        let mut table = Table {
+            array: values,
+            ..Default::default()
+        };
+        table.trim();
+        table
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(i) = array_index(key) {
+            if i < self.array.len() {
+                return self.array[i].clone();
+            }
+        }
+        match Key::new(key.clone()) {
+            Some(key) => self.index.get(&key).map_or(Value::Nil, |&i| self.entries[i].1.clone()),
+            None => Value::Nil,
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get(&Value::str(key))
+    }
+
+    /// Sets a key, or removes it when `value` is nil. Fails for nil and NaN keys.
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), &'static str> {
+        if let Some(i) = array_index(&key) {
+            if i < self.array.len() {
+                self.array[i] = value;
+                self.trim();
+                return Ok(());
+            }
+            if i == self.array.len() {
+                if !matches!(value, Value::Nil) {
+                    self.array.push(value);
+                    self.remove_entry(&key);
+                    self.migrate();
+                }
+                return Ok(());
+            }
+        }
+        let nil = matches!(key, Value::Nil);
+        let key = match Key::new(key) {
+            Some(key) => key,
+            None if matches!(value, Value::Nil) => return Ok(()),
+            None if nil => return Err("table index is nil"),
+            None => return Err("table index is NaN"),
+        };
+        match self.index.get(&key) {
+            Some(&i) => {
+                if matches!(self.entries[i].1, Value::Nil) && !matches!(value, Value::Nil) {
+                    self.removed -= 1;
+                } else if !matches!(self.entries[i].1, Value::Nil) && matches!(value, Value::Nil) {
+                    self.removed += 1;
+                }
+                self.entries[i].1 = value;
+            }
+            None if matches!(value, Value::Nil) => {}
+            None => {
+                if self.removed > 16 && self.removed * 2 > self.entries.len() {
+                    self.compact();
+                }
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_str(&mut self, key: &str, value: Value) {
+        let _ = self.set(Value::str(key), value);
+    }
+
+    /// Appends to the array part, as `t[#t + 1] = value`
+    pub fn push(&mut self, value: Value) {
+        let _ = self.set(Value::Number((self.len() + 1) as f64), value);
+    }
+
+    /// The length operator: a border, which for an array without holes is its length
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.array.is_empty() && self.entries.len() == self.removed
+    }
+
+    /// The entry after `key` in traversal order, or the first one for nil.
+    /// Fails if `key` isn't in the table.
+    #[allow(clippy::result_unit_err)]
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, ()> {
+        let entry = Key::new(key.clone()).and_then(|key| self.index.get(&key).copied());
+        let mut position = match (key, array_index(key), entry) {
+            (Value::Nil, _, _) => 0,
+            (_, Some(i), _) if i < self.array.len() => i + 1,
+            (_, _, Some(i)) => self.array.len() + i + 1,
+            // The array part shrank as its last values were cleared during the
+            // traversal, which then goes on with the other keys
+            (_, Some(_), None) => self.array.len(),
+            (_, None, None) => return Err(()),
+        };
+        while position < self.array.len() {
+            if !matches!(self.array[position], Value::Nil) {
+                return Ok(Some((Value::Number((position + 1) as f64), self.array[position].clone())));
+            }
+            position += 1;
+        }
+        for (key, value) in &self.entries[position - self.array.len()..] {
+            if !matches!(value, Value::Nil) {
+                return Ok(Some((key.value().clone(), value.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The values at 1, 2, ... up to the first nil
+    pub fn sequence(&self) -> Vec<Value> {
+        self.array
+            .iter()
+            .take_while(|value| !matches!(value, Value::Nil))
+            .cloned()
+            .collect()
+    }
+
+    /// Inserts at a position in the array part, shifting the rest up
+    pub fn insert(&mut self, position: usize, value: Value) {
+        let position = position.min(self.array.len());
+        self.array.insert(position, value);
+        self.trim();
+        self.migrate();
+    }
+
+    /// Removes from a position in the array part, shifting the rest down
+    pub fn remove(&mut self, position: usize) -> Value {
+        if position >= self.array.len() {
+            return Value::Nil;
+        }
+        let value = self.array.remove(position);
+        self.trim();
+        value
+    }
+
+    /// Drops nils from the end of the array part, keeping its length a border
+    fn trim(&mut self) {
+        while matches!(self.array.last(), Some(Value::Nil)) {
+            self.array.pop();
+        }
+    }
+
+    /// Moves the keys following the array part into it
+    fn migrate(&mut self) {
+        loop {
+            let key = Value::Number((self.array.len() + 1) as f64);
+            match self.remove_entry(&key) {
+                Some(value) => self.array.push(value),
+                None => return,
+            }
+        }
+    }
+
+    fn remove_entry(&mut self, key: &Value) -> Option<Value> {
+        let key = Key::new(key.clone())?;
+        let &i = self.index.get(&key)?;
+        let value = std::mem::take(&mut self.entries[i].1);
+        if matches!(value, Value::Nil) {
+            return None;
+        }
+        self.removed += 1;
+        Some(value)
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, value)| !matches!(value, Value::Nil));
+        self.index = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| (key.clone(), i))
+            .collect();
+        self.removed = 0;
+    }
+}
+
+/// The array slot for a key that's a positive integer
+fn array_index(key: &Value) -> Option<usize> {
+    match key {
+        Value::Number(n) if *n >= 1.0 && n.fract() == 0.0 && *n <= u32::MAX as f64 => Some(*n as usize - 1),
+        _ => None,
+    }
+}
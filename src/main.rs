@@ -1,46 +1,54 @@
-#![allow(unused_imports)]
-use bytes::BytesMut;
-use redis_starter_rust::{cast, ThreadPool};
-use std::{
-    collections::hash_map,
-    env, fs,
-    io::{prelude::*, BufReader, ErrorKind},
-    sync::{mpsc, Arc, Mutex},
-    thread,
-    time::Duration,
-    vec,
-};
-mod parser;
-use parser::{Parser, RedisBufSplit};
-
-mod server;
-use server::{RedisServer, RedisValue};
+use bytes::{Buf, Bytes, BytesMut};
+use redis_starter_rust::aof::Aof;
+use redis_starter_rust::backlog::Backlog;
+use redis_starter_rust::client::ClientState;
+use redis_starter_rust::log::Logger;
+use redis_starter_rust::parser::Parser;
+use redis_starter_rust::server::{RedisServer, RedisValue};
+use std::time::Duration;
+use std::{collections::hash_map, env, sync::Arc};
 
 use std::error::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
 
-mod log;
-use log::Logger;
+const READ_BUFFER_CAPACITY: usize = 4096;
+/// Most bytes of a read written to the log
+const LOGGED_BYTES: usize = 256;
+/// How long a replica waits before reconnecting to its master
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
 
-const PROPAGATE_COMMANDS_CAPACITY: usize = 10;
+/// Reads from `stream` into `buf` until a complete RESP value is available,
+/// then consumes and returns it as a string.
+async fn read_response(
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+) -> Result<String, Box<dyn Error>> {
+    loop {
+        if let Some((pos, value)) = Parser::parse(buf, 0)? {
+            let response = value.to_string(buf);
+            buf.advance(pos);
+            return Ok(response);
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err("connection closed by master".into());
+        }
+    }
+}
 
 async fn send_command_and_read_response(
     logger: &Logger,
     stream: &mut TcpStream,
+    buf: &mut BytesMut,
     command: RedisValue,
 ) -> Result<String, Box<dyn Error>> {
     // Send the command
-    stream.write_all(command.to_response().as_bytes()).await?;
+    stream.write_all(&command.to_response()).await?;
     stream.flush().await?;
-    logger.log(&format!("Sent command: {:?}", command));
+    logger.log(&format!("Sent command: {}", loggable(&command.to_response())));
 
     // Read the response
-    let mut buf = [0; 1024];
-    stream.readable().await?;
-    let n = stream.read(&mut buf).await?;
-    let response = String::from_utf8_lossy(&buf[..n]).to_string();
+    let response = read_response(stream, buf).await?;
     logger.log(&format!("Received response: {}", response));
 
     Ok(response)
@@ -51,66 +59,81 @@ async fn replication_connection(
     stream: &mut TcpStream,
     server: Arc<RedisServer>,
 ) -> Result<(), Box<dyn Error>> {
-    /// This is the connection that handles the handshake between master and slace
-    /// The established connection is used to send the replication data to the slave
+    // This is the connection that handles the handshake between master and slave
+    // The established connection is used to send the replication data to the slave
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_CAPACITY);
+
     // PING command
-    let ping_command = RedisValue::Array(vec![RedisValue::String("PING".to_string())]);
-    send_command_and_read_response(&logger, stream, ping_command).await?;
+    let ping_command = RedisValue::Array(vec![RedisValue::BulkString(Bytes::from("PING"))]);
+    send_command_and_read_response(logger, stream, &mut buf, ping_command).await?;
+
+    // AUTH command; a master with a password answered the PING with NOAUTH
+    if let Some(password) = &server.config.masterauth {
+        let auth_command = RedisValue::Array(vec![
+            RedisValue::BulkString(Bytes::from("AUTH")),
+            RedisValue::BulkString(Bytes::from(password.clone())),
+        ]);
+        let response = send_command_and_read_response(logger, stream, &mut buf, auth_command).await?;
+        if response != "OK" {
+            return Err(format!("Unable to AUTH to MASTER: {}", response).into());
+        }
+    }
 
     // REPLCONF listening-port command
     let replconf_listen_command = RedisValue::Array(vec![
-        RedisValue::String("REPLCONF".to_string()),
-        RedisValue::String("listening-port".to_string()),
-        RedisValue::String(server.config.port.to_string()),
+        RedisValue::BulkString(Bytes::from("REPLCONF")),
+        RedisValue::BulkString(Bytes::from("listening-port")),
+        RedisValue::BulkString(Bytes::from(server.config.port.to_string())),
     ]);
-    send_command_and_read_response(&logger, stream, replconf_listen_command).await?;
+    send_command_and_read_response(logger, stream, &mut buf, replconf_listen_command).await?;
 
     // REPLCONF capa command
     let replconf_capa_command = RedisValue::Array(vec![
-        RedisValue::String("REPLCONF".to_string()),
-        RedisValue::String("capa".to_string()),
-        RedisValue::String("psync2".to_string()),
+        RedisValue::BulkString(Bytes::from("REPLCONF")),
+        RedisValue::BulkString(Bytes::from("capa")),
+        RedisValue::BulkString(Bytes::from("psync2")),
     ]);
-    send_command_and_read_response(&logger, stream, replconf_capa_command).await?;
+    send_command_and_read_response(logger, stream, &mut buf, replconf_capa_command).await?;
 
     // PSYNC command
     let psync_command = RedisValue::Array(vec![
-        RedisValue::String("PSYNC".to_string()),
-        RedisValue::String("?".to_string()),
-        RedisValue::String("-1".to_string()),
+        RedisValue::BulkString(Bytes::from("PSYNC")),
+        RedisValue::BulkString(Bytes::from("?")),
+        RedisValue::BulkString(Bytes::from("-1")),
     ]);
-    let resp = send_command_and_read_response(&logger, stream, psync_command).await?;
-    logger.log("Handshake with master completed successfully.");
-    // Propagation of SET commands come through this stream
-
-    // The FULLLRESYNC response might also contain RESP ARRAY commands that need to be evaluated like "SET" or "REPLCONF GETACK"
-    let mut replicated_bytes_count = 0;
+    send_command_and_read_response(logger, stream, &mut buf, psync_command).await?;
 
-    let buf = BytesMut::from(resp.as_bytes());
+    // The FULLRESYNC reply is followed by the RDB snapshot, which may arrive across several reads
     loop {
-        if let Some(pos) = Parser::find_start_resp_data_type(&buf, 0, &parser::RESPDataType::Array)
-        {
+        if let Some((pos, rdb)) = Parser::parse_rdb_payload(&buf, 0)? {
+            let loaded = server.load_rdb_bytes(&rdb.to_bytes(&buf))?;
             logger.log(&format!(
-                "Found Redis Array start in FULLRESYNC response: {}",
-                String::from_utf8_lossy(&buf[pos..])
+                "Received RDB snapshot of {} bytes with {} keys",
+                rdb.len(),
+                loaded
             ));
-            let bm = BytesMut::from(buf[pos..].as_ref());
-            replicated_bytes_count += server.evaluate(&logger, bm, stream, None, replicated_bytes_count).await;
-            break;
-        } else {
+            buf.advance(pos);
             break;
         }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err("connection closed by master during full resync".into());
+        }
     }
-    // Try and poll the stream for new commands
+    logger.log("Handshake with master completed successfully.");
+
+    // Propagation of SET commands come through this stream, possibly already buffered
+    // behind the RDB payload.
+    let mut replicated_bytes_count = 0;
+    let mut client = ClientState::master_link(server.next_client_id());
     loop {
-        let mut buf = [0; 1024];
-        let n = stream.read(&mut buf).await?;
-        if n == 0 {
+        if !buf.is_empty() {
+            replicated_bytes_count += server
+                .evaluate(logger, &mut buf, stream, &mut client, None, replicated_bytes_count)
+                .await?;
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
             break;
         }
-        // TODO: This is probably fairly inefficient
-        let buf = BytesMut::from(&buf[..n]);
-        replicated_bytes_count += server.evaluate(&logger, buf, stream, None, replicated_bytes_count).await;
     }
     logger.log("Closing handshake connection with master.");
 
@@ -121,12 +144,36 @@ async fn replication_connection(
 async fn main() {
     let args = env::args().collect::<Vec<String>>();
     let server = RedisServer::new(&args);
+    // With AOF enabled the log is the more complete record, so the RDB file is ignored
+    let loaded = if server.config.appendonly {
+        server
+            .load_aof(&Logger::new())
+            .map(|replayed| format!("Replayed {} commands from the append only file", replayed))
+    } else {
+        server
+            .load_rdb()
+            .map(|loaded| format!("Loaded {} keys from RDB file", loaded))
+    };
+    match loaded {
+        Ok(message) => Logger::new().log(&message),
+        Err(e) => {
+            eprintln!("Failed to load the dataset: {}", e);
+            std::process::exit(1);
+        }
+    }
     let port = server.config.port;
     let config = server.config.clone();
-    let (tx, _rx) = broadcast::channel::<String>(PROPAGATE_COMMANDS_CAPACITY);
-    let arc_sender = Arc::new(tx);
+    let arc_sender = Arc::new(Backlog::new(config.repl_backlog_size));
     let arc_server = Arc::new(server);
 
+    tokio::spawn(expire_keys_on_schedule(Arc::clone(&arc_server)));
+    if !arc_server.config.save_rules.is_empty() {
+        tokio::spawn(save_on_schedule(Arc::clone(&arc_server)));
+    }
+    if let Some(aof) = arc_server.aof.clone() {
+        tokio::spawn(fsync_aof_every_second(aof));
+    }
+
     let mut logger = Logger::new();
     logger = logger.with("replica", &config.master_host_port.is_some().to_string());
 
@@ -166,16 +213,23 @@ async fn main() {
         let (master_host, master_port) = config.master_host_port.unwrap();
 
         let master_handle = tokio::spawn(async move {
-            let mut stream = TcpStream::connect((master_host, master_port))
-                .await
-                .expect("Failed to connect to master");
-            replication_connection(&logger, &mut stream, server_clone)
-                .await
-                .expect("Failed to handshake with master");
-            logger.log(&format!(
-                "peer_address master_handshake {:?}",
-                stream.peer_addr().unwrap()
-            ));
+            // The master closes the link when this falls behind its backlog, so once
+            // synced, a closed link is followed by a new full resync
+            let mut synced = false;
+            loop {
+                let result = match TcpStream::connect((master_host.as_str(), master_port)).await {
+                    Ok(mut stream) => replication_connection(&logger, &mut stream, Arc::clone(&server_clone))
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match result {
+                    Ok(()) => synced = true,
+                    Err(e) if synced => logger.log(&format!("Failed to resync with master: {}", e)),
+                    Err(e) => panic!("Failed to handshake with master: {}", e),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
         });
         master_handle
             .await
@@ -184,32 +238,93 @@ async fn main() {
     server_handle.await.unwrap();
 }
 
-async fn handle_connection<'a>(
+/// Runs the active expire cycle ten times a second, like Redis' default `hz`
+async fn expire_keys_on_schedule(server: Arc<RedisServer>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        server.active_expire_cycle();
+    }
+}
+
+/// Checks the `save` rules once a second and starts a background save when one is met
+async fn save_on_schedule(server: Arc<RedisServer>) {
+    let logger = Logger::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        server.save_if_due(&logger);
+    }
+}
+
+/// Flushes the append only file for `appendfsync everysec`; the other policies
+/// make this a no-op.
+async fn fsync_aof_every_second(aof: Arc<Aof>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let aof = Arc::clone(&aof);
+        match tokio::task::spawn_blocking(move || aof.fsync_if_needed()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => Logger::new().log(&format!("Failed to fsync the AOF: {}", e)),
+            Err(e) => Logger::new().log(&format!("AOF fsync task failed: {}", e)),
+        }
+    }
+}
+
+/// How bytes are shown in the log: their length, and a bounded prefix of them
+/// unless they may hold a password for AUTH or HELLO
+fn loggable(bytes: &[u8]) -> String {
+    if bytes.windows(4).any(|word| word.eq_ignore_ascii_case(b"auth")) {
+        return format!("{} bytes, not shown as they may hold a password", bytes.len());
+    }
+    let shown = &bytes[..bytes.len().min(LOGGED_BYTES)];
+    let ellipsis = if shown.len() < bytes.len() { "..." } else { "" };
+    format!("{} bytes: {}{}", bytes.len(), String::from_utf8_lossy(shown), ellipsis)
+}
+
+async fn handle_connection(
     logger: &Logger,
     server: &Arc<RedisServer>,
     mut stream: TcpStream,
-    tx: Arc<broadcast::Sender<String>>,
+    tx: Arc<Backlog>,
 ) {
+    // Commands can be split across reads or exceed a single read, so bytes accumulate
+    // here until the parser sees complete frames.
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_CAPACITY);
+    let mut client = ClientState::new(server.next_client_id());
     loop {
-        let mut buffer = [0; 1024];
-        if stream.readable().await.is_err() {
-            logger.log("Stream not readable");
-            break;
-        }
-        // Read up to 1024 bytes from the stream
-        let n = stream
-            .read(&mut buffer)
+        // Messages published to a subscribed client go out between its commands
+        let read = tokio::select! {
+            read = stream.read_buf(&mut buf) => read,
+            Some(message) = client.next_message() => {
+                if let Err(e) = stream.write_all(&message.encode(client.protocol)).await {
+                    logger.log(&format!("Failed to write message: {}", e));
+                    break;
+                }
+                continue;
+            }
+        };
+        let read = match read {
+            Ok(0) => {
+                logger.log("Connection closed by peer");
+                break;
+            }
+            Ok(read) => read,
+            Err(e) => {
+                logger.log(&format!("Failed to read from stream: {}", e));
+                break;
+            }
+        };
+        // Only what just arrived, as a large frame builds up over many reads
+        logger.log(&format!("Received {}", loggable(&buf[buf.len() - read..])));
+        if server
+            .evaluate(logger, &mut buf, &mut stream, &mut client, Some(tx.clone()), 0)
             .await
-            .expect("failed to read from stream");
-        if n == 0 {
-            continue;
+            .is_err()
+        {
+            break;
         }
-        // Print the contents to stdout
-        logger.log(&format!("Received: {}", String::from_utf8_lossy(&buffer)));
-        let bm = BytesMut::from(&buffer[0..n]);
-        assert!(bm.len() > 0);
-        server
-            .evaluate(&logger, bm, &mut stream, Some(tx.clone()), 0)
-            .await;
     }
+    server.disconnect(&client);
 }
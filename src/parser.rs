@@ -1,13 +1,15 @@
-use std::{time::Duration, vec};
+use std::time::Duration;
+use std::vec;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
-use crate::{log::Logger};
-use crate::cast;
+use crate::log::Logger;
+use crate::stream::{StreamId, TrimStrategy};
+
+/// How deeply aggregates may nest in a value, so a frame can't exhaust the stack
+const MAX_NESTING: usize = 64;
 /// Parser for Redis RESP protocol
-pub struct Parser {
-    index: usize,
-}
+pub struct Parser;
 
 pub enum RESPDataType {
     SimpleString,
@@ -16,7 +18,53 @@ pub enum RESPDataType {
     BulkString,
     Array,
     Null,
-    // etc ...
+    // RESP3 only
+    Map,
+    Set,
+    Double,
+    Boolean,
+    BigNumber,
+    VerbatimString,
+    Push,
+}
+
+impl RESPDataType {
+    pub fn from_prefix(byte: u8) -> Option<RESPDataType> {
+        match byte {
+            b'+' => Some(RESPDataType::SimpleString),
+            b'-' => Some(RESPDataType::SimpleError),
+            b':' => Some(RESPDataType::Integer),
+            b'$' => Some(RESPDataType::BulkString),
+            b'*' => Some(RESPDataType::Array),
+            b'_' => Some(RESPDataType::Null),
+            b'%' => Some(RESPDataType::Map),
+            b'~' => Some(RESPDataType::Set),
+            b',' => Some(RESPDataType::Double),
+            b'#' => Some(RESPDataType::Boolean),
+            b'(' => Some(RESPDataType::BigNumber),
+            b'=' => Some(RESPDataType::VerbatimString),
+            b'>' => Some(RESPDataType::Push),
+            _ => None,
+        }
+    }
+
+    pub fn prefix(&self) -> u8 {
+        match self {
+            RESPDataType::SimpleString => b'+',
+            RESPDataType::SimpleError => b'-',
+            RESPDataType::Integer => b':',
+            RESPDataType::BulkString => b'$',
+            RESPDataType::Array => b'*',
+            RESPDataType::Null => b'_',
+            RESPDataType::Map => b'%',
+            RESPDataType::Set => b'~',
+            RESPDataType::Double => b',',
+            RESPDataType::Boolean => b'#',
+            RESPDataType::BigNumber => b'(',
+            RESPDataType::VerbatimString => b'=',
+            RESPDataType::Push => b'>',
+        }
+    }
 }
 /// Fundamental struct for viewing byte slices
 ///
@@ -24,13 +72,22 @@ pub enum RESPDataType {
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct BufSplit(usize, usize);
 impl BufSplit {
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.1 - self.0
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn to_string(&self, src: &[u8]) -> String {
         String::from_utf8_lossy(&src[self.0..self.1]).to_string()
     }
+
+    /// Copies the exact bytes out of the buffer, without any UTF-8 interpretation.
+    pub fn to_bytes(&self, src: &[u8]) -> Bytes {
+        Bytes::copy_from_slice(&src[self.0..self.1])
+    }
 }
 
 /// BufSplit based equivalent to our output type RedisValueRef
@@ -42,36 +99,963 @@ pub enum RedisBufSplit {
     Array(Vec<RedisBufSplit>),
     NullArray,
     NullBulkString,
+    // RESP3 only
+    Null,
+    Boolean(bool),
+    Double(BufSplit),
+    BigNumber(BufSplit),
+    /// Verbatim string contents, without the `xxx:` format prefix
+    Verbatim(BufSplit),
+    Map(Vec<(RedisBufSplit, RedisBufSplit)>),
+    Set(Vec<RedisBufSplit>),
+    Push(Vec<RedisBufSplit>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ParsedCommand {
     pub command: Command,
+    /// The command's name, lowercased; empty for frames that aren't commands
+    pub name: String,
     pub bytes_read: usize,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
-    Set(String, String, Option<Duration>),
-    Get(String),
-    Ping,
-    Echo(String),
+    Set(Bytes, Bytes, SetOptions),
+    Get(Bytes),
+    Ping(Option<Bytes>),
+    Echo(Bytes),
     Docs,
     Info(String),
     ReplConf(Vec<String>),
     Psync,
+    Keys(Bytes),
+    ConfigGet(Vec<Bytes>),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT
+    Expire(Bytes, ExpireTime, ExpireCondition),
+    /// TTL, PTTL, EXPIRETIME and PEXPIRETIME
+    Ttl(Bytes, TtlFormat),
+    Persist(Bytes),
+    /// DEL and UNLINK
+    Del(Vec<Bytes>),
+    Exists(Vec<Bytes>),
+    Type(Bytes),
+    /// RENAME, or RENAMENX when `nx` is set
+    Rename {
+        from: Bytes,
+        to: Bytes,
+        nx: bool,
+    },
+    Copy {
+        source: Bytes,
+        destination: Bytes,
+        replace: bool,
+    },
+    Scan(u64, ScanOptions),
+    RandomKey,
+    DbSize,
+    /// FLUSHALL and FLUSHDB; there's only one database
+    FlushAll,
+    /// INCR, DECR, INCRBY and DECRBY
+    IncrBy(Bytes, i64),
+    IncrByFloat(Bytes, f64),
+    Append(Bytes, Bytes),
+    Strlen(Bytes),
+    GetRange(Bytes, i64, i64),
+    SetRange(Bytes, usize, Bytes),
+    GetDel(Bytes),
+    GetEx(Bytes, Option<GetExOption>),
+    MGet(Vec<Bytes>),
+    /// MSET, or MSETNX (and SETNX) when `nx` is set
+    MSet {
+        pairs: Vec<(Bytes, Bytes)>,
+        nx: bool,
+    },
+    /// LPUSH and RPUSH, or LPUSHX and RPUSHX when `only_if_exists` is set
+    Push {
+        key: Bytes,
+        elements: Vec<Bytes>,
+        end: ListEnd,
+        only_if_exists: bool,
+    },
+    /// LPOP and RPOP; with a count the reply is an array
+    Pop {
+        key: Bytes,
+        end: ListEnd,
+        count: Option<usize>,
+    },
+    LRange(Bytes, i64, i64),
+    LLen(Bytes),
+    LIndex(Bytes, i64),
+    LSet(Bytes, i64, Bytes),
+    LInsert {
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        element: Bytes,
+    },
+    LRem(Bytes, i64, Bytes),
+    LTrim(Bytes, i64, i64),
+    LPos {
+        key: Bytes,
+        element: Bytes,
+        options: LPosOptions,
+    },
+    /// LMOVE and RPOPLPUSH
+    LMove {
+        source: Bytes,
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    /// BLPOP, BRPOP, BLMOVE and BRPOPLPUSH. Without a timeout the client waits
+    /// until it's served.
+    Block {
+        keys: Vec<Bytes>,
+        op: BlockingOp,
+        timeout: Option<Duration>,
+    },
+    /// HSET, HMSET and HSETNX
+    HSet {
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+        /// Only set fields that don't exist yet
+        nx: bool,
+        /// Reply OK, as HMSET does, instead of the number of new fields
+        reply_ok: bool,
+    },
+    HGet(Bytes, Bytes),
+    HMGet(Bytes, Vec<Bytes>),
+    HDel(Bytes, Vec<Bytes>),
+    HExists(Bytes, Bytes),
+    HLen(Bytes),
+    HKeys(Bytes),
+    HVals(Bytes),
+    HGetAll(Bytes),
+    HIncrBy(Bytes, Bytes, i64),
+    HIncrByFloat(Bytes, Bytes, f64),
+    HStrlen(Bytes, Bytes),
+    /// Without a count, one random field. A negative count may repeat fields.
+    HRandField {
+        key: Bytes,
+        count: Option<i64>,
+        with_values: bool,
+    },
+    HScan(Bytes, u64, ScanOptions),
+    SAdd(Bytes, Vec<Bytes>),
+    SRem(Bytes, Vec<Bytes>),
+    SIsMember(Bytes, Bytes),
+    SMIsMember(Bytes, Vec<Bytes>),
+    SMembers(Bytes),
+    SCard(Bytes),
+    /// With a count the reply is an array
+    SPop {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    /// Without a count, one random member. A negative count may repeat members.
+    SRandMember {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    SMove {
+        source: Bytes,
+        destination: Bytes,
+        member: Bytes,
+    },
+    /// SINTER, SUNION and SDIFF, or their STORE variants when there's a destination
+    SetOp {
+        op: SetOperation,
+        keys: Vec<Bytes>,
+        destination: Option<Bytes>,
+    },
+    /// SINTERCARD; a limit of 0 means none
+    SInterCard {
+        keys: Vec<Bytes>,
+        limit: usize,
+    },
+    SScan(Bytes, u64, ScanOptions),
+    ObjectEncoding(Bytes),
+    ZAdd {
+        key: Bytes,
+        options: ZAddOptions,
+        /// Scores and members, in the order given
+        pairs: Vec<(f64, Bytes)>,
+    },
+    ZRem(Bytes, Vec<Bytes>),
+    ZCard(Bytes),
+    ZScore(Bytes, Bytes),
+    ZMScore(Bytes, Vec<Bytes>),
+    ZIncrBy(Bytes, f64, Bytes),
+    /// ZRANK, or ZREVRANK with `rev`
+    ZRank {
+        key: Bytes,
+        member: Bytes,
+        rev: bool,
+        with_score: bool,
+    },
+    /// ZRANGE and its older forms such as ZREVRANGEBYSCORE
+    ZRange {
+        key: Bytes,
+        range: ZRange,
+        with_scores: bool,
+    },
+    ZRangeStore {
+        destination: Bytes,
+        key: Bytes,
+        range: ZRange,
+    },
+    ZCount(Bytes, ScoreBound, ScoreBound),
+    ZLexCount(Bytes, LexBound, LexBound),
+    /// ZPOPMIN, or ZPOPMAX with `max`. With a count the reply may hold several
+    /// members.
+    ZPop {
+        key: Bytes,
+        max: bool,
+        count: Option<usize>,
+    },
+    /// ZUNIONSTORE and ZINTERSTORE, with a weight for each key
+    ZSetOp {
+        op: SetOperation,
+        destination: Bytes,
+        keys: Vec<Bytes>,
+        weights: Vec<f64>,
+        aggregate: Aggregate,
+    },
+    ZScan(Bytes, u64, ScanOptions),
+    XAdd {
+        key: Bytes,
+        /// Do nothing rather than create a missing stream
+        nomkstream: bool,
+        trim: Option<StreamTrim>,
+        id: XAddId,
+        fields: Vec<(Bytes, Bytes)>,
+    },
+    XLen(Bytes),
+    /// XRANGE, or XREVRANGE with `rev`. Exclusive bounds are already turned into
+    /// inclusive ones.
+    XRange {
+        key: Bytes,
+        start: StreamId,
+        end: StreamId,
+        rev: bool,
+        count: Option<usize>,
+    },
+    XDel(Bytes, Vec<StreamId>),
+    XTrim(Bytes, StreamTrim),
+    /// XREAD, which waits for new entries when `block` is given: for the timeout,
+    /// or forever when that's None
+    XRead {
+        count: Option<usize>,
+        block: Option<Option<Duration>>,
+        keys: Vec<Bytes>,
+        ids: Vec<XReadId>,
+    },
+    XGroupCreate {
+        key: Bytes,
+        group: Bytes,
+        id: XReadId,
+        /// Create the stream if it's missing
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    XGroupSetId {
+        key: Bytes,
+        group: Bytes,
+        id: XReadId,
+        entries_read: Option<u64>,
+    },
+    XGroupDestroy(Bytes, Bytes),
+    /// Key, group and consumer
+    XGroupCreateConsumer(Bytes, Bytes, Bytes),
+    /// Key, group and consumer
+    XGroupDelConsumer(Bytes, Bytes, Bytes),
+    /// XREADGROUP, which like XREAD waits for new entries when `block` is given
+    XReadGroup {
+        group: Bytes,
+        consumer: Bytes,
+        count: Option<usize>,
+        block: Option<Option<Duration>>,
+        /// Don't make the entries read pending
+        noack: bool,
+        keys: Vec<Bytes>,
+        ids: Vec<GroupReadId>,
+    },
+    /// Key, group and the IDs to acknowledge
+    XAck(Bytes, Bytes, Vec<StreamId>),
+    /// XPENDING: a summary of the pending entries, or with a range the entries
+    XPending {
+        key: Bytes,
+        group: Bytes,
+        range: Option<XPendingRange>,
+    },
+    XClaim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        /// Only claim entries idle for at least this many milliseconds
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: XClaimOptions,
+    },
+    XAutoClaim {
+        key: Bytes,
+        group: Bytes,
+        consumer: Bytes,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        justid: bool,
+    },
+    XInfoStream(Bytes),
+    XInfoGroups(Bytes),
+    /// Key and group
+    XInfoConsumers(Bytes, Bytes),
+    /// Channels to subscribe to
+    Subscribe(Vec<Bytes>),
+    /// Channels to unsubscribe from, or all of them when empty
+    Unsubscribe(Vec<Bytes>),
+    /// Patterns to subscribe to
+    PSubscribe(Vec<Bytes>),
+    /// Patterns to unsubscribe from, or all of them when empty
+    PUnsubscribe(Vec<Bytes>),
+    /// Channel and message
+    Publish(Bytes, Bytes),
+    /// PUBSUB CHANNELS, optionally only those matching a pattern
+    PubSubChannels(Option<Bytes>),
+    PubSubNumSub(Vec<Bytes>),
+    PubSubNumPat,
+    /// Shard channels to subscribe to
+    SSubscribe(Vec<Bytes>),
+    /// Shard channels to unsubscribe from, or all of them when empty
+    SUnsubscribe(Vec<Bytes>),
+    /// Shard channel and message
+    SPublish(Bytes, Bytes),
+    /// PUBSUB SHARDCHANNELS, optionally only those matching a pattern
+    PubSubShardChannels(Option<Bytes>),
+    PubSubShardNumSub(Vec<Bytes>),
+    Multi,
+    Exec,
+    Discard,
+    /// Keys to watch for changes until EXEC
+    Watch(Vec<Bytes>),
+    Unwatch,
+    /// EVAL, EVALSHA and their read-only forms
+    Eval {
+        script: EvalScript,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        read_only: bool,
+    },
+    ScriptLoad(Bytes),
+    ScriptExists(Vec<Bytes>),
+    /// SCRIPT FLUSH; ASYNC and SYNC both flush right away
+    ScriptFlush,
+    ScriptKill,
+    /// FCALL and FCALL_RO
+    FCall {
+        function: Bytes,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        read_only: bool,
+    },
+    /// Library code, and whether it may replace a library of the same name
+    FunctionLoad(Bytes, bool),
+    FunctionDelete(Bytes),
+    FunctionList {
+        /// Only libraries with names matching this
+        pattern: Option<Bytes>,
+        with_code: bool,
+    },
+    FunctionDump,
+    FunctionRestore(Bytes, RestorePolicy),
+    /// FUNCTION FLUSH; ASYNC and SYNC both flush right away
+    FunctionFlush,
+    FunctionStats,
+    FunctionKill,
+    /// AUTH [username] password; without a username it's the default user
+    Auth {
+        username: Option<Bytes>,
+        password: Bytes,
+    },
+    Hello {
+        protover: Option<i64>,
+        auth: Option<(Bytes, Bytes)>,
+        setname: Option<Bytes>,
+    },
+    /// A well-formed frame that isn't a command, e.g. a bare bulk string
     Unknown,
+    /// A command that can't be run; the error is sent back to the client
+    Invalid(RESPError),
+}
+
+/// The script EVAL runs: its source, or the SHA1 digest of a cached one
+#[derive(Debug, PartialEq, Clone)]
+pub enum EvalScript {
+    Body(Bytes),
+    /// Lowercase hex digest
+    Sha(String),
+}
+
+/// What FUNCTION RESTORE does with the libraries already loaded
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RestorePolicy {
+    /// Keeps them, failing if a restored library has the same name as one
+    Append,
+    /// Keeps them, except those with the same name as a restored library
+    Replace,
+    /// Deletes them all first
+    Flush,
+}
+
+/// What GETEX does to the key's deadline
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GetExOption {
+    /// EX, PX, EXAT or PXAT
+    Expire(ExpireTime),
+    Persist,
+}
+
+/// The head (left) or tail (right) of a list
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+impl ListEnd {
+    fn parse(word: &Bytes) -> Result<ListEnd, RESPError> {
+        match lossy(word).to_lowercase().as_str() {
+            "left" => Ok(ListEnd::Left),
+            "right" => Ok(ListEnd::Right),
+            _ => Err(RESPError::SyntaxError),
+        }
+    }
+
+    /// The argument naming this end in LMOVE
+    pub fn name(&self) -> &'static str {
+        match self {
+            ListEnd::Left => "LEFT",
+            ListEnd::Right => "RIGHT",
+        }
+    }
+}
+
+/// What a blocked client does once one of its keys has an element
+#[derive(Debug, PartialEq, Clone)]
+pub enum BlockingOp {
+    /// BLPOP and BRPOP
+    Pop(ListEnd),
+    /// BLMOVE and BRPOPLPUSH
+    Move {
+        destination: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    },
+    /// BZPOPMIN, or BZPOPMAX with `max`
+    ZPop { max: bool },
+    /// XREAD BLOCK, for entries past the ID given for each key
+    XRead {
+        ids: Vec<(Bytes, StreamId)>,
+        count: Option<usize>,
+    },
+    /// XREADGROUP BLOCK, for entries the group hasn't read yet
+    XReadGroup {
+        group: Bytes,
+        consumer: Bytes,
+        count: Option<usize>,
+        noack: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetOperation {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// The ID argument of XADD
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum XAddId {
+    /// `*`: the current time and the next sequence number
+    Auto,
+    /// `<ms>-*`: the next sequence number within `ms`
+    Partial(u64),
+    Explicit(StreamId),
+}
+
+/// The MAXLEN or MINID trimming of XADD and XTRIM
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StreamTrim {
+    pub strategy: TrimStrategy,
+    /// `~`, which Redis takes as leave to trim less. Trimming here is always exact.
+    pub approximate: bool,
+    /// The most entries to remove, only allowed along with `~`
+    pub limit: Option<usize>,
+}
+
+impl StreamTrim {
+    /// The words this trimming was given as
+    pub fn to_args(self) -> Vec<Bytes> {
+        let (name, threshold) = match self.strategy {
+            TrimStrategy::MaxLen(len) => ("MAXLEN", len.to_string()),
+            TrimStrategy::MinId(id) => ("MINID", id.to_string()),
+        };
+        let mode = if self.approximate { "~" } else { "=" };
+        let mut args = vec![Bytes::from(name), Bytes::from(mode), Bytes::from(threshold)];
+        if let Some(limit) = self.limit {
+            args.extend([Bytes::from("LIMIT"), Bytes::from(limit.to_string())]);
+        }
+        args
+    }
+}
+
+/// A position to read a stream after, as given to XREAD and XGROUP
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum XReadId {
+    /// `$`: only entries added from now on
+    Last,
+    After(StreamId),
+}
+
+/// An ID argument of XREADGROUP
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GroupReadId {
+    /// `>`: entries the group hasn't read yet
+    New,
+    /// The consumer's own pending entries past this ID
+    Pending(StreamId),
+}
+
+/// The extended form of XPENDING
+#[derive(Debug, PartialEq, Clone)]
+pub struct XPendingRange {
+    /// Only entries idle for at least this many milliseconds
+    pub idle: Option<u64>,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<Bytes>,
+}
+
+/// The options of XCLAIM
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct XClaimOptions {
+    /// Set the idle time to this many milliseconds rather than 0
+    pub idle: Option<u64>,
+    /// Set the last delivery to this unix time in milliseconds
+    pub time: Option<u64>,
+    /// Set the delivery count rather than adding one to it
+    pub retry_count: Option<u64>,
+    /// Claim entries that aren't pending, as long as they're in the stream
+    pub force: bool,
+    /// Reply with IDs only and leave the delivery count alone
+    pub justid: bool,
+    /// Move the group's last ID up to this one
+    pub last_id: Option<StreamId>,
+}
+
+/// The NX, XX, GT, LT, CH and INCR flags of ZADD
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ZAddOptions {
+    /// Only add new members
+    pub nx: bool,
+    /// Only update existing members
+    pub xx: bool,
+    /// Only update a score to a greater one; new members are still added
+    pub gt: bool,
+    /// Only update a score to a lesser one; new members are still added
+    pub lt: bool,
+    /// Count changed members in the reply, not just added ones
+    pub ch: bool,
+    /// Add to the score like ZINCRBY, replying with the new score
+    pub incr: bool,
+}
+
+/// One end of a range of scores, as in ZRANGEBYSCORE
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// `(1.5`, `1.5`, `-inf` or `+inf`
+    fn parse(word: &Bytes) -> Result<ScoreBound, RESPError> {
+        let (value, exclusive) = match word.strip_prefix(b"(") {
+            Some(value) => (value, true),
+            None => (&word[..], false),
+        };
+        let value = parse_float(value).ok_or(RESPError::InvalidScoreRange)?;
+        Ok(ScoreBound { value, exclusive })
+    }
+
+    /// Whether `score` is on the right side of this bound as a minimum
+    pub fn admits_above(&self, score: f64) -> bool {
+        if self.exclusive {
+            score > self.value
+        } else {
+            score >= self.value
+        }
+    }
+
+    /// Whether `score` is on the right side of this bound as a maximum
+    pub fn admits_below(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.value
+        } else {
+            score <= self.value
+        }
+    }
+}
+
+/// One end of a range of members, as in ZRANGEBYLEX
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexBound {
+    /// `-`, before every member
+    Min,
+    /// `+`, after every member
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+impl LexBound {
+    fn parse(word: &Bytes) -> Result<LexBound, RESPError> {
+        match word.first() {
+            Some(b'-') if word.len() == 1 => Ok(LexBound::Min),
+            Some(b'+') if word.len() == 1 => Ok(LexBound::Max),
+            Some(b'[') => Ok(LexBound::Inclusive(word.slice(1..))),
+            Some(b'(') => Ok(LexBound::Exclusive(word.slice(1..))),
+            _ => Err(RESPError::InvalidLexRange),
+        }
+    }
+
+    /// Whether `member` is on the right side of this bound as a minimum
+    pub fn admits_above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= &bound[..],
+            LexBound::Exclusive(bound) => member > &bound[..],
+        }
+    }
+
+    /// Whether `member` is on the right side of this bound as a maximum
+    pub fn admits_below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= &bound[..],
+            LexBound::Exclusive(bound) => member < &bound[..],
+        }
+    }
+}
+
+/// Which members of a sorted set ZRANGE selects. Score and lex ranges are kept
+/// as minimum then maximum, whatever order REV gave them in.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ZRangeBy {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ZRange {
+    pub by: ZRangeBy,
+    /// Highest scores first
+    pub rev: bool,
+    /// LIMIT offset and count; a negative count means no limit
+    pub limit: Option<(i64, i64)>,
+}
+
+/// How ZUNIONSTORE and ZINTERSTORE combine the scores of a member
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+/// The RANK, COUNT and MAXLEN options of LPOS
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LPosOptions {
+    /// Which match to start from; negative ranks search from the tail
+    pub rank: i64,
+    /// Reply with up to this many positions instead of one; 0 means all of them
+    pub count: Option<usize>,
+    /// How many elements to compare at most; 0 means the whole list
+    pub maxlen: usize,
+}
+
+impl Default for LPosOptions {
+    fn default() -> Self {
+        LPosOptions {
+            rank: 1,
+            count: None,
+            maxlen: 0,
+        }
+    }
+}
+
+/// The MATCH, COUNT and TYPE options of SCAN
+#[derive(Debug, PartialEq, Clone)]
+pub struct ScanOptions {
+    pub pattern: Option<Bytes>,
+    /// How many keys to look at, before filtering by pattern and type
+    pub count: usize,
+    pub type_name: Option<String>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            pattern: None,
+            count: 10,
+            type_name: None,
+        }
+    }
+}
+
+/// A key deadline as given to EXPIRE and friends, in milliseconds. Either may be
+/// negative, which deletes the key.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExpireTime {
+    /// From now
+    Relative(i64),
+    /// Unix time
+    At(i64),
+}
+
+impl ExpireTime {
+    /// The deadline as unix time in milliseconds, given the current time
+    pub fn resolve(&self, now_ms: u64) -> i64 {
+        match self {
+            ExpireTime::Relative(ms) => (now_ms as i64).saturating_add(*ms),
+            ExpireTime::At(ms) => *ms,
+        }
+    }
+}
+
+/// Options to SET, which may be given in any order
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SetOptions {
+    pub condition: Option<SetCondition>,
+    /// Reply with the previous value instead of OK
+    pub get: bool,
+    pub expiry: Option<SetExpiry>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetCondition {
+    /// Only set the key if it doesn't exist
+    Nx,
+    /// Only set the key if it already exists
+    Xx,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetExpiry {
+    /// EX, PX, EXAT or PXAT
+    Expire(ExpireTime),
+    /// Keep the key's current deadline, if it has one
+    KeepTtl,
 }
 
-#[derive(Debug)]
+/// The NX, XX, GT and LT flags of EXPIRE; any combination but NX with another,
+/// or GT with LT
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ExpireCondition {
+    /// Only if the key has no deadline
+    pub nx: bool,
+    /// Only if the key has a deadline
+    pub xx: bool,
+    /// Only if the new deadline is later; a key without one never qualifies
+    pub gt: bool,
+    /// Only if the new deadline is sooner; a key without one always qualifies
+    pub lt: bool,
+}
+
+impl ExpireCondition {
+    /// Whether a key whose deadline is `current` may be given `new`
+    pub fn allows(&self, current: Option<u64>, new: i64) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => {
+                !self.nx
+                    && (!self.gt || new > current as i64)
+                    && (!self.lt || new < current as i64)
+            }
+        }
+    }
+
+    fn to_args(self) -> Vec<Bytes> {
+        [(self.nx, "NX"), (self.xx, "XX"), (self.gt, "GT"), (self.lt, "LT")]
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| Bytes::from(flag))
+            .collect()
+    }
+}
+
+/// How TTL and friends report a key's deadline
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TtlFormat {
+    /// Seconds remaining (TTL)
+    Seconds,
+    /// Milliseconds remaining (PTTL)
+    Millis,
+    /// Unix time in seconds (EXPIRETIME)
+    UnixSeconds,
+    /// Unix time in milliseconds (PEXPIRETIME)
+    UnixMillis,
+}
+
+/// Errors from decoding frames or interpreting commands.
+///
+/// The `Display` text is the exact error line sent back to the client.
+#[derive(Debug, PartialEq, Clone, thiserror::Error)]
 pub enum RESPError {
-    UnexpectedEnd,
+    #[error("ERR Protocol error: unexpected byte '{}'", char::from(*.0))]
     UnknownStartingByte(u8),
-    IOError(std::io::Error),
+    #[error("ERR IO error: {0}")]
+    IOError(String),
+    #[error("ERR invalid argument '{0}'")]
     InvalidArgument(String),
+    #[error("ERR Protocol error: invalid integer '{0}'")]
     IntParseFailure(String),
+    #[error("ERR Protocol error: invalid bulk length {0}")]
     BadBulkStringSize(i64),
+    #[error("ERR Protocol error: invalid multibulk length {0}")]
     BadArraySize(i64),
+    #[error("ERR Protocol error: expected '$', got '{}'", char::from(*.0))]
+    ExpectedBulkString(u8),
+    #[error("ERR Protocol error: too many nested aggregates")]
+    NestingTooDeep,
+    #[error("ERR unknown command '{0}', with args beginning with: {}", format_args_list(.1))]
+    UnknownCommand(String, Vec<String>),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongNumberOfArguments(String),
+    #[error("ERR syntax error")]
+    SyntaxError,
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(String),
+    #[error("ERR Protocol version is not an integer or out of range")]
+    InvalidProtocolVersion,
+    #[error("NOPROTO unsupported protocol version")]
+    UnsupportedProtocol,
+    #[error("ERR unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("ERR Unsupported option {0}")]
+    UnsupportedOption(String),
+    #[error("ERR NX and XX, GT or LT options at the same time are not compatible")]
+    IncompatibleNxOptions,
+    #[error("ERR GT and LT options at the same time are not compatible")]
+    IncompatibleGtLt,
+    #[error("ERR invalid cursor")]
+    InvalidCursor,
+    #[error("ERR DB index is out of range")]
+    DbIndexOutOfRange,
+    #[error("ERR value is not a valid float")]
+    NotAFloat,
+    #[error("ERR decrement would overflow")]
+    DecrementOverflow,
+    #[error("ERR offset is out of range")]
+    OffsetOutOfRange,
+    #[error("ERR value is out of range, must be positive")]
+    NotPositive,
+    #[error("ERR timeout is not a float or out of range")]
+    InvalidTimeout,
+    #[error("ERR timeout is negative")]
+    NegativeTimeout,
+    #[error("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list")]
+    ZeroRank,
+    #[error("ERR COUNT can't be negative")]
+    NegativeCount,
+    #[error("ERR MAXLEN can't be negative")]
+    NegativeMaxLen,
+    #[error("ERR numkeys should be greater than 0")]
+    NumKeysNotPositive,
+    #[error("ERR Number of keys can't be greater than number of args")]
+    TooManyKeys,
+    #[error("ERR Number of keys can't be negative")]
+    NegativeKeys,
+    #[error("ERR {0} FLUSH only support SYNC|ASYNC option")]
+    FlushOption(String),
+    #[error("ERR Unknown option given: {0}")]
+    UnknownOption(String),
+    #[error("ERR Unknown argument {0}")]
+    UnknownArgument(String),
+    #[error("ERR library name argument was not given")]
+    MissingLibraryName,
+    #[error("ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.")]
+    InvalidRestorePolicy,
+    #[error("ERR LIMIT can't be negative")]
+    NegativeLimit,
+    #[error("ERR XX and NX options at the same time are not compatible")]
+    IncompatibleZAddXxNx,
+    #[error("ERR GT, LT, and/or NX options at the same time are not compatible")]
+    IncompatibleZAddGtLtNx,
+    #[error("ERR INCR option supports a single increment-element pair")]
+    IncrSinglePair,
+    #[error("ERR min or max is not a float")]
+    InvalidScoreRange,
+    #[error("ERR min or max not valid string range item")]
+    InvalidLexRange,
+    #[error("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX")]
+    LimitWithoutBy,
+    #[error("ERR syntax error, WITHSCORES not supported in combination with BYLEX")]
+    WithScoresByLex,
+    #[error("ERR at least 1 input key is needed for '{0}' command")]
+    NoInputKeys(String),
+    #[error("ERR weight value is not a float")]
+    InvalidWeight,
+    #[error("ERR Invalid stream ID specified as stream command argument")]
+    InvalidStreamId,
+    #[error("ERR The ID specified in XADD must be greater than 0-0")]
+    XAddIdZero,
+    #[error("ERR The MAXLEN argument must be >= 0.")]
+    StreamMaxLenNegative,
+    #[error("ERR The LIMIT argument must be >= 0.")]
+    StreamLimitNegative,
+    #[error("ERR syntax error, LIMIT cannot be used without the special ~ option")]
+    LimitWithoutApproximate,
+    #[error("ERR invalid start ID for the interval")]
+    InvalidIntervalStart,
+    #[error("ERR invalid end ID for the interval")]
+    InvalidIntervalEnd,
+    #[error("ERR Unbalanced '{0}' list of streams: for each stream key an ID or '{1}' must be specified.")]
+    UnbalancedStreams(String, String),
+    #[error("ERR timeout is not an integer or out of range")]
+    TimeoutNotInteger,
+    #[error("ERR The {0} option is only supported by XREADGROUP. You called XREAD instead.")]
+    OnlyInXReadGroup(String),
+    #[error("ERR Missing GROUP option for XREADGROUP")]
+    MissingGroupOption,
+    #[error("ERR The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option.")]
+    NewIdOutsideXReadGroup,
+    #[error("ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.")]
+    LastIdInXReadGroup,
+    #[error("ERR value for ENTRIESREAD must be positive or -1")]
+    InvalidEntriesRead,
+    #[error("ERR Invalid {0} argument for {1}")]
+    InvalidClaimArgument(String, String),
+    #[error("ERR Unrecognized XCLAIM option '{0}'")]
+    UnrecognizedXClaimOption(String),
+    #[error("ERR COUNT must be > 0")]
+    AutoClaimCount,
+}
+
+fn format_args_list(args: &[String]) -> String {
+    args.iter().map(|a| format!("'{}' ", a)).collect()
 }
 impl RedisBufSplit {
     pub fn to_string(&self, src: &BytesMut) -> String {
@@ -79,266 +1063,2067 @@ impl RedisBufSplit {
             RedisBufSplit::String(word) => word.to_string(src),
             RedisBufSplit::Error(word) => word.to_string(src),
             RedisBufSplit::Int(i) => i.to_string(),
-            RedisBufSplit::Array(words) => {
+            RedisBufSplit::Array(words) | RedisBufSplit::Set(words) | RedisBufSplit::Push(words) => {
                 let mut s = String::new();
                 s.push('[');
                 for (i, word) in words.iter().enumerate() {
                     if i > 0 {
                         s.push(',');
                     }
-                    s.push_str(&word.to_string(src));
+                    s.push_str(&word.to_string(src));
+                }
+                s.push(']');
+                s
+            }
+            RedisBufSplit::NullArray => "[]".to_string(),
+            RedisBufSplit::NullBulkString | RedisBufSplit::Null => "null".to_string(),
+            RedisBufSplit::Boolean(b) => b.to_string(),
+            RedisBufSplit::Double(word)
+            | RedisBufSplit::BigNumber(word)
+            | RedisBufSplit::Verbatim(word) => word.to_string(src),
+            RedisBufSplit::Map(pairs) => {
+                let mut s = String::new();
+                s.push('{');
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        s.push(',');
+                    }
+                    s.push_str(&format!("{}:{}", k.to_string(src), v.to_string(src)));
+                }
+                s.push('}');
+                s
+            }
+        }
+    }
+    /// The raw bytes of a bulk or simple string; other values fall back to their text form.
+    pub fn to_bytes(&self, src: &BytesMut) -> Bytes {
+        match self {
+            RedisBufSplit::String(word)
+            | RedisBufSplit::Error(word)
+            | RedisBufSplit::Verbatim(word) => word.to_bytes(src),
+            other => Bytes::from(other.to_string(src)),
+        }
+    }
+
+    pub fn to_resp(&self, src: &BytesMut) -> Vec<u8> {
+        match self {
+            RedisBufSplit::String(word) => {
+                let mut s = format!("${}\r\n", word.len()).into_bytes();
+                s.extend_from_slice(&word.to_bytes(src));
+                s.extend_from_slice(b"\r\n");
+                s
+            }
+            RedisBufSplit::Error(word) => format!("-{}\r\n", word.to_string(src)).into_bytes(),
+            RedisBufSplit::Int(i) => format!(":{}\r\n", i).into_bytes(),
+            RedisBufSplit::Array(words) => {
+                let mut s = format!("*{}\r\n", words.len()).into_bytes();
+                for word in words.iter() {
+                    s.extend_from_slice(&word.to_resp(src));
+                }
+                s
+            }
+            RedisBufSplit::NullArray => b"*-1\r\n".to_vec(),
+            RedisBufSplit::NullBulkString => b"$-1\r\n".to_vec(),
+            RedisBufSplit::Null => b"_\r\n".to_vec(),
+            RedisBufSplit::Boolean(b) => format!("#{}\r\n", if *b { 't' } else { 'f' }).into_bytes(),
+            RedisBufSplit::Double(word) => format!(",{}\r\n", word.to_string(src)).into_bytes(),
+            RedisBufSplit::BigNumber(word) => format!("({}\r\n", word.to_string(src)).into_bytes(),
+            RedisBufSplit::Verbatim(word) => {
+                let mut s = format!("={}\r\ntxt:", word.len() + 4).into_bytes();
+                s.extend_from_slice(&word.to_bytes(src));
+                s.extend_from_slice(b"\r\n");
+                s
+            }
+            RedisBufSplit::Map(pairs) => {
+                let mut s = format!("%{}\r\n", pairs.len()).into_bytes();
+                for (k, v) in pairs.iter() {
+                    s.extend_from_slice(&k.to_resp(src));
+                    s.extend_from_slice(&v.to_resp(src));
+                }
+                s
+            }
+            RedisBufSplit::Set(words) | RedisBufSplit::Push(words) => {
+                let prefix = if matches!(self, RedisBufSplit::Set(_)) { '~' } else { '>' };
+                let mut s = format!("{}{}\r\n", prefix, words.len()).into_bytes();
+                for word in words.iter() {
+                    s.extend_from_slice(&word.to_resp(src));
+                }
+                s
+            }
+        }
+    }
+}
+
+type RedisResult = Result<Option<(usize, RedisBufSplit)>, RESPError>;
+impl Parser {
+    /// Returns the position after the next CRLF and a split of the bytes before it,
+    /// or `None` if the terminator hasn't arrived yet.
+    pub fn token(src: &BytesMut, index: usize) -> Option<(usize, BufSplit)> {
+        let start = index;
+        let mut end = index;
+        while end + 1 < src.len() {
+            if src[end] == b'\r' && src[end + 1] == b'\n' {
+                return Some((end + 2, BufSplit(start, end)));
+            }
+            end += 1;
+        }
+        None
+    }
+
+    fn parse_int(src: &BytesMut, index: usize) -> Result<Option<(usize, i64)>, RESPError> {
+        if ![b'$', b':', b'*', b'%', b'~', b'>', b'='].contains(&src[index]) {
+            return Err(RESPError::UnknownStartingByte(src[index]));
+        }
+        let (pos, split) = match Parser::token(src, index) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let num_str = String::from_utf8_lossy(&src[split.0 + 1..split.1]);
+        match num_str.parse::<i64>() {
+            Ok(n) => Ok(Some((pos, n))),
+            Err(_) => Err(RESPError::IntParseFailure(num_str.to_string())),
+        }
+    }
+
+    /// Parses a single RESP value starting at `index`.
+    ///
+    /// Returns `Ok(None)` when the buffer doesn't hold a complete value yet, so callers
+    /// can read more bytes and retry without losing anything.
+    pub fn parse(src: &BytesMut, index: usize) -> RedisResult {
+        Parser::parse_nested(src, index, 0)
+    }
+
+    /// Parses a value inside `depth` aggregates
+    fn parse_nested(src: &BytesMut, index: usize, depth: usize) -> RedisResult {
+        if index >= src.len() {
+            return Ok(None);
+        }
+        match RESPDataType::from_prefix(src[index]) {
+            Some(RESPDataType::SimpleString) => Parser::simple_string(src, index),
+            Some(RESPDataType::SimpleError) => Parser::simple_error(src, index),
+            Some(RESPDataType::Integer) => Parser::integer(src, index),
+            Some(RESPDataType::BulkString) => Parser::parse_bulk_string(src, index),
+            Some(RESPDataType::Array) => Parser::parse_array_nested(src, index, depth),
+            Some(RESPDataType::Null) => match Parser::token(src, index + 1) {
+                Some((pos, _)) => Ok(Some((pos, RedisBufSplit::Null))),
+                None => Ok(None),
+            },
+            Some(RESPDataType::Boolean) => match Parser::token(src, index + 1) {
+                Some((pos, word)) => match &src[word.0..word.1] {
+                    b"t" => Ok(Some((pos, RedisBufSplit::Boolean(true)))),
+                    b"f" => Ok(Some((pos, RedisBufSplit::Boolean(false)))),
+                    _ => Err(RESPError::UnknownStartingByte(src[index])),
+                },
+                None => Ok(None),
+            },
+            Some(RESPDataType::Double) => match Parser::token(src, index + 1) {
+                Some((pos, word)) => Ok(Some((pos, RedisBufSplit::Double(word)))),
+                None => Ok(None),
+            },
+            Some(RESPDataType::BigNumber) => match Parser::token(src, index + 1) {
+                Some((pos, word)) => Ok(Some((pos, RedisBufSplit::BigNumber(word)))),
+                None => Ok(None),
+            },
+            Some(RESPDataType::VerbatimString) => Parser::parse_verbatim_string(src, index),
+            Some(RESPDataType::Map) => Parser::parse_map(src, index, depth),
+            Some(RESPDataType::Set) => match Parser::parse_aggregate(src, index, depth)? {
+                Some((pos, items)) => Ok(Some((pos, RedisBufSplit::Set(items)))),
+                None => Ok(None),
+            },
+            Some(RESPDataType::Push) => match Parser::parse_aggregate(src, index, depth)? {
+                Some((pos, items)) => Ok(Some((pos, RedisBufSplit::Push(items)))),
+                None => Ok(None),
+            },
+            None => Err(RESPError::UnknownStartingByte(src[index])),
+        }
+    }
+
+    fn parse_verbatim_string(src: &BytesMut, index: usize) -> RedisResult {
+        // Verbatim String format:
+        // =<usize>\r\n<3 byte format>:<data>\r\n
+        match Parser::parse_bulk_string(src, index)? {
+            Some((pos, RedisBufSplit::String(word))) if word.len() >= 4 => Ok(Some((
+                pos,
+                RedisBufSplit::Verbatim(BufSplit(word.0 + 4, word.1)),
+            ))),
+            Some(_) => Err(RESPError::BadBulkStringSize(-1)),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses the elements of a counted aggregate (set or push); same layout as an array.
+    fn parse_aggregate(
+        src: &BytesMut,
+        index: usize,
+        depth: usize,
+    ) -> Result<Option<(usize, Vec<RedisBufSplit>)>, RESPError> {
+        if depth >= MAX_NESTING {
+            return Err(RESPError::NestingTooDeep);
+        }
+        let (mut pos, size) = match Parser::parse_int(src, index)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if size < 0 {
+            return Err(RESPError::BadArraySize(size));
+        }
+        let mut items = vec![];
+        for _ in 0..size {
+            match Parser::parse_nested(src, pos, depth + 1)? {
+                Some((new_pos, item)) => {
+                    items.push(item);
+                    pos = new_pos;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((pos, items)))
+    }
+
+    fn parse_map(src: &BytesMut, index: usize, depth: usize) -> RedisResult {
+        // Map format:
+        // %<usize>\r\n<key_1><value_1>...
+        if depth >= MAX_NESTING {
+            return Err(RESPError::NestingTooDeep);
+        }
+        let (mut pos, size) = match Parser::parse_int(src, index)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if size < 0 {
+            return Err(RESPError::BadArraySize(size));
+        }
+        let mut pairs = vec![];
+        for _ in 0..size {
+            let (new_pos, key) = match Parser::parse_nested(src, pos, depth + 1)? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            let (new_pos, value) = match Parser::parse_nested(src, new_pos, depth + 1)? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            pairs.push((key, value));
+            pos = new_pos;
+        }
+        Ok(Some((pos, RedisBufSplit::Map(pairs))))
+    }
+
+    pub fn parse_bulk_string(src: &BytesMut, index: usize) -> RedisResult {
+        // Bulk String format:
+        // $<usize>\r\n<data>\r\n
+        let (index, size) = match Parser::parse_int(src, index)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if size == -1 {
+            return Ok(Some((index, RedisBufSplit::NullBulkString)));
+        }
+        if size < -1 {
+            return Err(RESPError::BadBulkStringSize(size));
+        }
+        let start = index;
+        let end = index + size as usize;
+        if end + 2 > src.len() {
+            return Ok(None);
+        }
+        if &src[end..end + 2] != b"\r\n" {
+            return Err(RESPError::BadBulkStringSize(size));
+        }
+        Ok(Some((end + 2, RedisBufSplit::String(BufSplit(start, end)))))
+    }
+
+    pub fn parse_array(src: &BytesMut, index: usize) -> RedisResult {
+        Parser::parse_array_nested(src, index, 0)
+    }
+
+    fn parse_array_nested(src: &BytesMut, index: usize, depth: usize) -> RedisResult {
+        // Array format:
+        // *<usize>\r\n<element_1>\r\n<element_2>\r\n...
+        if depth >= MAX_NESTING {
+            return Err(RESPError::NestingTooDeep);
+        }
+        let (index, size) = match Parser::parse_int(src, index)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if size == -1 {
+            return Ok(Some((index, RedisBufSplit::NullArray)));
+        }
+        if size < -1 {
+            return Err(RESPError::BadArraySize(size));
+        }
+        let mut tokens = vec![];
+        let mut pos = index;
+        for _ in 0..size {
+            match Parser::parse_nested(src, pos, depth + 1)? {
+                Some((new_pos, word)) => {
+                    tokens.push(word);
+                    pos = new_pos
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((pos, RedisBufSplit::Array(tokens))))
+    }
+
+    /// Parses a command as clients send it: an array of bulk strings, and nothing
+    /// else inside
+    fn parse_multibulk(src: &BytesMut, index: usize) -> RedisResult {
+        let (index, size) = match Parser::parse_int(src, index)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if size == -1 {
+            return Ok(Some((index, RedisBufSplit::NullArray)));
+        }
+        if size < -1 {
+            return Err(RESPError::BadArraySize(size));
+        }
+        let mut words = vec![];
+        let mut pos = index;
+        for _ in 0..size {
+            match src.get(pos) {
+                Some(b'$') => {}
+                Some(&other) => return Err(RESPError::ExpectedBulkString(other)),
+                None => return Ok(None),
+            }
+            match Parser::parse_bulk_string(src, pos)? {
+                // Every argument has a value, if only an empty one
+                Some((_, RedisBufSplit::NullBulkString)) => return Err(RESPError::BadBulkStringSize(-1)),
+                Some((new_pos, word)) => {
+                    words.push(word);
+                    pos = new_pos
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((pos, RedisBufSplit::Array(words))))
+    }
+
+    pub fn simple_string(buf: &BytesMut, pos: usize) -> RedisResult {
+        // Skip the first byte "+"
+        match Parser::token(buf, pos + 1) {
+            Some((pos, word)) => Ok(Some((pos, RedisBufSplit::String(word)))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn simple_error(buf: &BytesMut, pos: usize) -> RedisResult {
+        // Skip the first byte "-"
+        match Parser::token(buf, pos + 1) {
+            Some((pos, word)) => Ok(Some((pos, RedisBufSplit::Error(word)))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn integer(buf: &BytesMut, pos: usize) -> RedisResult {
+        match Parser::parse_int(buf, pos)? {
+            Some((pos, i)) => Ok(Some((pos, RedisBufSplit::Int(i)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses the RDB file a master sends after `+FULLRESYNC`.
+    ///
+    /// Format: $<usize>\r\n<contents>
+    /// Unlike a bulk string there is no trailing CRLF.
+    pub fn parse_rdb_payload(
+        src: &BytesMut,
+        index: usize,
+    ) -> Result<Option<(usize, BufSplit)>, RESPError> {
+        if index >= src.len() {
+            return Ok(None);
+        }
+        if src[index] != b'$' {
+            return Err(RESPError::UnknownStartingByte(src[index]));
+        }
+        let (index, size) = match Parser::parse_int(src, index)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if size < 0 {
+            return Err(RESPError::BadBulkStringSize(size));
+        }
+        let end = index + size as usize;
+        if end > src.len() {
+            return Ok(None);
+        }
+        Ok(Some((end, BufSplit(index, end))))
+    }
+
+    pub fn find_start_resp_data_type(
+        buf: &BytesMut,
+        index: usize,
+        query_type: &RESPDataType,
+    ) -> Option<usize> {
+        let mut pos = index;
+        loop {
+            if pos >= buf.len() {
+                return None;
+            }
+            match (buf[pos], query_type) {
+                (b'*', RESPDataType::Array) => {
+                    match Parser::parse_int(buf, pos) {
+                        Ok(Some(_)) => return Some(pos),
+                        _ => {
+                            pos += 1;
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    pos += 1;
+                    continue;
+                }
+            }
+        }
+    }
+
+    /// Parses every complete command frame at the front of `bm`.
+    ///
+    /// A trailing partial frame is left alone; the caller is expected to drop the
+    /// consumed bytes (the sum of `bytes_read`) and keep the rest for the next read.
+    /// Commands that are unknown or have bad arguments come back as `Command::Invalid`
+    /// so the caller can reply with an error; only malformed framing is an `Err`,
+    /// and only when it's the first thing in the buffer.
+    pub fn parse_commands(logger: &Logger, bm: &BytesMut) -> Result<Vec<ParsedCommand>, RESPError> {
+        let mut pos = 0;
+        let mut commands = Vec::new();
+
+        while pos < bm.len() {
+            let start_pos = pos;
+            let frame = match bm[pos] {
+                b'*' => Parser::parse_multibulk(bm, pos),
+                _ => Parser::parse(bm, pos),
+            };
+            let (i, res) = match frame {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                // Hand back what we have; the error surfaces on the next call
+                Err(_) if !commands.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            pos = i;
+            let bytes_read = pos - start_pos;
+            let (command, name) = match res {
+                RedisBufSplit::Array(a) if !a.is_empty() => {
+                    let args: Vec<Bytes> = a.iter().map(|w| w.to_bytes(bm)).collect();
+                    let command = Parser::parse_command(&args).unwrap_or_else(Command::Invalid);
+                    (command, lossy(&args[0]).to_lowercase())
+                }
+                other => {
+                    logger.log(&format!(
+                        "Non-command value: '{}' not doing anything with it",
+                        other.to_string(bm)
+                    ));
+                    (Command::Unknown, String::new())
+                }
+            };
+            commands.push(ParsedCommand {
+                command,
+                name,
+                bytes_read,
+            });
+        }
+        Ok(commands)
+    }
+
+    /// Builds a `Command` from the words of a command array, validating arity and arguments.
+    pub fn parse_command(a: &[Bytes]) -> Result<Command, RESPError> {
+        let command = lossy(&a[0]).to_lowercase();
+        let arity = match Command::arity(&command) {
+            Some(arity) => arity,
+            None => {
+                let args = a.iter().skip(1).map(lossy).collect();
+                return Err(RESPError::UnknownCommand(lossy(&a[0]), args));
+            }
+        };
+        let argc = a.len() as i64;
+        if (arity > 0 && argc != arity) || (arity < 0 && argc < -arity) {
+            return Err(RESPError::WrongNumberOfArguments(command));
+        }
+
+        match command.as_str() {
+            "echo" => Ok(Command::Echo(a[1].clone())),
+            "ping" => match a.len() {
+                1 => Ok(Command::Ping(None)),
+                2 => Ok(Command::Ping(Some(a[1].clone()))),
+                _ => Err(RESPError::WrongNumberOfArguments(command)),
+            },
+            "set" => {
+                let mut options = SetOptions::default();
+                let mut i = 3;
+                while i < a.len() {
+                    let option = lossy(&a[i]).to_lowercase();
+                    match option.as_str() {
+                        "nx" | "xx" if options.condition.is_none() => {
+                            options.condition = Some(if option == "nx" {
+                                SetCondition::Nx
+                            } else {
+                                SetCondition::Xx
+                            });
+                        }
+                        "get" => options.get = true,
+                        "keepttl" if options.expiry.is_none() => {
+                            options.expiry = Some(SetExpiry::KeepTtl)
+                        }
+                        "ex" | "px" | "exat" | "pxat" if options.expiry.is_none() && i + 1 < a.len() => {
+                            i += 1;
+                            let time = parse_expire_option(&option, &a[i], &command)?;
+                            options.expiry = Some(SetExpiry::Expire(time));
+                        }
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                    i += 1;
+                }
+                Ok(Command::Set(a[1].clone(), a[2].clone(), options))
+            }
+            "get" => Ok(Command::Get(a[1].clone())),
+            "docs" => Ok(Command::Docs),
+            "info" => match a.len() {
+                1 => Ok(Command::Info("default".to_string())),
+                2 => Ok(Command::Info(lossy(&a[1]))),
+                _ => Err(RESPError::SyntaxError),
+            },
+            "replconf" => {
+                let args: Vec<String> = a.iter().skip(1).map(|b| lossy(b).to_lowercase()).collect();
+                if args.chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RESPError::SyntaxError);
+                }
+                Ok(Command::ReplConf(args))
+            }
+            "psync" => Ok(Command::Psync),
+            "keys" => Ok(Command::Keys(a[1].clone())),
+            "config" => match lossy(&a[1]).to_lowercase().as_str() {
+                "get" if a.len() >= 3 => Ok(Command::ConfigGet(a[2..].to_vec())),
+                "get" => Err(RESPError::WrongNumberOfArguments("config|get".to_string())),
+                _ => Err(RESPError::UnknownSubcommand(
+                    lossy(&a[1]),
+                    "CONFIG".to_string(),
+                )),
+            },
+            "save" => Ok(Command::Save),
+            "bgsave" => match a.get(1).map(|o| lossy(o).to_lowercase()).as_deref() {
+                None | Some("schedule") => Ok(Command::BgSave),
+                Some(_) => Err(RESPError::SyntaxError),
+            },
+            "lastsave" => Ok(Command::LastSave),
+            "bgrewriteaof" => Ok(Command::BgRewriteAof),
+            "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                let n = parse_i64(&a[2])?;
+                let ms = if command.starts_with('p') {
+                    Some(n)
+                } else {
+                    n.checked_mul(1000)
+                };
+                let ms = ms.ok_or_else(|| RESPError::InvalidExpireTime(command.clone()))?;
+                let time = if command.ends_with("at") {
+                    ExpireTime::At(ms)
+                } else {
+                    ExpireTime::Relative(ms)
+                };
+                let mut condition = ExpireCondition::default();
+                for flag in &a[3..] {
+                    match lossy(flag).to_lowercase().as_str() {
+                        "nx" => condition.nx = true,
+                        "xx" => condition.xx = true,
+                        "gt" => condition.gt = true,
+                        "lt" => condition.lt = true,
+                        _ => return Err(RESPError::UnsupportedOption(lossy(flag))),
+                    }
+                }
+                if condition.nx && (condition.xx || condition.gt || condition.lt) {
+                    return Err(RESPError::IncompatibleNxOptions);
+                }
+                if condition.gt && condition.lt {
+                    return Err(RESPError::IncompatibleGtLt);
+                }
+                Ok(Command::Expire(a[1].clone(), time, condition))
+            }
+            "ttl" => Ok(Command::Ttl(a[1].clone(), TtlFormat::Seconds)),
+            "pttl" => Ok(Command::Ttl(a[1].clone(), TtlFormat::Millis)),
+            "expiretime" => Ok(Command::Ttl(a[1].clone(), TtlFormat::UnixSeconds)),
+            "pexpiretime" => Ok(Command::Ttl(a[1].clone(), TtlFormat::UnixMillis)),
+            "persist" => Ok(Command::Persist(a[1].clone())),
+            "del" | "unlink" => Ok(Command::Del(a[1..].to_vec())),
+            "exists" => Ok(Command::Exists(a[1..].to_vec())),
+            "type" => Ok(Command::Type(a[1].clone())),
+            "rename" | "renamenx" => Ok(Command::Rename {
+                from: a[1].clone(),
+                to: a[2].clone(),
+                nx: command == "renamenx",
+            }),
+            "copy" => {
+                let mut replace = false;
+                let mut i = 3;
+                while i < a.len() {
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "replace" => replace = true,
+                        "db" if i + 1 < a.len() => {
+                            i += 1;
+                            if parse_i64(&a[i])? != 0 {
+                                return Err(RESPError::DbIndexOutOfRange);
+                            }
+                        }
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                    i += 1;
+                }
+                Ok(Command::Copy {
+                    source: a[1].clone(),
+                    destination: a[2].clone(),
+                    replace,
+                })
+            }
+            "scan" => {
+                let cursor = lossy(&a[1]).parse().map_err(|_| RESPError::InvalidCursor)?;
+                Ok(Command::Scan(cursor, parse_scan_options(&a[2..], true)?))
+            }
+            "randomkey" => Ok(Command::RandomKey),
+            "dbsize" => Ok(Command::DbSize),
+            "incr" => Ok(Command::IncrBy(a[1].clone(), 1)),
+            "decr" => Ok(Command::IncrBy(a[1].clone(), -1)),
+            "incrby" => Ok(Command::IncrBy(a[1].clone(), parse_i64(&a[2])?)),
+            "decrby" => match parse_i64(&a[2])?.checked_neg() {
+                Some(n) => Ok(Command::IncrBy(a[1].clone(), n)),
+                None => Err(RESPError::DecrementOverflow),
+            },
+            "incrbyfloat" => Ok(Command::IncrByFloat(a[1].clone(), parse_f64(&a[2])?)),
+            "append" => Ok(Command::Append(a[1].clone(), a[2].clone())),
+            "strlen" => Ok(Command::Strlen(a[1].clone())),
+            "getrange" | "substr" => Ok(Command::GetRange(
+                a[1].clone(),
+                parse_i64(&a[2])?,
+                parse_i64(&a[3])?,
+            )),
+            "setrange" => match parse_i64(&a[2])? {
+                offset if offset < 0 => Err(RESPError::OffsetOutOfRange),
+                offset => Ok(Command::SetRange(a[1].clone(), offset as usize, a[3].clone())),
+            },
+            "getset" => Ok(Command::Set(
+                a[1].clone(),
+                a[2].clone(),
+                SetOptions {
+                    get: true,
+                    ..Default::default()
+                },
+            )),
+            "getdel" => Ok(Command::GetDel(a[1].clone())),
+            "getex" => {
+                let option = match a.len() {
+                    2 => None,
+                    3 if lossy(&a[2]).eq_ignore_ascii_case("persist") => Some(GetExOption::Persist),
+                    4 => {
+                        let option = lossy(&a[2]).to_lowercase();
+                        if !matches!(option.as_str(), "ex" | "px" | "exat" | "pxat") {
+                            return Err(RESPError::SyntaxError);
+                        }
+                        Some(GetExOption::Expire(parse_expire_option(&option, &a[3], &command)?))
+                    }
+                    _ => return Err(RESPError::SyntaxError),
+                };
+                Ok(Command::GetEx(a[1].clone(), option))
+            }
+            "mget" => Ok(Command::MGet(a[1..].to_vec())),
+            "mset" | "msetnx" => {
+                if a[1..].chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RESPError::WrongNumberOfArguments(command));
+                }
+                let pairs = a[1..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Ok(Command::MSet {
+                    pairs,
+                    nx: command == "msetnx",
+                })
+            }
+            "setnx" => Ok(Command::MSet {
+                pairs: vec![(a[1].clone(), a[2].clone())],
+                nx: true,
+            }),
+            "setex" | "psetex" => {
+                let unit = if command == "setex" { "ex" } else { "px" };
+                let time = parse_expire_option(unit, &a[2], &command)?;
+                Ok(Command::Set(
+                    a[1].clone(),
+                    a[3].clone(),
+                    SetOptions {
+                        expiry: Some(SetExpiry::Expire(time)),
+                        ..Default::default()
+                    },
+                ))
+            }
+            "flushall" | "flushdb" => match a.get(1).map(|o| lossy(o).to_lowercase()).as_deref() {
+                None | Some("sync") | Some("async") => Ok(Command::FlushAll),
+                Some(_) => Err(RESPError::SyntaxError),
+            },
+            "lpush" | "rpush" | "lpushx" | "rpushx" => Ok(Command::Push {
+                key: a[1].clone(),
+                elements: a[2..].to_vec(),
+                end: if command.starts_with('l') { ListEnd::Left } else { ListEnd::Right },
+                only_if_exists: command.ends_with('x'),
+            }),
+            "lpop" | "rpop" => {
+                let count = match a.get(2) {
+                    None => None,
+                    Some(_) if a.len() > 3 => return Err(RESPError::WrongNumberOfArguments(command)),
+                    Some(count) => match parse_i64(count)? {
+                        n if n < 0 => return Err(RESPError::NotPositive),
+                        n => Some(n as usize),
+                    },
+                };
+                Ok(Command::Pop {
+                    key: a[1].clone(),
+                    end: if command == "lpop" { ListEnd::Left } else { ListEnd::Right },
+                    count,
+                })
+            }
+            "lrange" => Ok(Command::LRange(a[1].clone(), parse_i64(&a[2])?, parse_i64(&a[3])?)),
+            "llen" => Ok(Command::LLen(a[1].clone())),
+            "lindex" => Ok(Command::LIndex(a[1].clone(), parse_i64(&a[2])?)),
+            "lset" => Ok(Command::LSet(a[1].clone(), parse_i64(&a[2])?, a[3].clone())),
+            "linsert" => {
+                let before = match lossy(&a[2]).to_lowercase().as_str() {
+                    "before" => true,
+                    "after" => false,
+                    _ => return Err(RESPError::SyntaxError),
+                };
+                Ok(Command::LInsert {
+                    key: a[1].clone(),
+                    before,
+                    pivot: a[3].clone(),
+                    element: a[4].clone(),
+                })
+            }
+            "lrem" => Ok(Command::LRem(a[1].clone(), parse_i64(&a[2])?, a[3].clone())),
+            "ltrim" => Ok(Command::LTrim(a[1].clone(), parse_i64(&a[2])?, parse_i64(&a[3])?)),
+            "lpos" => {
+                let mut options = LPosOptions::default();
+                let mut i = 3;
+                while i < a.len() {
+                    let value = a.get(i + 1).ok_or(RESPError::SyntaxError)?;
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "rank" => match parse_i64(value)? {
+                            0 => return Err(RESPError::ZeroRank),
+                            // So the rank can be negated
+                            i64::MIN => return Err(RESPError::NotAnInteger),
+                            rank => options.rank = rank,
+                        },
+                        "count" => match parse_i64(value)? {
+                            n if n < 0 => return Err(RESPError::NegativeCount),
+                            n => options.count = Some(n as usize),
+                        },
+                        "maxlen" => match parse_i64(value)? {
+                            n if n < 0 => return Err(RESPError::NegativeMaxLen),
+                            n => options.maxlen = n as usize,
+                        },
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                    i += 2;
+                }
+                Ok(Command::LPos {
+                    key: a[1].clone(),
+                    element: a[2].clone(),
+                    options,
+                })
+            }
+            "lmove" => Ok(Command::LMove {
+                source: a[1].clone(),
+                destination: a[2].clone(),
+                from: ListEnd::parse(&a[3])?,
+                to: ListEnd::parse(&a[4])?,
+            }),
+            "rpoplpush" => Ok(Command::LMove {
+                source: a[1].clone(),
+                destination: a[2].clone(),
+                from: ListEnd::Right,
+                to: ListEnd::Left,
+            }),
+            "blpop" | "brpop" => Ok(Command::Block {
+                keys: a[1..a.len() - 1].to_vec(),
+                op: BlockingOp::Pop(if command == "blpop" { ListEnd::Left } else { ListEnd::Right }),
+                timeout: parse_timeout(&a[a.len() - 1])?,
+            }),
+            "blmove" | "brpoplpush" => {
+                let (from, to) = if command == "blmove" {
+                    (ListEnd::parse(&a[3])?, ListEnd::parse(&a[4])?)
+                } else {
+                    (ListEnd::Right, ListEnd::Left)
+                };
+                Ok(Command::Block {
+                    keys: vec![a[1].clone()],
+                    op: BlockingOp::Move {
+                        destination: a[2].clone(),
+                        from,
+                        to,
+                    },
+                    timeout: parse_timeout(&a[a.len() - 1])?,
+                })
+            }
+            "hset" | "hmset" | "hsetnx" => {
+                if a[2..].chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RESPError::WrongNumberOfArguments(command));
+                }
+                let pairs = a[2..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Ok(Command::HSet {
+                    key: a[1].clone(),
+                    pairs,
+                    nx: command == "hsetnx",
+                    reply_ok: command == "hmset",
+                })
+            }
+            "hget" => Ok(Command::HGet(a[1].clone(), a[2].clone())),
+            "hmget" => Ok(Command::HMGet(a[1].clone(), a[2..].to_vec())),
+            "hdel" => Ok(Command::HDel(a[1].clone(), a[2..].to_vec())),
+            "hexists" => Ok(Command::HExists(a[1].clone(), a[2].clone())),
+            "hlen" => Ok(Command::HLen(a[1].clone())),
+            "hkeys" => Ok(Command::HKeys(a[1].clone())),
+            "hvals" => Ok(Command::HVals(a[1].clone())),
+            "hgetall" => Ok(Command::HGetAll(a[1].clone())),
+            "hincrby" => Ok(Command::HIncrBy(a[1].clone(), a[2].clone(), parse_i64(&a[3])?)),
+            "hincrbyfloat" => Ok(Command::HIncrByFloat(a[1].clone(), a[2].clone(), parse_f64(&a[3])?)),
+            "hstrlen" => Ok(Command::HStrlen(a[1].clone(), a[2].clone())),
+            "hrandfield" => {
+                let count = a.get(2).map(parse_i64).transpose()?;
+                let with_values = match a.get(3) {
+                    None => false,
+                    Some(option) if a.len() == 4 && lossy(option).eq_ignore_ascii_case("withvalues") => true,
+                    Some(_) => return Err(RESPError::SyntaxError),
+                };
+                Ok(Command::HRandField {
+                    key: a[1].clone(),
+                    count,
+                    with_values,
+                })
+            }
+            "hscan" => {
+                let cursor = lossy(&a[2]).parse().map_err(|_| RESPError::InvalidCursor)?;
+                Ok(Command::HScan(a[1].clone(), cursor, parse_scan_options(&a[3..], false)?))
+            }
+            "sadd" => Ok(Command::SAdd(a[1].clone(), a[2..].to_vec())),
+            "srem" => Ok(Command::SRem(a[1].clone(), a[2..].to_vec())),
+            "sismember" => Ok(Command::SIsMember(a[1].clone(), a[2].clone())),
+            "smismember" => Ok(Command::SMIsMember(a[1].clone(), a[2..].to_vec())),
+            "smembers" => Ok(Command::SMembers(a[1].clone())),
+            "scard" => Ok(Command::SCard(a[1].clone())),
+            "spop" => {
+                let count = match a.get(2) {
+                    None => None,
+                    Some(_) if a.len() > 3 => return Err(RESPError::SyntaxError),
+                    Some(count) => match parse_i64(count)? {
+                        n if n < 0 => return Err(RESPError::NotPositive),
+                        n => Some(n as usize),
+                    },
+                };
+                Ok(Command::SPop {
+                    key: a[1].clone(),
+                    count,
+                })
+            }
+            "srandmember" => {
+                if a.len() > 3 {
+                    return Err(RESPError::SyntaxError);
+                }
+                Ok(Command::SRandMember {
+                    key: a[1].clone(),
+                    count: a.get(2).map(parse_i64).transpose()?,
+                })
+            }
+            "smove" => Ok(Command::SMove {
+                source: a[1].clone(),
+                destination: a[2].clone(),
+                member: a[3].clone(),
+            }),
+            "sinter" | "sunion" | "sdiff" | "sinterstore" | "sunionstore" | "sdiffstore" => {
+                let op = match &command[1..3] {
+                    "in" => SetOperation::Inter,
+                    "un" => SetOperation::Union,
+                    _ => SetOperation::Diff,
+                };
+                let (destination, keys) = if command.ends_with("store") {
+                    (Some(a[1].clone()), a[2..].to_vec())
+                } else {
+                    (None, a[1..].to_vec())
+                };
+                Ok(Command::SetOp {
+                    op,
+                    keys,
+                    destination,
+                })
+            }
+            "sintercard" => {
+                let numkeys = match parse_i64(&a[1])? {
+                    n if n <= 0 => return Err(RESPError::NumKeysNotPositive),
+                    n if n as usize > a.len() - 2 => return Err(RESPError::TooManyKeys),
+                    n => n as usize,
+                };
+                let keys = a[2..2 + numkeys].to_vec();
+                let limit = match &a[2 + numkeys..] {
+                    [] => 0,
+                    [option, limit] if lossy(option).eq_ignore_ascii_case("limit") => {
+                        match parse_i64(limit)? {
+                            n if n < 0 => return Err(RESPError::NegativeLimit),
+                            n => n as usize,
+                        }
+                    }
+                    _ => return Err(RESPError::SyntaxError),
+                };
+                Ok(Command::SInterCard { keys, limit })
+            }
+            "sscan" => {
+                let cursor = lossy(&a[2]).parse().map_err(|_| RESPError::InvalidCursor)?;
+                Ok(Command::SScan(a[1].clone(), cursor, parse_scan_options(&a[3..], false)?))
+            }
+            "zadd" => {
+                let mut options = ZAddOptions::default();
+                let mut i = 2;
+                while let Some(flag) = a.get(i) {
+                    match lossy(flag).to_lowercase().as_str() {
+                        "nx" => options.nx = true,
+                        "xx" => options.xx = true,
+                        "gt" => options.gt = true,
+                        "lt" => options.lt = true,
+                        "ch" => options.ch = true,
+                        "incr" => options.incr = true,
+                        _ => break,
+                    }
+                    i += 1;
+                }
+                let rest = &a[i..];
+                if rest.is_empty() || rest.chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RESPError::SyntaxError);
+                }
+                if options.nx && options.xx {
+                    return Err(RESPError::IncompatibleZAddXxNx);
+                }
+                if [options.nx, options.gt, options.lt].iter().filter(|set| **set).count() > 1 {
+                    return Err(RESPError::IncompatibleZAddGtLtNx);
+                }
+                if options.incr && rest.len() > 2 {
+                    return Err(RESPError::IncrSinglePair);
+                }
+                let pairs = rest
+                    .chunks(2)
+                    .map(|pair| Ok((parse_f64(&pair[0])?, pair[1].clone())))
+                    .collect::<Result<_, RESPError>>()?;
+                Ok(Command::ZAdd {
+                    key: a[1].clone(),
+                    options,
+                    pairs,
+                })
+            }
+            "zrem" => Ok(Command::ZRem(a[1].clone(), a[2..].to_vec())),
+            "zcard" => Ok(Command::ZCard(a[1].clone())),
+            "zscore" => Ok(Command::ZScore(a[1].clone(), a[2].clone())),
+            "zmscore" => Ok(Command::ZMScore(a[1].clone(), a[2..].to_vec())),
+            "zincrby" => Ok(Command::ZIncrBy(a[1].clone(), parse_f64(&a[2])?, a[3].clone())),
+            "zrank" | "zrevrank" => {
+                let with_score = match a.get(3) {
+                    None => false,
+                    Some(option) if a.len() == 4 && lossy(option).eq_ignore_ascii_case("withscore") => true,
+                    Some(_) => return Err(RESPError::SyntaxError),
+                };
+                Ok(Command::ZRank {
+                    key: a[1].clone(),
+                    member: a[2].clone(),
+                    rev: command == "zrevrank",
+                    with_score,
+                })
+            }
+            "zrange" | "zrevrange" | "zrangebyscore" | "zrevrangebyscore" | "zrangebylex" | "zrevrangebylex" => {
+                let (range, with_scores) = parse_zrange(&command, &a[2..])?;
+                Ok(Command::ZRange {
+                    key: a[1].clone(),
+                    range,
+                    with_scores,
+                })
+            }
+            "zrangestore" => match parse_zrange(&command, &a[3..])? {
+                (_, true) => Err(RESPError::SyntaxError),
+                (range, false) => Ok(Command::ZRangeStore {
+                    destination: a[1].clone(),
+                    key: a[2].clone(),
+                    range,
+                }),
+            },
+            "zcount" => Ok(Command::ZCount(
+                a[1].clone(),
+                ScoreBound::parse(&a[2])?,
+                ScoreBound::parse(&a[3])?,
+            )),
+            "zlexcount" => Ok(Command::ZLexCount(
+                a[1].clone(),
+                LexBound::parse(&a[2])?,
+                LexBound::parse(&a[3])?,
+            )),
+            "zpopmin" | "zpopmax" => {
+                let count = match a.get(2) {
+                    None => None,
+                    Some(_) if a.len() > 3 => return Err(RESPError::SyntaxError),
+                    Some(count) => match parse_i64(count)? {
+                        n if n < 0 => return Err(RESPError::NotPositive),
+                        n => Some(n as usize),
+                    },
+                };
+                Ok(Command::ZPop {
+                    key: a[1].clone(),
+                    max: command == "zpopmax",
+                    count,
+                })
+            }
+            "bzpopmin" | "bzpopmax" => Ok(Command::Block {
+                keys: a[1..a.len() - 1].to_vec(),
+                op: BlockingOp::ZPop {
+                    max: command == "bzpopmax",
+                },
+                timeout: parse_timeout(&a[a.len() - 1])?,
+            }),
+            "zunionstore" | "zinterstore" => {
+                let numkeys = match parse_i64(&a[2])? {
+                    n if n <= 0 => return Err(RESPError::NoInputKeys(command)),
+                    n if n as usize > a.len() - 3 => return Err(RESPError::SyntaxError),
+                    n => n as usize,
+                };
+                let keys = a[3..3 + numkeys].to_vec();
+                let mut weights = vec![1.0; numkeys];
+                let mut aggregate = Aggregate::Sum;
+                let mut i = 3 + numkeys;
+                while i < a.len() {
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "weights" if i + numkeys < a.len() => {
+                            for (weight, word) in weights.iter_mut().zip(&a[i + 1..]) {
+                                *weight = parse_float(word).ok_or(RESPError::InvalidWeight)?;
+                            }
+                            i += numkeys;
+                        }
+                        "aggregate" if i + 1 < a.len() => {
+                            aggregate = match lossy(&a[i + 1]).to_lowercase().as_str() {
+                                "sum" => Aggregate::Sum,
+                                "min" => Aggregate::Min,
+                                "max" => Aggregate::Max,
+                                _ => return Err(RESPError::SyntaxError),
+                            };
+                            i += 1;
+                        }
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                    i += 1;
+                }
+                Ok(Command::ZSetOp {
+                    op: if command == "zunionstore" { SetOperation::Union } else { SetOperation::Inter },
+                    destination: a[1].clone(),
+                    keys,
+                    weights,
+                    aggregate,
+                })
+            }
+            "zscan" => {
+                let cursor = lossy(&a[2]).parse().map_err(|_| RESPError::InvalidCursor)?;
+                Ok(Command::ZScan(a[1].clone(), cursor, parse_scan_options(&a[3..], false)?))
+            }
+            "xadd" => {
+                let mut nomkstream = false;
+                let mut trim = None;
+                let mut i = 2;
+                loop {
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "nomkstream" => {
+                            nomkstream = true;
+                            i += 1;
+                        }
+                        "maxlen" | "minid" => {
+                            let (parsed, next) = parse_stream_trim(a, i)?;
+                            trim = Some(parsed);
+                            i = next;
+                        }
+                        _ => break,
+                    }
+                    if i >= a.len() {
+                        return Err(RESPError::WrongNumberOfArguments(command));
+                    }
+                }
+                let id = match &a[i][..] {
+                    b"*" => XAddId::Auto,
+                    id => match id.strip_suffix(b"-*") {
+                        Some(ms) if !ms.contains(&b'-') => {
+                            XAddId::Partial(StreamId::parse(ms, 0).ok_or(RESPError::InvalidStreamId)?.ms)
+                        }
+                        _ => match StreamId::parse(id, 0).ok_or(RESPError::InvalidStreamId)? {
+                            StreamId::MIN => return Err(RESPError::XAddIdZero),
+                            id => XAddId::Explicit(id),
+                        },
+                    },
+                };
+                let pairs = &a[i + 1..];
+                if pairs.is_empty() || pairs.chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RESPError::WrongNumberOfArguments(command));
+                }
+                Ok(Command::XAdd {
+                    key: a[1].clone(),
+                    nomkstream,
+                    trim,
+                    id,
+                    fields: pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect(),
+                })
+            }
+            "xlen" => Ok(Command::XLen(a[1].clone())),
+            "xrange" | "xrevrange" => {
+                let rev = command == "xrevrange";
+                let (start, end) = if rev { (&a[3], &a[2]) } else { (&a[2], &a[3]) };
+                let mut count = None;
+                let mut i = 4;
+                while i < a.len() {
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "count" if i + 1 < a.len() => count = Some(parse_i64(&a[i + 1])?.max(0) as usize),
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                    i += 2;
+                }
+                Ok(Command::XRange {
+                    key: a[1].clone(),
+                    start: parse_range_id(start, true)?,
+                    end: parse_range_id(end, false)?,
+                    rev,
+                    count,
+                })
+            }
+            "xdel" => {
+                let ids = a[2..]
+                    .iter()
+                    .map(|id| StreamId::parse(id, 0).ok_or(RESPError::InvalidStreamId))
+                    .collect::<Result<_, _>>()?;
+                Ok(Command::XDel(a[1].clone(), ids))
+            }
+            "xtrim" => {
+                if !matches!(lossy(&a[2]).to_lowercase().as_str(), "maxlen" | "minid") {
+                    return Err(RESPError::SyntaxError);
+                }
+                match parse_stream_trim(a, 2)? {
+                    (trim, next) if next == a.len() => Ok(Command::XTrim(a[1].clone(), trim)),
+                    _ => Err(RESPError::SyntaxError),
+                }
+            }
+            "xread" | "xreadgroup" => {
+                let in_group = command == "xreadgroup";
+                let mut group = None;
+                let mut count = None;
+                let mut block = None;
+                let mut noack = false;
+                let mut i = 1;
+                loop {
+                    match a.get(i).map(|word| lossy(word).to_lowercase()).as_deref() {
+                        Some("count") if i + 1 < a.len() => {
+                            count = Some(parse_i64(&a[i + 1])?).filter(|n| *n > 0).map(|n| n as usize);
+                            i += 2;
+                        }
+                        Some("block") if i + 1 < a.len() => {
+                            let ms = parse_integer(&a[i + 1]).ok_or(RESPError::TimeoutNotInteger)?;
+                            if ms < 0 {
+                                return Err(RESPError::NegativeTimeout);
+                            }
+                            block = Some((ms > 0).then(|| Duration::from_millis(ms as u64)));
+                            i += 2;
+                        }
+                        Some("group") if i + 2 < a.len() => {
+                            if !in_group {
+                                return Err(RESPError::OnlyInXReadGroup("GROUP".to_string()));
+                            }
+                            group = Some((a[i + 1].clone(), a[i + 2].clone()));
+                            i += 3;
+                        }
+                        Some("noack") => {
+                            if !in_group {
+                                return Err(RESPError::OnlyInXReadGroup("NOACK".to_string()));
+                            }
+                            noack = true;
+                            i += 1;
+                        }
+                        Some("streams") => break,
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                }
+                let streams = &a[i + 1..];
+                if streams.is_empty() || streams.chunks(2).any(|pair| pair.len() != 2) {
+                    let special = if in_group { ">" } else { "$" };
+                    return Err(RESPError::UnbalancedStreams(command, special.to_string()));
+                }
+                let (keys, ids) = streams.split_at(streams.len() / 2);
+                if in_group {
+                    let (group, consumer) = group.ok_or(RESPError::MissingGroupOption)?;
+                    let ids = ids
+                        .iter()
+                        .map(|id| match &id[..] {
+                            b">" => Ok(GroupReadId::New),
+                            b"$" => Err(RESPError::LastIdInXReadGroup),
+                            id => StreamId::parse(id, 0).map(GroupReadId::Pending).ok_or(RESPError::InvalidStreamId),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    return Ok(Command::XReadGroup {
+                        group,
+                        consumer,
+                        count,
+                        block,
+                        noack,
+                        keys: keys.to_vec(),
+                        ids,
+                    });
+                }
+                let ids = ids
+                    .iter()
+                    .map(|id| match &id[..] {
+                        b"$" => Ok(XReadId::Last),
+                        b">" => Err(RESPError::NewIdOutsideXReadGroup),
+                        id => StreamId::parse(id, 0).map(XReadId::After).ok_or(RESPError::InvalidStreamId),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Command::XRead {
+                    count,
+                    block,
+                    keys: keys.to_vec(),
+                    ids,
+                })
+            }
+            "xgroup" => {
+                let subcommand = lossy(&a[1]).to_lowercase();
+                let arity_ok = match subcommand.as_str() {
+                    "create" => (5..=8).contains(&a.len()),
+                    "setid" => a.len() == 5 || a.len() == 7,
+                    "destroy" => a.len() == 4,
+                    "createconsumer" | "delconsumer" => a.len() == 5,
+                    _ => return Err(RESPError::UnknownSubcommand(lossy(&a[1]), "XGROUP".to_string())),
+                };
+                if !arity_ok {
+                    return Err(RESPError::WrongNumberOfArguments(format!("xgroup|{}", subcommand)));
+                }
+                let (key, group) = (a[2].clone(), a[3].clone());
+                match subcommand.as_str() {
+                    "create" | "setid" => {
+                        let id = match &a[4][..] {
+                            b"$" => XReadId::Last,
+                            id => XReadId::After(StreamId::parse(id, 0).ok_or(RESPError::InvalidStreamId)?),
+                        };
+                        let mut mkstream = false;
+                        let mut entries_read = None;
+                        let mut i = 5;
+                        while i < a.len() {
+                            match lossy(&a[i]).to_lowercase().as_str() {
+                                "mkstream" if subcommand == "create" => {
+                                    mkstream = true;
+                                    i += 1;
+                                }
+                                "entriesread" if i + 1 < a.len() => {
+                                    entries_read = match parse_i64(&a[i + 1])? {
+                                        -1 => None,
+                                        n if n < 0 => return Err(RESPError::InvalidEntriesRead),
+                                        n => Some(n as u64),
+                                    };
+                                    i += 2;
+                                }
+                                _ => return Err(RESPError::SyntaxError),
+                            }
+                        }
+                        if subcommand == "setid" {
+                            return Ok(Command::XGroupSetId {
+                                key,
+                                group,
+                                id,
+                                entries_read,
+                            });
+                        }
+                        Ok(Command::XGroupCreate {
+                            key,
+                            group,
+                            id,
+                            mkstream,
+                            entries_read,
+                        })
+                    }
+                    "destroy" => Ok(Command::XGroupDestroy(key, group)),
+                    "createconsumer" => Ok(Command::XGroupCreateConsumer(key, group, a[4].clone())),
+                    _ => Ok(Command::XGroupDelConsumer(key, group, a[4].clone())),
+                }
+            }
+            "xack" => {
+                let ids = a[3..]
+                    .iter()
+                    .map(|id| StreamId::parse(id, 0).ok_or(RESPError::InvalidStreamId))
+                    .collect::<Result<_, _>>()?;
+                Ok(Command::XAck(a[1].clone(), a[2].clone(), ids))
+            }
+            "xpending" => {
+                let range = match &a[3..] {
+                    [] => None,
+                    rest => {
+                        let (idle, rest) = match rest {
+                            [option, idle, rest @ ..] if lossy(option).eq_ignore_ascii_case("idle") => {
+                                (Some(parse_i64(idle)?.max(0) as u64), rest)
+                            }
+                            rest => (None, rest),
+                        };
+                        let (start, end, count, consumer) = match rest {
+                            [start, end, count] => (start, end, count, None),
+                            [start, end, count, consumer] => (start, end, count, Some(consumer.clone())),
+                            _ => return Err(RESPError::SyntaxError),
+                        };
+                        Some(XPendingRange {
+                            idle,
+                            start: parse_range_id(start, true)?,
+                            end: parse_range_id(end, false)?,
+                            count: parse_i64(count)?.max(0) as usize,
+                            consumer,
+                        })
+                    }
+                };
+                Ok(Command::XPending {
+                    key: a[1].clone(),
+                    group: a[2].clone(),
+                    range,
+                })
+            }
+            "xclaim" => {
+                let min_idle = parse_integer(&a[4])
+                    .ok_or_else(|| RESPError::InvalidClaimArgument("min-idle-time".to_string(), "XCLAIM".to_string()))?;
+                let mut ids = Vec::new();
+                let mut i = 5;
+                while let Some(id) = a.get(i).and_then(|id| StreamId::parse(id, 0)) {
+                    ids.push(id);
+                    i += 1;
+                }
+                let mut options = XClaimOptions::default();
+                while i < a.len() {
+                    let option = lossy(&a[i]).to_lowercase();
+                    match (option.as_str(), a.get(i + 1)) {
+                        ("force", _) => options.force = true,
+                        ("justid", _) => options.justid = true,
+                        ("lastid", Some(id)) => {
+                            options.last_id = Some(StreamId::parse(id, 0).ok_or(RESPError::InvalidStreamId)?)
+                        }
+                        (name @ ("idle" | "time" | "retrycount"), Some(value)) => {
+                            let n = parse_integer(value).ok_or_else(|| {
+                                let argument = format!("{} option", name.to_uppercase());
+                                RESPError::InvalidClaimArgument(argument, "XCLAIM".to_string())
+                            })?;
+                            let n = Some(n.max(0) as u64);
+                            match name {
+                                "idle" => options.idle = n,
+                                "time" => options.time = n,
+                                _ => options.retry_count = n,
+                            }
+                        }
+                        _ => return Err(RESPError::UnrecognizedXClaimOption(lossy(&a[i]))),
+                    }
+                    i += if option == "force" || option == "justid" { 1 } else { 2 };
+                }
+                Ok(Command::XClaim {
+                    key: a[1].clone(),
+                    group: a[2].clone(),
+                    consumer: a[3].clone(),
+                    min_idle: min_idle.max(0) as u64,
+                    ids,
+                    options,
+                })
+            }
+            "xautoclaim" => {
+                let min_idle = parse_integer(&a[4]).ok_or_else(|| {
+                    RESPError::InvalidClaimArgument("min-idle-time".to_string(), "XAUTOCLAIM".to_string())
+                })?;
+                let mut count = 100;
+                let mut justid = false;
+                let mut i = 6;
+                while i < a.len() {
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "count" if i + 1 < a.len() => {
+                            count = match parse_i64(&a[i + 1])? {
+                                n if n < 1 => return Err(RESPError::AutoClaimCount),
+                                n => n as usize,
+                            };
+                            i += 2;
+                        }
+                        "justid" => {
+                            justid = true;
+                            i += 1;
+                        }
+                        _ => return Err(RESPError::SyntaxError),
+                    }
                 }
-                s.push(']');
-                s
+                Ok(Command::XAutoClaim {
+                    key: a[1].clone(),
+                    group: a[2].clone(),
+                    consumer: a[3].clone(),
+                    min_idle: min_idle.max(0) as u64,
+                    start: parse_range_id(&a[5], true)?,
+                    count,
+                    justid,
+                })
             }
-            RedisBufSplit::NullArray => "[]".to_string(),
-            RedisBufSplit::NullBulkString => "null".to_string(),
-        }
-    }
-    pub fn to_resp(&self, src: &BytesMut) -> String {
-        match self {
-            RedisBufSplit::String(word) => {
-                format!("${}\r\n{}\r\n", word.len(), word.to_string(src))
+            "xinfo" => match (lossy(&a[1]).to_lowercase().as_str(), a.len()) {
+                ("stream", 3) => Ok(Command::XInfoStream(a[2].clone())),
+                ("stream", n) if n > 3 => Err(RESPError::SyntaxError),
+                ("groups", 3) => Ok(Command::XInfoGroups(a[2].clone())),
+                ("consumers", 4) => Ok(Command::XInfoConsumers(a[2].clone(), a[3].clone())),
+                (subcommand @ ("stream" | "groups" | "consumers"), _) => {
+                    Err(RESPError::WrongNumberOfArguments(format!("xinfo|{}", subcommand)))
+                }
+                _ => Err(RESPError::UnknownSubcommand(lossy(&a[1]), "XINFO".to_string())),
+            },
+            "object" => match lossy(&a[1]).to_lowercase().as_str() {
+                "encoding" if a.len() == 3 => Ok(Command::ObjectEncoding(a[2].clone())),
+                "encoding" => Err(RESPError::WrongNumberOfArguments("object|encoding".to_string())),
+                _ => Err(RESPError::UnknownSubcommand(
+                    lossy(&a[1]),
+                    "OBJECT".to_string(),
+                )),
+            },
+            "subscribe" => Ok(Command::Subscribe(a[1..].to_vec())),
+            "unsubscribe" => Ok(Command::Unsubscribe(a[1..].to_vec())),
+            "psubscribe" => Ok(Command::PSubscribe(a[1..].to_vec())),
+            "punsubscribe" => Ok(Command::PUnsubscribe(a[1..].to_vec())),
+            "publish" => Ok(Command::Publish(a[1].clone(), a[2].clone())),
+            "ssubscribe" => Ok(Command::SSubscribe(a[1..].to_vec())),
+            "sunsubscribe" => Ok(Command::SUnsubscribe(a[1..].to_vec())),
+            "spublish" => Ok(Command::SPublish(a[1].clone(), a[2].clone())),
+            "pubsub" => match (lossy(&a[1]).to_lowercase().as_str(), a.len()) {
+                ("channels", 2 | 3) => Ok(Command::PubSubChannels(a.get(2).cloned())),
+                ("numsub", _) => Ok(Command::PubSubNumSub(a[2..].to_vec())),
+                ("numpat", 2) => Ok(Command::PubSubNumPat),
+                ("shardchannels", 2 | 3) => Ok(Command::PubSubShardChannels(a.get(2).cloned())),
+                ("shardnumsub", _) => Ok(Command::PubSubShardNumSub(a[2..].to_vec())),
+                (subcommand @ ("channels" | "numpat" | "shardchannels"), _) => {
+                    Err(RESPError::WrongNumberOfArguments(format!("pubsub|{}", subcommand)))
+                }
+                _ => Err(RESPError::UnknownSubcommand(lossy(&a[1]), "PUBSUB".to_string())),
+            },
+            "multi" => Ok(Command::Multi),
+            "exec" => Ok(Command::Exec),
+            "discard" => Ok(Command::Discard),
+            "watch" => Ok(Command::Watch(a[1..].to_vec())),
+            "unwatch" => Ok(Command::Unwatch),
+            "eval" | "evalsha" | "eval_ro" | "evalsha_ro" => {
+                let script = if command.starts_with("evalsha") {
+                    EvalScript::Sha(lossy(&a[1]).to_lowercase())
+                } else {
+                    EvalScript::Body(a[1].clone())
+                };
+                let (keys, args) = parse_script_keys(a)?;
+                Ok(Command::Eval {
+                    script,
+                    keys,
+                    args,
+                    read_only: command.ends_with("_ro"),
+                })
             }
-            RedisBufSplit::Error(word) => format!("-{}\r\n", word.to_string(src)),
-            RedisBufSplit::Int(i) => format!(":{}\r\n", i),
-            RedisBufSplit::Array(words) => {
-                let mut s = String::new();
-                s.push('*');
-                s.push_str(&words.len().to_string());
-                s.push_str("\r\n");
-                for (i, word) in words.iter().enumerate() {
-                    if i > 0 {
-                        s.push_str("\r\n");
+            "script" => match (lossy(&a[1]).to_lowercase().as_str(), a.len()) {
+                ("load", 3) => Ok(Command::ScriptLoad(a[2].clone())),
+                ("exists", n) if n > 2 => Ok(Command::ScriptExists(a[2..].to_vec())),
+                ("flush", 2) => Ok(Command::ScriptFlush),
+                ("flush", 3) => match lossy(&a[2]).to_lowercase().as_str() {
+                    "async" | "sync" => Ok(Command::ScriptFlush),
+                    _ => Err(RESPError::FlushOption("SCRIPT".to_string())),
+                },
+                ("kill", 2) => Ok(Command::ScriptKill),
+                (subcommand @ ("load" | "exists" | "flush" | "kill"), _) => {
+                    Err(RESPError::WrongNumberOfArguments(format!("script|{}", subcommand)))
+                }
+                _ => Err(RESPError::UnknownSubcommand(lossy(&a[1]), "SCRIPT".to_string())),
+            },
+            "fcall" | "fcall_ro" => {
+                let (keys, args) = parse_script_keys(a)?;
+                Ok(Command::FCall {
+                    function: a[1].clone(),
+                    keys,
+                    args,
+                    read_only: command == "fcall_ro",
+                })
+            }
+            "function" => match (lossy(&a[1]).to_lowercase().as_str(), a.len()) {
+                ("load", 3) => Ok(Command::FunctionLoad(a[2].clone(), false)),
+                ("load", 4) => match lossy(&a[2]).to_lowercase().as_str() {
+                    "replace" => Ok(Command::FunctionLoad(a[3].clone(), true)),
+                    _ => Err(RESPError::UnknownOption(lossy(&a[2]))),
+                },
+                ("delete", 3) => Ok(Command::FunctionDelete(a[2].clone())),
+                ("list", _) => {
+                    let mut pattern = None;
+                    let mut with_code = false;
+                    let mut i = 2;
+                    while i < a.len() {
+                        match lossy(&a[i]).to_lowercase().as_str() {
+                            "withcode" => with_code = true,
+                            "libraryname" => {
+                                i += 1;
+                                pattern = Some(a.get(i).ok_or(RESPError::MissingLibraryName)?.clone());
+                            }
+                            _ => return Err(RESPError::UnknownArgument(lossy(&a[i]))),
+                        }
+                        i += 1;
                     }
-                    s.push_str(&word.to_resp(src));
+                    Ok(Command::FunctionList { pattern, with_code })
                 }
-                s
+                ("dump", 2) => Ok(Command::FunctionDump),
+                ("restore", 3) => Ok(Command::FunctionRestore(a[2].clone(), RestorePolicy::Append)),
+                ("restore", 4) => {
+                    let policy = match lossy(&a[3]).to_lowercase().as_str() {
+                        "append" => RestorePolicy::Append,
+                        "replace" => RestorePolicy::Replace,
+                        "flush" => RestorePolicy::Flush,
+                        _ => return Err(RESPError::InvalidRestorePolicy),
+                    };
+                    Ok(Command::FunctionRestore(a[2].clone(), policy))
+                }
+                ("flush", 2) => Ok(Command::FunctionFlush),
+                ("flush", 3) => match lossy(&a[2]).to_lowercase().as_str() {
+                    "async" | "sync" => Ok(Command::FunctionFlush),
+                    _ => Err(RESPError::FlushOption("FUNCTION".to_string())),
+                },
+                ("stats", 2) => Ok(Command::FunctionStats),
+                ("kill", 2) => Ok(Command::FunctionKill),
+                (
+                    subcommand @ ("load" | "delete" | "dump" | "restore" | "flush" | "stats" | "kill"),
+                    _,
+                ) => Err(RESPError::WrongNumberOfArguments(format!("function|{}", subcommand))),
+                _ => Err(RESPError::UnknownSubcommand(lossy(&a[1]), "FUNCTION".to_string())),
+            },
+            "auth" => match a.len() {
+                2 => Ok(Command::Auth {
+                    username: None,
+                    password: a[1].clone(),
+                }),
+                3 => Ok(Command::Auth {
+                    username: Some(a[1].clone()),
+                    password: a[2].clone(),
+                }),
+                _ => Err(RESPError::SyntaxError),
+            },
+            "hello" => {
+                let protover = match a.get(1) {
+                    Some(v) => {
+                        let v = parse_i64(v).map_err(|_| RESPError::InvalidProtocolVersion)?;
+                        if v != 2 && v != 3 {
+                            return Err(RESPError::UnsupportedProtocol);
+                        }
+                        Some(v)
+                    }
+                    None => None,
+                };
+                let mut auth = None;
+                let mut setname = None;
+                let mut i = 2;
+                while i < a.len() {
+                    match lossy(&a[i]).to_lowercase().as_str() {
+                        "auth" if i + 2 < a.len() => {
+                            auth = Some((a[i + 1].clone(), a[i + 2].clone()));
+                            i += 3;
+                        }
+                        "setname" if i + 1 < a.len() => {
+                            setname = Some(a[i + 1].clone());
+                            i += 2;
+                        }
+                        _ => return Err(RESPError::SyntaxError),
+                    }
+                }
+                Ok(Command::Hello {
+                    protover,
+                    auth,
+                    setname,
+                })
             }
-            RedisBufSplit::NullArray => "*-1\r\n".to_string(),
-            RedisBufSplit::NullBulkString => "$-1\r\n".to_string(),
+            _ => unreachable!("arity table and parser disagree on '{}'", command),
         }
     }
 }
 
-type RedisResult = Result<Option<(usize, RedisBufSplit)>, RESPError>;
-impl Parser {
-    // fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-    //     unimplemented!()
-    // }
-    pub fn token(src: &BytesMut, index: usize) -> Option<(usize, BufSplit)> {
-        let start = index;
-        let mut end = index;
-        while end < src.len() && src[end] != b'\r' {
-            end += 1;
-        }
-        if end == src.len() {
-            return None;
+/// Text view of an argument, for command names, options and error messages.
+fn lossy(b: &Bytes) -> String {
+    String::from_utf8_lossy(b).to_string()
+}
+
+/// The options following the cursor of SCAN and its per-type variants; only SCAN
+/// itself takes TYPE.
+fn parse_scan_options(a: &[Bytes], allow_type: bool) -> Result<ScanOptions, RESPError> {
+    let mut options = ScanOptions::default();
+    let mut i = 0;
+    while i < a.len() {
+        let value = a.get(i + 1).ok_or(RESPError::SyntaxError)?;
+        match lossy(&a[i]).to_lowercase().as_str() {
+            "match" => options.pattern = Some(value.clone()),
+            "count" => {
+                options.count = match parse_i64(value)? {
+                    n if n < 1 => return Err(RESPError::SyntaxError),
+                    n => n as usize,
+                }
+            }
+            "type" if allow_type => options.type_name = Some(lossy(value).to_lowercase()),
+            _ => return Err(RESPError::SyntaxError),
         }
-        Some((end + 2, BufSplit(start, end)))
+        i += 2;
     }
+    Ok(options)
+}
 
-    fn parse_int(src: &BytesMut, index: usize) -> Result<(usize, i64), RESPError> {
-        if !vec![b'$', b':', b'*'].contains(&src[index]) {
-            return Err(RESPError::UnknownStartingByte(src[index].clone()));
-        }
-        let (index, split) = Parser::token(src, index).unwrap();
+/// The value of an EX, PX, EXAT or PXAT option, which has to be positive
+fn parse_expire_option(option: &str, value: &Bytes, command: &str) -> Result<ExpireTime, RESPError> {
+    let n = parse_i64(value)?;
+    let ms = if option.starts_with('p') {
+        Some(n)
+    } else {
+        n.checked_mul(1000)
+    };
+    let ms = match ms {
+        Some(ms) if n > 0 => ms,
+        _ => return Err(RESPError::InvalidExpireTime(command.to_string())),
+    };
+    Ok(if option.ends_with("at") {
+        ExpireTime::At(ms)
+    } else {
+        ExpireTime::Relative(ms)
+    })
+}
 
-        let num_str = String::from_utf8_lossy(&src[split.0 + 1..split.1]);
-        let res = num_str.parse::<i64>();
-        if res.is_err() {
-            return Err(RESPError::IntParseFailure(num_str.to_string()));
+/// The timeout of a blocking command in seconds, where 0 means forever
+/// The arguments of ZRANGE or ZRANGESTORE from the two bounds on. The older
+/// commands such as ZREVRANGEBYSCORE imply BY and REV through their name, and
+/// only take LIMIT and WITHSCORES. Returns the range and whether WITHSCORES
+/// was given.
+fn parse_zrange(command: &str, a: &[Bytes]) -> Result<(ZRange, bool), RESPError> {
+    let legacy = !matches!(command, "zrange" | "zrangestore");
+    let mut by_score = command.ends_with("byscore");
+    let mut by_lex = command.ends_with("bylex");
+    let mut rev = command.starts_with("zrev");
+    let mut limit = None;
+    let mut with_scores = false;
+    let mut i = 2;
+    while i < a.len() {
+        match lossy(&a[i]).to_lowercase().as_str() {
+            "byscore" if !legacy && !by_lex => by_score = true,
+            "bylex" if !legacy && !by_score => by_lex = true,
+            "rev" if !legacy => rev = true,
+            "limit" if i + 2 < a.len() => {
+                limit = Some((parse_i64(&a[i + 1])?, parse_i64(&a[i + 2])?));
+                i += 2;
+            }
+            "withscores" => with_scores = true,
+            _ => return Err(RESPError::SyntaxError),
         }
-        Ok((split.1 + 2, res.unwrap()))
+        i += 1;
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        return Err(RESPError::LimitWithoutBy);
     }
+    if with_scores && by_lex {
+        return Err(RESPError::WithScoresByLex);
+    }
+    // Reversed score and lex ranges are given maximum first
+    let (min, max) = if rev && (by_score || by_lex) {
+        (&a[1], &a[0])
+    } else {
+        (&a[0], &a[1])
+    };
+    let by = if by_score {
+        ZRangeBy::Score(ScoreBound::parse(min)?, ScoreBound::parse(max)?)
+    } else if by_lex {
+        ZRangeBy::Lex(LexBound::parse(min)?, LexBound::parse(max)?)
+    } else {
+        ZRangeBy::Rank(parse_i64(min)?, parse_i64(max)?)
+    };
+    Ok((ZRange { by, rev, limit }, with_scores))
+}
 
-    pub fn parse_bulk_string(src: &BytesMut, index: usize) -> RedisResult {
-        // Bulk String format:
-        // $<usize>\r\n<data>\r\n
-        assert!(src[index] == b'$');
-        let (index, size) = Parser::parse_int(src, index).map(|x| (x.0, x.1 as usize))?;
-        let start = index;
-        let end = index + size;
-        if end > src.len() {
-            return Err(RESPError::UnexpectedEnd);
+fn parse_timeout(value: &Bytes) -> Result<Option<Duration>, RESPError> {
+    let seconds = parse_float(value).ok_or(RESPError::InvalidTimeout)?;
+    if seconds < 0.0 {
+        return Err(RESPError::NegativeTimeout);
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(seconds)
+        .map(Some)
+        .map_err(|_| RESPError::InvalidTimeout)
+}
+
+/// The MAXLEN or MINID option starting at `a[i]`, with its optional `~` or `=`
+/// and LIMIT. Returns it and the index just past it.
+fn parse_stream_trim(a: &[Bytes], mut i: usize) -> Result<(StreamTrim, usize), RESPError> {
+    let max_len = lossy(&a[i]).eq_ignore_ascii_case("maxlen");
+    i += 1;
+    let approximate = match a.get(i).map(|word| &word[..]) {
+        Some(b"~") => true,
+        Some(b"=") => false,
+        _ => {
+            i -= 1;
+            false
         }
-        Ok(Some((end + 2, RedisBufSplit::String(BufSplit(start, end)))))
+    };
+    i += 1;
+    let threshold = a.get(i).ok_or(RESPError::SyntaxError)?;
+    let strategy = if max_len {
+        match parse_i64(threshold)? {
+            n if n < 0 => return Err(RESPError::StreamMaxLenNegative),
+            n => TrimStrategy::MaxLen(n as usize),
+        }
+    } else {
+        TrimStrategy::MinId(StreamId::parse(threshold, 0).ok_or(RESPError::InvalidStreamId)?)
+    };
+    i += 1;
+    let mut limit = None;
+    if a.get(i).is_some_and(|word| lossy(word).eq_ignore_ascii_case("limit")) {
+        let value = a.get(i + 1).ok_or(RESPError::SyntaxError)?;
+        limit = match parse_i64(value)? {
+            n if n < 0 => return Err(RESPError::StreamLimitNegative),
+            _ if !approximate => return Err(RESPError::LimitWithoutApproximate),
+            n => Some(n as usize),
+        };
+        i += 2;
     }
+    Ok((
+        StreamTrim {
+            strategy,
+            approximate,
+            limit,
+        },
+        i,
+    ))
+}
 
-    pub fn parse_array(src: &BytesMut, index: usize) -> RedisResult {
-        // Array format:
-        // *<usize>\r\n<element_1>\r\n<element_2>\r\n...
-        assert!(src[index] == b'*');
-        let (index, size) = Parser::parse_int(src, index).map(|x| (x.0, x.1 as usize))?;
-        let mut tokens = vec![];
-        let mut pos = index;
-        for _ in 0..size {
-            match src[pos] {
-                b'$' => {
-                    let (new_pos, word) = Parser::parse_bulk_string(src, pos)
-                        .expect("failed to parse bulk string")
-                        .unwrap();
-                    tokens.push(word);
-                    pos = new_pos
-                }
-                _ => {
-                    unimplemented!("No implementations for parsing any other array elements except bulk strings");
-                }
+/// An XRANGE bound: `-`, `+`, an ID, or an ID after `(` to leave it out. A bare
+/// millisecond time covers the whole millisecond.
+fn parse_range_id(word: &Bytes, start: bool) -> Result<StreamId, RESPError> {
+    let missing_seq = if start { 0 } else { u64::MAX };
+    match &word[..] {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => {
+            let id = StreamId::parse(id, missing_seq).ok_or(RESPError::InvalidStreamId)?;
+            if start {
+                id.next().ok_or(RESPError::InvalidIntervalStart)
+            } else {
+                id.prev().ok_or(RESPError::InvalidIntervalEnd)
             }
         }
-        Ok(Some((pos, RedisBufSplit::Array(tokens))))
+        id => StreamId::parse(id, missing_seq).ok_or(RESPError::InvalidStreamId),
     }
+}
 
-    pub fn simple_string(buf: &BytesMut, pos: usize) -> RedisResult {
-        // Skip the first byte "+"
-        match Parser::token(buf, pos + 1) {
-            Some((pos, word)) => Ok(Some((pos, RedisBufSplit::String(word)))),
-            None => Ok(None),
+/// The keys and the arguments after the numkeys of EVAL and FCALL
+fn parse_script_keys(a: &[Bytes]) -> Result<(Vec<Bytes>, Vec<Bytes>), RESPError> {
+    let numkeys = parse_i64(&a[2])?;
+    if numkeys < 0 {
+        return Err(RESPError::NegativeKeys);
+    }
+    if numkeys as usize > a.len() - 3 {
+        return Err(RESPError::TooManyKeys);
+    }
+    let keys_end = 3 + numkeys as usize;
+    Ok((a[3..keys_end].to_vec(), a[keys_end..].to_vec()))
+}
+
+fn parse_i64(b: &Bytes) -> Result<i64, RESPError> {
+    parse_integer(b).ok_or(RESPError::NotAnInteger)
+}
+
+fn parse_f64(b: &Bytes) -> Result<f64, RESPError> {
+    parse_float(b).ok_or(RESPError::NotAFloat)
+}
+
+/// Parses a decimal integer the way Redis does: an optional minus sign and digits,
+/// without leading zeros, a plus sign or surrounding spaces.
+pub fn parse_integer(b: &[u8]) -> Option<i64> {
+    let digits = b.strip_prefix(b"-").unwrap_or(b);
+    let canonical = match digits {
+        [] => false,
+        [b'0'] => digits.len() == b.len(),
+        [first, ..] => *first != b'0' && digits.iter().all(u8::is_ascii_digit),
+    };
+    if !canonical {
+        return None;
+    }
+    std::str::from_utf8(b).ok()?.parse().ok()
+}
+
+/// Parses a float argument or stored value; NaN and surrounding spaces are rejected
+pub fn parse_float(b: &[u8]) -> Option<f64> {
+    let s = std::str::from_utf8(b).ok()?;
+    if s.is_empty() || s.trim() != s {
+        return None;
+    }
+    s.parse::<f64>().ok().filter(|f| !f.is_nan())
+}
+
+impl Command {
+    /// Redis-style arity, counting the command name: a positive value is the exact
+    /// number of words, a negative value is the minimum. `None` for unknown commands.
+    pub fn arity(name: &str) -> Option<i64> {
+        match name {
+            "ping" => Some(-1),
+            "echo" => Some(2),
+            "set" => Some(-3),
+            "get" => Some(2),
+            "docs" => Some(1),
+            "info" => Some(-1),
+            "replconf" => Some(-1),
+            "psync" => Some(3),
+            "auth" => Some(-2),
+            "hello" => Some(-1),
+            "subscribe" | "psubscribe" => Some(-2),
+            "unsubscribe" | "punsubscribe" => Some(-1),
+            "publish" | "spublish" => Some(3),
+            "ssubscribe" => Some(-2),
+            "sunsubscribe" => Some(-1),
+            "pubsub" => Some(-2),
+            "multi" | "exec" | "discard" | "unwatch" => Some(1),
+            "watch" => Some(-2),
+            "eval" | "evalsha" | "eval_ro" | "evalsha_ro" => Some(-3),
+            "script" => Some(-2),
+            "fcall" | "fcall_ro" => Some(-3),
+            "function" => Some(-2),
+            "keys" => Some(2),
+            "config" => Some(-2),
+            "save" => Some(1),
+            "bgsave" => Some(-1),
+            "lastsave" => Some(1),
+            "bgrewriteaof" => Some(1),
+            "expire" | "pexpire" | "expireat" | "pexpireat" => Some(-3),
+            "ttl" | "pttl" | "expiretime" | "pexpiretime" => Some(2),
+            "persist" => Some(2),
+            "del" | "unlink" | "exists" => Some(-2),
+            "type" => Some(2),
+            "rename" | "renamenx" => Some(3),
+            "copy" => Some(-3),
+            "scan" => Some(-2),
+            "randomkey" | "dbsize" => Some(1),
+            "flushall" | "flushdb" => Some(-1),
+            "incr" | "decr" | "strlen" | "getdel" => Some(2),
+            "incrby" | "decrby" | "incrbyfloat" | "append" | "getset" | "setnx" => Some(3),
+            "getrange" | "substr" | "setrange" | "setex" | "psetex" => Some(4),
+            "getex" | "mget" => Some(-2),
+            "mset" | "msetnx" => Some(-3),
+            "lpush" | "rpush" | "lpushx" | "rpushx" | "lpos" | "blpop" | "brpop" => Some(-3),
+            "lpop" | "rpop" => Some(-2),
+            "llen" => Some(2),
+            "lindex" | "rpoplpush" => Some(3),
+            "lrange" | "lset" | "lrem" | "ltrim" | "brpoplpush" => Some(4),
+            "linsert" | "lmove" => Some(5),
+            "blmove" => Some(6),
+            "hset" | "hmset" => Some(-4),
+            "hmget" | "hdel" | "hscan" => Some(-3),
+            "hsetnx" | "hincrby" | "hincrbyfloat" => Some(4),
+            "hget" | "hexists" | "hstrlen" => Some(3),
+            "hlen" | "hkeys" | "hvals" | "hgetall" => Some(2),
+            "hrandfield" => Some(-2),
+            "sadd" | "srem" | "smismember" | "sscan" | "sintercard" => Some(-3),
+            "sinterstore" | "sunionstore" | "sdiffstore" => Some(-3),
+            "sinter" | "sunion" | "sdiff" | "spop" | "srandmember" | "object" => Some(-2),
+            "zadd" | "zrange" | "zrevrange" | "zunionstore" | "zinterstore" => Some(-4),
+            "zrangebyscore" | "zrevrangebyscore" | "zrangebylex" | "zrevrangebylex" => Some(-4),
+            "zrem" | "zmscore" | "zrank" | "zrevrank" | "zscan" | "bzpopmin" | "bzpopmax" => Some(-3),
+            "zrangestore" => Some(-5),
+            "zcard" => Some(2),
+            "zscore" => Some(3),
+            "zincrby" | "zcount" | "zlexcount" => Some(4),
+            "zpopmin" | "zpopmax" => Some(-2),
+            "sismember" => Some(3),
+            "smembers" | "scard" => Some(2),
+            "smove" => Some(4),
+            "xadd" => Some(-5),
+            "xrange" | "xrevrange" | "xtrim" | "xread" => Some(-4),
+            "xdel" => Some(-3),
+            "xlen" => Some(2),
+            "xgroup" | "xinfo" => Some(-2),
+            "xreadgroup" => Some(-7),
+            "xack" => Some(-4),
+            "xpending" => Some(-3),
+            "xclaim" | "xautoclaim" => Some(-6),
+            _ => None,
         }
     }
 
-    pub fn find_start_resp_data_type(
-        buf: &BytesMut,
-        index: usize,
-        query_type: &RESPDataType,
-    ) -> Option<usize> {
-        let mut pos = index;
-        loop {
-            if pos >= buf.len() {
-                return None;
-            }
-            match (buf[pos], query_type) {
-                (b'*', RESPDataType::Array) => {
-                    let res = Parser::parse_int(buf, pos);
-                    if res.is_err() {
-                        pos += 1;
-                        continue;
-                    }
-                    return Some(pos);
-                }
-                _ => {
-                    pos += 1;
-                    continue;
+    /// Whether the command modifies the dataset, and so is logged to the AOF and
+    /// propagated to replicas
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(..)
+                | Command::Expire(..)
+                | Command::Persist(_)
+                | Command::Del(_)
+                | Command::Rename { .. }
+                | Command::Copy { .. }
+                | Command::FlushAll
+                | Command::IncrBy(..)
+                | Command::IncrByFloat(..)
+                | Command::Append(..)
+                | Command::SetRange(..)
+                | Command::GetDel(_)
+                | Command::GetEx(_, Some(_))
+                | Command::MSet { .. }
+                | Command::Push { .. }
+                | Command::Pop { .. }
+                | Command::LSet(..)
+                | Command::LInsert { .. }
+                | Command::LRem(..)
+                | Command::LTrim(..)
+                | Command::LMove { .. }
+                | Command::HSet { .. }
+                | Command::HDel(..)
+                | Command::HIncrBy(..)
+                | Command::HIncrByFloat(..)
+                | Command::SAdd(..)
+                | Command::SRem(..)
+                | Command::SPop { .. }
+                | Command::SMove { .. }
+                | Command::SetOp {
+                    destination: Some(_),
+                    ..
                 }
+                | Command::ZAdd { .. }
+                | Command::ZRem(..)
+                | Command::ZIncrBy(..)
+                | Command::ZRangeStore { .. }
+                | Command::ZPop { .. }
+                | Command::ZSetOp { .. }
+                | Command::XAdd { .. }
+                | Command::XDel(..)
+                | Command::XTrim(..)
+                | Command::XGroupCreate { .. }
+                | Command::XGroupSetId { .. }
+                | Command::XGroupDestroy(..)
+                | Command::XGroupCreateConsumer(..)
+                | Command::XGroupDelConsumer(..)
+                | Command::XReadGroup { .. }
+                | Command::XAck(..)
+                | Command::XClaim { .. }
+                | Command::XAutoClaim { .. }
+                | Command::FunctionLoad(..)
+                | Command::FunctionDelete(_)
+                | Command::FunctionRestore(..)
+                | Command::FunctionFlush
+        )
+    }
+
+    /// The keys a write command may change in place, which WATCH has to notice.
+    /// Keys being added, removed or expiring are noticed by the dataset itself.
+    pub fn written_keys(&self) -> Vec<&Bytes> {
+        match self {
+            Command::Set(key, ..)
+            | Command::Expire(key, ..)
+            | Command::Persist(key)
+            | Command::IncrBy(key, _)
+            | Command::IncrByFloat(key, _)
+            | Command::Append(key, _)
+            | Command::SetRange(key, ..)
+            | Command::GetDel(key)
+            | Command::GetEx(key, _)
+            | Command::LSet(key, ..)
+            | Command::LRem(key, ..)
+            | Command::LTrim(key, ..)
+            | Command::HDel(key, _)
+            | Command::HIncrBy(key, ..)
+            | Command::HIncrByFloat(key, ..)
+            | Command::SAdd(key, _)
+            | Command::SRem(key, _)
+            | Command::ZRem(key, _)
+            | Command::ZIncrBy(key, ..)
+            | Command::XDel(key, _)
+            | Command::XTrim(key, _)
+            | Command::XGroupDestroy(key, _)
+            | Command::XGroupCreateConsumer(key, ..)
+            | Command::XGroupDelConsumer(key, ..)
+            | Command::XAck(key, ..) => vec![key],
+            Command::Push { key, .. }
+            | Command::Pop { key, .. }
+            | Command::LInsert { key, .. }
+            | Command::HSet { key, .. }
+            | Command::SPop { key, .. }
+            | Command::ZAdd { key, .. }
+            | Command::ZPop { key, .. }
+            | Command::XAdd { key, .. }
+            | Command::XGroupCreate { key, .. }
+            | Command::XGroupSetId { key, .. }
+            | Command::XClaim { key, .. }
+            | Command::XAutoClaim { key, .. } => vec![key],
+            Command::Del(keys) | Command::XReadGroup { keys, .. } => keys.iter().collect(),
+            Command::Rename { from, to, .. } => vec![from, to],
+            Command::Copy { destination, .. } => vec![destination],
+            Command::LMove {
+                source, destination, ..
             }
+            | Command::SMove {
+                source, destination, ..
+            } => vec![source, destination],
+            Command::SetOp {
+                destination: Some(destination),
+                ..
+            }
+            | Command::ZRangeStore { destination, .. }
+            | Command::ZSetOp { destination, .. } => vec![destination],
+            Command::MSet { pairs, .. } => pairs.iter().map(|(key, _)| key).collect(),
+            _ => Vec::new(),
         }
     }
 
-    pub fn parse_commands(logger: &Logger, bm: &BytesMut) -> Result<Vec<ParsedCommand>, RESPError> {
-        let mut pos = 0;
-        let mut commands = Vec::new();
+    /// Whether a RESP2 client may run the command while subscribed to channels
+    pub fn allowed_while_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::SSubscribe(_)
+                | Command::SUnsubscribe(_)
+                | Command::Ping(_)
+        )
+    }
 
-        while pos < bm.len() {
-            match bm[pos] {
-                b'*' => {
-                    let start_pos = pos;
-                    let (i, res) = Parser::parse_array(bm, pos)?.unwrap();
-                    pos = i;
-                    let a = cast!(res, RedisBufSplit::Array);
-                    let command = a[0].to_string(bm).to_lowercase();
-                    let bytes_read = pos - start_pos;
-
-                    match command.as_str() {
-                        "echo" => {
-                            let echo_str = a[1].to_string(bm);
-                            commands.push(ParsedCommand {
-                                command: Command::Echo(echo_str),
-                                bytes_read,
-                            });
-                        }
-                        "ping" => {
-                            commands.push(ParsedCommand {
-                                command: Command::Ping,
-                                bytes_read,
-                            });
-                        }
-                        "set" => {
-                            let key = a[1].to_string(bm);
-                            let value = a[2].to_string(bm);
-                            let expiry = if a.len() == 5 {
-                                let expiry_str = a[4].to_string(bm);
-                                let expiry_num =
-                                    expiry_str.parse::<u64>().map_err(|e| {
-                                        RESPError::IntParseFailure(expiry_str)
-                                    })?;
-                                let duration = match a[3].to_string(bm).to_lowercase().as_str() {
-                                    "px" => Ok(Duration::from_millis(expiry_num)),
-                                    "ex" => Ok(Duration::from_secs(expiry_num)),
-                                    _ => Err(RESPError::InvalidArgument(
-                                        a[3].to_string(bm),
-                                    )),
-                                }?;
-                                Some(duration)
-                            } else {
-                                None
-                            };
-                            commands.push(ParsedCommand {
-                                command: Command::Set(key, value, expiry),
-                                bytes_read,
-                            });
-                        }
-                        "get" => {
-                            let key = a[1].to_string(bm);
-                            commands.push(ParsedCommand {
-                                command: Command::Get(key),
-                                bytes_read,
-                            });
-                        }
-                        "docs" => {
-                            commands.push(ParsedCommand {
-                                command: Command::Docs,
-                                bytes_read,
-                            });
-                        }
-                        "info" => {
-                            let section = if a.len() == 2 {
-                                a[1].to_string(bm)
-                            } else {
-                                "default".to_string()
-                            };
-                            commands.push(ParsedCommand {
-                                command: Command::Info(section),
-                                bytes_read,
-                            });
-                        }
-                        "replconf" => {
-                            let args = a.iter().skip(1).map(|b| b.to_string(bm).to_lowercase()).collect();
-                            commands.push(ParsedCommand {
-                                command: Command::ReplConf(args),
-                                bytes_read,
-                            });
-                        }
-                        "psync" => {
-                            commands.push(ParsedCommand {
-                                command: Command::Psync,
-                                bytes_read,
-                            });
-                        }
-                        _ => {
-                            unimplemented!("Command not implemented: {}", command);
-                        }
-                    }
-                }
-                b'$' => {
-                    let start_pos = pos;
-                    let r = Parser::parse_bulk_string(bm, pos)?;
-                    if let Some(r) = r {
-                        pos = r.0;
-                        let bytes_read = pos - start_pos;
-                        logger.log(&format!(
-                            "Bulk string: '{}' not doing anything with it",
-                            r.1.to_string(&bm)
-                        ));
-                        continue;
-                        
-                    }
-                }
-                c => {
-                    unimplemented!("Unknown byte sequence: '{}'", String::from_utf8_lossy([c].as_slice()));
+    /// For commands with a deadline relative to now, the same command with the
+    /// deadline made absolute, and the words to send to replicas and the AOF so
+    /// that replaying it later doesn't push the deadline back.
+    pub fn with_absolute_deadline(&self, now_ms: u64) -> Option<(Command, Vec<Bytes>)> {
+        match self {
+            Command::Set(key, value, options) => {
+                let at = match options.expiry {
+                    Some(SetExpiry::Expire(time @ ExpireTime::Relative(_))) => time.resolve(now_ms),
+                    _ => return None,
+                };
+                let mut args = vec![Bytes::from("SET"), key.clone(), value.clone()];
+                match options.condition {
+                    Some(SetCondition::Nx) => args.push(Bytes::from("NX")),
+                    Some(SetCondition::Xx) => args.push(Bytes::from("XX")),
+                    None => {}
                 }
+                args.push(Bytes::from("PXAT"));
+                args.push(Bytes::from(at.to_string()));
+                let options = SetOptions {
+                    expiry: Some(SetExpiry::Expire(ExpireTime::At(at))),
+                    ..options.clone()
+                };
+                Some((Command::Set(key.clone(), value.clone(), options), args))
+            }
+            Command::GetEx(key, Some(GetExOption::Expire(time @ ExpireTime::Relative(_)))) => {
+                let at = time.resolve(now_ms);
+                let args = vec![
+                    Bytes::from("GETEX"),
+                    key.clone(),
+                    Bytes::from("PXAT"),
+                    Bytes::from(at.to_string()),
+                ];
+                let option = GetExOption::Expire(ExpireTime::At(at));
+                Some((Command::GetEx(key.clone(), Some(option)), args))
             }
+            Command::Expire(key, time @ ExpireTime::Relative(_), condition) => {
+                let at = time.resolve(now_ms);
+                let mut args = vec![Bytes::from("PEXPIREAT"), key.clone(), Bytes::from(at.to_string())];
+                args.extend(condition.to_args());
+                Some((Command::Expire(key.clone(), ExpireTime::At(at), *condition), args))
+            }
+            _ => None,
         }
-        Ok(commands)
     }
 }
 
@@ -350,16 +3135,17 @@ mod tests {
 
     #[test]
     fn test_word() {
-        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nSET\r\n$3\r\nfoo\r\n"[..]);
-        let (pos, word) = Parser::token(&mut buf, 0).unwrap();
+        let buf = BytesMut::from(&b"*2\r\n$3\r\nSET\r\n$3\r\nfoo\r\n"[..]);
+        let (pos, word) = Parser::token(&buf, 0).unwrap();
         assert_eq!(word, BufSplit(0, 2));
         assert_eq!(pos, 4);
     }
 
     #[test]
     fn test_int() {
-        let mut buf = BytesMut::from(&b"*2\r\n$10\r\nfoobarabcd\r\n"[..]);
-        let (pos, u) = Parser::parse_int(&mut buf, 4)
+        let buf = BytesMut::from(&b"*2\r\n$10\r\nfoobarabcd\r\n"[..]);
+        let (pos, u) = Parser::parse_int(&buf, 4)
+            .unwrap()
             .map(|x| (x.0, x.1 as usize))
             .unwrap();
         assert_eq!(u, 10);
@@ -389,8 +3175,8 @@ mod tests {
 
     #[test]
     fn test_bulk_string() {
-        let mut buf = BytesMut::from(&b"$3\r\nSET\r\n$10\r\nfoobarabcd\r\n"[..]);
-        let (pos, split) = Parser::parse_bulk_string(&mut buf, 0).unwrap().unwrap();
+        let buf = BytesMut::from(&b"$3\r\nSET\r\n$10\r\nfoobarabcd\r\n"[..]);
+        let (pos, split) = Parser::parse_bulk_string(&buf, 0).unwrap().unwrap();
         match split {
             RedisBufSplit::String(word) => {
                 assert_eq!(word.to_string(&buf), "SET");
@@ -398,7 +3184,7 @@ mod tests {
             _ => panic!("expected string"),
         }
         // Read the next bulk string
-        let (pos, split) = Parser::parse_bulk_string(&mut buf, pos).unwrap().unwrap();
+        let (_pos, split) = Parser::parse_bulk_string(&buf, pos).unwrap().unwrap();
         match split {
             RedisBufSplit::String(word) => {
                 assert_eq!(word.to_string(&buf), "foobarabcd");
@@ -409,8 +3195,8 @@ mod tests {
 
     #[test]
     fn test_array() {
-        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nSET\r\n$3\r\nfoo\r\n"[..]);
-        let (pos, split) = Parser::parse_array(&mut buf, 0).unwrap().unwrap();
+        let buf = BytesMut::from(&b"*2\r\n$3\r\nSET\r\n$3\r\nfoo\r\n"[..]);
+        let (_pos, split) = Parser::parse_array(&buf, 0).unwrap().unwrap();
         match split {
             RedisBufSplit::Array(words) => {
                 assert_eq!(words.len(), 2);
@@ -423,8 +3209,8 @@ mod tests {
 
     #[test]
     fn test_simple_string() {
-        let mut buf = BytesMut::from(&b"+OK\r\n"[..]);
-        let (pos, split) = Parser::simple_string(&mut buf, 0).unwrap().unwrap();
+        let buf = BytesMut::from(&b"+OK\r\n"[..]);
+        let (_pos, split) = Parser::simple_string(&buf, 0).unwrap().unwrap();
         match split {
             RedisBufSplit::String(word) => {
                 assert_eq!(word.to_string(&buf), "OK");
@@ -436,17 +3222,17 @@ mod tests {
     #[test]
     fn test_token() {
         let pysnc_resp = b"+FULLRESYNC 75cd7bc10c49047e0d163660f3b90625b1af31dc 0\r\n$88\r\nREDIS0011\xEF\xBF\xBD       redis-ver7.2.0\xEF\xBF\xBD\r\nredis-bits\xEF\xBF\xBD@\xEF\xBF\xBDctime\xEF\xBF\xBD\xEF\xBF\xBDused-mem\xC2\xB0\xEF\xBF\xBDaof-base\xEF\xBF\xBD\xEF\xBF\xBD\xEF\xBF\xBDn;\xEF\xBF\xBD\xEF\xBF\xBDZ\xEF\xBF\xBD\r\n*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\n123\r\n*3\r\n$3\r\nSET\r\n$3\r\nbar\r\n$3\r\n456\r\n*3\r\n$3\r\nSET\r\n$3\r\nbaz\r\n$3\r\n789\r\n";
-        let mut buf = BytesMut::from(&pysnc_resp[..]);
+        let buf = BytesMut::from(&pysnc_resp[..]);
         let mut i = 0;
         loop {
-            let (pos, word) = Parser::token(&mut buf, i).unwrap();
+            let (pos, word) = Parser::token(&buf, i).unwrap();
             let word = word.to_string(&buf);
             if word.starts_with("*") {
                 break;
             }
             i = pos;
         }
-        let (i, split) = Parser::parse_array(&mut buf, i).unwrap().unwrap();
+        let (i, split) = Parser::parse_array(&buf, i).unwrap().unwrap();
         match split {
             RedisBufSplit::Array(words) => {
                 assert_eq!(words.len(), 3);
@@ -456,7 +3242,7 @@ mod tests {
             }
             _ => panic!("expected array"),
         }
-        let (i, split) = Parser::parse_array(&mut buf, i).unwrap().unwrap();
+        let (i, split) = Parser::parse_array(&buf, i).unwrap().unwrap();
         match split {
             RedisBufSplit::Array(words) => {
                 assert_eq!(words.len(), 3);
@@ -466,7 +3252,7 @@ mod tests {
             }
             _ => panic!("expected array"),
         }
-        let (i, split) = Parser::parse_array(&mut buf, i).unwrap().unwrap();
+        let (_i, split) = Parser::parse_array(&buf, i).unwrap().unwrap();
         match split {
             RedisBufSplit::Array(words) => {
                 assert_eq!(words.len(), 3);
@@ -480,9 +3266,9 @@ mod tests {
 
     #[test]
     fn test_find_start_resp_type() {
-        let mut buf = BytesMut::from(&"+FULLRESYNC 75cd7bc10c49047e0d163660f3b90625b1af31dc 0\r\n$88\r\nREDIS0011\u{fffd}\tredis-ver\u{5}7.2.0\u{fffd}\nredis-bits\u{fffd}@\u{fffd}\u{5}ctime\u{fffd}m\u{8}\u{fffd}e\u{fffd}\u{8}used-mem\u{b0}\u{fffd}\u{10}\u{fffd}\u{8}aof-base\u{fffd}\u{fffd}\u{fffd}n;\u{fffd}\u{fffd}\u{fffd}Z\u{fffd}*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n*2\r\n$3\r\nSET\r\n$3\r\nfoo\r\n"[..]);
-        let pos = Parser::find_start_resp_data_type(&mut buf, 0, &RESPDataType::Array).unwrap();
-        let (pos, split) = Parser::parse_array(&mut buf, pos).unwrap().unwrap();
+        let buf = BytesMut::from("+FULLRESYNC 75cd7bc10c49047e0d163660f3b90625b1af31dc 0\r\n$88\r\nREDIS0011\u{fffd}\tredis-ver\u{5}7.2.0\u{fffd}\nredis-bits\u{fffd}@\u{fffd}\u{5}ctime\u{fffd}m\u{8}\u{fffd}e\u{fffd}\u{8}used-mem\u{b0}\u{fffd}\u{10}\u{fffd}\u{8}aof-base\u{fffd}\u{fffd}\u{fffd}n;\u{fffd}\u{fffd}\u{fffd}Z\u{fffd}*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n*2\r\n$3\r\nSET\r\n$3\r\nfoo\r\n");
+        let pos = Parser::find_start_resp_data_type(&buf, 0, &RESPDataType::Array).unwrap();
+        let (_pos, split) = Parser::parse_array(&buf, pos).unwrap().unwrap();
         match split {
             RedisBufSplit::Array(words) => {
                 assert_eq!(words.len(), 3);
@@ -497,39 +3283,923 @@ mod tests {
     #[test]
     fn test_parse_commands(){
         let log = Logger::new();
-        let mut buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n"[..]);
-        let r = Parser::parse_commands(&log, &mut buf).unwrap();
+        let buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
         assert_eq!(r.len(), 1);
-        assert_eq!(r[0].command, Command::Set("foo".to_string(), "bar".to_string(), None));
+        assert_eq!(r[0].command, Command::Set(Bytes::from("foo"), Bytes::from("bar"), SetOptions::default()));
         assert_eq!(r[0].bytes_read, 31);
         // With expiry
-        let mut buf = BytesMut::from(&b"*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$2\r\nPX\r\n$2\r\n10\r\n"[..]);
-        let r = Parser::parse_commands(&log, &mut buf).unwrap();
+        let buf = BytesMut::from(&b"*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$2\r\nPX\r\n$2\r\n10\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
         assert_eq!(r.len(), 1);
-        assert_eq!(r[0].command, Command::Set("foo".to_string(), "bar".to_string(), Some(Duration::from_millis(10))));
+        assert_eq!(r[0].command, Command::Set(Bytes::from("foo"), Bytes::from("bar"), SetOptions { expiry: Some(SetExpiry::Expire(ExpireTime::Relative(10))), ..Default::default() }));
         assert_eq!(r[0].bytes_read, 47);
 
         // replconf getack *
-        let mut buf = BytesMut::from(&b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n"[..]);
-        let r = Parser::parse_commands(&log, &mut buf).unwrap();
+        let buf = BytesMut::from(&b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
         assert_eq!(r.len(), 1);
         assert_eq!(r[0].command, Command::ReplConf(vec!["getack".to_string(), "*".to_string()]));
         assert_eq!(r[0].bytes_read, 37);
 
         // ping command
-        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n"[..]);
-        let r = Parser::parse_commands(&log, &mut buf).unwrap();
+        let buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
         assert_eq!(r.len(), 1);
-        assert_eq!(r[0].command, Command::Ping);
+        assert_eq!(r[0].command, Command::Ping(None));
         assert_eq!(r[0].bytes_read, 14);
 
         // multiple set commands
-        let mut buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n"[..]);
-        let r = Parser::parse_commands(&log, &mut buf).unwrap();
+        let buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
         assert_eq!(r.len(), 2);
-        assert_eq!(r[0].command, Command::Set("foo".to_string(), "bar".to_string(), None));
+        assert_eq!(r[0].command, Command::Set(Bytes::from("foo"), Bytes::from("bar"), SetOptions::default()));
         assert_eq!(r[0].bytes_read, 31);
-        assert_eq!(r[1].command, Command::Set("foo".to_string(), "bar".to_string(), None));
+        assert_eq!(r[1].command, Command::Set(Bytes::from("foo"), Bytes::from("bar"), SetOptions::default()));
         assert_eq!(r[1].bytes_read, 31);
     }
+
+    #[test]
+    fn test_partial_frames() {
+        let log = Logger::new();
+        let full = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        // Every strict prefix is incomplete rather than an error
+        for end in 0..full.len() {
+            let buf = BytesMut::from(&full[..end]);
+            assert!(Parser::parse(&buf, 0).unwrap().is_none(), "prefix {}", end);
+            assert!(Parser::parse_commands(&log, &buf).unwrap().is_empty());
+        }
+        // A complete frame followed by a partial one only yields the complete frame
+        let mut pipelined = full.to_vec();
+        pipelined.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$3\r\nf");
+        let buf = BytesMut::from(&pipelined[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].bytes_read, full.len());
+    }
+
+    #[test]
+    fn test_nested_frames() {
+        let log = Logger::new();
+        let mut nested = b"*1\r\n".repeat(200_000);
+        nested.extend_from_slice(b":1\r\n");
+        let buf = BytesMut::from(&nested[..]);
+        // Commands hold bulk strings only
+        assert_eq!(Parser::parse_commands(&log, &buf).err(), Some(RESPError::ExpectedBulkString(b'*')));
+        let buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n:1\r\n"[..]);
+        assert_eq!(Parser::parse_commands(&log, &buf).err(), Some(RESPError::ExpectedBulkString(b':')));
+        let buf = BytesMut::from(&b"*1\r\n$-1\r\n"[..]);
+        assert_eq!(Parser::parse_commands(&log, &buf).err(), Some(RESPError::BadBulkStringSize(-1)));
+        let buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$-1\r\n"[..]);
+        assert_eq!(Parser::parse_commands(&log, &buf).err(), Some(RESPError::BadBulkStringSize(-1)));
+        // Other values only nest so deep
+        let mut maps = b"%1\r\n+k\r\n".repeat(200_000);
+        maps.extend_from_slice(b":1\r\n");
+        assert_eq!(Parser::parse(&BytesMut::from(&maps[..]), 0), Err(RESPError::NestingTooDeep));
+        assert_eq!(Parser::parse(&buf, 0).unwrap().unwrap().0, buf.len());
+        let mut shallow = b"*1\r\n".repeat(MAX_NESTING);
+        shallow.extend_from_slice(b":1\r\n");
+        assert!(Parser::parse(&BytesMut::from(&shallow[..]), 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_large_bulk_string() {
+        let log = Logger::new();
+        let value = "x".repeat(64 * 1024);
+        let frame = format!(
+            "*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n{}\r\n",
+            value.len(),
+            value
+        );
+        let buf = BytesMut::from(frame.as_bytes());
+        let r = Parser::parse_commands(&log, &buf).unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].command, Command::Set(Bytes::from("big"), Bytes::from(value), SetOptions::default()));
+        assert_eq!(r[0].bytes_read, frame.len());
+    }
+
+    #[test]
+    fn test_null_and_invalid_sizes() {
+        let buf = BytesMut::from(&b"$-1\r\n*-1\r\n"[..]);
+        let (pos, split) = Parser::parse(&buf, 0).unwrap().unwrap();
+        assert_eq!(split, RedisBufSplit::NullBulkString);
+        let (_, split) = Parser::parse(&buf, pos).unwrap().unwrap();
+        assert_eq!(split, RedisBufSplit::NullArray);
+
+        let buf = BytesMut::from(&b"$-2\r\n"[..]);
+        assert!(matches!(Parser::parse(&buf, 0), Err(RESPError::BadBulkStringSize(-2))));
+        let buf = BytesMut::from(&b"$3\r\nfoobar\r\n"[..]);
+        assert!(matches!(Parser::parse(&buf, 0), Err(RESPError::BadBulkStringSize(3))));
+    }
+
+    #[test]
+    fn test_rdb_payload() {
+        let buf = BytesMut::from(&b"$5\r\nREDIS*1\r\n$4\r\nPING\r\n"[..]);
+        let (pos, rdb) = Parser::parse_rdb_payload(&buf, 0).unwrap().unwrap();
+        assert_eq!(rdb.to_string(&buf), "REDIS");
+        assert_eq!(buf[pos], b'*');
+        let buf = BytesMut::from(&b"$5\r\nRED"[..]);
+        assert!(Parser::parse_rdb_payload(&buf, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let log = Logger::new();
+        let buf = BytesMut::from(&b"*2\r\n$3\r\nFOO\r\n$3\r\nbar\r\n*1\r\n$3\r\nGET\r\n*4\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n$2\r\nXY\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
+        assert_eq!(r.len(), 3);
+        match &r[0].command {
+            Command::Invalid(e) => {
+                assert_eq!(e.to_string(), "ERR unknown command 'FOO', with args beginning with: 'bar' ")
+            }
+            c => panic!("expected invalid command, got {:?}", c),
+        }
+        match &r[1].command {
+            Command::Invalid(e) => {
+                assert_eq!(e.to_string(), "ERR wrong number of arguments for 'get' command")
+            }
+            c => panic!("expected invalid command, got {:?}", c),
+        }
+        assert_eq!(r[2].command, Command::Invalid(RESPError::SyntaxError));
+
+        // Framing errors are only reported once the good commands before them are consumed
+        let buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n!oops\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
+        assert_eq!(r.len(), 1);
+        let rest = BytesMut::from(&buf[r[0].bytes_read..]);
+        let e = Parser::parse_commands(&log, &rest).unwrap_err();
+        assert_eq!(e, RESPError::UnknownStartingByte(b'!'));
+    }
+
+    #[test]
+    fn test_binary_safe_arguments() {
+        let log = Logger::new();
+        let buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$2\r\n\x00\xff\r\n$4\r\n\r\n\xc3\x28\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
+        assert_eq!(
+            r[0].command,
+            Command::Set(
+                Bytes::from_static(b"\x00\xff"),
+                Bytes::from_static(b"\r\n\xc3\x28"),
+                SetOptions::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_resp3_values() {
+        let buf = BytesMut::from(
+            &b"%2\r\n$5\r\nproto\r\n:3\r\n$4\r\nmode\r\n=14\r\ntxt:standalone\r\n~2\r\n#t\r\n,1.5\r\n>2\r\n_\r\n(123\r\n"[..],
+        );
+        let (pos, map) = Parser::parse(&buf, 0).unwrap().unwrap();
+        assert_eq!(map.to_string(&buf), "{proto:3,mode:standalone}");
+        let (pos, set) = Parser::parse(&buf, pos).unwrap().unwrap();
+        assert_eq!(set.to_string(&buf), "[true,1.5]");
+        let (pos, push) = Parser::parse(&buf, pos).unwrap().unwrap();
+        assert_eq!(push.to_string(&buf), "[null,123]");
+        assert_eq!(pos, buf.len());
+        // Re-encoding gives back the original bytes
+        let mut encoded = map.to_resp(&buf);
+        encoded.extend(set.to_resp(&buf));
+        encoded.extend(push.to_resp(&buf));
+        assert_eq!(&encoded[..], &buf[..]);
+    }
+
+    fn words(s: &str) -> Vec<Bytes> {
+        s.split(' ').map(|w| Bytes::from(w.to_string())).collect()
+    }
+
+    #[test]
+    fn test_expire() {
+        assert_eq!(
+            Parser::parse_command(&words("EXPIRE k 10 xx gt")),
+            Ok(Command::Expire(
+                Bytes::from("k"),
+                ExpireTime::Relative(10_000),
+                ExpireCondition {
+                    xx: true,
+                    gt: true,
+                    ..Default::default()
+                }
+            ))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("PEXPIREAT k -5")),
+            Ok(Command::Expire(
+                Bytes::from("k"),
+                ExpireTime::At(-5),
+                ExpireCondition::default()
+            ))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("EXPIRE k 10 NX LT")),
+            Err(RESPError::IncompatibleNxOptions)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("EXPIRE k 10 GT LT")),
+            Err(RESPError::IncompatibleGtLt)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("EXPIRE k 9223372036854775807")),
+            Err(RESPError::InvalidExpireTime("expire".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("EXPIRE k 10 FOO")).unwrap_err().to_string(),
+            "ERR Unsupported option FOO"
+        );
+        let expire = Parser::parse_command(&words("PEXPIRE k 500 GT")).unwrap();
+        let (absolute, args) = expire.with_absolute_deadline(1_000).unwrap();
+        assert_eq!(args, words("PEXPIREAT k 1500 GT"));
+        assert_eq!(Parser::parse_command(&args), Ok(absolute));
+
+        let gt = ExpireCondition {
+            gt: true,
+            ..Default::default()
+        };
+        assert!(!gt.allows(None, 10));
+        assert!(gt.allows(Some(5), 10));
+        assert!(!gt.allows(Some(10), 10));
+        let lt = ExpireCondition {
+            lt: true,
+            ..Default::default()
+        };
+        assert!(lt.allows(None, 10));
+        assert!(!lt.allows(Some(5), 10));
+    }
+
+    #[test]
+    fn test_set_options() {
+        assert_eq!(
+            Parser::parse_command(&words("SET k v GET xx EXAT 100")),
+            Ok(Command::Set(
+                Bytes::from("k"),
+                Bytes::from("v"),
+                SetOptions {
+                    condition: Some(SetCondition::Xx),
+                    get: true,
+                    expiry: Some(SetExpiry::Expire(ExpireTime::At(100_000))),
+                }
+            ))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("set k v keepttl nx")),
+            Ok(Command::Set(
+                Bytes::from("k"),
+                Bytes::from("v"),
+                SetOptions {
+                    condition: Some(SetCondition::Nx),
+                    get: false,
+                    expiry: Some(SetExpiry::KeepTtl),
+                }
+            ))
+        );
+        for bad in ["SET k v NX XX", "SET k v EX 1 PX 1", "SET k v KEEPTTL PX 1", "SET k v EX", "SET k v FOO"] {
+            assert_eq!(Parser::parse_command(&words(bad)), Err(RESPError::SyntaxError), "{}", bad);
+        }
+        assert_eq!(
+            Parser::parse_command(&words("SET k v PX 0")),
+            Err(RESPError::InvalidExpireTime("set".to_string()))
+        );
+        let set = Parser::parse_command(&words("SET k v EX 10 NX GET")).unwrap();
+        let (absolute, args) = set.with_absolute_deadline(1_000).unwrap();
+        assert_eq!(args, words("SET k v NX PXAT 11000"));
+        assert_eq!(
+            absolute,
+            Parser::parse_command(&words("SET k v NX PXAT 11000 GET")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_keyspace_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("SCAN 17 count 5 MATCH user:* type STRING")),
+            Ok(Command::Scan(
+                17,
+                ScanOptions {
+                    pattern: Some(Bytes::from("user:*")),
+                    count: 5,
+                    type_name: Some("string".to_string()),
+                }
+            ))
+        );
+        assert_eq!(Parser::parse_command(&words("SCAN x")), Err(RESPError::InvalidCursor));
+        assert_eq!(Parser::parse_command(&words("SCAN 0 COUNT 0")), Err(RESPError::SyntaxError));
+        assert_eq!(Parser::parse_command(&words("SCAN 0 MATCH")), Err(RESPError::SyntaxError));
+        assert_eq!(
+            Parser::parse_command(&words("COPY a b DB 0 REPLACE")),
+            Ok(Command::Copy {
+                source: Bytes::from("a"),
+                destination: Bytes::from("b"),
+                replace: true,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("COPY a b DB 1")), Err(RESPError::DbIndexOutOfRange));
+        assert_eq!(
+            Parser::parse_command(&words("UNLINK a b")),
+            Ok(Command::Del(words("a b")))
+        );
+    }
+
+    #[test]
+    fn test_string_commands() {
+        assert_eq!(parse_integer(b"-12"), Some(-12));
+        assert_eq!(parse_integer(b"0"), Some(0));
+        for bad in ["", "-", "+1", "01", "-0", " 1", "1.0", "9223372036854775808"] {
+            assert_eq!(parse_integer(bad.as_bytes()), None, "{}", bad);
+        }
+        assert_eq!(parse_float(b"1e3"), Some(1000.0));
+        assert_eq!(parse_float(b" 1"), None);
+        assert_eq!(parse_float(b"nan"), None);
+
+        assert_eq!(Parser::parse_command(&words("DECR k")), Ok(Command::IncrBy(Bytes::from("k"), -1)));
+        assert_eq!(
+            Parser::parse_command(&words("DECRBY k -9223372036854775808")),
+            Err(RESPError::DecrementOverflow)
+        );
+        assert_eq!(Parser::parse_command(&words("INCRBYFLOAT k x")), Err(RESPError::NotAFloat));
+        assert_eq!(Parser::parse_command(&words("SETRANGE k -1 v")), Err(RESPError::OffsetOutOfRange));
+        assert_eq!(
+            Parser::parse_command(&words("MSET a 1 b")),
+            Err(RESPError::WrongNumberOfArguments("mset".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("SETEX k 0 v")),
+            Err(RESPError::InvalidExpireTime("setex".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("GETEX k persist")),
+            Ok(Command::GetEx(Bytes::from("k"), Some(GetExOption::Persist)))
+        );
+        assert_eq!(Parser::parse_command(&words("GETEX k EX 1 PERSIST")), Err(RESPError::SyntaxError));
+        let (_, args) = Parser::parse_command(&words("GETEX k PX 10"))
+            .unwrap()
+            .with_absolute_deadline(5)
+            .unwrap();
+        assert_eq!(args, words("GETEX k PXAT 15"));
+    }
+
+    #[test]
+    fn test_list_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("RPUSHX q a b")),
+            Ok(Command::Push {
+                key: Bytes::from("q"),
+                elements: words("a b"),
+                end: ListEnd::Right,
+                only_if_exists: true,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("LPOP q -1")), Err(RESPError::NotPositive));
+        assert_eq!(
+            Parser::parse_command(&words("LPOP q 1 2")),
+            Err(RESPError::WrongNumberOfArguments("lpop".to_string()))
+        );
+        assert_eq!(Parser::parse_command(&words("LINSERT q AROUND a b")), Err(RESPError::SyntaxError));
+        assert_eq!(
+            Parser::parse_command(&words("LPOS q a MAXLEN 10 RANK -2 COUNT 0")),
+            Ok(Command::LPos {
+                key: Bytes::from("q"),
+                element: Bytes::from("a"),
+                options: LPosOptions {
+                    rank: -2,
+                    count: Some(0),
+                    maxlen: 10,
+                },
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("LPOS q a RANK 0")), Err(RESPError::ZeroRank));
+        assert_eq!(Parser::parse_command(&words("LPOS q a COUNT -1")), Err(RESPError::NegativeCount));
+        assert_eq!(Parser::parse_command(&words("LMOVE a b LEFT UP")), Err(RESPError::SyntaxError));
+
+        assert_eq!(
+            Parser::parse_command(&words("BRPOP a b 0.5")),
+            Ok(Command::Block {
+                keys: words("a b"),
+                op: BlockingOp::Pop(ListEnd::Right),
+                timeout: Some(Duration::from_millis(500)),
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("BRPOPLPUSH a b 0")),
+            Ok(Command::Block {
+                keys: words("a"),
+                op: BlockingOp::Move {
+                    destination: Bytes::from("b"),
+                    from: ListEnd::Right,
+                    to: ListEnd::Left,
+                },
+                timeout: None,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("BLPOP a -1")), Err(RESPError::NegativeTimeout));
+        assert_eq!(Parser::parse_command(&words("BLPOP a soon")), Err(RESPError::InvalidTimeout));
+    }
+
+    #[test]
+    fn test_hash_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("HMSET h a 1 b 2")),
+            Ok(Command::HSet {
+                key: Bytes::from("h"),
+                pairs: vec![
+                    (Bytes::from("a"), Bytes::from("1")),
+                    (Bytes::from("b"), Bytes::from("2")),
+                ],
+                nx: false,
+                reply_ok: true,
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("HSET h a 1 b")),
+            Err(RESPError::WrongNumberOfArguments("hset".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("HRANDFIELD h -3 withvalues")),
+            Ok(Command::HRandField {
+                key: Bytes::from("h"),
+                count: Some(-3),
+                with_values: true,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("HRANDFIELD h 1 foo")), Err(RESPError::SyntaxError));
+        assert_eq!(
+            Parser::parse_command(&words("HSCAN h 0 TYPE string")),
+            Err(RESPError::SyntaxError)
+        );
+        assert_eq!(Parser::parse_command(&words("HINCRBY h f 1.5")), Err(RESPError::NotAnInteger));
+
+        // The single field forms
+        assert_eq!(
+            Parser::parse_command(&words("HDEL h f")),
+            Ok(Command::HDel(Bytes::from("h"), words("f")))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("HMGET h f")),
+            Ok(Command::HMGet(Bytes::from("h"), words("f")))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("HSCAN h 0")),
+            Ok(Command::HScan(Bytes::from("h"), 0, ScanOptions::default()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("HDEL h")),
+            Err(RESPError::WrongNumberOfArguments("hdel".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_set_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("SDIFFSTORE d a b")),
+            Ok(Command::SetOp {
+                op: SetOperation::Diff,
+                keys: words("a b"),
+                destination: Some(Bytes::from("d")),
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("SINTERCARD 2 a b LIMIT 5")),
+            Ok(Command::SInterCard {
+                keys: words("a b"),
+                limit: 5,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("SINTERCARD 0 a")), Err(RESPError::NumKeysNotPositive));
+        assert_eq!(Parser::parse_command(&words("SINTERCARD 3 a b")), Err(RESPError::TooManyKeys));
+        assert_eq!(Parser::parse_command(&words("SINTERCARD 1 a LIMIT -1")), Err(RESPError::NegativeLimit));
+        assert_eq!(Parser::parse_command(&words("SINTERCARD 1 a b")), Err(RESPError::SyntaxError));
+        assert_eq!(Parser::parse_command(&words("SPOP s -1")), Err(RESPError::NotPositive));
+        assert_eq!(
+            Parser::parse_command(&words("SRANDMEMBER s -3")),
+            Ok(Command::SRandMember {
+                key: Bytes::from("s"),
+                count: Some(-3),
+            })
+        );
+        assert!(!Parser::parse_command(&words("SUNION a b")).unwrap().is_write());
+    }
+
+    #[test]
+    fn test_sorted_set_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("ZADD z XX CH 1 a +inf b")),
+            Ok(Command::ZAdd {
+                key: Bytes::from("z"),
+                options: ZAddOptions {
+                    xx: true,
+                    ch: true,
+                    ..Default::default()
+                },
+                pairs: vec![(1.0, Bytes::from("a")), (f64::INFINITY, Bytes::from("b"))],
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("ZADD z NX XX 1 a")), Err(RESPError::IncompatibleZAddXxNx));
+        assert_eq!(Parser::parse_command(&words("ZADD z GT NX 1 a")), Err(RESPError::IncompatibleZAddGtLtNx));
+        assert_eq!(Parser::parse_command(&words("ZADD z INCR 1 a 2 b")), Err(RESPError::IncrSinglePair));
+        assert_eq!(Parser::parse_command(&words("ZADD z 1 a 2")), Err(RESPError::SyntaxError));
+        assert_eq!(Parser::parse_command(&words("ZADD z x a")), Err(RESPError::NotAFloat));
+
+        // Reversed ranges are given maximum first
+        assert_eq!(
+            Parser::parse_command(&words("ZRANGE z (5 -inf BYSCORE REV LIMIT 1 2 WITHSCORES")),
+            Ok(Command::ZRange {
+                key: Bytes::from("z"),
+                range: ZRange {
+                    by: ZRangeBy::Score(
+                        ScoreBound { value: f64::NEG_INFINITY, exclusive: false },
+                        ScoreBound { value: 5.0, exclusive: true },
+                    ),
+                    rev: true,
+                    limit: Some((1, 2)),
+                },
+                with_scores: true,
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("ZREVRANGEBYLEX z + [b")),
+            Ok(Command::ZRange {
+                key: Bytes::from("z"),
+                range: ZRange {
+                    by: ZRangeBy::Lex(LexBound::Inclusive(Bytes::from("b")), LexBound::Max),
+                    rev: true,
+                    limit: None,
+                },
+                with_scores: false,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("ZRANGE z 0 1 LIMIT 0 1")), Err(RESPError::LimitWithoutBy));
+        assert_eq!(Parser::parse_command(&words("ZRANGE z - + BYLEX WITHSCORES")), Err(RESPError::WithScoresByLex));
+        assert_eq!(Parser::parse_command(&words("ZRANGE z a b BYLEX")), Err(RESPError::InvalidLexRange));
+        assert_eq!(Parser::parse_command(&words("ZCOUNT z x 1")), Err(RESPError::InvalidScoreRange));
+        assert_eq!(Parser::parse_command(&words("ZRANGESTORE d z 0 -1 WITHSCORES")), Err(RESPError::SyntaxError));
+
+        assert_eq!(
+            Parser::parse_command(&words("ZINTERSTORE d 2 a b WEIGHTS 2 0.5 AGGREGATE MAX")),
+            Ok(Command::ZSetOp {
+                op: SetOperation::Inter,
+                destination: Bytes::from("d"),
+                keys: words("a b"),
+                weights: vec![2.0, 0.5],
+                aggregate: Aggregate::Max,
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("ZUNIONSTORE d 0 a")),
+            Err(RESPError::NoInputKeys("zunionstore".to_string()))
+        );
+        assert_eq!(Parser::parse_command(&words("ZUNIONSTORE d 1 a WEIGHTS x")), Err(RESPError::InvalidWeight));
+        assert_eq!(Parser::parse_command(&words("ZUNIONSTORE d 2 a b WEIGHTS 1")), Err(RESPError::SyntaxError));
+        assert_eq!(
+            Parser::parse_command(&words("BZPOPMAX a b 0.5")),
+            Ok(Command::Block {
+                keys: words("a b"),
+                op: BlockingOp::ZPop { max: true },
+                timeout: Some(Duration::from_millis(500)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_stream_commands() {
+        let id = |ms, seq| StreamId { ms, seq };
+        assert_eq!(
+            Parser::parse_command(&words("XADD s NOMKSTREAM MAXLEN ~ 10 LIMIT 5 5-* f v")),
+            Ok(Command::XAdd {
+                key: Bytes::from("s"),
+                nomkstream: true,
+                trim: Some(StreamTrim {
+                    strategy: TrimStrategy::MaxLen(10),
+                    approximate: true,
+                    limit: Some(5),
+                }),
+                id: XAddId::Partial(5),
+                fields: vec![(Bytes::from("f"), Bytes::from("v"))],
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("XADD s 0-0 f v")), Err(RESPError::XAddIdZero));
+        assert_eq!(Parser::parse_command(&words("XADD s 1-x f v")), Err(RESPError::InvalidStreamId));
+        assert_eq!(Parser::parse_command(&words("XADD s 1-2-* f v")), Err(RESPError::InvalidStreamId));
+        assert_eq!(
+            Parser::parse_command(&words("XADD s * f v g")),
+            Err(RESPError::WrongNumberOfArguments("xadd".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XADD s MAXLEN -1 * f v")),
+            Err(RESPError::StreamMaxLenNegative)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XTRIM s MINID 5 LIMIT 1")),
+            Err(RESPError::LimitWithoutApproximate)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XTRIM s MINID = 5")),
+            Ok(Command::XTrim(
+                Bytes::from("s"),
+                StreamTrim {
+                    strategy: TrimStrategy::MinId(id(5, 0)),
+                    approximate: false,
+                    limit: None,
+                }
+            ))
+        );
+
+        // Reversed ranges are given end first; a bare time covers its millisecond
+        assert_eq!(
+            Parser::parse_command(&words("XREVRANGE s 5 (3-1 COUNT 2")),
+            Ok(Command::XRange {
+                key: Bytes::from("s"),
+                start: id(3, 2),
+                end: id(5, u64::MAX),
+                rev: true,
+                count: Some(2),
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XRANGE s (18446744073709551615-18446744073709551615 +")),
+            Err(RESPError::InvalidIntervalStart)
+        );
+        assert_eq!(Parser::parse_command(&words("XRANGE s - (0-0")), Err(RESPError::InvalidIntervalEnd));
+
+        assert_eq!(
+            Parser::parse_command(&words("XREAD COUNT 2 BLOCK 0 STREAMS a b $ 1-1")),
+            Ok(Command::XRead {
+                count: Some(2),
+                block: Some(None),
+                keys: words("a b"),
+                ids: vec![XReadId::Last, XReadId::After(id(1, 1))],
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XREAD STREAMS a b $")),
+            Err(RESPError::UnbalancedStreams("xread".to_string(), "$".to_string()))
+        );
+        assert_eq!(Parser::parse_command(&words("XREAD BLOCK x STREAMS a $")), Err(RESPError::TimeoutNotInteger));
+        assert_eq!(Parser::parse_command(&words("XREAD COUNT 1 a $")), Err(RESPError::SyntaxError));
+    }
+
+    #[test]
+    fn test_stream_group_commands() {
+        let id = |ms, seq| StreamId { ms, seq };
+        assert_eq!(
+            Parser::parse_command(&words("XGROUP CREATE s g $ MKSTREAM ENTRIESREAD 3")),
+            Ok(Command::XGroupCreate {
+                key: Bytes::from("s"),
+                group: Bytes::from("g"),
+                id: XReadId::Last,
+                mkstream: true,
+                entries_read: Some(3),
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XGROUP SETID s g 0 MKSTREAM")),
+            Err(RESPError::WrongNumberOfArguments("xgroup|setid".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XGROUP NOPE s g")),
+            Err(RESPError::UnknownSubcommand("NOPE".to_string(), "XGROUP".to_string()))
+        );
+
+        assert_eq!(
+            Parser::parse_command(&words("XREADGROUP GROUP g c COUNT 1 NOACK STREAMS a b > 0")),
+            Ok(Command::XReadGroup {
+                group: Bytes::from("g"),
+                consumer: Bytes::from("c"),
+                count: Some(1),
+                block: None,
+                noack: true,
+                keys: words("a b"),
+                ids: vec![GroupReadId::New, GroupReadId::Pending(id(0, 0))],
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XREADGROUP COUNT 1 NOACK STREAMS a >")),
+            Err(RESPError::MissingGroupOption)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XREADGROUP GROUP g c STREAMS a $")),
+            Err(RESPError::LastIdInXReadGroup)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XREAD NOACK STREAMS a $")),
+            Err(RESPError::OnlyInXReadGroup("NOACK".to_string()))
+        );
+        assert_eq!(Parser::parse_command(&words("XREAD STREAMS a >")), Err(RESPError::NewIdOutsideXReadGroup));
+
+        assert_eq!(
+            Parser::parse_command(&words("XPENDING s g IDLE 10 - + 5 c")),
+            Ok(Command::XPending {
+                key: Bytes::from("s"),
+                group: Bytes::from("g"),
+                range: Some(XPendingRange {
+                    idle: Some(10),
+                    start: StreamId::MIN,
+                    end: StreamId::MAX,
+                    count: 5,
+                    consumer: Some(Bytes::from("c")),
+                }),
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XCLAIM s g c 100 1-1 2 RETRYCOUNT 3 FORCE LASTID 5")),
+            Ok(Command::XClaim {
+                key: Bytes::from("s"),
+                group: Bytes::from("g"),
+                consumer: Bytes::from("c"),
+                min_idle: 100,
+                ids: vec![id(1, 1), id(2, 0)],
+                options: XClaimOptions {
+                    retry_count: Some(3),
+                    force: true,
+                    last_id: Some(id(5, 0)),
+                    ..Default::default()
+                },
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XCLAIM s g c 100 1-1 BOGUS")),
+            Err(RESPError::UnrecognizedXClaimOption("BOGUS".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XAUTOCLAIM s g c 0 0 COUNT 0")),
+            Err(RESPError::AutoClaimCount)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("XINFO CONSUMERS s")),
+            Err(RESPError::WrongNumberOfArguments("xinfo|consumers".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_pubsub_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("SUBSCRIBE a b")),
+            Ok(Command::Subscribe(words("a b")))
+        );
+        assert_eq!(Parser::parse_command(&words("PUNSUBSCRIBE")), Ok(Command::PUnsubscribe(vec![])));
+        assert_eq!(
+            Parser::parse_command(&words("PSUBSCRIBE")),
+            Err(RESPError::WrongNumberOfArguments("psubscribe".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("PUBSUB CHANNELS news.*")),
+            Ok(Command::PubSubChannels(Some(Bytes::from("news.*"))))
+        );
+        assert_eq!(Parser::parse_command(&words("PUBSUB NUMSUB")), Ok(Command::PubSubNumSub(vec![])));
+        assert_eq!(
+            Parser::parse_command(&words("PUBSUB NUMPAT x")),
+            Err(RESPError::WrongNumberOfArguments("pubsub|numpat".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("PUBSUB SHARDCHANNELS a b")),
+            Err(RESPError::WrongNumberOfArguments("pubsub|shardchannels".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("SPUBLISH a")),
+            Err(RESPError::WrongNumberOfArguments("spublish".to_string()))
+        );
+        assert!(Command::Ping(None).allowed_while_subscribed());
+        assert!(Command::SUnsubscribe(vec![]).allowed_while_subscribed());
+        assert!(!Command::Publish(Bytes::from("a"), Bytes::from("b")).allowed_while_subscribed());
+    }
+
+    #[test]
+    fn test_transaction_commands() {
+        assert_eq!(Parser::parse_command(&words("MULTI")), Ok(Command::Multi));
+        assert_eq!(
+            Parser::parse_command(&words("EXEC now")),
+            Err(RESPError::WrongNumberOfArguments("exec".to_string()))
+        );
+        assert_eq!(Parser::parse_command(&words("WATCH a b")), Ok(Command::Watch(words("a b"))));
+        assert_eq!(
+            Parser::parse_command(&words("WATCH")),
+            Err(RESPError::WrongNumberOfArguments("watch".to_string()))
+        );
+        let rename = Parser::parse_command(&words("RENAME a b")).unwrap();
+        assert_eq!(rename.written_keys(), vec![&Bytes::from("a"), &Bytes::from("b")]);
+        let mset = Parser::parse_command(&words("MSET a 1 b 2")).unwrap();
+        assert_eq!(mset.written_keys(), vec![&Bytes::from("a"), &Bytes::from("b")]);
+        assert!(Command::Get(Bytes::from("a")).written_keys().is_empty());
+    }
+
+    #[test]
+    fn test_scripting_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("EVAL body 1 k a b")),
+            Ok(Command::Eval {
+                script: EvalScript::Body(Bytes::from("body")),
+                keys: words("k"),
+                args: words("a b"),
+                read_only: false,
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("EVALSHA_RO ABC 0")),
+            Ok(Command::Eval {
+                script: EvalScript::Sha("abc".to_string()),
+                keys: vec![],
+                args: vec![],
+                read_only: true,
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("EVAL body 2 k")), Err(RESPError::TooManyKeys));
+        assert_eq!(Parser::parse_command(&words("EVAL body -1")), Err(RESPError::NegativeKeys));
+        assert_eq!(Parser::parse_command(&words("EVAL body x")), Err(RESPError::NotAnInteger));
+        assert_eq!(
+            Parser::parse_command(&words("SCRIPT EXISTS a b")),
+            Ok(Command::ScriptExists(words("a b")))
+        );
+        assert_eq!(Parser::parse_command(&words("SCRIPT FLUSH ASYNC")), Ok(Command::ScriptFlush));
+        assert_eq!(
+            Parser::parse_command(&words("SCRIPT FLUSH NOW")),
+            Err(RESPError::FlushOption("SCRIPT".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("SCRIPT LOAD")),
+            Err(RESPError::WrongNumberOfArguments("script|load".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_function_commands() {
+        assert_eq!(
+            Parser::parse_command(&words("FCALL_RO f 1 k a")),
+            Ok(Command::FCall {
+                function: Bytes::from("f"),
+                keys: words("k"),
+                args: words("a"),
+                read_only: true,
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION LOAD REPLACE code")),
+            Ok(Command::FunctionLoad(Bytes::from("code"), true))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION LOAD NOW code")),
+            Err(RESPError::UnknownOption("NOW".to_string()))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION LIST WITHCODE LIBRARYNAME lib*")),
+            Ok(Command::FunctionList {
+                pattern: Some(Bytes::from("lib*")),
+                with_code: true,
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION LIST LIBRARYNAME")),
+            Err(RESPError::MissingLibraryName)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION RESTORE payload FLUSH")),
+            Ok(Command::FunctionRestore(Bytes::from("payload"), RestorePolicy::Flush))
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION RESTORE payload MERGE")),
+            Err(RESPError::InvalidRestorePolicy)
+        );
+        assert_eq!(
+            Parser::parse_command(&words("FUNCTION STATS now")),
+            Err(RESPError::WrongNumberOfArguments("function|stats".to_string()))
+        );
+        assert!(Parser::parse_command(&words("FUNCTION FLUSH")).unwrap().is_write());
+    }
+
+    #[test]
+    fn test_hello() {
+        let log = Logger::new();
+        let buf = BytesMut::from(&b"*4\r\n$5\r\nHELLO\r\n$1\r\n3\r\n$7\r\nSETNAME\r\n$3\r\ncli\r\n*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n"[..]);
+        let r = Parser::parse_commands(&log, &buf).unwrap();
+        assert_eq!(
+            r[0].command,
+            Command::Hello {
+                protover: Some(3),
+                auth: None,
+                setname: Some(Bytes::from("cli")),
+            }
+        );
+        assert_eq!(r[1].command, Command::Invalid(RESPError::UnsupportedProtocol));
+    }
+
+    #[test]
+    fn test_auth() {
+        assert_eq!(
+            Parser::parse_command(&words("AUTH secret")),
+            Ok(Command::Auth {
+                username: None,
+                password: Bytes::from("secret"),
+            })
+        );
+        assert_eq!(
+            Parser::parse_command(&words("auth default secret")),
+            Ok(Command::Auth {
+                username: Some(Bytes::from("default")),
+                password: Bytes::from("secret"),
+            })
+        );
+        assert_eq!(Parser::parse_command(&words("AUTH a b c")), Err(RESPError::SyntaxError));
+        assert_eq!(
+            Parser::parse_command(&words("AUTH")),
+            Err(RESPError::WrongNumberOfArguments("auth".to_string()))
+        );
+    }
 }
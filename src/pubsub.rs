@@ -0,0 +1,188 @@
+//! Channels and patterns clients subscribe to for PUBLISH, and shard channels for
+//! SPUBLISH
+//!
+//! A subscribing client registers a channel for its messages. PUBLISH finds the
+//! clients subscribed to the channel, or to a pattern matching it, and sends each
+//! of them the message; the connection writes it out when it's not busy with a
+//! command. Shard channels are a namespace of their own, with no patterns.
+use bytes::Bytes;
+use std::collections::{BTreeSet, HashMap};
+use tokio::sync::mpsc;
+
+use crate::client::ClientState;
+use crate::glob::glob_match;
+use crate::server::RedisValue;
+
+/// Where a subscribed client's messages arrive
+pub type Messages = mpsc::UnboundedReceiver<RedisValue>;
+
+/// The clients subscribed to each of a set of names, channels or patterns
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    by_name: HashMap<Bytes, BTreeSet<u64>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self, name: &Bytes, client: u64) {
+        self.by_name.entry(name.clone()).or_default().insert(client);
+    }
+
+    pub fn unsubscribe(&mut self, name: &[u8], client: u64) {
+        if let Some(clients) = self.by_name.get_mut(name) {
+            clients.remove(&client);
+            if clients.is_empty() {
+                self.by_name.remove(name);
+            }
+        }
+    }
+
+    /// How many clients are subscribed to `name`
+    pub fn count(&self, name: &[u8]) -> usize {
+        self.by_name.get(name).map_or(0, BTreeSet::len)
+    }
+
+    /// Every name with at least one subscriber
+    pub fn names(&self) -> impl Iterator<Item = &Bytes> {
+        self.by_name.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    fn clients(&self, name: &[u8]) -> impl Iterator<Item = u64> + '_ {
+        self.by_name.get(name).into_iter().flatten().copied()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PubSub {
+    senders: HashMap<u64, mpsc::UnboundedSender<RedisValue>>,
+    pub channels: Subscribers,
+    pub patterns: Subscribers,
+    pub shard_channels: Subscribers,
+}
+
+impl PubSub {
+    /// Gives a client somewhere to receive messages, returning where they arrive
+    pub fn register(&mut self, client: u64) -> Messages {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.senders.insert(client, tx);
+        rx
+    }
+
+    /// Forgets a client that went away, along with its subscriptions
+    pub fn unregister(&mut self, client: &ClientState) {
+        self.senders.remove(&client.id);
+        for channel in &client.channels {
+            self.channels.unsubscribe(channel, client.id);
+        }
+        for pattern in &client.patterns {
+            self.patterns.unsubscribe(pattern, client.id);
+        }
+        for channel in &client.shard_channels {
+            self.shard_channels.unsubscribe(channel, client.id);
+        }
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of the patterns
+    /// matching it, returning how many messages were sent. A client subscribed
+    /// both ways gets it once for each.
+    pub fn publish(&self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut sent = 0;
+        for client in self.channels.clients(channel) {
+            let frame = message_frame(vec![Bytes::from("message"), channel.clone(), message.clone()]);
+            sent += self.send(client, frame);
+        }
+        for pattern in self.patterns.names() {
+            if !glob_match(pattern, channel, false) {
+                continue;
+            }
+            for client in self.patterns.clients(pattern) {
+                let words = vec![Bytes::from("pmessage"), pattern.clone(), channel.clone(), message.clone()];
+                sent += self.send(client, message_frame(words));
+            }
+        }
+        sent
+    }
+
+    /// Sends `message` to the subscribers of the shard channel, returning how
+    /// many there were
+    pub fn spublish(&self, channel: &Bytes, message: &Bytes) -> usize {
+        self.shard_channels
+            .clients(channel)
+            .map(|client| {
+                let frame = message_frame(vec![Bytes::from("smessage"), channel.clone(), message.clone()]);
+                self.send(client, frame)
+            })
+            .sum()
+    }
+
+    fn send(&self, client: u64, frame: RedisValue) -> usize {
+        // A client that went away is still counted, as it was subscribed when
+        // the message was published
+        if let Some(sender) = self.senders.get(&client) {
+            let _ = sender.send(frame);
+        }
+        1
+    }
+}
+
+/// A message as pushed to a subscriber
+fn message_frame(words: Vec<Bytes>) -> RedisValue {
+    RedisValue::Push(words.into_iter().map(RedisValue::BulkString).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_to_channels_and_patterns() {
+        let mut pubsub = PubSub::default();
+        let mut first = pubsub.register(1);
+        let mut second = pubsub.register(2);
+        pubsub.channels.subscribe(&Bytes::from("news"), 1);
+        pubsub.patterns.subscribe(&Bytes::from("n*"), 1);
+        pubsub.patterns.subscribe(&Bytes::from("sport.*"), 2);
+
+        assert_eq!(pubsub.publish(&Bytes::from("news"), &Bytes::from("hi")), 2);
+        assert_eq!(pubsub.publish(&Bytes::from("weather"), &Bytes::from("hi")), 0);
+        assert_eq!(
+            first.try_recv().unwrap(),
+            message_frame(vec![Bytes::from("message"), Bytes::from("news"), Bytes::from("hi")])
+        );
+        assert_eq!(
+            first.try_recv().unwrap(),
+            message_frame(vec![
+                Bytes::from("pmessage"),
+                Bytes::from("n*"),
+                Bytes::from("news"),
+                Bytes::from("hi"),
+            ])
+        );
+        assert!(second.try_recv().is_err());
+
+        let mut client = ClientState::new(1);
+        client.channels.insert(Bytes::from("news"));
+        client.patterns.insert(Bytes::from("n*"));
+        pubsub.unregister(&client);
+        assert_eq!(pubsub.channels.count(b"news"), 0);
+        assert_eq!(pubsub.patterns.len(), 1);
+        assert_eq!(pubsub.publish(&Bytes::from("sport.chess"), &Bytes::from("e4")), 1);
+        assert!(second.try_recv().is_ok());
+
+        // Shard channels don't see PUBLISH, nor channels SPUBLISH
+        pubsub.shard_channels.subscribe(&Bytes::from("news"), 2);
+        assert_eq!(pubsub.spublish(&Bytes::from("news"), &Bytes::from("hi")), 1);
+        assert_eq!(pubsub.publish(&Bytes::from("news"), &Bytes::from("hi")), 0);
+        assert_eq!(
+            second.try_recv().unwrap(),
+            message_frame(vec![Bytes::from("smessage"), Bytes::from("news"), Bytes::from("hi")])
+        );
+    }
+}
@@ -0,0 +1,1220 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use bytes::Bytes;
+
+use crate::stream::{Consumer, ConsumerGroup, Fields, PendingEntry, Stream, StreamId};
+
+/// Reading and writing RDB snapshot files
+///
+/// Format reference: https://rdb.fnordig.de/file_format.html
+const MAGIC: &[u8] = b"REDIS";
+const VERSION: u32 = 11;
+
+// Opcodes
+/// A function library, given as its code
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+// Value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Quicklist node containers
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// Flags of an entry in a stream node
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+/// Entries per stream node, Redis' default stream-node-max-entries
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+// Special string encodings, flagged by a length byte starting with 0b11
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum RdbError {
+    #[error("not an RDB file")]
+    BadMagic,
+    #[error("unexpected end of RDB file at byte {0}")]
+    UnexpectedEnd(usize),
+    #[error("unsupported value type {0} in RDB file")]
+    UnsupportedType(u8),
+    #[error("unknown string encoding {0} in RDB file")]
+    UnknownEncoding(u8),
+    #[error("corrupt LZF compressed string in RDB file")]
+    BadLzf,
+    #[error("corrupt listpack in RDB file")]
+    BadListpack,
+    #[error("corrupt intset in RDB file")]
+    BadIntset,
+    #[error("invalid sorted set score in RDB file")]
+    BadScore,
+    #[error("corrupt stream in RDB file")]
+    BadStream,
+    #[error("RDB file checksum mismatch")]
+    BadChecksum,
+    #[error("failed to load function library from RDB file: {0}")]
+    BadFunction(String),
+    #[error("failed to access RDB file: {0}")]
+    Io(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RdbValue {
+    String(Bytes),
+    List(Vec<Bytes>),
+    /// Field and value pairs
+    Hash(Vec<(Bytes, Bytes)>),
+    Set(Vec<Bytes>),
+    /// A set of integers, written in the compact intset encoding
+    IntSet(Vec<i64>),
+    /// Member and score pairs
+    SortedSet(Vec<(Bytes, f64)>),
+    Stream(Stream),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RdbEntry {
+    pub db: u64,
+    pub key: Bytes,
+    pub value: RdbValue,
+    /// Absolute deadline in milliseconds since the unix epoch
+    pub expires_at_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RdbFile {
+    pub version: u32,
+    pub aux: Vec<(Bytes, Bytes)>,
+    /// The code of each function library
+    pub functions: Vec<Bytes>,
+    pub entries: Vec<RdbEntry>,
+}
+
+/// A decoded length prefix: either a plain length or the marker for a specially
+/// encoded string.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct RdbReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        let b = *self.buf.get(self.pos).ok_or(RdbError::UnexpectedEnd(self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        if self.pos + n > self.buf.len() {
+            return Err(RdbError::UnexpectedEnd(self.buf.len()));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_u32_le(&mut self) -> Result<u32, RdbError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64, RdbError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            // 00: the next 6 bits are the length
+            0b00 => Ok(Length::Len((first & 0x3F) as u64)),
+            // 01: the next 14 bits are the length
+            0b01 => {
+                let second = self.read_u8()?;
+                Ok(Length::Len((((first & 0x3F) as u64) << 8) | second as u64))
+            }
+            // 10: a 32 or 64 bit big endian length follows
+            0b10 => match first {
+                0x80 => Ok(Length::Len(
+                    u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()) as u64,
+                )),
+                0x81 => Ok(Length::Len(u64::from_be_bytes(
+                    self.read_bytes(8)?.try_into().unwrap(),
+                ))),
+                _ => Err(RdbError::UnknownEncoding(first)),
+            },
+            // 11: special format, the remaining 6 bits say which
+            _ => Ok(Length::Encoded(first & 0x3F)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(enc) => Err(RdbError::UnknownEncoding(enc)),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Bytes, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(Bytes::copy_from_slice(self.read_bytes(len as usize)?)),
+            Length::Encoded(ENC_INT8) => Ok(Bytes::from((self.read_u8()? as i8).to_string())),
+            Length::Encoded(ENC_INT16) => {
+                let n = i16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap());
+                Ok(Bytes::from(n.to_string()))
+            }
+            Length::Encoded(ENC_INT32) => {
+                let n = i32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap());
+                Ok(Bytes::from(n.to_string()))
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                Ok(Bytes::from(lzf_decompress(compressed, len)?))
+            }
+            Length::Encoded(enc) => Err(RdbError::UnknownEncoding(enc)),
+        }
+    }
+
+    /// A score as written by the old ZSET type: a length byte, with three values
+    /// reserved for NaN and the infinities, followed by that many ASCII digits
+    fn read_string_double(&mut self) -> Result<f64, RdbError> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let digits = self.read_bytes(len as usize)?;
+                std::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|digits| digits.parse().ok())
+                    .ok_or(RdbError::BadScore)
+            }
+        }
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<RdbValue, RdbError> {
+        match value_type {
+            TYPE_STRING => Ok(RdbValue::String(self.read_string()?)),
+            TYPE_LIST => {
+                let len = self.read_length()?;
+                let items = (0..len)
+                    .map(|_| self.read_string())
+                    .collect::<Result<_, _>>()?;
+                Ok(RdbValue::List(items))
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut items = Vec::new();
+                for _ in 0..nodes {
+                    let container = self.read_length()?;
+                    let node = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => items.push(node),
+                        QUICKLIST_NODE_PACKED => items.extend(listpack_entries(&node)?),
+                        _ => return Err(RdbError::UnsupportedType(value_type)),
+                    }
+                }
+                Ok(RdbValue::List(items))
+            }
+            TYPE_HASH => {
+                let len = self.read_length()?;
+                let pairs = (0..len)
+                    .map(|_| Ok((self.read_string()?, self.read_string()?)))
+                    .collect::<Result<_, _>>()?;
+                Ok(RdbValue::Hash(pairs))
+            }
+            TYPE_HASH_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?)?;
+                if entries.chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RdbError::BadListpack);
+                }
+                let pairs = entries
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                Ok(RdbValue::Hash(pairs))
+            }
+            TYPE_SET => {
+                let len = self.read_length()?;
+                let members = (0..len)
+                    .map(|_| self.read_string())
+                    .collect::<Result<_, _>>()?;
+                Ok(RdbValue::Set(members))
+            }
+            TYPE_SET_LISTPACK => Ok(RdbValue::Set(listpack_entries(&self.read_string()?)?)),
+            TYPE_SET_INTSET => Ok(RdbValue::IntSet(intset_members(&self.read_string()?)?)),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut pairs = Vec::new();
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap())
+                    } else {
+                        self.read_string_double()?
+                    };
+                    pairs.push((member, score));
+                }
+                Ok(RdbValue::SortedSet(pairs))
+            }
+            TYPE_ZSET_LISTPACK => {
+                // Members and scores alternate
+                let entries = listpack_entries(&self.read_string()?)?;
+                if entries.chunks(2).any(|pair| pair.len() != 2) {
+                    return Err(RdbError::BadListpack);
+                }
+                let pairs = entries
+                    .chunks(2)
+                    .map(|pair| {
+                        let score = std::str::from_utf8(&pair[1]).ok().and_then(|score| score.parse().ok());
+                        score.map(|score| (pair[0].clone(), score)).ok_or(RdbError::BadScore)
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(RdbValue::SortedSet(pairs))
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                let mut stream = Stream::default();
+                let nodes = self.read_length()?;
+                for _ in 0..nodes {
+                    let master = self.read_string()?;
+                    let master = <[u8; 16]>::try_from(&master[..]).map_err(|_| RdbError::BadStream)?;
+                    let entries = listpack_entries(&self.read_string()?)?;
+                    stream.entries.extend(stream_node_entries(master, &entries)?);
+                }
+                let len = self.read_length()?;
+                stream.last_id = self.read_stream_id()?;
+                if value_type == TYPE_STREAM_LISTPACKS {
+                    stream.entries_added = len;
+                } else {
+                    self.read_stream_id()?; // The first ID, which the entries give
+                    stream.max_deleted_id = self.read_stream_id()?;
+                    stream.entries_added = self.read_length()?;
+                }
+                if len != stream.len() as u64 {
+                    return Err(RdbError::BadStream);
+                }
+                for _ in 0..self.read_length()? {
+                    let name = self.read_string()?;
+                    let group = self.read_consumer_group(value_type)?;
+                    stream.groups.insert(name, group);
+                }
+                Ok(RdbValue::Stream(stream))
+            }
+            t => Err(RdbError::UnsupportedType(t)),
+        }
+    }
+
+    fn read_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            ms: self.read_length()?,
+            seq: self.read_length()?,
+        })
+    }
+
+    /// An ID stored as 16 raw big-endian bytes, as pending lists have them
+    fn read_raw_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        let raw = self.read_bytes(16)?;
+        Ok(StreamId {
+            ms: u64::from_be_bytes(raw[..8].try_into().unwrap()),
+            seq: u64::from_be_bytes(raw[8..].try_into().unwrap()),
+        })
+    }
+
+    /// A consumer group after its name: where it's read up to, its pending list,
+    /// then its consumers with the IDs each of them holds
+    fn read_consumer_group(&mut self, value_type: u8) -> Result<ConsumerGroup, RdbError> {
+        let last_id = self.read_stream_id()?;
+        let entries_read = match value_type {
+            TYPE_STREAM_LISTPACKS => None,
+            // -1 for unknown
+            _ => Some(self.read_length()?).filter(|read| *read != u64::MAX),
+        };
+        let mut group = ConsumerGroup::new(last_id, entries_read);
+        for _ in 0..self.read_length()? {
+            let id = self.read_raw_stream_id()?;
+            let entry = PendingEntry {
+                // Filled in from the consumer holding it
+                consumer: Bytes::new(),
+                delivery_time: self.read_u64_le()?,
+                delivery_count: self.read_length()?,
+            };
+            group.pending.insert(id, entry);
+        }
+        for _ in 0..self.read_length()? {
+            let name = self.read_string()?;
+            let seen_time = self.read_u64_le()?;
+            let active_time = match value_type {
+                // -1 for never
+                TYPE_STREAM_LISTPACKS_3 => Some(self.read_u64_le()? as i64)
+                    .filter(|time| *time >= 0)
+                    .map(|time| time as u64),
+                _ => Some(seen_time),
+            };
+            let mut consumer = Consumer {
+                seen_time,
+                active_time,
+                ..Default::default()
+            };
+            for _ in 0..self.read_length()? {
+                let id = self.read_raw_stream_id()?;
+                group.pending.get_mut(&id).ok_or(RdbError::BadStream)?.consumer = name.clone();
+                consumer.pending.insert(id);
+            }
+            group.consumers.insert(name, consumer);
+        }
+        if group.pending.values().any(|entry| entry.consumer.is_empty()) {
+            return Err(RdbError::BadStream);
+        }
+        Ok(group)
+    }
+}
+
+/// Decodes the live entries of a stream node: a listpack whose IDs are relative to
+/// the node's master ID, 16 big endian bytes. The listpack starts with a master
+/// entry naming the fields most entries have, so those can leave them out.
+fn stream_node_entries(master: [u8; 16], lp: &[Bytes]) -> Result<Vec<(StreamId, Fields)>, RdbError> {
+    let master = StreamId {
+        ms: u64::from_be_bytes(master[..8].try_into().unwrap()),
+        seq: u64::from_be_bytes(master[8..].try_into().unwrap()),
+    };
+    let mut lp = lp.iter();
+    let mut next = || lp.next().ok_or(RdbError::BadStream);
+    let int = |b: &Bytes| std::str::from_utf8(b).ok().and_then(|n| n.parse::<i64>().ok()).ok_or(RdbError::BadStream);
+
+    let count = int(next()?)?;
+    let deleted = int(next()?)?;
+    let master_fields = (0..int(next()?)?).map(|_| next().cloned()).collect::<Result<Vec<_>, _>>()?;
+    next()?; // The master entry's terminator
+    let mut entries = Vec::new();
+    for _ in 0..count + deleted {
+        let flags = int(next()?)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(int(next()?)? as u64),
+            seq: master.seq.wrapping_add(int(next()?)? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            let mut fields = Vec::with_capacity(master_fields.len());
+            for field in &master_fields {
+                fields.push((field.clone(), next()?.clone()));
+            }
+            fields
+        } else {
+            let mut fields = Vec::new();
+            for _ in 0..int(next()?)? {
+                fields.push((next()?.clone(), next()?.clone()));
+            }
+            fields
+        };
+        next()?; // The entry's length, for walking backwards
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push((id, fields));
+        }
+    }
+    Ok(entries)
+}
+
+/// Decodes the entries of a listpack, the compact encoding Redis uses for small
+/// collections. Integers come back in their decimal string form.
+fn listpack_entries(lp: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let byte = |i: usize| lp.get(i).copied().ok_or(RdbError::BadListpack);
+    let slice = |from: usize, len: usize| lp.get(from..from + len).ok_or(RdbError::BadListpack);
+    // Sign extends the low `bits` bits of `n`
+    let signed = |n: u64, bits: u32| ((n << (64 - bits)) as i64) >> (64 - bits);
+    let uint = |bytes: &[u8]| bytes.iter().rev().fold(0u64, |n, b| (n << 8) | *b as u64);
+
+    // 4 byte total length and 2 byte element count header
+    let mut pos = 6;
+    let mut entries = Vec::new();
+    loop {
+        let first = byte(pos)?;
+        let (header, len, entry) = match first {
+            0xFF => break,
+            b if b & 0x80 == 0 => (1, 0, Bytes::from((b as i64).to_string())),
+            b if b & 0xC0 == 0x80 => {
+                let len = (b & 0x3F) as usize;
+                (1, len, Bytes::copy_from_slice(slice(pos + 1, len)?))
+            }
+            b if b & 0xE0 == 0xC0 => {
+                let n = (((b & 0x1F) as u64) << 8) | byte(pos + 1)? as u64;
+                (2, 0, Bytes::from(signed(n, 13).to_string()))
+            }
+            b if b & 0xF0 == 0xE0 => {
+                let len = (((b & 0x0F) as usize) << 8) | byte(pos + 1)? as usize;
+                (2, len, Bytes::copy_from_slice(slice(pos + 2, len)?))
+            }
+            0xF0 => {
+                let len = uint(slice(pos + 1, 4)?) as usize;
+                (5, len, Bytes::copy_from_slice(slice(pos + 5, len)?))
+            }
+            0xF1..=0xF4 => {
+                let width = [2, 3, 4, 8][(first - 0xF1) as usize];
+                let n = signed(uint(slice(pos + 1, width)?), width as u32 * 8);
+                (1 + width, 0, Bytes::from(n.to_string()))
+            }
+            _ => return Err(RdbError::BadListpack),
+        };
+        entries.push(entry);
+        // Each entry ends with its own length, encoded in 7 bit groups
+        let entry_len = header + len;
+        let backlen = backlen_size(entry_len);
+        pos += entry_len + backlen;
+    }
+    Ok(entries)
+}
+
+/// How many bytes the length of a listpack entry takes at its end
+fn backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Encodes a listpack of `entries`, with the ones that are canonical integers in
+/// the integer encodings
+fn encode_listpack(entries: &[Bytes]) -> Vec<u8> {
+    let mut lp = vec![0; 6];
+    for entry in entries {
+        let start = lp.len();
+        match std::str::from_utf8(entry).ok().and_then(|n| n.parse::<i64>().ok()) {
+            Some(n) if n.to_string().as_bytes() == &entry[..] => match n {
+                0..=127 => lp.push(n as u8),
+                -4096..=4095 => lp.extend_from_slice(&[0xC0 | ((n >> 8) as u8 & 0x1F), n as u8]),
+                _ if i16::try_from(n).is_ok() => {
+                    lp.push(0xF1);
+                    lp.extend_from_slice(&n.to_le_bytes()[..2]);
+                }
+                -8388608..=8388607 => {
+                    lp.push(0xF2);
+                    lp.extend_from_slice(&n.to_le_bytes()[..3]);
+                }
+                _ if i32::try_from(n).is_ok() => {
+                    lp.push(0xF3);
+                    lp.extend_from_slice(&n.to_le_bytes()[..4]);
+                }
+                _ => {
+                    lp.push(0xF4);
+                    lp.extend_from_slice(&n.to_le_bytes());
+                }
+            },
+            _ => {
+                let len = entry.len();
+                if len < 64 {
+                    lp.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    lp.extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]);
+                } else {
+                    lp.push(0xF0);
+                    lp.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                lp.extend_from_slice(entry);
+            }
+        }
+        // The entry's length in 7 bit groups, most significant first, with the
+        // high bit set on all but the first so it reads backwards
+        let entry_len = lp.len() - start;
+        let size = backlen_size(entry_len);
+        for i in (0..size).rev() {
+            let group = ((entry_len >> (7 * i)) & 0x7F) as u8;
+            lp.push(if i == size - 1 { group } else { group | 0x80 });
+        }
+    }
+    lp.push(0xFF);
+    let total = lp.len() as u32;
+    lp[..4].copy_from_slice(&total.to_le_bytes());
+    lp[4..6].copy_from_slice(&(entries.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    lp
+}
+
+/// Encodes a stream node of `entries`, all live, as `stream_node_entries` reads
+/// them back. The first entry's ID and fields are the master entry.
+fn encode_stream_node(entries: &[(&StreamId, &Fields)]) -> ([u8; 16], Vec<u8>) {
+    let (master, master_fields) = entries[0];
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&master.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&master.seq.to_be_bytes());
+
+    let int = |n: i64| Bytes::from(n.to_string());
+    let mut lp = vec![int(entries.len() as i64), int(0), int(master_fields.len() as i64)];
+    lp.extend(master_fields.iter().map(|(field, _)| field.clone()));
+    lp.push(int(0));
+    for (id, fields) in entries {
+        let same_fields = fields.len() == master_fields.len()
+            && fields.iter().zip(master_fields.iter()).all(|((a, _), (b, _))| a == b);
+        let first = lp.len();
+        lp.push(int(if same_fields { STREAM_ITEM_FLAG_SAMEFIELDS } else { 0 }));
+        lp.push(int(id.ms.wrapping_sub(master.ms) as i64));
+        lp.push(int(id.seq.wrapping_sub(master.seq) as i64));
+        if same_fields {
+            lp.extend(fields.iter().map(|(_, value)| value.clone()));
+        } else {
+            lp.push(int(fields.len() as i64));
+            lp.extend(fields.iter().flat_map(|(field, value)| [field.clone(), value.clone()]));
+        }
+        // How many listpack entries this one took, not counting this count
+        lp.push(int((lp.len() - first) as i64));
+    }
+    (raw, encode_listpack(&lp))
+}
+
+/// Decodes an intset: the width of its integers, their count and then the sorted
+/// integers themselves, all little endian.
+fn intset_members(blob: &[u8]) -> Result<Vec<i64>, RdbError> {
+    let header = |i: usize| blob.get(i..i + 4).ok_or(RdbError::BadIntset);
+    let width = u32::from_le_bytes(header(0)?.try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(header(4)?.try_into().unwrap()) as usize;
+    if ![2, 4, 8].contains(&width) || blob.len() != 8 + width * len {
+        return Err(RdbError::BadIntset);
+    }
+    Ok(blob[8..]
+        .chunks(width)
+        .map(|n| match width {
+            2 => i16::from_le_bytes(n.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(n.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(n.try_into().unwrap()),
+        })
+        .collect())
+}
+
+/// Decompresses an LZF block as written by Redis' lzf_compress.
+fn lzf_decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, RdbError> {
+    let mut out = Vec::with_capacity(expected_len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = ctrl + 1;
+            if i + run > input.len() {
+                return Err(RdbError::BadLzf);
+            }
+            out.extend_from_slice(&input[i..i + run]);
+            i += run;
+        } else {
+            // Back reference
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or(RdbError::BadLzf)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or(RdbError::BadLzf)? as usize;
+            i += 1;
+            let offset = ((ctrl & 0x1F) << 8) + low + 1;
+            if offset > out.len() {
+                return Err(RdbError::BadLzf);
+            }
+            let start = out.len() - offset;
+            // Copy byte by byte since the reference may overlap what's being written
+            for k in 0..len + 2 {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != expected_len {
+        return Err(RdbError::BadLzf);
+    }
+    Ok(out)
+}
+
+pub fn parse_rdb(buf: &[u8]) -> Result<RdbFile, RdbError> {
+    parse_rdb_prefix(buf).map(|(rdb, _)| rdb)
+}
+
+/// Parses an RDB file at the start of `buf`, returning it along with the number of
+/// bytes it took up. Used for AOF files, which may begin with an RDB preamble.
+pub fn parse_rdb_prefix(buf: &[u8]) -> Result<(RdbFile, usize), RdbError> {
+    let mut reader = RdbReader { buf, pos: 0 };
+    if reader.read_bytes(MAGIC.len()).map_err(|_| RdbError::BadMagic)? != MAGIC {
+        return Err(RdbError::BadMagic);
+    }
+    let version = std::str::from_utf8(reader.read_bytes(4).map_err(|_| RdbError::BadMagic)?)
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(RdbError::BadMagic)?;
+
+    let mut rdb = RdbFile {
+        version,
+        ..Default::default()
+    };
+    let mut db = 0;
+    let mut expires_at_ms = None;
+    loop {
+        let opcode = reader.read_u8()?;
+        match opcode {
+            OPCODE_AUX => {
+                let key = reader.read_string()?;
+                let value = reader.read_string()?;
+                rdb.aux.push((key, value));
+            }
+            OPCODE_FUNCTION2 => {
+                rdb.functions.push(reader.read_string()?);
+            }
+            OPCODE_SELECTDB => {
+                db = reader.read_length()?;
+            }
+            OPCODE_RESIZEDB => {
+                // Hash table size hints, only useful for preallocation
+                let _db_size = reader.read_length()?;
+                let _expires_size = reader.read_length()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expires_at_ms = Some(reader.read_u64_le()?);
+            }
+            OPCODE_EXPIRETIME => {
+                expires_at_ms = Some(reader.read_u32_le()? as u64 * 1000);
+            }
+            OPCODE_EOF => {
+                // An 8 byte checksum follows in version 5 and later; zero means it was disabled
+                if version >= 5 {
+                    let body_len = reader.pos;
+                    let checksum = reader.read_u64_le()?;
+                    if checksum != 0 && checksum != crc64(0, &buf[..body_len]) {
+                        return Err(RdbError::BadChecksum);
+                    }
+                }
+                break;
+            }
+            value_type => {
+                let key = reader.read_string()?;
+                let value = reader.read_value(value_type)?;
+                rdb.entries.push(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expires_at_ms: expires_at_ms.take(),
+                });
+            }
+        }
+    }
+    Ok((rdb, reader.pos))
+}
+
+struct RdbWriter {
+    buf: Vec<u8>,
+}
+
+impl RdbWriter {
+    fn write_length(&mut self, len: u64) {
+        if len < 1 << 6 {
+            self.buf.push(len as u8);
+        } else if len < 1 << 14 {
+            self.buf.push(0x40 | (len >> 8) as u8);
+            self.buf.push(len as u8);
+        } else if len <= u32::MAX as u64 {
+            self.buf.push(0x80);
+            self.buf.extend_from_slice(&(len as u32).to_be_bytes());
+        } else {
+            self.buf.push(0x81);
+            self.buf.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, s: &[u8]) {
+        // Small canonical integers get the compact integer encoding, like Redis does
+        if let Some(n) = std::str::from_utf8(s)
+            .ok()
+            .filter(|t| t.len() <= 11)
+            .and_then(|t| t.parse::<i32>().ok())
+            .filter(|n| n.to_string().as_bytes() == s)
+        {
+            if let Ok(n) = i8::try_from(n) {
+                self.buf.extend_from_slice(&[0xC0 | ENC_INT8, n as u8]);
+            } else if let Ok(n) = i16::try_from(n) {
+                self.buf.push(0xC0 | ENC_INT16);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            } else {
+                self.buf.push(0xC0 | ENC_INT32);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            }
+            return;
+        }
+        self.write_length(s.len() as u64);
+        self.buf.extend_from_slice(s);
+    }
+
+    fn write_value(&mut self, value: &RdbValue) {
+        match value {
+            RdbValue::String(s) => self.write_string(s),
+            RdbValue::List(items) => {
+                self.write_length(items.len() as u64);
+                for item in items {
+                    self.write_string(item);
+                }
+            }
+            RdbValue::Hash(pairs) => {
+                self.write_length(pairs.len() as u64);
+                for (field, value) in pairs {
+                    self.write_string(field);
+                    self.write_string(value);
+                }
+            }
+            RdbValue::Set(members) => {
+                self.write_length(members.len() as u64);
+                for member in members {
+                    self.write_string(member);
+                }
+            }
+            RdbValue::IntSet(ints) => {
+                // The narrowest width that fits every integer
+                let width: usize = if ints.iter().all(|n| i16::try_from(*n).is_ok()) {
+                    2
+                } else if ints.iter().all(|n| i32::try_from(*n).is_ok()) {
+                    4
+                } else {
+                    8
+                };
+                let mut blob = Vec::with_capacity(8 + width * ints.len());
+                blob.extend_from_slice(&(width as u32).to_le_bytes());
+                blob.extend_from_slice(&(ints.len() as u32).to_le_bytes());
+                for n in ints {
+                    blob.extend_from_slice(&n.to_le_bytes()[..width]);
+                }
+                self.write_length(blob.len() as u64);
+                self.buf.extend_from_slice(&blob);
+            }
+            RdbValue::SortedSet(pairs) => {
+                self.write_length(pairs.len() as u64);
+                for (member, score) in pairs {
+                    self.write_string(member);
+                    self.buf.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+            RdbValue::Stream(stream) => {
+                let entries: Vec<_> = stream.entries.iter().collect();
+                let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+                self.write_length(nodes.len() as u64);
+                for node in nodes {
+                    let (master, lp) = encode_stream_node(node);
+                    self.write_string(&master);
+                    self.write_string(&lp);
+                }
+                self.write_length(stream.len() as u64);
+                for id in [stream.last_id, stream.first_id(), stream.max_deleted_id] {
+                    self.write_length(id.ms);
+                    self.write_length(id.seq);
+                }
+                self.write_length(stream.entries_added);
+                self.write_length(stream.groups.len() as u64);
+                for (name, group) in &stream.groups {
+                    self.write_string(name);
+                    self.write_consumer_group(group);
+                }
+            }
+        }
+    }
+
+    fn write_consumer_group(&mut self, group: &ConsumerGroup) {
+        self.write_length(group.last_id.ms);
+        self.write_length(group.last_id.seq);
+        self.write_length(group.entries_read.unwrap_or(u64::MAX));
+        self.write_length(group.pending.len() as u64);
+        for (id, entry) in &group.pending {
+            self.write_raw_stream_id(id);
+            self.buf.extend_from_slice(&entry.delivery_time.to_le_bytes());
+            self.write_length(entry.delivery_count);
+        }
+        self.write_length(group.consumers.len() as u64);
+        for (name, consumer) in &group.consumers {
+            self.write_string(name);
+            self.buf.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            let active_time = consumer.active_time.map_or(-1, |time| time as i64);
+            self.buf.extend_from_slice(&active_time.to_le_bytes());
+            self.write_length(consumer.pending.len() as u64);
+            for id in &consumer.pending {
+                self.write_raw_stream_id(id);
+            }
+        }
+    }
+
+    fn write_raw_stream_id(&mut self, id: &StreamId) {
+        self.buf.extend_from_slice(&id.ms.to_be_bytes());
+        self.buf.extend_from_slice(&id.seq.to_be_bytes());
+    }
+
+    fn value_type(value: &RdbValue) -> u8 {
+        match value {
+            RdbValue::String(_) => TYPE_STRING,
+            RdbValue::List(_) => TYPE_LIST,
+            RdbValue::Hash(_) => TYPE_HASH,
+            RdbValue::Set(_) => TYPE_SET,
+            RdbValue::IntSet(_) => TYPE_SET_INTSET,
+            RdbValue::SortedSet(_) => TYPE_ZSET_2,
+            RdbValue::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+        }
+    }
+}
+
+/// Serializes function libraries and `entries` (all in database 0) into an RDB
+/// file, checksum included.
+pub fn encode_rdb(aux: &[(&str, String)], functions: &[Bytes], entries: &[RdbEntry]) -> Vec<u8> {
+    let mut writer = RdbWriter {
+        buf: format!("REDIS{:04}", VERSION).into_bytes(),
+    };
+    for (key, value) in aux {
+        writer.buf.push(OPCODE_AUX);
+        writer.write_string(key.as_bytes());
+        writer.write_string(value.as_bytes());
+    }
+    for code in functions {
+        writer.buf.push(OPCODE_FUNCTION2);
+        writer.write_string(code);
+    }
+    if !entries.is_empty() {
+        writer.buf.push(OPCODE_SELECTDB);
+        writer.write_length(0);
+        writer.buf.push(OPCODE_RESIZEDB);
+        writer.write_length(entries.len() as u64);
+        writer.write_length(entries.iter().filter(|e| e.expires_at_ms.is_some()).count() as u64);
+    }
+    for entry in entries {
+        if let Some(at) = entry.expires_at_ms {
+            writer.buf.push(OPCODE_EXPIRETIME_MS);
+            writer.buf.extend_from_slice(&at.to_le_bytes());
+        }
+        writer.buf.push(RdbWriter::value_type(&entry.value));
+        writer.write_string(&entry.key);
+        writer.write_value(&entry.value);
+    }
+    writer.buf.push(OPCODE_EOF);
+    let checksum = crc64(0, &writer.buf);
+    writer.buf.extend_from_slice(&checksum.to_le_bytes());
+    writer.buf
+}
+
+/// Serializes function libraries for FUNCTION DUMP. Like a DUMP payload, it ends
+/// with the RDB version and a checksum.
+pub fn encode_function_dump(functions: &[Bytes]) -> Vec<u8> {
+    let mut writer = RdbWriter { buf: Vec::new() };
+    for code in functions {
+        writer.buf.push(OPCODE_FUNCTION2);
+        writer.write_string(code);
+    }
+    writer.buf.extend_from_slice(&(VERSION as u16).to_le_bytes());
+    let checksum = crc64(0, &writer.buf);
+    writer.buf.extend_from_slice(&checksum.to_le_bytes());
+    writer.buf
+}
+
+/// The library code in a FUNCTION DUMP payload
+pub fn parse_function_dump(payload: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    if payload.len() < 10 {
+        return Err(RdbError::BadChecksum);
+    }
+    let (body, checksum) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([body[body.len() - 2], body[body.len() - 1]]);
+    if version as u32 > VERSION || u64::from_le_bytes(checksum.try_into().unwrap()) != crc64(0, body) {
+        return Err(RdbError::BadChecksum);
+    }
+    let mut reader = RdbReader {
+        buf: &body[..body.len() - 2],
+        pos: 0,
+    };
+    let mut functions = Vec::new();
+    while reader.pos < reader.buf.len() {
+        match reader.read_u8()? {
+            OPCODE_FUNCTION2 => functions.push(reader.read_string()?),
+            other => return Err(RdbError::UnsupportedType(other)),
+        }
+    }
+    Ok(functions)
+}
+
+/// Writes `contents` to `path` through a temporary file so a crash mid-write
+/// never leaves a truncated snapshot behind.
+pub fn write_rdb_file(path: &Path, contents: &[u8]) -> Result<(), RdbError> {
+    let tmp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        RdbError::Io(e.to_string())
+    })
+}
+
+/// CRC-64/Jones, the checksum Redis appends to RDB files
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC9329AC4BC9B5; // 0xad93d23594c935a9 reflected
+    static TABLE: std::sync::OnceLock<[u64; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u64; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u64;
+            for _ in 0..8 {
+                c = if c & 1 == 1 { (c >> 1) ^ POLY } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        table
+    });
+    for &b in data {
+        crc = table[((crc ^ b as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rdb() {
+        let mut file = b"REDIS0011".to_vec();
+        // aux field with an int8 encoded value
+        file.extend_from_slice(b"\xfa\x0aredis-bits\xc0\x40");
+        file.extend_from_slice(b"\xfe\x00\xfb\x03\x02");
+        // plain string
+        file.extend_from_slice(b"\x00\x03foo\x03bar");
+        // int16 encoded value, millisecond expiry
+        file.extend_from_slice(b"\xfc\x00\x0c\x28\x8a\xc7\x01\x00\x00\x00\x03num\xc1\x39\x30");
+        // int32 encoded value, second expiry
+        file.extend_from_slice(b"\xfd\x52\xed\x2a\x66\x00\x03big\xc2\x40\xe2\x01\x00");
+        file.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        let rdb = parse_rdb(&file).unwrap();
+        assert_eq!(rdb.version, 11);
+        assert_eq!(rdb.aux, vec![(Bytes::from("redis-bits"), Bytes::from("64"))]);
+        assert_eq!(rdb.entries.len(), 3);
+        assert_eq!(rdb.entries[0].key, Bytes::from("foo"));
+        assert_eq!(rdb.entries[0].value, RdbValue::String(Bytes::from("bar")));
+        assert_eq!(rdb.entries[0].expires_at_ms, None);
+        assert_eq!(rdb.entries[1].value, RdbValue::String(Bytes::from("12345")));
+        assert_eq!(rdb.entries[1].expires_at_ms, Some(1956528000000));
+        assert_eq!(rdb.entries[2].value, RdbValue::String(Bytes::from("123456")));
+        assert_eq!(rdb.entries[2].expires_at_ms, Some(1714089298000));
+    }
+
+    #[test]
+    fn test_lzf_string() {
+        // "aaaaaaaaaaaaaaaaaaaa" as compressed by lzf: one literal then a back reference
+        let compressed = b"\x00a\xe0\x0a\x00";
+        assert_eq!(lzf_decompress(compressed, 20).unwrap(), b"a".repeat(20));
+        assert_eq!(lzf_decompress(compressed, 21), Err(RdbError::BadLzf));
+    }
+
+    #[test]
+    fn test_quicklist() {
+        // One packed node holding "a", 5, -2 and 1000, then a plain node
+        let lp = b"\x12\x00\x00\x00\x04\x00\x81a\x02\x05\x01\xdf\xfe\x02\xc3\xe8\x02\xff";
+        let mut file = b"REDIS0011\x12\x04list\x02\x02".to_vec();
+        file.push(lp.len() as u8);
+        file.extend_from_slice(lp);
+        file.extend_from_slice(b"\x01\x03big\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+        let rdb = parse_rdb(&file).unwrap();
+        let items = ["a", "5", "-2", "1000", "big"].map(Bytes::from).to_vec();
+        assert_eq!(rdb.entries[0].value, RdbValue::List(items));
+    }
+
+    #[test]
+    fn test_listpack_hash() {
+        // Fields and values alternate
+        let lp = b"\x0c\x00\x00\x00\x02\x00\x81f\x02\x07\x01\xff";
+        let mut file = b"REDIS0011\x10\x01h".to_vec();
+        file.push(lp.len() as u8);
+        file.extend_from_slice(lp);
+        file.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+        let rdb = parse_rdb(&file).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::Hash(vec![(Bytes::from("f"), Bytes::from("7"))])
+        );
+    }
+
+    #[test]
+    fn test_intset() {
+        let blob = b"\x02\x00\x00\x00\x03\x00\x00\x00\xfe\xff\x05\x00\x00\x01";
+        assert_eq!(intset_members(blob), Ok(vec![-2, 5, 256]));
+        assert_eq!(intset_members(&blob[..12]), Err(RdbError::BadIntset));
+    }
+
+    #[test]
+    fn test_zset_scores() {
+        // The old ZSET type with scores as strings, then a listpack with an integer score
+        let mut file = b"REDIS0011\x03\x01z\x02\x01a\x031.5\x01b\xff".to_vec();
+        let lp = b"\x0c\x00\x00\x00\x02\x00\x81m\x02\x07\x01\xff";
+        file.extend_from_slice(b"\x11\x01l");
+        file.push(lp.len() as u8);
+        file.extend_from_slice(lp);
+        file.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+        let rdb = parse_rdb(&file).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::SortedSet(vec![(Bytes::from("a"), 1.5), (Bytes::from("b"), f64::NEG_INFINITY)])
+        );
+        assert_eq!(rdb.entries[1].value, RdbValue::SortedSet(vec![(Bytes::from("m"), 7.0)]));
+    }
+
+    #[test]
+    fn test_bad_files() {
+        assert_eq!(parse_rdb(b"RADIS0011\xff"), Err(RdbError::BadMagic));
+        assert_eq!(parse_rdb(b"REDIS0011\x00\x03foo"), Err(RdbError::UnexpectedEnd(14)));
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    /// A stream spanning two nodes, with entries both with and without the
+    /// master entry's fields
+    fn stream() -> Stream {
+        let mut stream = Stream::default();
+        for n in 0..150u64 {
+            let mut fields = vec![(Bytes::from("n"), Bytes::from(n.to_string()))];
+            if n % 7 == 0 {
+                fields.push((Bytes::from("text"), Bytes::from("x".repeat(n as usize))));
+            }
+            stream.add(StreamId { ms: 1 << 40, seq: n }, fields);
+        }
+        stream.delete(StreamId { ms: 1 << 40, seq: 3 });
+        stream.add(StreamId { ms: u64::MAX, seq: 0 }, vec![(Bytes::from("n"), Bytes::from("-1"))]);
+        let mut group = ConsumerGroup::new(StreamId { ms: 1 << 40, seq: 1 }, Some(2));
+        group.create_consumer(&Bytes::from("idle"), 1000);
+        group.deliver(StreamId { ms: 1 << 40, seq: 0 }, &Bytes::from("busy"), 2000, 3);
+        group.deliver(StreamId { ms: 1 << 40, seq: 1 }, &Bytes::from("busy"), 2500, 1);
+        stream.groups.insert(Bytes::from("workers"), group);
+        stream.groups.insert(Bytes::from("unread"), ConsumerGroup::new(StreamId::MIN, None));
+        stream
+    }
+
+    #[test]
+    fn test_stream_listpacks() {
+        let lp = encode_listpack(&[Bytes::from("7"), Bytes::from("-4096"), Bytes::from("70000"), Bytes::from("07")]);
+        assert_eq!(listpack_entries(&lp).unwrap(), [Bytes::from("7"), Bytes::from("-4096"), Bytes::from("70000"), Bytes::from("07")]);
+        let long = Bytes::from(vec![b'y'; 5000]);
+        assert_eq!(listpack_entries(&encode_listpack(std::slice::from_ref(&long))).unwrap(), [long]);
+
+        let stream = stream();
+        let entries: Vec<_> = stream.entries.iter().collect();
+        let (master, lp) = encode_stream_node(&entries);
+        let decoded = stream_node_entries(master, &listpack_entries(&lp).unwrap()).unwrap();
+        assert_eq!(decoded, stream.entries.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let entries = vec![
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("foo"),
+                value: RdbValue::String(Bytes::from("bar")),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("n"),
+                value: RdbValue::String(Bytes::from("-70000")),
+                expires_at_ms: Some(1956528000000),
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("padded"),
+                value: RdbValue::String(Bytes::from("007")),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("list"),
+                value: RdbValue::List(vec![Bytes::from("a"), Bytes::from("12")]),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("hash"),
+                value: RdbValue::Hash(vec![(Bytes::from("f"), Bytes::from("1"))]),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("set"),
+                value: RdbValue::Set(vec![Bytes::from("x")]),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("ints"),
+                value: RdbValue::IntSet(vec![-40000, 7, 1 << 40]),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("zset"),
+                value: RdbValue::SortedSet(vec![(Bytes::from("a"), -0.5), (Bytes::from("b"), f64::INFINITY)]),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("stream"),
+                value: RdbValue::Stream(stream()),
+                expires_at_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: Bytes::from("long"),
+                value: RdbValue::String(Bytes::from(vec![b'x'; 20000])),
+                expires_at_ms: None,
+            },
+        ];
+        let functions = vec![Bytes::from("#!lua name=lib\nredis.register_function('f', function() end)")];
+        let file = encode_rdb(&[("redis-ver", "7.2.0".to_string())], &functions, &entries);
+        let rdb = parse_rdb(&file).unwrap();
+        assert_eq!(rdb.version, VERSION);
+        assert_eq!(rdb.aux, vec![(Bytes::from("redis-ver"), Bytes::from("7.2.0"))]);
+        assert_eq!(rdb.functions, functions);
+        assert_eq!(rdb.entries, entries);
+
+        // Corrupting a byte is caught by the checksum
+        let mut corrupt = file.clone();
+        corrupt[20] ^= 0xFF;
+        assert!(parse_rdb(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_function_dump() {
+        let functions = vec![Bytes::from("a"), Bytes::from(vec![b'x'; 100])];
+        let payload = encode_function_dump(&functions);
+        assert_eq!(parse_function_dump(&payload), Ok(functions));
+        assert_eq!(parse_function_dump(&encode_function_dump(&[])), Ok(vec![]));
+
+        let mut corrupt = payload.clone();
+        corrupt[3] ^= 0xFF;
+        assert_eq!(parse_function_dump(&corrupt), Err(RdbError::BadChecksum));
+        assert_eq!(parse_function_dump(b"short"), Err(RdbError::BadChecksum));
+    }
+}
@@ -0,0 +1,138 @@
+//! The order SCAN, HSCAN, SSCAN and ZSCAN visit names in
+//!
+//! Names are visited by a hash that's stable across runs, then by name, rather
+//! than in the order a hash table stores them, which changes as it grows. The
+//! cursor is the hash to resume at. Anything present for a whole iteration is
+//! returned at least once; names sharing a hash are always returned together.
+use bytes::Bytes;
+use std::collections::BTreeSet;
+
+/// The names of a hash table in scan order, kept beside it so a step of an
+/// iteration only looks at the names it returns
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScanOrder {
+    names: BTreeSet<(u64, Bytes)>,
+}
+
+impl ScanOrder {
+    pub fn insert(&mut self, name: &Bytes) {
+        self.names.insert((scan_hash(name), name.clone()));
+    }
+
+    pub fn remove(&mut self, name: &[u8]) {
+        let hash = scan_hash(name);
+        let found = self
+            .names
+            .range((hash, Bytes::new())..)
+            .take_while(|(other, _)| *other == hash)
+            .find(|(_, other)| other == name)
+            .cloned();
+        if let Some(found) = found {
+            self.names.remove(&found);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.names.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// One step of an iteration: `count` names at or past `cursor`, more if the
+    /// last of them shares its hash with the next, and the cursor to continue
+    /// from, which is 0 once everything was seen
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&Bytes>) {
+        let mut names: Vec<&Bytes> = Vec::new();
+        let mut last = None;
+        for (hash, name) in self.names.range((cursor, Bytes::new())..) {
+            if names.len() >= count && last != Some(*hash) {
+                return (*hash, names);
+            }
+            names.push(name);
+            last = Some(*hash);
+        }
+        (0, names)
+    }
+
+    /// The first name at or past `hash`, wrapping around to the first of all;
+    /// with a random `hash`, a name picked about at random
+    pub fn at_or_after(&self, hash: u64) -> Option<&Bytes> {
+        self.names
+            .range((hash, Bytes::new())..)
+            .next()
+            .or_else(|| self.names.first())
+            .map(|(_, name)| name)
+    }
+}
+
+/// FNV-1a, chosen for being stable across runs so cursors stay valid
+fn scan_hash(name: &[u8]) -> u64 {
+    name.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_visits_everything_once() {
+        let keys: Vec<Bytes> = (0..100).map(|i| Bytes::from(format!("key:{}", i))).collect();
+        let mut order = ScanOrder::default();
+        for key in &keys {
+            order.insert(key);
+        }
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = order.scan(cursor, 7);
+            assert!(batch.len() <= 8);
+            seen.extend(batch.into_iter().cloned());
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn test_changes_during_a_scan() {
+        let mut order = ScanOrder::default();
+        for i in 0..50 {
+            order.insert(&Bytes::from(format!("{}", i)));
+        }
+        let (cursor, first) = order.scan(0, 10);
+        let first: Vec<Bytes> = first.into_iter().cloned().collect();
+        // Removing what was returned and adding new names doesn't lose the rest
+        for name in &first {
+            order.remove(name);
+        }
+        order.remove(b"missing");
+        order.insert(&Bytes::from("new"));
+        let mut seen = first;
+        let mut cursor = cursor;
+        while cursor != 0 {
+            let (next, batch) = order.scan(cursor, 10);
+            seen.extend(batch.into_iter().cloned());
+            cursor = next;
+        }
+        for i in 0..50 {
+            assert!(seen.contains(&Bytes::from(format!("{}", i))), "{} missed", i);
+        }
+        assert_eq!(order.len(), 41);
+        assert!(order.at_or_after(u64::MAX).is_some());
+        order.clear();
+        assert!(order.at_or_after(0).is_none());
+    }
+}
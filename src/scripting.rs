@@ -0,0 +1,616 @@
+//! The scripts EVAL has seen, kept by SHA1 for EVALSHA, the function libraries
+//! FUNCTION LOAD has loaded, and the state of the script or function running
+//!
+//! A script holds the server to itself while it runs. Other clients wait for it
+//! to finish, until it's been going for longer than the busy threshold; from then
+//! on they're told the server is busy, and may stop the script with SCRIPT KILL
+//! (FUNCTION KILL for a function) as long as it hasn't written anything.
+//!
+//! Lua values only live for one run of the interpreter, so a library keeps its
+//! compiled code rather than the functions it registered. Each FCALL runs the
+//! library's code again to get at the function it calls.
+use bytes::Bytes;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::lua::{self, FunctionProto, Host, LuaError, Runtime, Value};
+use crate::sha1::sha1_hex;
+
+/// Stack for the thread a script runs on, deep enough for the interpreter's
+/// call depth limit
+const SCRIPT_STACK_SIZE: usize = 256 << 20;
+
+/// A compiled script
+#[derive(Debug)]
+pub struct Script {
+    pub body: Bytes,
+    pub proto: Arc<FunctionProto>,
+    /// Set by the `no-writes` flag in the shebang line
+    pub no_writes: bool,
+}
+
+impl Script {
+    /// Compiles a script, reading flags from a `#!lua flags=...` first line if there
+    /// is one. Errors are the replies to send.
+    pub fn compile(body: &Bytes) -> Result<Script, String> {
+        let mut source = &body[..];
+        let mut no_writes = false;
+        if let Some(shebang) = body.strip_prefix(b"#!") {
+            let end = shebang.iter().position(|&b| b == b'\n').unwrap_or(shebang.len());
+            let line = String::from_utf8_lossy(&shebang[..end]);
+            let mut parts = line.split(' ').filter(|part| !part.is_empty());
+            let engine = parts.next().unwrap_or_default();
+            if engine != "lua" {
+                return Err(format!("ERR Unexpected engine in script shebang: {}", engine));
+            }
+            for part in parts {
+                let flags = match part.strip_prefix("flags=") {
+                    Some(flags) => flags,
+                    None => return Err(format!("ERR Unknown lua shebang option: {}", part)),
+                };
+                for flag in flags.split(',').filter(|flag| !flag.is_empty()) {
+                    match flag {
+                        "no-writes" => no_writes = true,
+                        "allow-oom" | "allow-stale" | "no-cluster" | "allow-cross-slot-keys" => {}
+                        _ => return Err(format!("ERR Unexpected flag in script shebang: {}", flag)),
+                    }
+                }
+            }
+            // The newline stays, so line numbers still match the script as sent
+            source = &shebang[end..];
+        }
+        let proto = on_script_thread(|| lua::compile("user_script", source))
+            .map_err(|e| format!("ERR Error compiling script (new function): {}", e))?;
+        Ok(Script {
+            body: body.clone(),
+            proto,
+            no_writes,
+        })
+    }
+}
+
+/// Runs `run` on a thread of its own with room for deep recursion, waiting for it
+pub fn on_script_thread<T: Send>(run: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .name("script".to_string())
+            .stack_size(SCRIPT_STACK_SIZE)
+            .spawn_scoped(scope, run)
+            .expect("Failed to start a thread for the script");
+        thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Compiled scripts by the hex SHA1 digest of their source
+#[derive(Debug, Default)]
+pub struct ScriptCache {
+    scripts: HashMap<String, Arc<Script>>,
+}
+
+impl ScriptCache {
+    pub fn get(&self, sha: &str) -> Option<Arc<Script>> {
+        self.scripts.get(sha).cloned()
+    }
+
+    pub fn contains(&self, sha: &[u8]) -> bool {
+        std::str::from_utf8(sha).is_ok_and(|sha| self.scripts.contains_key(&sha.to_lowercase()))
+    }
+
+    /// Adds a script, returning its digest
+    pub fn insert(&mut self, script: Script) -> String {
+        let sha = sha1_hex(&script.body);
+        self.scripts.insert(sha.clone(), Arc::new(script));
+        sha
+    }
+
+    pub fn clear(&mut self) {
+        self.scripts.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+}
+
+/// How long a library's code may run while it's being loaded
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Flags scripts and functions may declare
+const SCRIPT_FLAGS: &[&str] = &["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+/// A function as a library's code registers it
+pub struct Registration {
+    pub name: String,
+    pub callback: Value,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+/// Runs a library's code, returning the functions it registers with
+/// redis.register_function
+pub fn register_functions(runtime: &mut Runtime, proto: &Arc<FunctionProto>) -> Result<Vec<Registration>, LuaError> {
+    let registered: Rc<RefCell<Vec<Registration>>> = Rc::default();
+    let register = {
+        let registered = Rc::clone(&registered);
+        Value::native("register_function", move |rt, args| {
+            let registration = registration(rt, args)?;
+            let mut registered = registered.borrow_mut();
+            if registered.iter().any(|other| other.name == registration.name) {
+                return Err(rt.error("Function already exists in the library"));
+            }
+            registered.push(registration);
+            Ok(Vec::new())
+        })
+    };
+    let redis = runtime.global("redis");
+    if let Value::Table(redis) = &redis {
+        redis.borrow_mut().set_str("register_function", register);
+    }
+    let result = runtime.run(proto);
+    // Only the library's code may register functions
+    if let Value::Table(redis) = &redis {
+        redis.borrow_mut().set_str("register_function", Value::Nil);
+    }
+    result?;
+    Ok(registered.take())
+}
+
+/// The arguments to redis.register_function: a name and a callback, or a table
+/// of named arguments
+fn registration(rt: &mut Runtime, args: Vec<Value>) -> Result<Registration, LuaError> {
+    let (name, callback, description, flags) = match args.as_slice() {
+        [name, callback] => (name.clone(), callback.clone(), Value::Nil, Value::Nil),
+        [Value::Table(named)] => {
+            let named = named.borrow();
+            let mut key = Value::Nil;
+            while let Ok(Some((k, _))) = named.next(&key) {
+                let known = ["function_name", "callback", "description", "flags"];
+                if !matches!(&k, Value::String(k) if known.iter().any(|name| name.as_bytes() == k)) {
+                    return Err(rt.error("unknown argument given to redis.register_function"));
+                }
+                key = k;
+            }
+            (
+                named.get_str("function_name"),
+                named.get_str("callback"),
+                named.get_str("description"),
+                named.get_str("flags"),
+            )
+        }
+        [_] => {
+            return Err(rt.error(
+                "calling redis.register_function with a single argument is only applicable to Lua table \
+                 (representing named arguments).",
+            ))
+        }
+        _ => return Err(rt.error("wrong number of arguments to redis.register_function")),
+    };
+    let name = match name {
+        Value::String(name) => String::from_utf8_lossy(&name).into_owned(),
+        _ => return Err(rt.error("redis.register_function must get a function name argument")),
+    };
+    if !valid_name(&name) {
+        return Err(rt.error(
+            "Function names can only contain letters, numbers, or underscores(_) and must be at least one \
+             character long",
+        ));
+    }
+    if !matches!(callback, Value::Function(_)) {
+        return Err(rt.error("redis.register_function must get a callback argument"));
+    }
+    let description = match description {
+        Value::Nil => None,
+        Value::String(description) => Some(String::from_utf8_lossy(&description).into_owned()),
+        _ => return Err(rt.error("description argument given to redis.register_function must be a string")),
+    };
+    let flags = match flags {
+        Value::Nil => Vec::new(),
+        Value::Table(flags) => {
+            let mut names = Vec::new();
+            for flag in flags.borrow().sequence() {
+                match flag {
+                    Value::String(flag) if SCRIPT_FLAGS.iter().any(|known| known.as_bytes() == flag) => {
+                        names.push(String::from_utf8_lossy(&flag).into_owned())
+                    }
+                    _ => return Err(rt.error("unknown flag given")),
+                }
+            }
+            names
+        }
+        _ => return Err(rt.error("flags argument to redis.register_function must be a table representing function flags")),
+    };
+    Ok(Registration {
+        name,
+        callback,
+        description,
+        flags,
+    })
+}
+
+/// Whether `name` will do for a library or function: letters, digits and underscores
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// What a library's code may reach while it loads: nothing but the clock
+struct LoadHost {
+    started: Instant,
+}
+
+impl Host for LoadHost {
+    fn call(&mut self, _: Vec<Bytes>) -> Value {
+        unreachable!("redis.call is removed while a library loads")
+    }
+
+    fn log(&mut self, _: i64, _: &str) {}
+
+    fn check(&mut self) -> Result<(), LuaError> {
+        if self.started.elapsed() > LOAD_TIMEOUT {
+            return Err(LuaError::Interrupted("ERR FUNCTION LOAD timeout".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A function a library registered
+#[derive(Debug, Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    pub fn no_writes(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+/// A library loaded with FUNCTION LOAD
+#[derive(Debug)]
+pub struct Library {
+    pub name: String,
+    pub code: Bytes,
+    pub proto: Arc<FunctionProto>,
+    pub functions: Vec<FunctionInfo>,
+}
+
+impl Library {
+    /// Compiles a library and runs its code to see what functions it registers.
+    /// The code starts with a `#!lua name=<library>` line. Errors are the replies
+    /// to send.
+    pub fn load(code: &Bytes) -> Result<Library, String> {
+        let shebang = code.strip_prefix(b"#!").ok_or("ERR Missing library metadata")?;
+        let end = shebang.iter().position(|&b| b == b'\n').unwrap_or(shebang.len());
+        let line = String::from_utf8_lossy(&shebang[..end]);
+        let mut parts = line.split(' ').filter(|part| !part.is_empty());
+        let engine = parts.next().unwrap_or_default();
+        if !engine.eq_ignore_ascii_case("lua") {
+            return Err(format!("ERR Engine '{}' not found", engine));
+        }
+        let mut name = None;
+        for part in parts {
+            match part.strip_prefix("name=") {
+                Some(value) => name = Some(value.to_string()),
+                None => return Err(format!("ERR Invalid metadata value given: {}", part)),
+            }
+        }
+        let name = name.ok_or("ERR Library name was not given")?;
+        if !valid_name(&name) {
+            return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at \
+                        least one character long"
+                .to_string());
+        }
+        let source = &shebang[end..];
+        let functions: Result<_, String> = on_script_thread(|| {
+            let proto = lua::compile("user_function", source)
+                .map_err(|e| format!("ERR Error compiling function: {}", e))?;
+            let mut host = LoadHost {
+                started: Instant::now(),
+            };
+            let mut runtime = Runtime::new("user_function", &mut host);
+            if let Value::Table(redis) = runtime.global("redis") {
+                let mut redis = redis.borrow_mut();
+                redis.set_str("call", Value::Nil);
+                redis.set_str("pcall", Value::Nil);
+            }
+            let registered = register_functions(&mut runtime, &proto).map_err(|e| match e {
+                LuaError::Interrupted(message) => message,
+                LuaError::Error(error) => {
+                    let message = error.to_bytes().unwrap_or_else(|| Bytes::from(error.type_name()));
+                    format!("ERR Error registering functions: {}", String::from_utf8_lossy(&message))
+                }
+            })?;
+            let functions: Vec<FunctionInfo> = registered
+                .into_iter()
+                .map(|registration| FunctionInfo {
+                    name: registration.name,
+                    description: registration.description,
+                    flags: registration.flags,
+                })
+                .collect();
+            Ok((proto, functions))
+        });
+        let (proto, functions) = functions?;
+        if functions.is_empty() {
+            return Err("ERR No functions registered".to_string());
+        }
+        Ok(Library {
+            name,
+            code: code.clone(),
+            proto,
+            functions,
+        })
+    }
+}
+
+/// The loaded function libraries by name
+#[derive(Debug, Default, Clone)]
+pub struct Libraries {
+    libraries: BTreeMap<String, Arc<Library>>,
+}
+
+impl Libraries {
+    /// Adds a library, replacing one of the same name only if `replace` is set.
+    /// Fails if a function it registers belongs to another library already.
+    pub fn add(&mut self, library: Library, replace: bool) -> Result<(), String> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(format!("ERR Library '{}' already exists", library.name));
+        }
+        for function in &library.functions {
+            if let Some((other, _)) = self.function(&function.name) {
+                if other.name != library.name {
+                    return Err(format!("ERR Function {} already exists", function.name));
+                }
+            }
+        }
+        self.libraries.insert(library.name.clone(), Arc::new(library));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.libraries.remove(name).is_some()
+    }
+
+    /// The library with the function called `name`, and the function
+    pub fn function(&self, name: &str) -> Option<(Arc<Library>, FunctionInfo)> {
+        self.libraries.values().find_map(|library| {
+            let function = library.functions.iter().find(|function| function.name == name)?;
+            Some((Arc::clone(library), function.clone()))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Library>> {
+        self.libraries.values()
+    }
+
+    pub fn clear(&mut self) {
+        self.libraries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.libraries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.libraries.is_empty()
+    }
+
+    pub fn function_count(&self) -> usize {
+        self.libraries.values().map(|library| library.functions.len()).sum()
+    }
+}
+
+/// Why SCRIPT KILL couldn't stop a script
+#[derive(Debug, PartialEq)]
+pub enum KillError {
+    NotBusy,
+    /// The script already wrote, and stopping it halfway would leave its
+    /// changes half done
+    Unkillable,
+}
+
+/// A function running, for FUNCTION STATS
+#[derive(Debug, Clone)]
+pub struct RunningFunction {
+    pub name: String,
+    /// The FCALL that called it
+    pub command: Vec<Bytes>,
+}
+
+/// The script or function running, if any. Only one runs at a time.
+#[derive(Debug, Default)]
+pub struct RunningScript {
+    /// When it started, and what function it is; None for a script
+    started: Mutex<Option<(Instant, Option<RunningFunction>)>>,
+    kill: AtomicBool,
+    wrote: AtomicBool,
+    finished: Notify,
+}
+
+impl RunningScript {
+    pub fn start(&self, function: Option<RunningFunction>) {
+        self.kill.store(false, Ordering::SeqCst);
+        self.wrote.store(false, Ordering::SeqCst);
+        *self.started.lock().unwrap() = Some((Instant::now(), function));
+    }
+
+    pub fn finish(&self) {
+        *self.started.lock().unwrap() = None;
+        self.finished.notify_waiters();
+    }
+
+    /// When the script or function running started
+    pub fn started(&self) -> Option<Instant> {
+        self.started.lock().unwrap().as_ref().map(|(started, _)| *started)
+    }
+
+    /// The function running and how long it's been running
+    pub fn function(&self) -> Option<(RunningFunction, Duration)> {
+        match &*self.started.lock().unwrap() {
+            Some((started, Some(function))) => Some((function.clone(), started.elapsed())),
+            _ => None,
+        }
+    }
+
+    /// Notes that the script has written to the dataset, after which it can't be killed
+    pub fn wrote(&self) {
+        self.wrote.store(true, Ordering::SeqCst);
+    }
+
+    /// Asks the script running to stop, or the function running with `function` set
+    pub fn kill(&self, function: bool) -> Result<(), KillError> {
+        let started = self.started.lock().unwrap();
+        match &*started {
+            Some((_, running)) if running.is_some() == function => {}
+            _ => return Err(KillError::NotBusy),
+        }
+        if self.wrote.load(Ordering::SeqCst) {
+            return Err(KillError::Unkillable);
+        }
+        self.kill.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether SCRIPT KILL or FUNCTION KILL asked the run to stop
+    pub fn killed(&self) -> bool {
+        self.kill.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the script running to finish, for at most `threshold` since it
+    /// started. Returns whether it did.
+    pub async fn wait(&self, threshold: Duration) -> bool {
+        loop {
+            // Created before looking, so a finish in between isn't missed
+            let finished = self.finished.notified();
+            let deadline = match self.started() {
+                Some(started) => started + threshold,
+                None => return true,
+            };
+            if Instant::now() >= deadline {
+                return false;
+            }
+            let _ = tokio::time::timeout_at(deadline.into(), finished).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shebang() {
+        let script = Script::compile(&Bytes::from("#!lua flags=no-writes,allow-stale\nreturn 1")).unwrap();
+        assert!(script.no_writes);
+        assert!(!Script::compile(&Bytes::from("return 1")).unwrap().no_writes);
+        assert_eq!(
+            Script::compile(&Bytes::from("#!lua flags=bogus\nreturn 1")).unwrap_err(),
+            "ERR Unexpected flag in script shebang: bogus"
+        );
+        assert_eq!(
+            Script::compile(&Bytes::from("#!python\nreturn 1")).unwrap_err(),
+            "ERR Unexpected engine in script shebang: python"
+        );
+        assert_eq!(
+            Script::compile(&Bytes::from("#!lua\n\nreturn +")).unwrap_err(),
+            "ERR Error compiling script (new function): user_script:3: unexpected symbol near '+'"
+        );
+    }
+
+    #[test]
+    fn test_kill() {
+        let running = RunningScript::default();
+        assert_eq!(running.kill(false), Err(KillError::NotBusy));
+        running.start(None);
+        assert_eq!(running.kill(true), Err(KillError::NotBusy));
+        assert!(running.kill(false).is_ok());
+        assert!(running.killed());
+        running.start(None);
+        running.wrote();
+        assert_eq!(running.kill(false), Err(KillError::Unkillable));
+        running.finish();
+        assert!(running.started().is_none());
+
+        let function = RunningFunction {
+            name: "f".to_string(),
+            command: vec![Bytes::from("FCALL")],
+        };
+        running.start(Some(function));
+        assert_eq!(running.kill(false), Err(KillError::NotBusy));
+        assert!(running.kill(true).is_ok());
+        assert_eq!(running.function().unwrap().0.name, "f");
+    }
+
+    fn load(code: &str) -> Result<Library, String> {
+        Library::load(&Bytes::from(code.to_string()))
+    }
+
+    #[test]
+    fn test_library_load() {
+        let code = "#!lua name=lib\n\
+                    local function helper() return 1 end\n\
+                    redis.register_function('one', helper)\n\
+                    redis.register_function{function_name = 'two', callback = helper, flags = {'no-writes'}, \
+                    description = 'the second'}";
+        let library = load(code).unwrap();
+        assert_eq!(library.name, "lib");
+        let names: Vec<&str> = library.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["one", "two"]);
+        assert!(!library.functions[0].no_writes());
+        assert!(library.functions[1].no_writes());
+        assert_eq!(library.functions[1].description.as_deref(), Some("the second"));
+
+        let error = |code: &str| load(code).unwrap_err();
+        assert_eq!(error("return 1"), "ERR Missing library metadata");
+        assert_eq!(error("#!js name=lib\n"), "ERR Engine 'js' not found");
+        assert_eq!(error("#!lua\n"), "ERR Library name was not given");
+        assert_eq!(error("#!lua name=lib version=1\n"), "ERR Invalid metadata value given: version=1");
+        assert!(error("#!lua name=a-b\n").starts_with("ERR Library names can only contain"));
+        assert_eq!(error("#!lua name=lib\nlocal x = 1"), "ERR No functions registered");
+        assert_eq!(
+            error("#!lua name=lib\nreturn +"),
+            "ERR Error compiling function: user_function:2: unexpected symbol near '+'"
+        );
+        assert_eq!(
+            error("#!lua name=lib\nredis.call('set', 'k', 'v')"),
+            "ERR Error registering functions: user_function:2: attempt to call field 'call' (a nil value)"
+        );
+        assert_eq!(
+            error("#!lua name=lib\nredis.register_function('f', function() end, 1)"),
+            "ERR Error registering functions: user_function:2: wrong number of arguments to redis.register_function"
+        );
+        assert_eq!(
+            error("#!lua name=lib\nredis.register_function{function_name = 'f', callback = type, flags = {'x'}}"),
+            "ERR Error registering functions: user_function:2: unknown flag given"
+        );
+        assert_eq!(error("#!lua name=lib\nwhile true do end"), "ERR FUNCTION LOAD timeout");
+    }
+
+    #[test]
+    fn test_libraries() {
+        let mut libraries = Libraries::default();
+        let code = |library: &str, function: &str| {
+            format!("#!lua name={}\nredis.register_function('{}', function() end)", library, function)
+        };
+        libraries.add(load(&code("a", "f")).unwrap(), false).unwrap();
+        assert_eq!(libraries.add(load(&code("a", "g")).unwrap(), false), Err("ERR Library 'a' already exists".to_string()));
+        assert_eq!(libraries.add(load(&code("b", "f")).unwrap(), false), Err("ERR Function f already exists".to_string()));
+        libraries.add(load(&code("a", "g")).unwrap(), true).unwrap();
+        libraries.add(load(&code("b", "f")).unwrap(), false).unwrap();
+        assert_eq!(libraries.function("f").unwrap().0.name, "b");
+        assert!(libraries.function("nope").is_none());
+        assert_eq!((libraries.len(), libraries.function_count()), (2, 2));
+        assert!(libraries.remove("a"));
+        assert!(!libraries.remove("a"));
+    }
+}
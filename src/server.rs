@@ -1,64 +1,237 @@
-use bytes::BytesMut;
-use std::collections::HashMap;
+use bytes::{Buf, Bytes, BytesMut};
 use std::fmt::Display;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec;
-use std::{fmt::Write, num::ParseIntError};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::broadcast;
 
-use crate::cast;
+use crate::aof::{self, Aof, AofWriter, FsyncPolicy};
+use crate::backlog::{Backlog, DEFAULT_BACKLOG_SIZE};
+use crate::client::ClientState;
+use crate::db::{Db, Value};
+use crate::glob::glob_match;
 use crate::log::Logger;
-use crate::parser::{self, Command, ParsedCommand, Parser};
+use crate::pubsub::PubSub;
+use crate::scripting::{Libraries, Library, RunningScript, ScriptCache};
+use crate::parser::{
+    parse_float, parse_integer, Command, ExpireCondition, ExpireTime, GetExOption, Parser,
+    RESPError, ScanOptions, SetCondition, SetExpiry, SetOptions, TtlFormat,
+};
+use crate::rdb::{self, RdbEntry, RdbError, RdbFile, RdbValue};
+use crate::set::{Set, MAX_INTSET_ENTRIES};
 
-// Empty RDB file
-const EMPTY_RDB_HEX: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+mod hash;
+mod list;
+mod pubsub;
+mod scripting;
+mod set;
+mod stream;
+mod transaction;
+mod zset;
 
-const PONG_RESP: &[u8; 7] = b"+PONG\r\n";
-const OK_RESP: &[u8; 5] = b"+OK\r\n";
-const NULL_RESP: &[u8; 5] = b"$-1\r\n";
 const DOCS_STRING: &str = "https://github.com/redis/redis-doc/blob/master/commands.md";
+const SERVER_VERSION: &str = "7.2.0";
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+/// Largest string SETRANGE and APPEND may build, as in Redis' proto-max-bulk-len
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+/// Keys with deadlines checked per step of the active expire cycle
+const ACTIVE_EXPIRE_KEYS_PER_STEP: usize = 20;
+/// Longest a single active expire cycle may hold up the server
+const ACTIVE_EXPIRE_CYCLE_BUDGET: Duration = Duration::from_millis(25);
+/// How long to wait before retrying a failed automatic background save
+const BGSAVE_RETRY_DELAY_SECS: u64 = 5;
+/// Largest count HRANDFIELD and SRANDMEMBER take either way, as in Redis
+const MAX_RANDOM_COUNT: u64 = (i64::MAX / 2) as u64;
+/// Bytes of a long reply gathered before writing them out
+const REPLY_CHUNK_SIZE: usize = 64 * 1024;
 // Commands
 
+/// RESP version spoken on a connection, negotiated with HELLO
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum RedisValue {
     String(String),
-    BulkString(String),
+    BulkString(Bytes),
+    Error(String),
     Int(i64),
     Array(Vec<RedisValue>),
     Null,
+    NullArray,
+    // RESP3 types; each has a RESP2 fallback encoding
+    Map(Vec<(RedisValue, RedisValue)>),
+    Set(Vec<RedisValue>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    /// Format (e.g. "txt") and contents
+    Verbatim(String, Bytes),
+    Push(Vec<RedisValue>),
 }
 
 impl RedisValue {
-    pub fn to_response(&self) -> String {
+    /// RESP2 encoding, used for commands sent to other servers
+    pub fn to_response(&self) -> Vec<u8> {
+        self.encode(Protocol::Resp2)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        self.to_response()
+    }
+
+    pub fn encode(&self, protocol: Protocol) -> Vec<u8> {
+        let mut response = Vec::new();
+        self.write_to(&mut response, protocol);
+        response
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
         match self {
-            RedisValue::String(s) => format!("+{}\r\n", s),
-            RedisValue::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s),
-            RedisValue::Int(i) => format!(":{}\r\n", i),
-            RedisValue::Array(a) => {
-                let mut response = String::from("*");
-                response.push_str(format!("{}\r\n", a.len()).as_str());
-                for (_, v) in a.iter().enumerate() {
-                    response.push_str(&v.to_response());
+            RedisValue::String(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            RedisValue::BulkString(s) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s);
+                out.extend_from_slice(b"\r\n");
+            }
+            RedisValue::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            RedisValue::Int(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            RedisValue::Array(a) => write_aggregate(out, b'*', a, protocol),
+            RedisValue::Null if resp3 => out.extend_from_slice(b"_\r\n"),
+            RedisValue::Null => out.extend_from_slice(b"$-1\r\n"),
+            RedisValue::NullArray if resp3 => out.extend_from_slice(b"_\r\n"),
+            RedisValue::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            RedisValue::Map(pairs) => {
+                if resp3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (k, v) in pairs.iter() {
+                    k.write_to(out, protocol);
+                    v.write_to(out, protocol);
                 }
-                response
             }
-            RedisValue::Null => String::from("$-1\r\n"),
+            RedisValue::Set(a) => write_aggregate(out, if resp3 { b'~' } else { b'*' }, a, protocol),
+            RedisValue::Push(a) => write_aggregate(out, if resp3 { b'>' } else { b'*' }, a, protocol),
+            RedisValue::Double(d) if resp3 => {
+                out.extend_from_slice(format!(",{}\r\n", format_double(*d)).as_bytes())
+            }
+            RedisValue::Double(d) => {
+                RedisValue::BulkString(Bytes::from(format_double(*d))).write_to(out, protocol)
+            }
+            RedisValue::Boolean(b) if resp3 => {
+                out.extend_from_slice(if *b { b"#t\r\n" } else { b"#f\r\n" })
+            }
+            RedisValue::Boolean(b) => RedisValue::Int(*b as i64).write_to(out, protocol),
+            RedisValue::BigNumber(n) if resp3 => {
+                out.extend_from_slice(format!("({}\r\n", n).as_bytes())
+            }
+            RedisValue::BigNumber(n) => {
+                RedisValue::BulkString(Bytes::from(n.clone())).write_to(out, protocol)
+            }
+            RedisValue::Verbatim(format, s) if resp3 => {
+                out.extend_from_slice(format!("={}\r\n{}:", s.len() + 4, format).as_bytes());
+                out.extend_from_slice(s);
+                out.extend_from_slice(b"\r\n");
+            }
+            RedisValue::Verbatim(_, s) => RedisValue::BulkString(s.clone()).write_to(out, protocol),
         }
     }
+}
 
-    pub fn as_bytes(&self) -> Vec<u8> {
-        self.to_response().as_bytes().to_owned()
+fn write_aggregate(out: &mut Vec<u8>, prefix: u8, items: &[RedisValue], protocol: Protocol) {
+    out.push(prefix);
+    out.extend_from_slice(format!("{}\r\n", items.len()).as_bytes());
+    for v in items.iter() {
+        v.write_to(out, protocol);
     }
 }
+
+/// Formats a double the way Redis replies with it: integral values without a
+/// fractional part and infinities as `inf`/`-inf`.
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        format!("{}", d)
+    }
+}
+
+/// What HRANDFIELD or SRANDMEMBER picked. Picks that may repeat are drawn as
+/// they're read, so a big count doesn't build a big list.
+#[derive(Debug, PartialEq)]
+pub enum RandomPicks<T> {
+    Distinct(Vec<T>),
+    /// How many to draw from the candidates
+    Repeated(Vec<T>, u64),
+}
+
+impl<T> RandomPicks<T> {
+    pub fn len(&self) -> u64 {
+        match self {
+            RandomPicks::Distinct(picks) => picks.len() as u64,
+            RandomPicks::Repeated(_, count) => *count,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let (candidates, repeated) = match self {
+            RandomPicks::Distinct(picks) => (picks, false),
+            RandomPicks::Repeated(candidates, _) => (candidates, true),
+        };
+        (0..self.len()).map(move |i| {
+            if repeated {
+                &candidates[random_u64() as usize % candidates.len()]
+            } else {
+                &candidates[i as usize]
+            }
+        })
+    }
+}
+
+/// A count HRANDFIELD or SRANDMEMBER can take
+fn check_random_count(count: i64) -> Result<(), RedisValue> {
+    if count.unsigned_abs() > MAX_RANDOM_COUNT {
+        return Err(RedisValue::Error("ERR value is out of range".to_string()));
+    }
+    Ok(())
+}
+
+/// The reply to HRANDFIELD with a count: fields, each followed by its value if asked for
+fn hrandfield_items(picks: &RandomPicks<(Bytes, Bytes)>, with_values: bool) -> impl Iterator<Item = RedisValue> + '_ {
+    picks.iter().flat_map(move |(field, value)| {
+        let value = with_values.then(|| RedisValue::BulkString(value.clone()));
+        std::iter::once(RedisValue::BulkString(field.clone())).chain(value)
+    })
+}
+
 impl Display for RedisValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RedisValue::String(s) => write!(f, "{}", s),
-            RedisValue::BulkString(s) => write!(f, "{}", s),
+            RedisValue::String(s) | RedisValue::BigNumber(s) => write!(f, "{}", s),
+            RedisValue::BulkString(s) | RedisValue::Verbatim(_, s) => {
+                write!(f, "{}", String::from_utf8_lossy(s))
+            }
+            RedisValue::Error(e) => write!(f, "{}", e),
             RedisValue::Int(i) => write!(f, "{}", i),
-            RedisValue::Array(a) => {
+            RedisValue::Double(d) => write!(f, "{}", format_double(*d)),
+            RedisValue::Boolean(b) => write!(f, "{}", b),
+            RedisValue::Array(a) | RedisValue::Set(a) | RedisValue::Push(a) => {
                 let mut s = String::new();
                 s.push('[');
                 for (i, v) in a.iter().enumerate() {
@@ -70,7 +243,19 @@ impl Display for RedisValue {
                 s.push(']');
                 write!(f, "{}", s)
             }
-            RedisValue::Null => write!(f, "null"),
+            RedisValue::Map(pairs) => {
+                let mut s = String::new();
+                s.push('{');
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        s.push(',');
+                    }
+                    s.push_str(&format!("{}:{}", k, v));
+                }
+                s.push('}');
+                write!(f, "{}", s)
+            }
+            RedisValue::Null | RedisValue::NullArray => write!(f, "null"),
         }
     }
 }
@@ -83,17 +268,64 @@ pub struct RedisConfig {
     pub is_replica: bool,
     pub master_replid: String,
     pub master_reploffset: usize, // Number of bytes processed from master
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+    /// Snapshot rules as (seconds, changes): save once at least `changes` writes
+    /// happened and `seconds` passed since the last save
+    pub save_rules: Vec<(u64, u64)>,
+    /// Milliseconds a script may run before other clients are told the server is busy
+    pub busy_reply_threshold: u64,
+    /// Password clients must AUTH with before running commands
+    pub requirepass: Option<String>,
+    /// Password a replica sends its master with AUTH during the handshake
+    pub masterauth: Option<String>,
+    /// Bytes of writes kept for replicas that haven't read them yet
+    pub repl_backlog_size: usize,
 }
 pub struct RedisServer {
     // Need to make thread safe for concurrent access
-    pub db: Mutex<HashMap<String, (RedisValue, Option<Instant>)>>,
+    pub db: Mutex<Db>,
     pub config: RedisConfig,
+    next_client_id: AtomicU64,
+    pub save_state: Arc<SaveState>,
+    /// Open when `appendonly` is enabled
+    pub aof: Option<Arc<Aof>>,
+    pub pubsub: Mutex<PubSub>,
+    /// Shared by clients while they run a command, and held alone by EXEC so a
+    /// transaction runs with nothing in between
+    exec_lock: RwLock<()>,
+    scripts: Mutex<ScriptCache>,
+    /// Libraries loaded with FUNCTION LOAD
+    functions: Mutex<Libraries>,
+    script: RunningScript,
+}
+
+/// Bookkeeping for RDB snapshots, shared with background saves
+#[derive(Debug)]
+pub struct SaveState {
+    /// Unix time in seconds of the last successful save
+    pub last_save: AtomicU64,
+    pub in_progress: AtomicBool,
+    pub last_bgsave_ok: AtomicBool,
+    /// Unix time in seconds of the last background save attempt
+    pub last_bgsave_try: AtomicU64,
+    /// Writes since the last successful save
+    pub dirty: AtomicU64,
+}
+
+impl SaveState {
+    /// Records a successful save of a snapshot taken when the counter was at `dirty`
+    fn saved(&self, dirty: u64) {
+        self.dirty.fetch_sub(dirty, Ordering::SeqCst);
+        self.last_save.store(unix_time_ms() / 1000, Ordering::Relaxed);
+    }
 }
 
 impl RedisServer {
-    pub fn new(args: &Vec<String>) -> RedisServer {
+    pub fn new(args: &[String]) -> RedisServer {
         let mut rs = RedisServer {
-            db: Mutex::new(HashMap::new()),
+            db: Mutex::new(Db::default()),
             config: RedisConfig {
                 dir: ".".to_string(),
                 dbfilename: "dump.rdb".to_string(),
@@ -102,56 +334,845 @@ impl RedisServer {
                 is_replica: false,
                 master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
                 master_reploffset: 0,
+                appendonly: false,
+                appendfilename: "appendonly.aof".to_string(),
+                appendfsync: FsyncPolicy::EverySec,
+                save_rules: Vec::new(),
+                busy_reply_threshold: 5000,
+                requirepass: None,
+                masterauth: None,
+                repl_backlog_size: DEFAULT_BACKLOG_SIZE,
             },
+            next_client_id: AtomicU64::new(1),
+            save_state: Arc::new(SaveState {
+                last_save: AtomicU64::new(unix_time_ms() / 1000),
+                in_progress: AtomicBool::new(false),
+                last_bgsave_ok: AtomicBool::new(true),
+                last_bgsave_try: AtomicU64::new(0),
+                dirty: AtomicU64::new(0),
+            }),
+            aof: None,
+            pubsub: Mutex::new(PubSub::default()),
+            exec_lock: RwLock::new(()),
+            scripts: Mutex::new(ScriptCache::default()),
+            functions: Mutex::new(Libraries::default()),
+            script: RunningScript::default(),
         };
-        rs.parse_command_line(&args);
+        rs.parse_command_line(args);
+        if rs.config.appendonly {
+            let aof = Aof::open(&rs.aof_path(), rs.config.appendfsync)
+                .expect("Failed to open the append only file");
+            rs.aof = Some(Arc::new(aof));
+        }
 
         rs
     }
 
-    pub fn get(&self, key: &str) -> Option<RedisValue> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        // Expired keys are removed on access
+        RedisServer::get_string(&mut db, key)
+    }
+
+    /// SET with its options. Returns the reply and whether the key was written.
+    pub fn set(&self, key: Bytes, value: Bytes, options: &SetOptions) -> (RedisValue, bool) {
+        let now_ms = unix_time_ms();
+        let mut db = self.db.lock().unwrap();
+        let existing = db
+            .get(&key)
+            .map(|(old, expiration)| (options.get.then(|| old.clone()), *expiration));
+        let reply = match &existing {
+            _ if !options.get => RedisValue::String("OK".to_string()),
+            Some((Some(Value::String(old)), _)) => RedisValue::BulkString(old.clone()),
+            Some((Some(_), _)) => return (RedisValue::Error(WRONGTYPE.to_string()), false),
+            _ => RedisValue::Null,
+        };
+        let allowed = match options.condition {
+            Some(SetCondition::Nx) => existing.is_none(),
+            Some(SetCondition::Xx) => existing.is_some(),
+            None => true,
+        };
+        if !allowed {
+            let reply = if options.get { reply } else { RedisValue::Null };
+            return (reply, false);
+        }
+        let expiration = match options.expiry {
+            None => None,
+            Some(SetExpiry::KeepTtl) => existing.and_then(|(_, expiration)| expiration),
+            Some(SetExpiry::Expire(time)) => {
+                let at = time.resolve(now_ms);
+                if at <= now_ms as i64 {
+                    // Already past, e.g. a PXAT replayed from the AOF
+                    db.remove(&key);
+                    return (reply, true);
+                }
+                Some(at as u64)
+            }
+        };
+        db.insert(key, Value::String(value), expiration);
+        (reply, true)
+    }
+
+    /// Gives `key` a new deadline if `condition` allows it; a deadline that already
+    /// passed deletes the key. Returns 1 if the key was changed, 0 otherwise.
+    pub fn expire(&self, key: &[u8], time: ExpireTime, condition: ExpireCondition) -> i64 {
+        let now_ms = unix_time_ms();
+        let at = time.resolve(now_ms);
+        let mut db = self.db.lock().unwrap();
+        let current = match db.get(key) {
+            Some((_, expiration)) => *expiration,
+            None => return 0,
+        };
+        if !condition.allows(current, at) {
+            return 0;
+        }
+        if at <= now_ms as i64 {
+            db.remove(key);
+        } else {
+            db.set_expiration(key, Some(at as u64));
+        }
+        1
+    }
+
+    /// The deadline of `key` in the given format, -1 if it has none or -2 if
+    /// there's no such key
+    pub fn ttl(&self, key: &[u8], format: TtlFormat) -> i64 {
+        let mut db = self.db.lock().unwrap();
+        let at = match db.get(key) {
+            Some((_, Some(at))) => *at,
+            Some((_, None)) => return -1,
+            None => return -2,
+        };
+        let ms = match format {
+            TtlFormat::Seconds | TtlFormat::Millis => at.saturating_sub(unix_time_ms()),
+            TtlFormat::UnixSeconds | TtlFormat::UnixMillis => at,
+        } as i64;
+        match format {
+            TtlFormat::Seconds | TtlFormat::UnixSeconds => (ms + 500) / 1000,
+            TtlFormat::Millis | TtlFormat::UnixMillis => ms,
+        }
+    }
+
+    /// Removes the deadline of `key`. Returns 1 if it had one, 0 otherwise.
+    pub fn persist(&self, key: &[u8]) -> i64 {
+        let mut db = self.db.lock().unwrap();
+        match db.get(key) {
+            Some((_, Some(_))) => {
+                db.set_expiration(key, None);
+                1
+            }
+            _ => 0,
+        }
+    }
+
+    /// The string stored at `key`, or a WRONGTYPE error for other types
+    fn get_string(db: &mut Db, key: &[u8]) -> Result<Option<Bytes>, RedisValue> {
+        match db.get(key) {
+            Some((Value::String(s), _)) => Ok(Some(s.clone())),
+            Some(_) => Err(RedisValue::Error(WRONGTYPE.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the string at `key`, keeping its deadline
+    fn put_string(db: &mut Db, key: &[u8], value: Bytes) {
+        let expiration = db.get(key).and_then(|(_, expiration)| *expiration);
+        db.insert(Bytes::copy_from_slice(key), Value::String(value), expiration);
+    }
+
+    pub fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let current = match RedisServer::get_string(&mut db, key)? {
+            Some(s) => parse_integer(&s).ok_or_else(|| RedisValue::Error(RESPError::NotAnInteger.to_string()))?,
+            None => 0,
+        };
+        let value = current.checked_add(delta).ok_or_else(|| {
+            RedisValue::Error("ERR increment or decrement would overflow".to_string())
+        })?;
+        RedisServer::put_string(&mut db, key, Bytes::from(value.to_string()));
+        Ok(value)
+    }
+
+    pub fn incr_by_float(&self, key: &[u8], delta: f64) -> Result<Bytes, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let current = match RedisServer::get_string(&mut db, key)? {
+            Some(s) => parse_float(&s).ok_or_else(|| RedisValue::Error(RESPError::NotAFloat.to_string()))?,
+            None => 0.0,
+        };
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(RedisValue::Error(
+                "ERR increment would produce NaN or Infinity".to_string(),
+            ));
+        }
+        let value = Bytes::from(format_double(value));
+        RedisServer::put_string(&mut db, key, value.clone());
+        Ok(value)
+    }
+
+    /// Appends to the string at `key`, creating it if needed. Returns the new length.
+    pub fn append(&self, key: &[u8], suffix: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let mut value = RedisServer::get_string(&mut db, key)?.unwrap_or_default().to_vec();
+        if value.len() + suffix.len() > MAX_STRING_LEN {
+            return Err(RedisValue::Error(
+                "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+            ));
+        }
+        value.extend_from_slice(suffix);
+        let len = value.len() as i64;
+        RedisServer::put_string(&mut db, key, Bytes::from(value));
+        Ok(len)
+    }
+
+    pub fn strlen(&self, key: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_string(&mut db, key)?.map_or(0, |s| s.len() as i64))
+    }
+
+    /// The substring between the inclusive offsets `start` and `end`, which count
+    /// from the end when negative
+    pub fn getrange(&self, key: &[u8], start: i64, end: i64) -> Result<Bytes, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let value = RedisServer::get_string(&mut db, key)?.unwrap_or_default();
+        let len = value.len() as i64;
+        if len == 0 || (start < 0 && end < 0 && start > end) {
+            return Ok(Bytes::new());
+        }
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+        if start > end {
+            return Ok(Bytes::new());
+        }
+        Ok(value.slice(start as usize..=end as usize))
+    }
+
+    /// Overwrites part of the string at `key`, padding with zero bytes if it's too
+    /// short. Returns the new length.
+    pub fn setrange(&self, key: &[u8], offset: usize, patch: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let current = RedisServer::get_string(&mut db, key)?;
+        if patch.is_empty() {
+            return Ok(current.map_or(0, |s| s.len() as i64));
+        }
+        if offset + patch.len() > MAX_STRING_LEN {
+            return Err(RedisValue::Error(
+                "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+            ));
+        }
+        let mut value = current.unwrap_or_default().to_vec();
+        if value.len() < offset + patch.len() {
+            value.resize(offset + patch.len(), 0);
+        }
+        value[offset..offset + patch.len()].copy_from_slice(patch);
+        let len = value.len() as i64;
+        RedisServer::put_string(&mut db, key, Bytes::from(value));
+        Ok(len)
+    }
+
+    pub fn getdel(&self, key: &[u8]) -> Result<Option<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let value = RedisServer::get_string(&mut db, key)?;
+        if value.is_some() {
+            db.remove(key);
+        }
+        Ok(value)
+    }
+
+    /// GET that can also change the key's deadline
+    pub fn getex(&self, key: &[u8], option: Option<GetExOption>) -> Result<Option<Bytes>, RedisValue> {
+        let now_ms = unix_time_ms();
         let mut db = self.db.lock().unwrap();
-        let res = db.get(key).cloned();
-        // Check  if the key has an expiration time and if it has expired
-        if let Some((value, expiration)) = res.as_ref() {
-            if let Some(expiration) = expiration {
-                if Instant::now() > *expiration {
-                    // Key has expired, remove it from the database
+        let value = RedisServer::get_string(&mut db, key)?;
+        match option {
+            _ if value.is_none() => {}
+            None => {}
+            Some(GetExOption::Persist) => {
+                db.set_expiration(key, None);
+            }
+            Some(GetExOption::Expire(time)) => {
+                let at = time.resolve(now_ms);
+                if at <= now_ms as i64 {
                     db.remove(key);
-                    return None;
+                } else {
+                    db.set_expiration(key, Some(at as u64));
                 }
-                return Some(value.clone());
-            } else {
-                return Some(value.clone());
             }
         }
-        None
+        Ok(value)
+    }
+
+    /// Values of the given keys, with nil for missing keys and ones that aren't strings
+    pub fn mget(&self, keys: &[Bytes]) -> Vec<RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        keys.iter()
+            .map(|key| match RedisServer::get_string(&mut db, key) {
+                Ok(Some(value)) => RedisValue::BulkString(value),
+                _ => RedisValue::Null,
+            })
+            .collect()
+    }
+
+    /// Sets every pair, clearing deadlines. With `nx`, sets nothing if any key
+    /// exists. Returns whether the keys were set.
+    pub fn mset(&self, pairs: &[(Bytes, Bytes)], nx: bool) -> bool {
+        let mut db = self.db.lock().unwrap();
+        if nx && pairs.iter().any(|(key, _)| db.contains(key)) {
+            return false;
+        }
+        for (key, value) in pairs {
+            db.insert(key.clone(), Value::String(value.clone()), None);
+        }
+        true
+    }
+
+    /// Deletes the given keys, returning how many existed
+    pub fn del(&self, keys: &[Bytes]) -> i64 {
+        let mut db = self.db.lock().unwrap();
+        keys.iter().filter(|key| db.contains(key) && db.remove(key).is_some()).count() as i64
     }
 
-    pub fn set(&self, key: &str, value: RedisValue, duration: Option<Duration>) {
+    /// How many of the given keys exist; a key named twice counts twice
+    pub fn exists(&self, keys: &[Bytes]) -> i64 {
         let mut db = self.db.lock().unwrap();
-        let mut ttl = None;
-        if duration.is_some() {
-            ttl = Some(Instant::now() + duration.unwrap());
+        keys.iter().filter(|key| db.contains(key)).count() as i64
+    }
+
+    pub fn key_type(&self, key: &[u8]) -> &'static str {
+        let mut db = self.db.lock().unwrap();
+        db.get(key).map_or("none", |(value, _)| value.type_name())
+    }
+
+    /// How the value at `key` would be encoded by Redis, as in OBJECT ENCODING
+    pub fn object_encoding(&self, key: &[u8]) -> Option<&'static str> {
+        let mut db = self.db.lock().unwrap();
+        let encoding = match db.get(key)? {
+            (Value::String(s), _) if s.len() <= 20 && parse_integer(s).is_some() => "int",
+            (Value::String(s), _) if s.len() <= 44 => "embstr",
+            (Value::String(_), _) => "raw",
+            (Value::List(items), _) if items.len() <= 128 => "listpack",
+            (Value::List(_), _) => "quicklist",
+            (Value::Hash(pairs), _) if pairs.len() <= 128 => "listpack",
+            (Value::Hash(_), _) => "hashtable",
+            (Value::Set(set), _) => set.encoding(),
+            (Value::ZSet(zset), _) => zset.encoding(),
+            (Value::Stream(_), _) => "stream",
+        };
+        Some(encoding)
+    }
+
+    /// Moves `from` to `to` along with its deadline. With `nx`, only if `to` doesn't
+    /// exist; returns whether the key moved.
+    pub fn rename(&self, from: &[u8], to: &Bytes, nx: bool) -> Result<bool, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        if !db.contains(from) {
+            return Err(RedisValue::Error("ERR no such key".to_string()));
+        }
+        if nx && db.contains(to) {
+            return Ok(false);
+        }
+        if from != &to[..] {
+            let (value, expiration) = db.remove(from).unwrap();
+            db.insert(to.clone(), value, expiration);
+        }
+        Ok(true)
+    }
+
+    /// Copies `source` and its deadline to `destination`, which must not exist unless
+    /// `replace` is set. Returns whether anything was copied.
+    pub fn copy(&self, source: &[u8], destination: &Bytes, replace: bool) -> bool {
+        let mut db = self.db.lock().unwrap();
+        if source == &destination[..] {
+            return false;
+        }
+        let (value, expiration) = match db.get(source) {
+            Some(entry) => entry.clone(),
+            None => return false,
+        };
+        if !replace && db.contains(destination) {
+            return false;
+        }
+        db.insert(destination.clone(), value, expiration);
+        true
+    }
+
+    /// One step of a SCAN iteration: the next cursor and the matching keys
+    pub fn scan(&self, cursor: u64, options: &ScanOptions) -> (u64, Vec<Bytes>) {
+        let db = self.db.lock().unwrap();
+        let (next, batch) = db.scan(cursor, options.count, unix_time_ms());
+        let keys = batch
+            .into_iter()
+            .filter(|(key, _)| {
+                options
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, key, false))
+            })
+            .filter(|(_, (value, _))| {
+                options
+                    .type_name
+                    .as_ref()
+                    .is_none_or(|type_name| value.type_name() == type_name)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        (next, keys)
+    }
+
+    pub fn random_key(&self) -> Option<Bytes> {
+        let db = self.db.lock().unwrap();
+        db.random_key(unix_time_ms()).cloned()
+    }
+
+    /// Number of keys, counting ones that expired but haven't been removed yet,
+    /// as Redis does
+    pub fn dbsize(&self) -> i64 {
+        self.db.lock().unwrap().len() as i64
+    }
+
+    /// Deletes every key, returning how many there were
+    pub fn flush(&self) -> u64 {
+        let mut db = self.db.lock().unwrap();
+        let removed = db.len() as u64;
+        db.clear();
+        removed
+    }
+
+    /// Keys matching the glob `pattern`, skipping any that have expired
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Bytes> {
+        let db = self.db.lock().unwrap();
+        db.iter_live(unix_time_ms())
+            .filter(|(key, _)| glob_match(pattern, key, false))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Values of the configuration parameters matching any of the glob `patterns`
+    pub fn config_get(&self, patterns: &[Bytes]) -> RedisValue {
+        let params = [
+            ("dir", self.config.dir.clone()),
+            ("dbfilename", self.config.dbfilename.clone()),
+            ("port", self.config.port.to_string()),
+            (
+                "appendonly",
+                if self.config.appendonly { "yes" } else { "no" }.to_string(),
+            ),
+            ("appendfilename", self.config.appendfilename.clone()),
+            ("appendfsync", self.config.appendfsync.name().to_string()),
+            (
+                "save",
+                self.config
+                    .save_rules
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            ("busy-reply-threshold", self.config.busy_reply_threshold.to_string()),
+            ("lua-time-limit", self.config.busy_reply_threshold.to_string()),
+            ("requirepass", self.config.requirepass.clone().unwrap_or_default()),
+            ("masterauth", self.config.masterauth.clone().unwrap_or_default()),
+            ("repl-backlog-size", self.config.repl_backlog_size.to_string()),
+        ];
+        let pairs = params
+            .into_iter()
+            .filter(|(name, _)| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern, name.as_bytes(), true))
+            })
+            .map(|(name, value)| {
+                (
+                    RedisValue::BulkString(Bytes::from(name)),
+                    RedisValue::BulkString(Bytes::from(value)),
+                )
+            })
+            .collect();
+        RedisValue::Map(pairs)
+    }
+
+    fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.config.dir).join(&self.config.dbfilename)
+    }
+
+    fn aof_path(&self) -> PathBuf {
+        Path::new(&self.config.dir).join(&self.config.appendfilename)
+    }
+
+    /// Loads the snapshot at `dir/dbfilename` if there is one.
+    ///
+    /// Returns the number of keys loaded; keys that already expired are skipped.
+    pub fn load_rdb(&self) -> Result<usize, RdbError> {
+        let contents = match fs::read(self.rdb_path()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(RdbError::Io(e.to_string())),
+        };
+        self.load_rdb_bytes(&contents)
+    }
+
+    /// Replaces the whole dataset with the contents of an RDB file.
+    pub fn load_rdb_bytes(&self, contents: &[u8]) -> Result<usize, RdbError> {
+        self.load_rdb_file(rdb::parse_rdb(contents)?)
+    }
+
+    fn load_rdb_file(&self, rdb: RdbFile) -> Result<usize, RdbError> {
+        let mut functions = Libraries::default();
+        for code in &rdb.functions {
+            let library = Library::load(code).map_err(RdbError::BadFunction)?;
+            functions.add(library, false).map_err(RdbError::BadFunction)?;
+        }
+        *self.functions.lock().unwrap() = functions;
+        let now_ms = unix_time_ms();
+        let mut db = self.db.lock().unwrap();
+        db.clear();
+        let mut loaded = 0;
+        for entry in rdb.entries {
+            // Only a single database is supported
+            if entry.db != 0 {
+                continue;
+            }
+            if entry.expires_at_ms.is_some_and(|at| at <= now_ms) {
+                continue;
+            }
+            let value = match entry.value {
+                RdbValue::String(s) => Value::String(s),
+                RdbValue::List(items) => Value::List(items.into()),
+                RdbValue::Hash(pairs) => Value::Hash(pairs.into_iter().collect()),
+                RdbValue::Set(members) => Value::Set(members.into_iter().collect()),
+                RdbValue::IntSet(ints) if ints.len() <= MAX_INTSET_ENTRIES => Value::Set(Set::Ints(ints)),
+                RdbValue::IntSet(ints) => {
+                    Value::Set(ints.into_iter().map(|n| Bytes::from(n.to_string())).collect())
+                }
+                RdbValue::SortedSet(pairs) => {
+                    Value::ZSet(pairs.into_iter().filter(|(_, score)| !score.is_nan()).collect())
+                }
+                RdbValue::Stream(stream) => Value::Stream(stream),
+            };
+            db.insert(entry.key, value, entry.expires_at_ms);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Rebuilds the dataset from the append only file: an optional RDB preamble left
+    /// by the last rewrite, followed by the write commands logged since.
+    ///
+    /// A command cut off at the end of the file, as left by a crash mid-write, is
+    /// dropped and the file truncated to the last complete command. So is a
+    /// transaction missing its EXEC, as a whole. Returns the number of commands
+    /// replayed.
+    pub fn load_aof(&self, logger: &Logger) -> Result<usize, RdbError> {
+        let path = self.aof_path();
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(RdbError::Io(e.to_string())),
+        };
+        let mut offset = 0;
+        if contents.starts_with(b"REDIS") {
+            let (rdb, len) = rdb::parse_rdb_prefix(&contents)?;
+            let loaded = self.load_rdb_file(rdb)?;
+            logger.log(&format!("Loaded {} keys from the AOF preamble", loaded));
+            offset = len;
+        }
+
+        let mut buf = BytesMut::from(&contents[offset..]);
+        let mut replayed = 0;
+        let mut truncate_at = None;
+        // Where the open transaction's MULTI is, and what it has queued so far
+        let mut transaction: Option<(usize, Vec<Command>)> = None;
+        while !buf.is_empty() {
+            let commands = Parser::parse_commands(logger, &buf)
+                .map_err(|e| RdbError::Io(format!("Bad file format reading the append only file: {}", e)))?;
+            if commands.is_empty() {
+                logger.log(&format!(
+                    "!!! Warning: short read while loading the AOF. Truncating the AOF at offset {}",
+                    offset
+                ));
+                truncate_at = Some(offset);
+                break;
+            }
+            for command in commands {
+                match (command.command, transaction.as_mut()) {
+                    (Command::Multi, _) => transaction = Some((offset, Vec::new())),
+                    (Command::Exec, Some((_, queued))) => {
+                        for command in queued.drain(..) {
+                            self.execute(logger, &command);
+                        }
+                        transaction = None;
+                    }
+                    (other, Some((_, queued))) => queued.push(other),
+                    (other, None) => {
+                        self.execute(logger, &other);
+                    }
+                }
+                buf.advance(command.bytes_read);
+                offset += command.bytes_read;
+                replayed += 1;
+            }
+        }
+        if let Some((start, _)) = transaction {
+            logger.log(&format!(
+                "!!! Warning: revert incomplete MULTI/EXEC transaction in the AOF. Truncating the AOF at offset {}",
+                start
+            ));
+            truncate_at = Some(start);
+        }
+        if let Some(offset) = truncate_at {
+            aof::truncate(&path, offset as u64).map_err(|e| RdbError::Io(e.to_string()))?;
         }
-        db.insert(key.to_string(), (value, ttl));
+        Ok(replayed)
     }
 
+    /// Copies the live dataset into RDB entries. Only the copy happens under the
+    /// lock; encoding and writing can then proceed without blocking clients.
+    fn snapshot_entries(&self) -> Vec<RdbEntry> {
+        let db = self.db.lock().unwrap();
+        db.iter_live(unix_time_ms())
+            .map(|(key, (value, expiration))| {
+                let value = match value {
+                    Value::String(s) => RdbValue::String(s.clone()),
+                    Value::List(items) => RdbValue::List(items.iter().cloned().collect()),
+                    Value::Hash(pairs) => RdbValue::Hash(
+                        pairs
+                            .iter()
+                            .map(|(field, value)| (field.clone(), value.clone()))
+                            .collect(),
+                    ),
+                    Value::Set(Set::Ints(ints)) => RdbValue::IntSet(ints.clone()),
+                    Value::Set(set) => RdbValue::Set(set.members()),
+                    Value::ZSet(zset) => RdbValue::SortedSet(zset.iter().collect()),
+                    Value::Stream(stream) => RdbValue::Stream(stream.clone()),
+                };
+                RdbEntry {
+                    db: 0,
+                    key: key.clone(),
+                    value,
+                    expires_at_ms: *expiration,
+                }
+            })
+            .collect()
+    }
+
+    fn encode_snapshot(functions: &[Bytes], entries: &[RdbEntry]) -> Vec<u8> {
+        let aux = [
+            ("redis-ver", SERVER_VERSION.to_string()),
+            ("redis-bits", "64".to_string()),
+            ("ctime", (unix_time_ms() / 1000).to_string()),
+        ];
+        rdb::encode_rdb(&aux, functions, entries)
+    }
+
+    /// Serializes the current dataset, as sent to replicas on a full resync
+    pub fn rdb_dump(&self) -> Vec<u8> {
+        RedisServer::encode_snapshot(&self.function_codes(), &self.snapshot_entries())
+    }
+
+    /// Synchronously writes the dataset to `dir/dbfilename`
+    pub fn save(&self) -> Result<(), RdbError> {
+        let dirty = self.save_state.dirty.load(Ordering::SeqCst);
+        let result = rdb::write_rdb_file(&self.rdb_path(), &self.rdb_dump());
+        if result.is_ok() {
+            self.save_state.saved(dirty);
+        }
+        result
+    }
+
+    /// Starts writing the dataset in the background. Returns false if a background
+    /// save is already running.
+    pub fn bgsave(&self, logger: &Logger) -> bool {
+        if self.save_state.in_progress.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        self.save_state
+            .last_bgsave_try
+            .store(unix_time_ms() / 1000, Ordering::Relaxed);
+        // Writes made after this point aren't in the snapshot and stay dirty
+        let dirty = self.save_state.dirty.load(Ordering::SeqCst);
+        let functions = self.function_codes();
+        let entries = self.snapshot_entries();
+        let path = self.rdb_path();
+        let state = Arc::clone(&self.save_state);
+        let logger = logger.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = rdb::write_rdb_file(&path, &RedisServer::encode_snapshot(&functions, &entries));
+            match &result {
+                Ok(()) => {
+                    logger.log("Background saving terminated with success");
+                    state.saved(dirty);
+                }
+                Err(e) => logger.log(&format!("Background saving error: {}", e)),
+            }
+            state.last_bgsave_ok.store(result.is_ok(), Ordering::Relaxed);
+            state.in_progress.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    /// Deletes expired keys that nobody reads. Called periodically.
+    ///
+    /// Like Redis, this checks keys with deadlines in small steps and keeps going
+    /// while more than a quarter of each step turns out to be expired, until the
+    /// time budget runs out. The lock is released between steps so clients aren't
+    /// held up for long. Returns the number of keys deleted.
+    pub fn active_expire_cycle(&self) -> usize {
+        let start = Instant::now();
+        let mut deleted = 0;
+        loop {
+            let step = self.unless_busy(|| {
+                self.db
+                    .lock()
+                    .unwrap()
+                    .expire_step(ACTIVE_EXPIRE_KEYS_PER_STEP, unix_time_ms())
+            });
+            let (checked, expired) = match step {
+                Some(step) => step,
+                None => return deleted,
+            };
+            deleted += expired;
+            if checked == 0
+                || expired * 4 <= checked
+                || start.elapsed() >= ACTIVE_EXPIRE_CYCLE_BUDGET
+            {
+                return deleted;
+            }
+        }
+    }
+
+    /// Starts a background save if any `save` rule is satisfied. Called periodically.
+    ///
+    /// After a failed background save, the next attempt waits a few seconds rather
+    /// than retrying on every call.
+    pub fn save_if_due(&self, logger: &Logger) {
+        let state = &self.save_state;
+        if state.in_progress.load(Ordering::SeqCst) {
+            return;
+        }
+        let now = unix_time_ms() / 1000;
+        let dirty = state.dirty.load(Ordering::SeqCst);
+        let since_save = now.saturating_sub(state.last_save.load(Ordering::Relaxed));
+        let retry_ok = state.last_bgsave_ok.load(Ordering::Relaxed)
+            || now.saturating_sub(state.last_bgsave_try.load(Ordering::Relaxed))
+                >= BGSAVE_RETRY_DELAY_SECS;
+        let rule = self
+            .config
+            .save_rules
+            .iter()
+            .find(|(seconds, changes)| dirty >= *changes && since_save >= *seconds);
+        if let (Some((seconds, changes)), true) = (rule, retry_ok) {
+            logger.log(&format!(
+                "{} changes in {} seconds. Saving...",
+                changes, seconds
+            ));
+            self.unless_busy(|| self.bgsave(logger));
+        }
+    }
+
+    /// Starts compacting the append only file in the background. The dataset is
+    /// copied while holding the log, so every write lands either in the snapshot or
+    /// in the rewrite buffer.
+    pub fn bgrewriteaof(&self, logger: &Logger) -> RedisValue {
+        let aof = match &self.aof {
+            Some(aof) => Arc::clone(aof),
+            None => return RedisValue::Error("ERR Append only file is disabled".to_string()),
+        };
+        let (functions, entries) = {
+            let mut writer = aof.lock();
+            if writer.is_rewriting() {
+                return RedisValue::Error(
+                    "ERR Background append only file rewriting already in progress".to_string(),
+                );
+            }
+            writer.start_rewrite();
+            (self.function_codes(), self.snapshot_entries())
+        };
+        let logger = logger.clone();
+        tokio::task::spawn_blocking(move || {
+            let base = RedisServer::encode_snapshot(&functions, &entries);
+            let mut writer = aof.lock();
+            match writer.finish_rewrite(&base) {
+                Ok(()) => logger.log("Background AOF rewrite finished successfully"),
+                Err(e) => {
+                    writer.abort_rewrite();
+                    logger.log(&format!("Background AOF rewrite error: {}", e));
+                }
+            }
+        });
+        RedisValue::String("Background append only file rewriting started".to_string())
+    }
+
+    /// INFO output for `section`, or every section for "default", "all" and
+    /// "everything". Unknown sections give an empty reply.
     pub fn info(&self, section: &str) -> RedisValue {
-        match section {
+        let section = section.to_lowercase();
+        let all = matches!(section.as_str(), "default" | "all" | "everything");
+        let text = ["persistence", "stats", "replication"]
+            .into_iter()
+            .filter(|name| all || *name == section)
+            .filter_map(|name| self.info_section(name))
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        RedisValue::Verbatim("txt".to_string(), Bytes::from(text))
+    }
+
+    fn info_section(&self, section: &str) -> Option<String> {
+        let fields = match section {
+            "persistence" => {
+                let state = &self.save_state;
+                let status = |ok: bool| if ok { "ok" } else { "err" };
+                let rewriting = self
+                    .aof
+                    .as_ref()
+                    .is_some_and(|aof| aof.lock().is_rewriting());
+                vec![
+                    ("loading", "0".to_string()),
+                    (
+                        "rdb_changes_since_last_save",
+                        state.dirty.load(Ordering::SeqCst).to_string(),
+                    ),
+                    (
+                        "rdb_bgsave_in_progress",
+                        (state.in_progress.load(Ordering::SeqCst) as u8).to_string(),
+                    ),
+                    (
+                        "rdb_last_save_time",
+                        state.last_save.load(Ordering::Relaxed).to_string(),
+                    ),
+                    (
+                        "rdb_last_bgsave_status",
+                        status(state.last_bgsave_ok.load(Ordering::Relaxed)).to_string(),
+                    ),
+                    ("aof_enabled", (self.config.appendonly as u8).to_string()),
+                    ("aof_rewrite_in_progress", (rewriting as u8).to_string()),
+                ]
+            }
+            "stats" => vec![(
+                "expired_keys",
+                self.db.lock().unwrap().expired_keys.to_string(),
+            )],
             "replication" => {
                 let role = if self.config.master_host_port.is_some() {
                     "slave"
                 } else {
                     "master"
                 };
-                RedisValue::String(format!(
-                    "role:{}\nmaster_replid:{}\nmaster_repl_offset:{}",
-                    role, self.config.master_replid, self.config.master_reploffset
-                ))
+                vec![
+                    ("role", role.to_string()),
+                    ("master_replid", self.config.master_replid.clone()),
+                    (
+                        "master_repl_offset",
+                        self.config.master_reploffset.to_string(),
+                    ),
+                ]
             }
-            _ => RedisValue::Null,
+            _ => return None,
+        };
+        let mut text = format!("# {}{}\r\n", section[..1].to_uppercase(), &section[1..]);
+        for (name, value) in fields {
+            text.push_str(&format!("{}:{}\r\n", name, value));
         }
+        Some(text)
     }
 
     async fn reply(
@@ -165,131 +1186,1100 @@ impl RedisServer {
             return;
         }
         logger.log(&format!("Sending Reply: {}", String::from_utf8_lossy(resp)));
-        stream
-            .write_all(resp)
-            .await
-            .expect("failed to write to stream");
+        if let Err(e) = stream.write_all(resp).await {
+            logger.log(&format!("Failed to write reply: {}", e));
+        }
+    }
+
+    /// Writes an array of `len` items a chunk at a time, rather than encoding it whole
+    async fn reply_array(
+        &self,
+        stream: &mut tokio::net::TcpStream,
+        protocol: Protocol,
+        len: u64,
+        items: impl Iterator<Item = RedisValue>,
+    ) -> Result<(), RESPError> {
+        let mut chunk = format!("*{}\r\n", len).into_bytes();
+        for item in items {
+            item.write_to(&mut chunk, protocol);
+            if chunk.len() >= REPLY_CHUNK_SIZE {
+                stream.write_all(&chunk).await.map_err(|e| RESPError::IOError(e.to_string()))?;
+                chunk.clear();
+            }
+        }
+        stream.write_all(&chunk).await.map_err(|e| RESPError::IOError(e.to_string()))
     }
 
+    /// Runs every complete command at the front of `bm` and drops the consumed bytes,
+    /// leaving any partially received frame in place for the next read.
+    ///
+    /// Returns the number of bytes processed, or the protocol error that was sent to the
+    /// client, after which the connection should be closed.
     pub async fn evaluate(
         &self,
         logger: &Logger,
-        bm: BytesMut,
+        bm: &mut BytesMut,
         stream: &mut tokio::net::TcpStream,
-        tx: Option<Arc<broadcast::Sender<String>>>,
+        client: &mut ClientState,
+        tx: Option<Arc<Backlog>>,
         already_processed_bytes: usize,
-    ) -> usize {
-        let commands = Parser::parse_commands(logger, &bm).expect("Failed to parse commands");
+    ) -> Result<usize, RESPError> {
+        let commands = match Parser::parse_commands(logger, bm) {
+            Ok(commands) => commands,
+            Err(e) => {
+                logger.log(&format!("Failed to parse commands: {}", e));
+                let error_resp = RedisValue::Error(e.to_string());
+                self.reply(logger, stream, &error_resp.encode(client.protocol), false)
+                    .await;
+                bm.clear();
+                return Err(e);
+            }
+        };
         let mut processed_bytes = 0;
         for command in commands {
-            match &command.command {
-                Command::Ping => {
-                    self.reply(&logger, stream, PONG_RESP, self.config.is_replica)
-                        .await;
+            let raw = &bm[processed_bytes..processed_bytes + command.bytes_read];
+            let subscribed = client.is_subscribed() && client.protocol == Protocol::Resp2;
+            // Replicated writes wait their turn however long a script takes
+            let busy = match &command.command {
+                Command::ScriptKill | Command::FunctionKill | Command::FunctionStats | Command::Auth { .. } => None,
+                _ if client.is_master_link => None,
+                _ => self.wait_for_script().await,
+            };
+            let response = match &command.command {
+                _ if busy.is_some() => busy,
+                Command::Hello { auth: None, .. } if !self.is_authenticated(client) => Some(RedisValue::Error(
+                    "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> \
+                     AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol \
+                     version at the same time"
+                        .to_string(),
+                )),
+                other
+                    if !self.is_authenticated(client)
+                        && !matches!(other, Command::Auth { .. } | Command::Hello { .. } | Command::Invalid(_)) =>
+                {
+                    Some(RedisValue::Error("NOAUTH Authentication required.".to_string()))
                 }
-                Command::Echo(s) => {
-                    let echo_resp = RedisValue::BulkString(s.clone());
-                    self.reply(&logger, stream, &echo_resp.as_bytes(), false)
-                        .await;
+                queued if client.transaction.is_some()
+                    && !matches!(
+                        queued,
+                        Command::Multi
+                            | Command::Exec
+                            | Command::Discard
+                            | Command::Watch(_)
+                            | Command::ReplConf(_)
+                            | Command::Unknown
+                    ) =>
+                {
+                    Some(self.queue(client, queued, raw))
                 }
-                Command::Set(key, value, duration) => {
-                    if !self.config.is_replica {
-                        let s = String::from_utf8_lossy(&bm);
-                        logger.log(&format!("master received set command: {}", s));
-                        tx.as_ref()
-                            .unwrap()
-                            .send(s.into_owned())
-                            .expect("failed to send to broadcast");
-                    }
-                    // TODO: In the future, we don't have to assume it's a string
-                    self.set(&key, RedisValue::String(value.to_owned()), duration.to_owned());
-                    self.reply(&logger, stream, OK_RESP, self.config.is_replica)
-                        .await;
+                Command::Invalid(e) => Some(RedisValue::Error(e.to_string())),
+                other if subscribed && !other.allowed_while_subscribed() => Some(RedisValue::Error(format!(
+                    "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING are allowed in this context",
+                    command.name
+                ))),
+                Command::Ping(message) if subscribed => Some(RedisValue::Array(vec![
+                    RedisValue::BulkString(Bytes::from("pong")),
+                    RedisValue::BulkString(message.clone().unwrap_or_default()),
+                ])),
+                subscription @ (Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::SSubscribe(_)
+                | Command::SUnsubscribe(_)) => {
+                    let frames = self.subscription_command(client, subscription);
+                    let encoded: Vec<u8> = frames.iter().flat_map(|frame| frame.encode(client.protocol)).collect();
+                    self.reply(logger, stream, &encoded, client.is_master_link).await;
+                    None
                 }
-                Command::Get(key) => {
-                    if let Some(value) = self.get(&key) {
-                        self.reply(&logger, stream, &value.as_bytes(), false)
-                            .await;
-                    } else {
-                        self.reply(&logger, stream, NULL_RESP, false)
-                            .await;
-                    }
+                Command::Block { keys, op, timeout } => {
+                    Some(self.block(logger, keys, op, *timeout, stream, &tx).await)
                 }
-                Command::Info(section) => {
-                    self.reply(
-                        &logger,
-                        stream,
-                        &self.info(&section).as_bytes(),
-                        false,
-                    )
-                    .await;
-                }
-                Command::ReplConf(args) => match args[0].as_str() {
-                    "getack" => {
-                        if args[1].as_str() == "*" {
-                            let response_command = RedisValue::Array(vec![
-                                RedisValue::BulkString("REPLCONF".to_string()),
-                                RedisValue::BulkString("ACK".to_string()),
-                                RedisValue::BulkString( (processed_bytes + already_processed_bytes).to_string()),
-                            ]);
-                            let response = response_command.to_response();
-                            self.reply(&logger, stream, response.as_bytes(), false)
-                                .await;
-                        } else {
-                            unimplemented!("Only support REPLCONF ACK * for now");
-                        }
-                    }
-                    "ack" => {
-                        logger.log(&format!("Received an REPLCONF ACK from replica"));
+                Command::XRead {
+                    count,
+                    block: Some(timeout),
+                    keys,
+                    ids,
+                } => Some(self.xread_block(logger, keys, ids, *count, *timeout, stream).await),
+                Command::XReadGroup {
+                    group,
+                    consumer,
+                    count,
+                    block: Some(timeout),
+                    noack,
+                    keys,
+                    ids,
+                } => Some(
+                    self.xreadgroup_block(logger, group, consumer, keys, ids, *count, *noack, *timeout, stream, &tx)
+                        .await,
+                ),
+                Command::Multi => Some(self.multi(client)),
+                // EXEC waits for exec_lock the way scripts do, off the runtime's workers
+                Command::Exec => Some(tokio::task::block_in_place(|| self.exec(logger, client, &tx))),
+                Command::Discard => Some(self.discard(client)),
+                Command::Watch(keys) => Some(self.watch(client, keys)),
+                Command::Unwatch => Some(self.unwatch(client)),
+                Command::Eval {
+                    script,
+                    keys,
+                    args,
+                    read_only,
+                } => Some(tokio::task::block_in_place(|| {
+                    let _exclusive = self.exec_lock.write().unwrap();
+                    self.eval(logger, script, keys, args, *read_only, &tx, false)
+                })),
+                Command::FCall {
+                    function,
+                    keys,
+                    args,
+                    read_only,
+                } => Some(tokio::task::block_in_place(|| {
+                    let _exclusive = self.exec_lock.write().unwrap();
+                    self.fcall(logger, function, keys, args, *read_only, &tx, false)
+                })),
+                // The script running holds exec_lock
+                Command::ScriptKill => Some(self.script_kill()),
+                Command::FunctionKill => Some(self.function_kill()),
+                Command::FunctionStats => Some(self.function_stats()),
+                write if write.is_write() => Some(self.outside_exec(|| self.execute_write(logger, write, raw, &tx))),
+                Command::ReplConf(args) => match args.first().map(String::as_str) {
+                    Some("getack") => {
+                        let response_command = RedisValue::Array(vec![
+                            RedisValue::BulkString(Bytes::from("REPLCONF")),
+                            RedisValue::BulkString(Bytes::from("ACK")),
+                            RedisValue::BulkString(Bytes::from(
+                                (processed_bytes + already_processed_bytes).to_string(),
+                            )),
+                        ]);
+                        // The master asks for this one, so it's sent even on the master link
+                        self.reply(logger, stream, &response_command.to_response(), false)
+                            .await;
+                        None
                     }
-                    _ => {
-                        self.reply(&logger, stream, OK_RESP, false).await;
+                    Some("ack") => {
+                        logger.log("Received an REPLCONF ACK from replica");
+                        None
                     }
+                    _ => Some(RedisValue::String("OK".to_string())),
                 },
                 Command::Psync => {
                     let command = RedisValue::String(format!(
                         "FULLRESYNC {} 0",
                         self.config.master_replid
                     ));
-                    self.reply(&logger, stream, command.to_response().as_bytes(), false)
+                    self.reply(logger, stream, &command.to_response(), false)
                         .await;
-                    let rdb_content = self.rdb_dump();
+                    // At this point we know this connection is from master -> replica. Writes
+                    // hold exec_lock and the AOF lock while they propagate, so every write is
+                    // either in the snapshot or sent to the replica after it, never both.
+                    let (mut rx, rdb_content) = tokio::task::block_in_place(|| {
+                        let _exclusive = self.exec_lock.write().unwrap();
+                        let _aof = self.aof.as_deref().map(Aof::lock);
+                        (tx.as_ref().unwrap().subscribe(), self.rdb_dump())
+                    });
                     self.reply(
-                        &logger,
+                        logger,
                         stream,
                         format!("${}\r\n", rdb_content.len()).as_bytes(),
                         false,
                     )
                     .await;
-                    self.reply(&logger, stream, &rdb_content, false).await;
+                    self.reply(logger, stream, &rdb_content, false).await;
 
-                    // At this point we know this connection is from master -> replica
-                    let mut rx = tx.as_ref().unwrap().subscribe();
+                    // A replica that fell behind the backlog is dropped, and resyncs when it
+                    // reconnects
                     loop {
-                        let msg = rx.recv().await.unwrap();
-                        logger.log(&format!("Received message: {}", msg));
-                        stream
-                            .write_all(msg.as_bytes())
-                            .await
-                            .expect("failed to write to stream");
+                        let msg = match rx.recv().await {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                logger.log(&format!("Closing the replica link: {}", e));
+                                return Err(RESPError::IOError(e.to_string()));
+                            }
+                        };
+                        logger.log(&format!(
+                            "Received message: {}",
+                            String::from_utf8_lossy(&msg)
+                        ));
+                        if let Err(e) = stream.write_all(&msg).await {
+                            logger.log(&format!("Failed to write to replica: {}", e));
+                            return Err(RESPError::IOError(e.to_string()));
+                        }
                     }
                 }
-                Command::Docs => {
-                    self.reply(&logger, stream, DOCS_STRING.as_bytes(), false)
-                        .await;
-                }
-                _ => {
-                    unimplemented!("Command {:?} not implemented", command.command);
+                Command::Auth { username, password } => Some(self.auth(client, username.as_ref(), password)),
+                Command::Hello {
+                    protover,
+                    auth,
+                    setname,
+                } => match auth.as_ref().map(|(username, password)| self.auth(client, Some(username), password)) {
+                    // A failed AUTH leaves the protocol and name as they were
+                    Some(error @ RedisValue::Error(_)) => Some(error),
+                    _ => {
+                        if let Some(protover) = protover {
+                            client.protocol = match protover {
+                                3 => Protocol::Resp3,
+                                _ => Protocol::Resp2,
+                            };
+                        }
+                        if let Some(name) = setname {
+                            client.name = Some(name.clone());
+                        }
+                        Some(self.hello(client))
+                    }
+                },
+                // Streamed, as the picks may well outgrow memory
+                Command::HRandField {
+                    key,
+                    count: Some(count),
+                    with_values,
+                } if *count < 0 && !client.is_master_link => match self.outside_exec(|| self.hrandfield(key, *count)) {
+                    Ok(Some(picks)) => {
+                        let len = picks.len() * if *with_values { 2 } else { 1 };
+                        let items = hrandfield_items(&picks, *with_values);
+                        self.reply_array(stream, client.protocol, len, items).await?;
+                        None
+                    }
+                    Ok(None) => Some(RedisValue::Array(Vec::new())),
+                    Err(e) => Some(e),
+                },
+                Command::SRandMember { key, count: Some(count) } if *count < 0 && !client.is_master_link => {
+                    match self.outside_exec(|| self.srandmember(key, *count)) {
+                        Ok(Some(picks)) => {
+                            let items = picks.iter().cloned().map(RedisValue::BulkString);
+                            self.reply_array(stream, client.protocol, picks.len(), items).await?;
+                            None
+                        }
+                        Ok(None) => Some(RedisValue::Array(Vec::new())),
+                        Err(e) => Some(e),
+                    }
                 }
+                Command::Unknown => None,
+                other => Some(self.outside_exec(|| self.execute(logger, other))),
+            };
+            if let Some(response) = response {
+                self.reply(
+                    logger,
+                    stream,
+                    &response.encode(client.protocol),
+                    client.is_master_link,
+                )
+                .await;
             }
-            logger.log(&format!("Previously processed, processed in this iteration, current command bytes, command: {} {} {} {:?}", already_processed_bytes, processed_bytes, command.bytes_read, command.command));
+            // Passwords stay out of the log
+            let described = match &command.command {
+                Command::Auth { .. } | Command::Hello { auth: Some(_), .. } => command.name.clone(),
+                other => format!("{:?}", other),
+            };
+            logger.log(&format!("Previously processed, processed in this iteration, current command bytes, command: {} {} {} {}", already_processed_bytes, processed_bytes, command.bytes_read, described));
             processed_bytes += command.bytes_read;
         }
+        bm.advance(processed_bytes);
+
+        Ok(processed_bytes)
+    }
 
-        return processed_bytes;
+    /// Runs a command that only needs the dataset and server state, not the
+    /// connection it arrived on. Also used to replay the AOF.
+    pub fn execute(&self, logger: &Logger, command: &Command) -> RedisValue {
+        match command {
+            Command::Ping(None) => RedisValue::String("PONG".to_string()),
+            Command::Ping(Some(message)) => RedisValue::BulkString(message.clone()),
+            Command::Echo(s) => RedisValue::BulkString(s.clone()),
+            write if write.is_write() => self.apply(write).0,
+            Command::Get(key) => match self.get(key) {
+                Ok(value) => value.map_or(RedisValue::Null, RedisValue::BulkString),
+                Err(e) => e,
+            },
+            Command::Keys(pattern) => RedisValue::Array(
+                self.keys(pattern)
+                    .into_iter()
+                    .map(RedisValue::BulkString)
+                    .collect(),
+            ),
+            Command::ConfigGet(patterns) => self.config_get(patterns),
+            Command::Save => match self.save() {
+                Ok(()) => RedisValue::String("OK".to_string()),
+                Err(e) => RedisValue::Error(format!("ERR {}", e)),
+            },
+            Command::BgSave => {
+                if self.bgsave(logger) {
+                    RedisValue::String("Background saving started".to_string())
+                } else {
+                    RedisValue::Error("ERR Background save already in progress".to_string())
+                }
+            }
+            Command::LastSave => {
+                RedisValue::Int(self.save_state.last_save.load(Ordering::Relaxed) as i64)
+            }
+            Command::BgRewriteAof => self.bgrewriteaof(logger),
+            Command::Ttl(key, format) => RedisValue::Int(self.ttl(key, *format)),
+            Command::Exists(keys) => RedisValue::Int(self.exists(keys)),
+            Command::Type(key) => RedisValue::String(self.key_type(key).to_string()),
+            Command::Scan(cursor, options) => {
+                let (next, keys) = self.scan(*cursor, options);
+                RedisValue::Array(vec![
+                    RedisValue::BulkString(Bytes::from(next.to_string())),
+                    RedisValue::Array(keys.into_iter().map(RedisValue::BulkString).collect()),
+                ])
+            }
+            Command::RandomKey => self
+                .random_key()
+                .map_or(RedisValue::Null, RedisValue::BulkString),
+            Command::DbSize => RedisValue::Int(self.dbsize()),
+            Command::Strlen(key) => self.strlen(key).map_or_else(|e| e, RedisValue::Int),
+            Command::GetRange(key, start, end) => self
+                .getrange(key, *start, *end)
+                .map_or_else(|e| e, RedisValue::BulkString),
+            Command::GetEx(key, None) => match self.getex(key, None) {
+                Ok(value) => value.map_or(RedisValue::Null, RedisValue::BulkString),
+                Err(e) => e,
+            },
+            Command::MGet(keys) => RedisValue::Array(self.mget(keys)),
+            Command::HGet(key, field) => match self.hget(key, field) {
+                Ok(value) => value.map_or(RedisValue::Null, RedisValue::BulkString),
+                Err(e) => e,
+            },
+            Command::HMGet(key, fields) => match self.hmget(key, fields) {
+                Ok(values) => RedisValue::Array(
+                    values
+                        .into_iter()
+                        .map(|value| value.map_or(RedisValue::Null, RedisValue::BulkString))
+                        .collect(),
+                ),
+                Err(e) => e,
+            },
+            Command::HExists(key, field) => match self.hget(key, field) {
+                Ok(value) => RedisValue::Int(value.is_some() as i64),
+                Err(e) => e,
+            },
+            Command::HStrlen(key, field) => match self.hget(key, field) {
+                Ok(value) => RedisValue::Int(value.map_or(0, |value| value.len() as i64)),
+                Err(e) => e,
+            },
+            Command::HLen(key) => self.hlen(key).map_or_else(|e| e, RedisValue::Int),
+            Command::HKeys(key) | Command::HVals(key) | Command::HGetAll(key) => {
+                let pairs = match self.hgetall(key) {
+                    Ok(pairs) => pairs,
+                    Err(e) => return e,
+                };
+                let pairs = pairs
+                    .into_iter()
+                    .map(|(field, value)| (RedisValue::BulkString(field), RedisValue::BulkString(value)));
+                match command {
+                    Command::HKeys(_) => RedisValue::Array(pairs.map(|(field, _)| field).collect()),
+                    Command::HVals(_) => RedisValue::Array(pairs.map(|(_, value)| value).collect()),
+                    _ => RedisValue::Map(pairs.collect()),
+                }
+            }
+            Command::HRandField {
+                key,
+                count,
+                with_values,
+            } => match self.hrandfield(key, count.unwrap_or(1)) {
+                Ok(picks) if count.is_none() => picks
+                    .and_then(|picks| picks.iter().next().map(|(field, _)| RedisValue::BulkString(field.clone())))
+                    .unwrap_or(RedisValue::Null),
+                Ok(picks) => RedisValue::Array(
+                    picks.map_or_else(Vec::new, |picks| hrandfield_items(&picks, *with_values).collect()),
+                ),
+                Err(e) => e,
+            },
+            Command::HScan(key, cursor, options) => match self.hscan(key, *cursor, options) {
+                Ok((next, pairs)) => RedisValue::Array(vec![
+                    RedisValue::BulkString(Bytes::from(next.to_string())),
+                    RedisValue::Array(
+                        pairs
+                            .into_iter()
+                            .flat_map(|(field, value)| [RedisValue::BulkString(field), RedisValue::BulkString(value)])
+                            .collect(),
+                    ),
+                ]),
+                Err(e) => e,
+            },
+            Command::SIsMember(key, member) => match self.smismember(key, std::slice::from_ref(member)) {
+                Ok(found) => RedisValue::Int(found[0] as i64),
+                Err(e) => e,
+            },
+            Command::SMIsMember(key, members) => match self.smismember(key, members) {
+                Ok(found) => RedisValue::Array(found.into_iter().map(|found| RedisValue::Int(found as i64)).collect()),
+                Err(e) => e,
+            },
+            Command::SMembers(key) => match self.smembers(key) {
+                Ok(members) => RedisValue::Set(members.into_iter().map(RedisValue::BulkString).collect()),
+                Err(e) => e,
+            },
+            Command::SCard(key) => self.scard(key).map_or_else(|e| e, RedisValue::Int),
+            Command::SRandMember { key, count } => match self.srandmember(key, count.unwrap_or(1)) {
+                Ok(picks) if count.is_none() => picks
+                    .and_then(|picks| picks.iter().next().cloned())
+                    .map_or(RedisValue::Null, RedisValue::BulkString),
+                Ok(picks) => RedisValue::Array(
+                    picks.map_or_else(Vec::new, |picks| picks.iter().cloned().map(RedisValue::BulkString).collect()),
+                ),
+                Err(e) => e,
+            },
+            Command::SetOp { op, keys, .. } => match self.set_operation(*op, keys) {
+                Ok(members) => RedisValue::Set(members.into_iter().map(RedisValue::BulkString).collect()),
+                Err(e) => e,
+            },
+            Command::SInterCard { keys, limit } => {
+                self.sintercard(keys, *limit).map_or_else(|e| e, RedisValue::Int)
+            }
+            Command::SScan(key, cursor, options) => match self.sscan(key, *cursor, options) {
+                Ok((next, members)) => RedisValue::Array(vec![
+                    RedisValue::BulkString(Bytes::from(next.to_string())),
+                    RedisValue::Array(members.into_iter().map(RedisValue::BulkString).collect()),
+                ]),
+                Err(e) => e,
+            },
+            Command::ObjectEncoding(key) => self
+                .object_encoding(key)
+                .map_or(RedisValue::Null, |encoding| RedisValue::BulkString(Bytes::from(encoding))),
+            Command::ZCard(key) => self.zcard(key).map_or_else(|e| e, RedisValue::Int),
+            Command::ZScore(key, member) => match self.zmscore(key, std::slice::from_ref(member)) {
+                Ok(scores) => scores[0].map_or(RedisValue::Null, RedisValue::Double),
+                Err(e) => e,
+            },
+            Command::ZMScore(key, members) => match self.zmscore(key, members) {
+                Ok(scores) => RedisValue::Array(
+                    scores
+                        .into_iter()
+                        .map(|score| score.map_or(RedisValue::Null, RedisValue::Double))
+                        .collect(),
+                ),
+                Err(e) => e,
+            },
+            Command::ZRank {
+                key,
+                member,
+                rev,
+                with_score,
+            } => match self.zrank(key, member, *rev) {
+                Ok(Some((rank, score))) if *with_score => {
+                    RedisValue::Array(vec![RedisValue::Int(rank as i64), RedisValue::Double(score)])
+                }
+                Ok(Some((rank, _))) => RedisValue::Int(rank as i64),
+                Ok(None) if *with_score => RedisValue::NullArray,
+                Ok(None) => RedisValue::Null,
+                Err(e) => e,
+            },
+            Command::ZRange {
+                key,
+                range,
+                with_scores,
+            } => match self.zrange(key, range) {
+                Ok(pairs) => RedisValue::Array(
+                    pairs
+                        .into_iter()
+                        .flat_map(|(member, score)| {
+                            let score = with_scores.then_some(RedisValue::Double(score));
+                            std::iter::once(RedisValue::BulkString(member)).chain(score)
+                        })
+                        .collect(),
+                ),
+                Err(e) => e,
+            },
+            Command::ZCount(key, min, max) => self.zcount(key, min, max).map_or_else(|e| e, RedisValue::Int),
+            Command::ZLexCount(key, min, max) => self.zlexcount(key, min, max).map_or_else(|e| e, RedisValue::Int),
+            Command::ZScan(key, cursor, options) => match self.zscan(key, *cursor, options) {
+                Ok((next, pairs)) => RedisValue::Array(vec![
+                    RedisValue::BulkString(Bytes::from(next.to_string())),
+                    RedisValue::Array(
+                        pairs
+                            .into_iter()
+                            .flat_map(|(member, score)| {
+                                [
+                                    RedisValue::BulkString(member),
+                                    RedisValue::BulkString(Bytes::from(format_double(score))),
+                                ]
+                            })
+                            .collect(),
+                    ),
+                ]),
+                Err(e) => e,
+            },
+            Command::XLen(key) => self.xlen(key).map_or_else(|e| e, RedisValue::Int),
+            Command::XRange {
+                key,
+                start,
+                end,
+                rev,
+                count,
+            } => match self.xrange(key, *start, *end, *rev, *count) {
+                Ok(entries) => stream::entries_reply(entries),
+                Err(e) => e,
+            },
+            Command::XRead { count, keys, ids, .. } => match self.xread(keys, ids, *count) {
+                Ok(read) => stream::read_reply(read),
+                Err(e) => e,
+            },
+            Command::XPending { key, group, range } => self.xpending(key, group, range.as_ref()).unwrap_or_else(|e| e),
+            Command::XInfoStream(key) => self.xinfo_stream(key).unwrap_or_else(|e| e),
+            Command::XInfoGroups(key) => self.xinfo_groups(key).unwrap_or_else(|e| e),
+            Command::XInfoConsumers(key, group) => self.xinfo_consumers(key, group).unwrap_or_else(|e| e),
+            Command::LRange(key, start, stop) => match self.lrange(key, *start, *stop) {
+                Ok(items) => RedisValue::Array(items.into_iter().map(RedisValue::BulkString).collect()),
+                Err(e) => e,
+            },
+            Command::LLen(key) => self.llen(key).map_or_else(|e| e, RedisValue::Int),
+            Command::LIndex(key, index) => match self.lindex(key, *index) {
+                Ok(value) => value.map_or(RedisValue::Null, RedisValue::BulkString),
+                Err(e) => e,
+            },
+            Command::LPos {
+                key,
+                element,
+                options,
+            } => match self.lpos(key, element, options) {
+                Ok(positions) if options.count.is_some() => {
+                    RedisValue::Array(positions.into_iter().map(RedisValue::Int).collect())
+                }
+                Ok(positions) => positions.first().map_or(RedisValue::Null, |i| RedisValue::Int(*i)),
+                Err(e) => e,
+            },
+            Command::Publish(channel, message) => RedisValue::Int(self.publish(channel, message)),
+            Command::PubSubChannels(pattern) => {
+                let channels = self.pubsub_channels(pattern.as_ref());
+                RedisValue::Array(channels.into_iter().map(RedisValue::BulkString).collect())
+            }
+            Command::PubSubNumSub(channels) => RedisValue::Map(
+                self.pubsub_numsub(channels)
+                    .into_iter()
+                    .map(|(channel, count)| (RedisValue::BulkString(channel), RedisValue::Int(count)))
+                    .collect(),
+            ),
+            Command::PubSubNumPat => RedisValue::Int(self.pubsub_numpat()),
+            Command::SPublish(channel, message) => RedisValue::Int(self.spublish(channel, message)),
+            Command::PubSubShardChannels(pattern) => {
+                let channels = self.pubsub_shardchannels(pattern.as_ref());
+                RedisValue::Array(channels.into_iter().map(RedisValue::BulkString).collect())
+            }
+            Command::PubSubShardNumSub(channels) => RedisValue::Map(
+                self.pubsub_shardnumsub(channels)
+                    .into_iter()
+                    .map(|(channel, count)| (RedisValue::BulkString(channel), RedisValue::Int(count)))
+                    .collect(),
+            ),
+            Command::Info(section) => self.info(section),
+            Command::ScriptLoad(body) => self.script_load(body),
+            Command::ScriptExists(shas) => self.script_exists(shas),
+            Command::ScriptFlush => self.script_flush(),
+            Command::ScriptKill => self.script_kill(),
+            Command::FunctionList { pattern, with_code } => self.function_list(pattern, *with_code),
+            Command::FunctionDump => self.function_dump(),
+            Command::FunctionStats => self.function_stats(),
+            Command::FunctionKill => self.function_kill(),
+            Command::Docs => RedisValue::BulkString(Bytes::from(DOCS_STRING)),
+            Command::Invalid(e) => RedisValue::Error(e.to_string()),
+            _ => RedisValue::Error("ERR command not allowed here".to_string()),
+        }
     }
 
-    fn parse_command_line(&mut self, args: &Vec<String>) {
+    /// Runs a write command, returning its reply and the number of changes it made
+    fn apply(&self, command: &Command) -> (RedisValue, u64) {
+        match command {
+            Command::Set(key, value, options) => {
+                let (reply, written) = self.set(key.clone(), value.clone(), options);
+                (reply, written as u64)
+            }
+            Command::Expire(key, time, condition) => {
+                let changed = self.expire(key, *time, *condition);
+                (RedisValue::Int(changed), changed as u64)
+            }
+            Command::Persist(key) => {
+                let changed = self.persist(key);
+                (RedisValue::Int(changed), changed as u64)
+            }
+            Command::Del(keys) => {
+                let deleted = self.del(keys);
+                (RedisValue::Int(deleted), deleted as u64)
+            }
+            Command::Rename { from, to, nx } => match self.rename(from, to, *nx) {
+                Ok(renamed) if *nx => (RedisValue::Int(renamed as i64), renamed as u64),
+                Ok(_) => (RedisValue::String("OK".to_string()), 1),
+                Err(e) => (e, 0),
+            },
+            Command::Copy {
+                source,
+                destination,
+                replace,
+            } => {
+                let copied = self.copy(source, destination, *replace);
+                (RedisValue::Int(copied as i64), copied as u64)
+            }
+            Command::IncrBy(key, delta) => match self.incr_by(key, *delta) {
+                Ok(value) => (RedisValue::Int(value), 1),
+                Err(e) => (e, 0),
+            },
+            Command::IncrByFloat(key, delta) => match self.incr_by_float(key, *delta) {
+                Ok(value) => (RedisValue::BulkString(value), 1),
+                Err(e) => (e, 0),
+            },
+            Command::Append(key, suffix) => match self.append(key, suffix) {
+                Ok(len) => (RedisValue::Int(len), 1),
+                Err(e) => (e, 0),
+            },
+            Command::SetRange(key, offset, patch) => match self.setrange(key, *offset, patch) {
+                Ok(len) => (RedisValue::Int(len), !patch.is_empty() as u64),
+                Err(e) => (e, 0),
+            },
+            Command::GetDel(key) => match self.getdel(key) {
+                Ok(Some(value)) => (RedisValue::BulkString(value), 1),
+                Ok(None) => (RedisValue::Null, 0),
+                Err(e) => (e, 0),
+            },
+            Command::GetEx(key, option) => match self.getex(key, *option) {
+                Ok(Some(value)) => (RedisValue::BulkString(value), 1),
+                Ok(None) => (RedisValue::Null, 0),
+                Err(e) => (e, 0),
+            },
+            Command::MSet { pairs, nx } => {
+                let set = self.mset(pairs, *nx);
+                let reply = if *nx {
+                    RedisValue::Int(set as i64)
+                } else {
+                    RedisValue::String("OK".to_string())
+                };
+                (reply, if set { pairs.len() as u64 } else { 0 })
+            }
+            Command::Push {
+                key,
+                elements,
+                end,
+                only_if_exists,
+            } => match self.push(key, elements, *end, *only_if_exists) {
+                Ok(0) => (RedisValue::Int(0), 0),
+                Ok(len) => (RedisValue::Int(len), elements.len() as u64),
+                Err(e) => (e, 0),
+            },
+            Command::Pop { key, end, count } => match self.pop(key, *end, count.unwrap_or(1)) {
+                Ok(None) if count.is_some() => (RedisValue::NullArray, 0),
+                Ok(None) => (RedisValue::Null, 0),
+                Ok(Some(mut items)) if count.is_none() => {
+                    (items.pop().map_or(RedisValue::Null, RedisValue::BulkString), 1)
+                }
+                Ok(Some(items)) => {
+                    let popped = items.len() as u64;
+                    let items = items.into_iter().map(RedisValue::BulkString).collect();
+                    (RedisValue::Array(items), popped)
+                }
+                Err(e) => (e, 0),
+            },
+            Command::LSet(key, index, element) => match self.lset(key, *index, element) {
+                Ok(()) => (RedisValue::String("OK".to_string()), 1),
+                Err(e) => (e, 0),
+            },
+            Command::LInsert {
+                key,
+                before,
+                pivot,
+                element,
+            } => match self.linsert(key, *before, pivot, element) {
+                Ok(len) => (RedisValue::Int(len), (len > 0) as u64),
+                Err(e) => (e, 0),
+            },
+            Command::LRem(key, count, element) => match self.lrem(key, *count, element) {
+                Ok(removed) => (RedisValue::Int(removed), removed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::LTrim(key, start, stop) => match self.ltrim(key, *start, *stop) {
+                Ok(removed) => (RedisValue::String("OK".to_string()), removed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::LMove {
+                source,
+                destination,
+                from,
+                to,
+            } => match self.lmove(source, destination, *from, *to) {
+                Ok(Some(element)) => (RedisValue::BulkString(element), 1),
+                Ok(None) => (RedisValue::Null, 0),
+                Err(e) => (e, 0),
+            },
+            Command::HSet {
+                key,
+                pairs,
+                nx,
+                reply_ok,
+            } => match self.hset(key, pairs, *nx) {
+                Ok(_) if *reply_ok => (RedisValue::String("OK".to_string()), pairs.len() as u64),
+                Ok(added) if *nx => (RedisValue::Int(added), added as u64),
+                Ok(added) => (RedisValue::Int(added), pairs.len() as u64),
+                Err(e) => (e, 0),
+            },
+            Command::HDel(key, fields) => match self.hdel(key, fields) {
+                Ok(removed) => (RedisValue::Int(removed), removed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::HIncrBy(key, field, delta) => match self.hincr_by(key, field, *delta) {
+                Ok(value) => (RedisValue::Int(value), 1),
+                Err(e) => (e, 0),
+            },
+            Command::HIncrByFloat(key, field, delta) => match self.hincr_by_float(key, field, *delta) {
+                Ok(value) => (RedisValue::BulkString(value), 1),
+                Err(e) => (e, 0),
+            },
+            Command::SAdd(key, members) => match self.sadd(key, members) {
+                Ok(added) => (RedisValue::Int(added), added as u64),
+                Err(e) => (e, 0),
+            },
+            Command::SRem(key, members) => match self.srem(key, members) {
+                Ok(removed) => (RedisValue::Int(removed), removed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::SPop { key, count } => match self.spop(key, count.unwrap_or(1)) {
+                Ok(mut popped) if count.is_none() => {
+                    let changes = popped.len() as u64;
+                    (popped.pop().map_or(RedisValue::Null, RedisValue::BulkString), changes)
+                }
+                Ok(popped) => {
+                    let changes = popped.len() as u64;
+                    (RedisValue::Array(popped.into_iter().map(RedisValue::BulkString).collect()), changes)
+                }
+                Err(e) => (e, 0),
+            },
+            Command::SMove {
+                source,
+                destination,
+                member,
+            } => match self.smove(source, destination, member) {
+                Ok(moved) => (RedisValue::Int(moved as i64), moved as u64),
+                Err(e) => (e, 0),
+            },
+            Command::SetOp {
+                op,
+                keys,
+                destination: Some(destination),
+            } => match self.set_operation_store(*op, destination, keys) {
+                Ok((len, changed)) => (RedisValue::Int(len), changed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::ZAdd { key, options, pairs } => match self.zadd(key, options, pairs) {
+                Ok((added, changed, score)) if options.incr => {
+                    (score.map_or(RedisValue::Null, RedisValue::Double), (added + changed) as u64)
+                }
+                Ok((added, changed, _)) if options.ch => (RedisValue::Int((added + changed) as i64), (added + changed) as u64),
+                Ok((added, changed, _)) => (RedisValue::Int(added as i64), (added + changed) as u64),
+                Err(e) => (e, 0),
+            },
+            Command::ZRem(key, members) => match self.zrem(key, members) {
+                Ok(removed) => (RedisValue::Int(removed), removed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::ZIncrBy(key, delta, member) => match self.zincrby(key, *delta, member) {
+                Ok(score) => (RedisValue::Double(score), 1),
+                Err(e) => (e, 0),
+            },
+            Command::ZRangeStore {
+                destination,
+                key,
+                range,
+            } => match self.zrangestore(destination, key, range) {
+                Ok((len, changed)) => (RedisValue::Int(len), changed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::ZPop { key, max, count } => match self.zpop(key, *max, count.unwrap_or(1)) {
+                Ok(popped) => {
+                    let changes = popped.len() as u64;
+                    let reply = popped
+                        .into_iter()
+                        .flat_map(|(member, score)| [RedisValue::BulkString(member), RedisValue::Double(score)])
+                        .collect();
+                    (RedisValue::Array(reply), changes)
+                }
+                Err(e) => (e, 0),
+            },
+            Command::ZSetOp {
+                op,
+                destination,
+                keys,
+                weights,
+                aggregate,
+            } => match self.zset_operation_store(*op, destination, keys, weights, *aggregate) {
+                Ok((len, changed)) => (RedisValue::Int(len), changed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::XAdd {
+                key,
+                nomkstream,
+                trim,
+                id,
+                fields,
+            } => match self.xadd(key, *nomkstream, trim.as_ref(), *id, fields) {
+                Ok(Some(id)) => (RedisValue::BulkString(Bytes::from(id.to_string())), 1),
+                Ok(None) => (RedisValue::Null, 0),
+                Err(e) => (e, 0),
+            },
+            Command::XDel(key, ids) => match self.xdel(key, ids) {
+                Ok(deleted) => (RedisValue::Int(deleted), deleted as u64),
+                Err(e) => (e, 0),
+            },
+            Command::XTrim(key, trim) => match self.xtrim(key, trim) {
+                Ok(removed) => (RedisValue::Int(removed), removed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::XGroupCreate {
+                key,
+                group,
+                id,
+                mkstream,
+                entries_read,
+            } => match self.xgroup_create(key, group, *id, *mkstream, *entries_read) {
+                Ok(()) => (RedisValue::String("OK".to_string()), 1),
+                Err(e) => (e, 0),
+            },
+            Command::XGroupSetId {
+                key,
+                group,
+                id,
+                entries_read,
+            } => match self.xgroup_setid(key, group, *id, *entries_read) {
+                Ok(()) => (RedisValue::String("OK".to_string()), 1),
+                Err(e) => (e, 0),
+            },
+            Command::XGroupDestroy(key, group) => match self.xgroup_destroy(key, group) {
+                Ok(destroyed) => (RedisValue::Int(destroyed as i64), destroyed as u64),
+                Err(e) => (e, 0),
+            },
+            Command::XGroupCreateConsumer(key, group, consumer) => {
+                match self.xgroup_createconsumer(key, group, consumer) {
+                    Ok(created) => (RedisValue::Int(created as i64), created as u64),
+                    Err(e) => (e, 0),
+                }
+            }
+            Command::XGroupDelConsumer(key, group, consumer) => match self.xgroup_delconsumer(key, group, consumer) {
+                Ok(Some(pending)) => (RedisValue::Int(pending), 1),
+                Ok(None) => (RedisValue::Int(0), 0),
+                Err(e) => (e, 0),
+            },
+            Command::XAck(key, group, ids) => match self.xack(key, group, ids) {
+                Ok(acked) => (RedisValue::Int(acked), acked as u64),
+                Err(e) => (e, 0),
+            },
+            Command::XReadGroup { .. } | Command::XClaim { .. } | Command::XAutoClaim { .. } => {
+                let (reply, effects) = self.apply_with_effects(command).unwrap();
+                (reply, effects.len() as u64)
+            }
+            // Counted as one more change than keys removed so it's always propagated
+            Command::FlushAll => (RedisValue::String("OK".to_string()), self.flush() + 1),
+            Command::FunctionLoad(code, replace) => self.function_load(code, *replace),
+            Command::FunctionDelete(name) => self.function_delete(name),
+            Command::FunctionRestore(payload, policy) => self.function_restore(payload, *policy),
+            Command::FunctionFlush => self.function_flush(),
+            _ => (RedisValue::Error("ERR not a write command".to_string()), 0),
+        }
+    }
+
+    /// Runs a write command that's propagated as the commands redoing its changes
+    /// rather than as itself, returning its reply and those commands. None for
+    /// every other command.
+    fn apply_with_effects(&self, command: &Command) -> Option<(RedisValue, Vec<Vec<Bytes>>)> {
+        let result = match command {
+            Command::XReadGroup {
+                group,
+                consumer,
+                count,
+                noack,
+                keys,
+                ids,
+                ..
+            } => self.xreadgroup(group, consumer, keys, ids, *count, *noack),
+            Command::XClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                ids,
+                options,
+            } => self.xclaim(key, group, consumer, *min_idle, ids, options),
+            Command::XAutoClaim {
+                key,
+                group,
+                consumer,
+                min_idle,
+                start,
+                count,
+                justid,
+            } => self.xautoclaim(key, group, consumer, *min_idle, *start, *count, *justid),
+            _ => return None,
+        };
+        Some(result.unwrap_or_else(|e| (e, Vec::new())))
+    }
+
+    /// Runs a write command, then appends it to the AOF and sends it to replicas
+    /// if it changed anything. The log stays locked throughout so a rewrite can't start
+    /// between the change and its log entry.
+    fn execute_write(
+        &self,
+        logger: &Logger,
+        command: &Command,
+        raw: &[u8],
+        tx: &Option<Arc<Backlog>>,
+    ) -> RedisValue {
+        // Relative deadlines are fixed now, so the AOF and replicas see the same ones
+        let absolute = command
+            .with_absolute_deadline(unix_time_ms())
+            .map(|(command, args)| {
+                let encoded = RedisValue::Array(args.into_iter().map(RedisValue::BulkString).collect());
+                (command, encoded.to_response())
+            });
+        let (command, raw) = match &absolute {
+            Some((command, encoded)) => (command, &encoded[..]),
+            None => (command, raw),
+        };
+        let mut aof = self.aof.as_deref().map(Aof::lock);
+        if let Some((response, effects)) = self.apply_with_effects(command) {
+            if !effects.is_empty() {
+                let mut db = self.db.lock().unwrap();
+                for key in command.written_keys() {
+                    db.touch(key);
+                }
+            }
+            for args in effects {
+                self.propagate_args(logger, &mut aof, tx, args);
+            }
+            return response;
+        }
+        let (response, changes) = self.apply(command);
+        if changes == 0 {
+            return response;
+        }
+        // SPOP picks at random and XADD may pick the ID from the clock, so others
+        // are told which members were removed and which ID was used
+        let rewritten = match command {
+            Command::SPop { key, .. } => Some(spop_as_srem(key, &response)),
+            Command::XAdd { .. } => xadd_with_id(command, &response),
+            _ => None,
+        };
+        let raw = rewritten.as_deref().unwrap_or(raw);
+        self.save_state.dirty.fetch_add(changes, Ordering::SeqCst);
+        self.propagate(logger, &mut aof, tx, raw);
+        // Pops made for clients blocked on the keys this command pushed to
+        let served = {
+            let mut db = self.db.lock().unwrap();
+            for key in command.written_keys() {
+                db.touch(key);
+            }
+            RedisServer::serve_blocked(&mut db)
+        };
+        for args in served {
+            self.propagate_args(logger, &mut aof, tx, args);
+        }
+        response
+    }
+
+    /// Runs `run` once no transaction is running, keeping one from starting until
+    /// it's done
+    fn outside_exec<T>(&self, run: impl FnOnce() -> T) -> T {
+        let _shared = self.exec_lock.read().unwrap();
+        run()
+    }
+
+    /// `outside_exec` for periodic jobs, which skip their turn rather than wait
+    /// while a transaction or a script runs
+    fn unless_busy<T>(&self, run: impl FnOnce() -> T) -> Option<T> {
+        let _shared = self.exec_lock.try_read().ok()?;
+        Some(run())
+    }
+
+    /// Appends a write to the AOF and sends it to replicas
+    fn propagate(
+        &self,
+        logger: &Logger,
+        aof: &mut Option<MutexGuard<'_, AofWriter>>,
+        tx: &Option<Arc<Backlog>>,
+        raw: &[u8],
+    ) {
+        if let Some(aof) = aof.as_mut() {
+            if let Err(e) = aof.append(raw) {
+                logger.log(&format!("Failed to append to the AOF: {}", e));
+            }
+        }
+        if let (Some(tx), false) = (tx, self.config.is_replica) {
+            logger.log(&format!(
+                "master received write command: {}",
+                String::from_utf8_lossy(raw)
+            ));
+            tx.send(raw);
+        }
+    }
+
+    /// `propagate` for a write the server made up itself, such as the pop done for
+    /// a blocked client, which also counts as a change
+    fn propagate_args(
+        &self,
+        logger: &Logger,
+        aof: &mut Option<MutexGuard<'_, AofWriter>>,
+        tx: &Option<Arc<Backlog>>,
+        args: Vec<Bytes>,
+    ) {
+        self.save_state.dirty.fetch_add(1, Ordering::SeqCst);
+        let encoded = RedisValue::Array(args.into_iter().map(RedisValue::BulkString).collect());
+        self.propagate(logger, aof, tx, &encoded.to_response());
+    }
+
+    /// Connection summary sent in reply to HELLO
+    fn hello(&self, client: &ClientState) -> RedisValue {
+        let field = |name: &str| RedisValue::BulkString(Bytes::from(name.to_string()));
+        let role = if self.config.is_replica { "replica" } else { "master" };
+        let proto = match client.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        RedisValue::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(SERVER_VERSION)),
+            (field("proto"), RedisValue::Int(proto)),
+            (field("id"), RedisValue::Int(client.id as i64)),
+            (field("mode"), field("standalone")),
+            (field("role"), field(role)),
+            (field("modules"), RedisValue::Array(vec![])),
+        ])
+    }
+
+    /// Whether the client may run commands: it has authenticated, or there's no
+    /// password to authenticate with. The master's link to a replica needs none.
+    fn is_authenticated(&self, client: &ClientState) -> bool {
+        client.authenticated || client.is_master_link || self.config.requirepass.is_none()
+    }
+
+    /// Checks a password for AUTH and HELLO AUTH. The only user is "default",
+    /// whose password is `requirepass`.
+    fn auth(&self, client: &mut ClientState, username: Option<&Bytes>, password: &Bytes) -> RedisValue {
+        let default_user = username.is_none_or(|username| &username[..] == b"default");
+        let accepted = match &self.config.requirepass {
+            None if username.is_none() => {
+                return RedisValue::Error(
+                    "ERR AUTH <password> called without any password configured for the default user. Are you sure \
+                     your configuration is correct?"
+                        .to_string(),
+                )
+            }
+            // Without a password, the default user takes any
+            None => default_user,
+            Some(requirepass) => default_user && same_secret(password, requirepass.as_bytes()),
+        };
+        if !accepted {
+            return RedisValue::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string());
+        }
+        client.authenticated = true;
+        RedisValue::String("OK".to_string())
+    }
+
+    /// Drops the subscriptions and watched keys of a client that went away
+    pub fn disconnect(&self, client: &ClientState) {
+        if client.messages.is_some() {
+            let mut pubsub = self.pubsub.lock().unwrap();
+            pubsub.unregister(client);
+        }
+        self.release_watches(&client.watched);
+    }
+
+    pub fn next_client_id(&self) -> u64 {
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn parse_command_line(&mut self, args: &[String]) {
         let mut args_iter = args.iter();
         while let Some(arg) = args_iter.next() {
             match arg.as_str() {
@@ -317,6 +2307,85 @@ impl RedisServer {
                         return;
                     }
                 }
+                "--appendonly" => {
+                    if let Some(value) = args_iter.next() {
+                        self.config.appendonly = value.eq_ignore_ascii_case("yes");
+                    } else {
+                        eprintln!("Expected yes or no after --appendonly");
+                        return;
+                    }
+                }
+                "--appendfilename" => {
+                    if let Some(filename) = args_iter.next() {
+                        self.config.appendfilename = filename.to_string();
+                    } else {
+                        eprintln!("Expected a filename after --appendfilename");
+                        return;
+                    }
+                }
+                "--appendfsync" => {
+                    if let Some(policy) = args_iter.next() {
+                        self.config.appendfsync =
+                            FsyncPolicy::parse(policy).expect("Invalid appendfsync policy");
+                    } else {
+                        eprintln!("Expected always, everysec or no after --appendfsync");
+                        return;
+                    }
+                }
+                "--save" => {
+                    // --save "<seconds> <changes> [<seconds> <changes> ...]"; "" disables
+                    if let Some(rules) = args_iter.next() {
+                        let numbers: Vec<u64> = rules
+                            .split_whitespace()
+                            .map(|n| n.parse().expect("Invalid save rule"))
+                            .collect();
+                        if numbers.chunks(2).any(|pair| pair.len() != 2) {
+                            panic!("Invalid save rule '{}'", rules);
+                        }
+                        if numbers.is_empty() {
+                            self.config.save_rules.clear();
+                        }
+                        self.config
+                            .save_rules
+                            .extend(numbers.chunks(2).map(|pair| (pair[0], pair[1])));
+                    } else {
+                        eprintln!("Expected save rules after --save");
+                        return;
+                    }
+                }
+                "--busy-reply-threshold" | "--lua-time-limit" => {
+                    if let Some(ms) = args_iter.next() {
+                        self.config.busy_reply_threshold = ms.parse().expect("Invalid busy reply threshold");
+                    } else {
+                        eprintln!("Expected milliseconds after {}", arg);
+                        return;
+                    }
+                }
+                "--requirepass" => {
+                    if let Some(password) = args_iter.next() {
+                        // An empty password turns authentication off, as in redis.conf
+                        self.config.requirepass = Some(password.to_string()).filter(|p| !p.is_empty());
+                    } else {
+                        eprintln!("Expected a password after --requirepass");
+                        return;
+                    }
+                }
+                "--masterauth" => {
+                    if let Some(password) = args_iter.next() {
+                        self.config.masterauth = Some(password.to_string()).filter(|p| !p.is_empty());
+                    } else {
+                        eprintln!("Expected a password after --masterauth");
+                        return;
+                    }
+                }
+                "--repl-backlog-size" => {
+                    if let Some(size) = args_iter.next() {
+                        self.config.repl_backlog_size = size.parse().expect("Invalid replication backlog size");
+                    } else {
+                        eprintln!("Expected a size in bytes after --repl-backlog-size");
+                        return;
+                    }
+                }
                 "--replicaof" => {
                     // --replicaof "<MASTER_HOST> <MASTER_PORT>"
                     if let Some(host_and_port) = args_iter.next() {
@@ -335,15 +2404,171 @@ impl RedisServer {
             }
         }
     }
-    // For the purposes of this exercise, we'll just use the empty RDB const
-    pub fn rdb_dump(&self) -> Vec<u8> {
-        decode_hex(EMPTY_RDB_HEX).expect("Failed to decode empty RDB hex")
+}
+
+/// Compares secrets in time that doesn't depend on where they differ
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The SREM equivalent of an SPOP, given its reply
+fn spop_as_srem(key: &Bytes, reply: &RedisValue) -> Vec<u8> {
+    let mut args = vec![RedisValue::BulkString(Bytes::from("SREM")), RedisValue::BulkString(key.clone())];
+    match reply {
+        RedisValue::Array(members) => args.extend(members.iter().cloned()),
+        member => args.push(member.clone()),
     }
+    RedisValue::Array(args).to_response()
 }
 
-fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
-    (0..s.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
-        .collect()
+/// The XADD to propagate, naming the ID it was given in its reply
+fn xadd_with_id(command: &Command, reply: &RedisValue) -> Option<Vec<u8>> {
+    let (Command::XAdd { key, nomkstream, trim, fields, .. }, RedisValue::BulkString(id)) = (command, reply) else {
+        return None;
+    };
+    let mut args = vec![Bytes::from("XADD"), key.clone()];
+    if *nomkstream {
+        args.push(Bytes::from("NOMKSTREAM"));
+    }
+    args.extend(trim.iter().flat_map(|trim| trim.to_args()));
+    args.push(id.clone());
+    args.extend(fields.iter().flat_map(|(field, value)| [field.clone(), value.clone()]));
+    Some(RedisValue::Array(args.into_iter().map(RedisValue::BulkString).collect()).to_response())
+}
+
+/// A random number from the standard library's per-process hash seed, which is
+/// enough for picking a random key
+pub(crate) fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(unix_time_ms());
+    hasher.finish()
+}
+
+/// Current wall clock time in milliseconds since the unix epoch
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_per_protocol() {
+        let value = RedisValue::Map(vec![
+            (
+                RedisValue::BulkString(Bytes::from("score")),
+                RedisValue::Double(1.5),
+            ),
+            (
+                RedisValue::BulkString(Bytes::from("ok")),
+                RedisValue::Boolean(true),
+            ),
+            (RedisValue::BulkString(Bytes::from("none")), RedisValue::Null),
+        ]);
+        assert_eq!(
+            value.encode(Protocol::Resp2),
+            b"*6\r\n$5\r\nscore\r\n$3\r\n1.5\r\n$2\r\nok\r\n:1\r\n$4\r\nnone\r\n$-1\r\n".to_vec()
+        );
+        assert_eq!(
+            value.encode(Protocol::Resp3),
+            b"%3\r\n$5\r\nscore\r\n,1.5\r\n$2\r\nok\r\n#t\r\n$4\r\nnone\r\n_\r\n".to_vec()
+        );
+        let verbatim = RedisValue::Verbatim("txt".to_string(), Bytes::from("a:b"));
+        assert_eq!(verbatim.encode(Protocol::Resp3), b"=7\r\ntxt:a:b\r\n".to_vec());
+        assert_eq!(verbatim.encode(Protocol::Resp2), b"$3\r\na:b\r\n".to_vec());
+        let push = RedisValue::Push(vec![RedisValue::Int(1)]);
+        assert_eq!(push.encode(Protocol::Resp3), b">1\r\n:1\r\n".to_vec());
+        assert_eq!(push.encode(Protocol::Resp2), b"*1\r\n:1\r\n".to_vec());
+        assert_eq!(RedisValue::Double(f64::INFINITY).encode(Protocol::Resp3), b",inf\r\n".to_vec());
+        assert_eq!(RedisValue::NullArray.encode(Protocol::Resp2), b"*-1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_save_rules_and_dirty_counter() {
+        let args = ["--save", "60 1000 300 10", "--save", "5 1"].map(String::from);
+        let server = RedisServer::new(&args);
+        assert_eq!(server.config.save_rules, vec![(60, 1000), (300, 10), (5, 1)]);
+
+        let set = Parser::parse_command(&[Bytes::from("SET"), Bytes::from("k"), Bytes::from("v")])
+            .unwrap();
+        server.execute_write(&Logger::new(), &set, b"", &None);
+        server.execute_write(&Logger::new(), &set, b"", &None);
+        let info = server.info("persistence").to_string();
+        assert!(info.contains("rdb_changes_since_last_save:2\r\n"), "{}", info);
+        assert!(info.contains("rdb_last_bgsave_status:ok\r\n"), "{}", info);
+
+        server.save_state.saved(1);
+        assert_eq!(server.save_state.dirty.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_auth() {
+        let wrongpass = RedisValue::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string());
+        let ok = RedisValue::String("OK".to_string());
+        let password = Bytes::from("secret");
+
+        let open = RedisServer::new(&[]);
+        let mut client = ClientState::new(1);
+        assert!(open.is_authenticated(&client));
+        let reply = open.auth(&mut client, None, &password);
+        assert!(matches!(reply, RedisValue::Error(e) if e.contains("without any password")));
+        assert_eq!(open.auth(&mut client, Some(&Bytes::from("default")), &password), ok);
+        assert_eq!(open.auth(&mut client, Some(&Bytes::from("alice")), &password), wrongpass);
+
+        let server = RedisServer::new(&["--requirepass", "secret"].map(String::from));
+        let mut client = ClientState::new(1);
+        assert!(!server.is_authenticated(&client));
+        assert!(server.is_authenticated(&ClientState::master_link(2)));
+        assert_eq!(server.auth(&mut client, None, &Bytes::from("wrong")), wrongpass);
+        assert_eq!(server.auth(&mut client, Some(&Bytes::from("alice")), &password), wrongpass);
+        assert!(!server.is_authenticated(&client));
+        assert_eq!(server.auth(&mut client, None, &password), ok);
+        assert!(server.is_authenticated(&client));
+        let mut client = ClientState::new(3);
+        assert_eq!(server.auth(&mut client, Some(&Bytes::from("default")), &password), ok);
+        assert!(server.is_authenticated(&client));
+    }
+
+    #[test]
+    fn test_load_aof_truncates_partial_command() {
+        let dir = std::env::temp_dir().join(format!("server-aof-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let complete = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let mut contents = complete.to_vec();
+        contents.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+        fs::write(dir.join("appendonly.aof"), &contents).unwrap();
+
+        let args = ["--dir", dir.to_str().unwrap(), "--appendonly", "yes"].map(String::from);
+        let server = RedisServer::new(&args);
+        assert_eq!(server.load_aof(&Logger::new()), Ok(1));
+        assert_eq!(server.get(b"a"), Ok(Some(Bytes::from("1"))));
+        assert_eq!(server.get(b"b"), Ok(None));
+        assert_eq!(fs::read(dir.join("appendonly.aof")).unwrap(), complete.to_vec());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_aof_reverts_incomplete_transaction() {
+        let dir = std::env::temp_dir().join(format!("server-aof-multi-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let set = |key: &str| format!("*3\r\n$3\r\nSET\r\n$1\r\n{}\r\n$1\r\n1\r\n", key);
+        let (multi, exec) = ("*1\r\n$5\r\nMULTI\r\n", "*1\r\n$4\r\nEXEC\r\n");
+        let complete = [multi, &set("a"), exec, &set("b")].concat();
+        let contents = [&complete, multi, &set("c")].concat();
+        fs::write(dir.join("appendonly.aof"), &contents).unwrap();
+
+        let args = ["--dir", dir.to_str().unwrap(), "--appendonly", "yes"].map(String::from);
+        let server = RedisServer::new(&args);
+        assert_eq!(server.load_aof(&Logger::new()), Ok(6));
+        assert_eq!(server.get(b"a"), Ok(Some(Bytes::from("1"))));
+        assert_eq!(server.get(b"b"), Ok(Some(Bytes::from("1"))));
+        assert_eq!(server.get(b"c"), Ok(None));
+        assert_eq!(fs::read(dir.join("appendonly.aof")).unwrap(), complete.into_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,233 @@
+//! Hash commands
+use bytes::Bytes;
+
+use super::{check_random_count, format_double, random_u64, RandomPicks, RedisServer, RedisValue, WRONGTYPE};
+use crate::db::{Db, Value};
+use crate::glob::glob_match;
+use crate::hash::Hash;
+use crate::parser::{parse_float, parse_integer, ScanOptions};
+
+impl RedisServer {
+    /// The hash stored at `key`, or a WRONGTYPE error for other types
+    fn get_hash<'a>(db: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut Hash>, RedisValue> {
+        match db.get_mut(key) {
+            Some((Value::Hash(hash), _)) => Ok(Some(hash)),
+            Some(_) => Err(RedisValue::Error(WRONGTYPE.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// The hash at `key`, created empty if there's none
+    fn get_or_create_hash<'a>(db: &'a mut Db, key: &[u8]) -> Result<&'a mut Hash, RedisValue> {
+        if RedisServer::get_hash(db, key)?.is_none() {
+            db.insert(Bytes::copy_from_slice(key), Value::Hash(Hash::default()), None);
+        }
+        Ok(RedisServer::get_hash(db, key)?.unwrap())
+    }
+
+    /// Sets the given fields, or with `nx` only those that don't exist yet.
+    /// Returns the number of fields added.
+    pub fn hset(&self, key: &[u8], pairs: &[(Bytes, Bytes)], nx: bool) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let hash = RedisServer::get_or_create_hash(&mut db, key)?;
+        let mut added = 0;
+        for (field, value) in pairs {
+            if nx && hash.contains_key(field) {
+                continue;
+            }
+            if hash.insert(field.clone(), value.clone()).is_none() {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_hash(&mut db, key)?.and_then(|hash| hash.get(field).cloned()))
+    }
+
+    pub fn hmget(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<Option<Bytes>>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let hash = RedisServer::get_hash(&mut db, key)?;
+        Ok(fields
+            .iter()
+            .map(|field| hash.as_ref().and_then(|hash| hash.get(field).cloned()))
+            .collect())
+    }
+
+    /// Removes the given fields, deleting the key once it's empty. Returns how
+    /// many fields existed.
+    pub fn hdel(&self, key: &[u8], fields: &[Bytes]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let hash = match RedisServer::get_hash(&mut db, key)? {
+            Some(hash) => hash,
+            None => return Ok(0),
+        };
+        let removed = fields.iter().filter(|field| hash.remove(field).is_some()).count();
+        if hash.is_empty() {
+            db.remove(key);
+        }
+        Ok(removed as i64)
+    }
+
+    pub fn hlen(&self, key: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_hash(&mut db, key)?.map_or(0, |hash| hash.len() as i64))
+    }
+
+    /// Every field and value, empty if there's no such key
+    pub fn hgetall(&self, key: &[u8]) -> Result<Vec<(Bytes, Bytes)>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_hash(&mut db, key)?.map_or_else(Vec::new, |hash| {
+            hash.iter().map(|(field, value)| (field.clone(), value.clone())).collect()
+        }))
+    }
+
+    pub fn hincr_by(&self, key: &[u8], field: &Bytes, delta: i64) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let current = match RedisServer::get_hash(&mut db, key)?.and_then(|hash| hash.get(field)) {
+            Some(value) => parse_integer(value)
+                .ok_or_else(|| RedisValue::Error("ERR hash value is not an integer".to_string()))?,
+            None => 0,
+        };
+        let value = current.checked_add(delta).ok_or_else(|| {
+            RedisValue::Error("ERR increment or decrement would overflow".to_string())
+        })?;
+        RedisServer::get_or_create_hash(&mut db, key)?.insert(field.clone(), Bytes::from(value.to_string()));
+        Ok(value)
+    }
+
+    pub fn hincr_by_float(&self, key: &[u8], field: &Bytes, delta: f64) -> Result<Bytes, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let current = match RedisServer::get_hash(&mut db, key)?.and_then(|hash| hash.get(field)) {
+            Some(value) => parse_float(value)
+                .ok_or_else(|| RedisValue::Error("ERR hash value is not a float".to_string()))?,
+            None => 0.0,
+        };
+        let value = current + delta;
+        if !value.is_finite() {
+            return Err(RedisValue::Error(
+                "ERR increment would produce NaN or Infinity".to_string(),
+            ));
+        }
+        let value = Bytes::from(format_double(value));
+        RedisServer::get_or_create_hash(&mut db, key)?.insert(field.clone(), value.clone());
+        Ok(value)
+    }
+
+    /// Random fields with their values: `count` distinct ones, or when negative
+    /// that many with repeats allowed. None if there's no such key.
+    pub fn hrandfield(&self, key: &[u8], count: i64) -> Result<Option<RandomPicks<(Bytes, Bytes)>>, RedisValue> {
+        check_random_count(count)?;
+        let mut db = self.db.lock().unwrap();
+        let hash = match RedisServer::get_hash(&mut db, key)? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let mut pairs: Vec<(Bytes, Bytes)> = hash
+            .iter()
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        if count < 0 {
+            return Ok(Some(RandomPicks::Repeated(pairs, count.unsigned_abs())));
+        }
+        // A partial shuffle, keeping the first `count` pairs
+        let count = (count as usize).min(pairs.len());
+        for i in 0..count {
+            let j = i + random_u64() as usize % (pairs.len() - i);
+            pairs.swap(i, j);
+        }
+        pairs.truncate(count);
+        Ok(Some(RandomPicks::Distinct(pairs)))
+    }
+
+    /// One step of an HSCAN iteration: the next cursor and the matching fields with
+    /// their values
+    pub fn hscan(&self, key: &[u8], cursor: u64, options: &ScanOptions) -> Result<(u64, Vec<(Bytes, Bytes)>), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let hash = match RedisServer::get_hash(&mut db, key)? {
+            Some(hash) => hash,
+            None => return Ok((0, Vec::new())),
+        };
+        let (next, batch) = hash.scan(cursor, options.count);
+        let pairs = batch
+            .into_iter()
+            .filter(|(field, _)| {
+                options
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, field, false))
+            })
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        Ok((next, pairs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(words: &str) -> Vec<(Bytes, Bytes)> {
+        let words: Vec<&str> = words.split_whitespace().collect();
+        words
+            .chunks(2)
+            .map(|pair| (Bytes::from(pair[0].to_string()), Bytes::from(pair[1].to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_operations() {
+        let server = RedisServer::new(&[]);
+        assert_eq!(server.hset(b"h", &pairs("a 1 b 2"), false), Ok(2));
+        assert_eq!(server.hset(b"h", &pairs("a 3 c x"), false), Ok(1));
+        assert_eq!(server.hset(b"h", &pairs("a 4"), true), Ok(0));
+        assert_eq!(server.hget(b"h", b"a"), Ok(Some(Bytes::from("3"))));
+        assert_eq!(
+            server.hmget(b"h", &[Bytes::from("b"), Bytes::from("nope")]),
+            Ok(vec![Some(Bytes::from("2")), None])
+        );
+        assert_eq!(server.hincr_by(b"h", &Bytes::from("a"), 10), Ok(13));
+        assert_eq!(
+            server.hincr_by(b"h", &Bytes::from("c"), 1),
+            Err(RedisValue::Error("ERR hash value is not an integer".to_string()))
+        );
+        assert_eq!(
+            server.hincr_by_float(b"h", &Bytes::from("b"), 0.5),
+            Ok(Bytes::from("2.5"))
+        );
+        assert_eq!(server.key_type(b"h"), "hash");
+        assert_eq!(
+            server.get(b"h"),
+            Err(RedisValue::Error(WRONGTYPE.to_string()))
+        );
+
+        let picked = server.hrandfield(b"h", 10).unwrap().unwrap();
+        assert_eq!(picked.iter().count(), 3);
+        let repeated = server.hrandfield(b"h", -10).unwrap().unwrap();
+        assert_eq!(repeated.iter().count(), 10);
+        assert_eq!(server.hrandfield(b"nope", 1), Ok(None));
+        // Repeats are drawn as they're read, and a count that could never be met is refused
+        let huge = server.hrandfield(b"h", -(i64::MAX / 2)).unwrap().unwrap();
+        assert_eq!(huge.len(), (i64::MAX / 2) as u64);
+        assert_eq!(huge.iter().take(5).count(), 5);
+        let out_of_range = Err(RedisValue::Error("ERR value is out of range".to_string()));
+        assert_eq!(server.hrandfield(b"h", -i64::MAX), out_of_range);
+        assert_eq!(server.hrandfield(b"h", i64::MIN), out_of_range);
+
+        let (mut cursor, mut seen) = (0, 0);
+        loop {
+            let (next, batch) = server.hscan(b"h", cursor, &ScanOptions { count: 1, ..Default::default() }).unwrap();
+            seen += batch.len();
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(seen, 3);
+
+        assert_eq!(server.hdel(b"h", &[Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]), Ok(3));
+        assert_eq!(server.key_type(b"h"), "none");
+    }
+}
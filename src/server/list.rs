@@ -0,0 +1,560 @@
+//! List commands, including the blocking ones
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use super::{RedisServer, RedisValue, WRONGTYPE};
+use crate::aof::Aof;
+use crate::backlog::Backlog;
+use crate::db::{Db, Value};
+use crate::log::Logger;
+use crate::parser::{BlockingOp, ListEnd, LPosOptions};
+use crate::stream::StreamId;
+
+/// The range of positions between the inclusive indexes `start` and `stop`, which
+/// count from the end when negative, or None if it's empty
+pub(super) fn list_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+/// A blocked client's reply, and the command to propagate for it if any
+type Served = (RedisValue, Vec<Vec<Bytes>>);
+
+/// A position in a list of `len` elements, counting from the end when negative
+fn list_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+impl RedisServer {
+    /// The list stored at `key`, or a WRONGTYPE error for other types
+    fn get_list<'a>(db: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut VecDeque<Bytes>>, RedisValue> {
+        match db.get_mut(key) {
+            Some((Value::List(list), _)) => Ok(Some(list)),
+            Some(_) => Err(RedisValue::Error(WRONGTYPE.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds `elements` to one end of the list at `key`, creating it if needed, and
+    /// wakes clients blocked on it
+    fn push_to(db: &mut Db, key: &[u8], elements: &[Bytes], end: ListEnd) -> Result<usize, RedisValue> {
+        if RedisServer::get_list(db, key)?.is_none() {
+            db.insert(Bytes::copy_from_slice(key), Value::List(VecDeque::new()), None);
+        }
+        let list = RedisServer::get_list(db, key)?.unwrap();
+        for element in elements {
+            match end {
+                ListEnd::Left => list.push_front(element.clone()),
+                ListEnd::Right => list.push_back(element.clone()),
+            }
+        }
+        let len = list.len();
+        db.blocked.signal_ready(&Bytes::copy_from_slice(key));
+        Ok(len)
+    }
+
+    /// Removes up to `count` elements from one end of the list at `key`, deleting
+    /// the key once it's empty
+    fn pop_from(db: &mut Db, key: &[u8], end: ListEnd, count: usize) -> Result<Vec<Bytes>, RedisValue> {
+        let list = match RedisServer::get_list(db, key)? {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let count = count.min(list.len());
+        let popped = match end {
+            ListEnd::Left => list.drain(..count).collect(),
+            ListEnd::Right => list.drain(list.len() - count..).rev().collect(),
+        };
+        if list.is_empty() {
+            db.remove(key);
+        }
+        Ok(popped)
+    }
+
+    /// Moves one element between the ends of two lists. A source and destination
+    /// that are the same key rotate the list.
+    fn move_element(
+        db: &mut Db,
+        source: &[u8],
+        destination: &[u8],
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<Bytes>, RedisValue> {
+        if RedisServer::get_list(db, source)?.is_none() {
+            return Ok(None);
+        }
+        // Checked before anything is popped so a bad destination changes nothing
+        RedisServer::get_list(db, destination)?;
+        let element = RedisServer::pop_from(db, source, from, 1)?.remove(0);
+        RedisServer::push_to(db, destination, std::slice::from_ref(&element), to)?;
+        Ok(Some(element))
+    }
+
+    /// Tries what a blocked client would do once `key` has an element. Returns the
+    /// client's reply and the commands to propagate for what it changed, or None
+    /// if there's nothing for the client yet.
+    fn pop_for(db: &mut Db, key: &Bytes, op: &BlockingOp) -> Result<Option<Served>, RedisValue> {
+        match op {
+            BlockingOp::Pop(end) => {
+                let element = match RedisServer::pop_from(db, key, *end, 1)?.pop() {
+                    Some(element) => element,
+                    None => return Ok(None),
+                };
+                db.touch(key);
+                let name = match end {
+                    ListEnd::Left => "LPOP",
+                    ListEnd::Right => "RPOP",
+                };
+                let reply = RedisValue::Array(vec![
+                    RedisValue::BulkString(key.clone()),
+                    RedisValue::BulkString(element),
+                ]);
+                Ok(Some((reply, vec![vec![Bytes::from(name), key.clone()]])))
+            }
+            BlockingOp::Move {
+                destination,
+                from,
+                to,
+            } => {
+                let element = match RedisServer::move_element(db, key, destination, *from, *to)? {
+                    Some(element) => element,
+                    None => return Ok(None),
+                };
+                db.touch(key);
+                db.touch(destination);
+                let args = vec![
+                    Bytes::from("LMOVE"),
+                    key.clone(),
+                    destination.clone(),
+                    Bytes::from(from.name()),
+                    Bytes::from(to.name()),
+                ];
+                Ok(Some((RedisValue::BulkString(element), vec![args])))
+            }
+            BlockingOp::ZPop { max } => {
+                let (member, score) = match RedisServer::zpop_from(db, key, *max, 1)?.pop() {
+                    Some(popped) => popped,
+                    None => return Ok(None),
+                };
+                db.touch(key);
+                let reply = RedisValue::Array(vec![
+                    RedisValue::BulkString(key.clone()),
+                    RedisValue::BulkString(member),
+                    RedisValue::Double(score),
+                ]);
+                let name = if *max { "ZPOPMAX" } else { "ZPOPMIN" };
+                Ok(Some((reply, vec![vec![Bytes::from(name), key.clone()]])))
+            }
+            BlockingOp::XRead { ids, count } => {
+                let after = ids.iter().find(|(waited, _)| waited == key).map_or(StreamId::MAX, |(_, id)| *id);
+                Ok(RedisServer::xread_from(db, key, after, *count)?.map(|reply| (reply, vec![])))
+            }
+            BlockingOp::XReadGroup {
+                group,
+                consumer,
+                count,
+                noack,
+            } => {
+                let served = RedisServer::xreadgroup_from(db, key, group, consumer, *count, *noack)?;
+                if served.is_some() {
+                    db.touch(key);
+                }
+                Ok(served)
+            }
+        }
+    }
+
+    /// Serves a client from the first of `keys` with something for it, skipping
+    /// keys older clients are waiting on. Returns the reply and the commands to
+    /// propagate, including the pops for clients served after it, or None if no
+    /// key has anything yet.
+    fn pop_first(db: &mut Db, keys: &[Bytes], op: &BlockingOp) -> Result<Option<Served>, RedisValue> {
+        for key in keys {
+            if db.blocked.first(key).is_some() {
+                continue;
+            }
+            if let Some((reply, mut served)) = RedisServer::pop_for(db, key, op)? {
+                served.extend(RedisServer::serve_blocked(db));
+                return Ok(Some((reply, served)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The reply to a blocked client that waited in vain
+    fn timed_out(op: &BlockingOp) -> RedisValue {
+        match op {
+            BlockingOp::Pop(_) | BlockingOp::ZPop { .. } | BlockingOp::XRead { .. } | BlockingOp::XReadGroup { .. } => {
+                RedisValue::NullArray
+            }
+            BlockingOp::Move { .. } => RedisValue::Null,
+        }
+    }
+
+    /// Hands elements pushed to keys that clients are blocked on to those clients,
+    /// longest waiting first. Returns the pops made for them, as commands to
+    /// propagate after the write that pushed the elements.
+    pub(super) fn serve_blocked(db: &mut Db) -> Vec<Vec<Bytes>> {
+        let mut served = Vec::new();
+        loop {
+            let ready = db.blocked.take_ready();
+            if ready.is_empty() {
+                return served;
+            }
+            for key in ready {
+                for id in db.blocked.waiting(&key) {
+                    let (gone, op) = match db.blocked.get(id) {
+                        Some(waiter) => (waiter.is_gone(), waiter.op.clone()),
+                        None => continue,
+                    };
+                    if gone {
+                        db.blocked.take(id);
+                        continue;
+                    }
+                    let reply = match RedisServer::pop_for(db, &key, &op) {
+                        Ok(Some((reply, args))) => {
+                            served.extend(args);
+                            reply
+                        }
+                        // A stream reader may still be waiting for a later entry
+                        // than the next client
+                        Ok(None) => continue,
+                        Err(e) => e,
+                    };
+                    if let Some(waiter) = db.blocked.take(id) {
+                        waiter.wake(reply);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn push(&self, key: &[u8], elements: &[Bytes], end: ListEnd, only_if_exists: bool) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        if only_if_exists && RedisServer::get_list(&mut db, key)?.is_none() {
+            return Ok(0);
+        }
+        Ok(RedisServer::push_to(&mut db, key, elements, end)? as i64)
+    }
+
+    /// LPOP and RPOP. None if there's no such key.
+    pub fn pop(&self, key: &[u8], end: ListEnd, count: usize) -> Result<Option<Vec<Bytes>>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        if RedisServer::get_list(&mut db, key)?.is_none() {
+            return Ok(None);
+        }
+        RedisServer::pop_from(&mut db, key, end, count).map(Some)
+    }
+
+    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let list = match RedisServer::get_list(&mut db, key)? {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        Ok(match list_range(list.len(), start, stop) {
+            Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+            None => Vec::new(),
+        })
+    }
+
+    pub fn llen(&self, key: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_list(&mut db, key)?.map_or(0, |list| list.len() as i64))
+    }
+
+    pub fn lindex(&self, key: &[u8], index: i64) -> Result<Option<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_list(&mut db, key)?
+            .and_then(|list| list_index(list.len(), index).map(|i| list[i].clone())))
+    }
+
+    pub fn lset(&self, key: &[u8], index: i64, element: &Bytes) -> Result<(), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let list = RedisServer::get_list(&mut db, key)?
+            .ok_or_else(|| RedisValue::Error("ERR no such key".to_string()))?;
+        let index = list_index(list.len(), index)
+            .ok_or_else(|| RedisValue::Error("ERR index out of range".to_string()))?;
+        list[index] = element.clone();
+        Ok(())
+    }
+
+    /// Inserts `element` next to the first `pivot`. Returns the new length, -1 if
+    /// there's no pivot or 0 if there's no list.
+    pub fn linsert(&self, key: &[u8], before: bool, pivot: &[u8], element: &Bytes) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let list = match RedisServer::get_list(&mut db, key)? {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let position = match list.iter().position(|e| e == pivot) {
+            Some(position) => position,
+            None => return Ok(-1),
+        };
+        list.insert(if before { position } else { position + 1 }, element.clone());
+        Ok(list.len() as i64)
+    }
+
+    /// Removes `count` occurrences of `element`, from the tail when negative or all
+    /// of them when 0. Returns how many were removed.
+    pub fn lrem(&self, key: &[u8], count: i64, element: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let list = match RedisServer::get_list(&mut db, key)? {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+        let positions: Vec<usize> = if count < 0 {
+            (0..list.len()).rev().filter(|&i| list[i] == element).take(limit).collect()
+        } else {
+            (0..list.len()).filter(|&i| list[i] == element).take(limit).collect()
+        };
+        let mut index = 0;
+        list.retain(|_| {
+            index += 1;
+            !positions.contains(&(index - 1))
+        });
+        if list.is_empty() {
+            db.remove(key);
+        }
+        Ok(positions.len() as i64)
+    }
+
+    /// Keeps only the elements between `start` and `stop`. Returns how many were
+    /// removed.
+    pub fn ltrim(&self, key: &[u8], start: i64, stop: i64) -> Result<usize, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let list = match RedisServer::get_list(&mut db, key)? {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let len = list.len();
+        match list_range(len, start, stop) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+            None => list.clear(),
+        }
+        let removed = len - list.len();
+        if list.is_empty() {
+            db.remove(key);
+        }
+        Ok(removed)
+    }
+
+    /// Positions of `element`, as many as the options ask for
+    pub fn lpos(&self, key: &[u8], element: &[u8], options: &LPosOptions) -> Result<Vec<i64>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let list = match RedisServer::get_list(&mut db, key)? {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let maxlen = if options.maxlen == 0 { list.len() } else { options.maxlen };
+        let wanted = match options.count {
+            Some(0) => usize::MAX,
+            Some(count) => count,
+            None => 1,
+        };
+        let skip = options.rank.unsigned_abs() as usize - 1;
+        let matches = |i: &usize| list[*i] == element;
+        let positions: Vec<usize> = if options.rank > 0 {
+            (0..list.len()).take(maxlen).filter(matches).skip(skip).take(wanted).collect()
+        } else {
+            (0..list.len()).rev().take(maxlen).filter(matches).skip(skip).take(wanted).collect()
+        };
+        Ok(positions.into_iter().map(|i| i as i64).collect())
+    }
+
+    pub fn lmove(&self, source: &[u8], destination: &[u8], from: ListEnd, to: ListEnd) -> Result<Option<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        RedisServer::move_element(&mut db, source, destination, from, to)
+    }
+
+    /// BLPOP and friends. A key that already has an element is served right away,
+    /// unless older clients are waiting on it; otherwise the client waits for a
+    /// push, the timeout or its connection closing, whichever comes first.
+    pub async fn block(
+        &self,
+        logger: &Logger,
+        keys: &[Bytes],
+        op: &BlockingOp,
+        timeout: Option<Duration>,
+        stream: &tokio::net::TcpStream,
+        tx: &Option<Arc<Backlog>>,
+    ) -> RedisValue {
+        let (id, rx) = {
+            let _shared = self.exec_lock.read().unwrap();
+            let mut aof = self.aof.as_deref().map(Aof::lock);
+            let mut db = self.db.lock().unwrap();
+            match RedisServer::pop_first(&mut db, keys, op) {
+                Ok(Some((reply, served))) => {
+                    drop(db);
+                    for args in served {
+                        self.propagate_args(logger, &mut aof, tx, args);
+                    }
+                    return reply;
+                }
+                Ok(None) => {}
+                Err(e) => return e,
+            }
+            db.blocked.block(keys.to_vec(), op.clone())
+        };
+        self.wait_blocked(logger, id, rx, timeout, RedisServer::timed_out(op), stream).await
+    }
+
+    /// A blocking pop queued in a transaction, which can't wait: with nothing to
+    /// pop it replies as if it timed out
+    pub(super) fn pop_now(
+        &self,
+        logger: &Logger,
+        keys: &[Bytes],
+        op: &BlockingOp,
+        tx: &Option<Arc<Backlog>>,
+    ) -> RedisValue {
+        let mut aof = self.aof.as_deref().map(Aof::lock);
+        let popped = RedisServer::pop_first(&mut self.db.lock().unwrap(), keys, op);
+        match popped {
+            Ok(Some((reply, served))) => {
+                for args in served {
+                    self.propagate_args(logger, &mut aof, tx, args);
+                }
+                reply
+            }
+            Ok(None) => RedisServer::timed_out(op),
+            Err(e) => e,
+        }
+    }
+
+    /// Waits for a client parked as `id` to be served, giving up with `timed_out`
+    /// on the timeout or the connection closing
+    pub(super) async fn wait_blocked(
+        &self,
+        logger: &Logger,
+        id: u64,
+        mut rx: oneshot::Receiver<RedisValue>,
+        timeout: Option<Duration>,
+        timed_out: RedisValue,
+        stream: &tokio::net::TcpStream,
+    ) -> RedisValue {
+        let wait = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let closed = async {
+            // Anything else the client sends stays queued until this command is done
+            match stream.peek(&mut [0]).await {
+                Ok(0) | Err(_) => {}
+                Ok(_) => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            reply = &mut rx => return reply.unwrap_or(timed_out),
+            _ = wait => {}
+            _ = closed => logger.log("Blocked client disconnected"),
+        }
+        if self.db.lock().unwrap().blocked.unblock(id) {
+            timed_out
+        } else {
+            // Served while giving up; the reply is already on its way
+            rx.await.unwrap_or(timed_out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: &str) -> Vec<Bytes> {
+        items.split_whitespace().map(|s| Bytes::from(s.to_string())).collect()
+    }
+
+    #[test]
+    fn test_list_operations() {
+        let server = RedisServer::new(&[]);
+        assert_eq!(server.push(b"q", &list("a b c"), ListEnd::Left, true), Ok(0));
+        assert_eq!(server.push(b"q", &list("a b c"), ListEnd::Left, false), Ok(3));
+        assert_eq!(server.push(b"q", &list("a d"), ListEnd::Right, false), Ok(5));
+        assert_eq!(server.lrange(b"q", 0, -1), Ok(list("c b a a d")));
+        assert_eq!(server.lrange(b"q", -2, 100), Ok(list("a d")));
+        assert_eq!(server.lrange(b"q", 3, 1), Ok(vec![]));
+        assert_eq!(server.lindex(b"q", -1), Ok(Some(Bytes::from("d"))));
+        assert_eq!(server.lindex(b"q", 5), Ok(None));
+
+        let all = LPosOptions {
+            count: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(server.lpos(b"q", b"a", &all), Ok(vec![2, 3]));
+        let last = LPosOptions {
+            rank: -1,
+            ..Default::default()
+        };
+        assert_eq!(server.lpos(b"q", b"a", &last), Ok(vec![3]));
+        let short = LPosOptions {
+            maxlen: 2,
+            ..Default::default()
+        };
+        assert_eq!(server.lpos(b"q", b"a", &short), Ok(vec![]));
+
+        assert_eq!(server.linsert(b"q", false, b"d", &Bytes::from("e")), Ok(6));
+        assert_eq!(server.linsert(b"q", true, b"x", &Bytes::from("e")), Ok(-1));
+        assert_eq!(server.lrem(b"q", -1, b"a"), Ok(1));
+        assert_eq!(server.lrange(b"q", 0, -1), Ok(list("c b a d e")));
+        assert_eq!(server.ltrim(b"q", 1, -2), Ok(2));
+        assert_eq!(server.lrange(b"q", 0, -1), Ok(list("b a d")));
+        assert!(server.lset(b"q", 3, &Bytes::from("z")).is_err());
+
+        assert_eq!(server.lmove(b"q", b"q", ListEnd::Left, ListEnd::Right), Ok(Some(Bytes::from("b"))));
+        assert_eq!(server.pop(b"q", ListEnd::Right, 2), Ok(Some(list("b d"))));
+        assert_eq!(server.pop(b"q", ListEnd::Left, 5), Ok(Some(list("a"))));
+        // The emptied list is gone
+        assert_eq!(server.pop(b"q", ListEnd::Left, 1), Ok(None));
+        assert_eq!(server.key_type(b"q"), "none");
+
+        server.mset(&[(Bytes::from("s"), Bytes::from("v"))], false);
+        assert_eq!(
+            server.llen(b"s"),
+            Err(RedisValue::Error(WRONGTYPE.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_blocked_clients_are_served_in_order() {
+        let server = RedisServer::new(&[]);
+        let pop = BlockingOp::Pop(ListEnd::Left);
+        let (_, mut first) = server.db.lock().unwrap().blocked.block(list("q"), pop.clone());
+        let (_, mut second) = server.db.lock().unwrap().blocked.block(list("other q"), pop);
+
+        server.push(b"q", &list("a b c"), ListEnd::Right, false).unwrap();
+        let served = RedisServer::serve_blocked(&mut server.db.lock().unwrap());
+        assert_eq!(served, vec![list("LPOP q"), list("LPOP q")]);
+        assert_eq!(
+            first.try_recv(),
+            Ok(RedisValue::Array(vec![
+                RedisValue::BulkString(Bytes::from("q")),
+                RedisValue::BulkString(Bytes::from("a")),
+            ]))
+        );
+        assert_eq!(
+            second.try_recv(),
+            Ok(RedisValue::Array(vec![
+                RedisValue::BulkString(Bytes::from("q")),
+                RedisValue::BulkString(Bytes::from("b")),
+            ]))
+        );
+        assert_eq!(server.lrange(b"q", 0, -1), Ok(list("c")));
+    }
+}
@@ -0,0 +1,242 @@
+//! Pub/sub commands, sharded pub/sub included
+//!
+//! Shard channels belong to key slots as keys do. There's only the one node here,
+//! but the commands still insist on a single slot, as a cluster would.
+use bytes::Bytes;
+use std::collections::BTreeSet;
+
+use super::{RedisServer, RedisValue};
+use crate::client::ClientState;
+use crate::cluster::key_slot;
+use crate::glob::glob_match;
+use crate::parser::Command;
+use crate::pubsub::{PubSub, Subscribers};
+
+/// What a client subscribes to
+#[derive(Debug, Clone, Copy)]
+enum Subscription {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Subscription {
+    /// The kind of frame confirming a subscription or its end
+    fn frame(self, subscribe: bool) -> &'static str {
+        match (self, subscribe) {
+            (Subscription::Channel, true) => "subscribe",
+            (Subscription::Channel, false) => "unsubscribe",
+            (Subscription::Pattern, true) => "psubscribe",
+            (Subscription::Pattern, false) => "punsubscribe",
+            (Subscription::Shard, true) => "ssubscribe",
+            (Subscription::Shard, false) => "sunsubscribe",
+        }
+    }
+
+    /// The count sent along with a confirmation: shard channels are counted
+    /// apart from the rest
+    fn count(self, client: &ClientState) -> usize {
+        match self {
+            Subscription::Shard => client.shard_channels.len(),
+            _ => client.subscriptions(),
+        }
+    }
+
+    fn of_client(self, client: &mut ClientState) -> &mut BTreeSet<Bytes> {
+        match self {
+            Subscription::Channel => &mut client.channels,
+            Subscription::Pattern => &mut client.patterns,
+            Subscription::Shard => &mut client.shard_channels,
+        }
+    }
+
+    fn of_server(self, pubsub: &mut PubSub) -> &mut Subscribers {
+        match self {
+            Subscription::Channel => &mut pubsub.channels,
+            Subscription::Pattern => &mut pubsub.patterns,
+            Subscription::Shard => &mut pubsub.shard_channels,
+        }
+    }
+}
+
+/// The channels with subscribers, optionally only those matching `pattern`
+fn matching(subscribers: &Subscribers, pattern: Option<&Bytes>) -> Vec<Bytes> {
+    subscribers
+        .names()
+        .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel, false)))
+        .cloned()
+        .collect()
+}
+
+/// How many clients are subscribed to each of `channels`
+fn counts(subscribers: &Subscribers, channels: &[Bytes]) -> Vec<(Bytes, i64)> {
+    channels
+        .iter()
+        .map(|channel| (channel.clone(), subscribers.count(channel) as i64))
+        .collect()
+}
+
+/// A confirmation of a subscription or its end, with how many the client has left
+fn confirmation(kind: &str, name: Option<Bytes>, count: usize) -> RedisValue {
+    RedisValue::Push(vec![
+        RedisValue::BulkString(Bytes::copy_from_slice(kind.as_bytes())),
+        name.map_or(RedisValue::Null, RedisValue::BulkString),
+        RedisValue::Int(count as i64),
+    ])
+}
+
+impl RedisServer {
+    /// Runs SUBSCRIBE, UNSUBSCRIBE and their pattern and shard versions, replying
+    /// with a frame per channel or pattern
+    pub(super) fn subscription_command(&self, client: &mut ClientState, command: &Command) -> Vec<RedisValue> {
+        match command {
+            Command::Subscribe(names) => self.subscribe(client, Subscription::Channel, names),
+            Command::Unsubscribe(names) => self.unsubscribe(client, Subscription::Channel, names),
+            Command::PSubscribe(names) => self.subscribe(client, Subscription::Pattern, names),
+            Command::PUnsubscribe(names) => self.unsubscribe(client, Subscription::Pattern, names),
+            Command::SSubscribe(names) | Command::SUnsubscribe(names) if !same_slot(names) => {
+                vec![RedisValue::Error(
+                    "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+                )]
+            }
+            Command::SSubscribe(names) => self.subscribe(client, Subscription::Shard, names),
+            Command::SUnsubscribe(names) => self.unsubscribe(client, Subscription::Shard, names),
+            _ => unreachable!("not a subscription command"),
+        }
+    }
+
+    /// Subscribes a client to channels or patterns, replying with a
+    /// confirmation for each
+    fn subscribe(&self, client: &mut ClientState, kind: Subscription, names: &[Bytes]) -> Vec<RedisValue> {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        if client.messages.is_none() {
+            client.messages = Some(pubsub.register(client.id));
+        }
+        names
+            .iter()
+            .map(|name| {
+                if kind.of_client(client).insert(name.clone()) {
+                    kind.of_server(&mut pubsub).subscribe(name, client.id);
+                }
+                confirmation(kind.frame(true), Some(name.clone()), kind.count(client))
+            })
+            .collect()
+    }
+
+    /// Unsubscribes a client from channels or patterns, or from all of them when
+    /// `names` is empty, replying with a confirmation for each
+    fn unsubscribe(&self, client: &mut ClientState, kind: Subscription, names: &[Bytes]) -> Vec<RedisValue> {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        let names = match names {
+            [] => kind.of_client(client).iter().cloned().collect(),
+            names => names.to_vec(),
+        };
+        if names.is_empty() {
+            return vec![confirmation(kind.frame(false), None, kind.count(client))];
+        }
+        names
+            .into_iter()
+            .map(|name| {
+                if kind.of_client(client).remove(&name) {
+                    kind.of_server(&mut pubsub).unsubscribe(&name, client.id);
+                }
+                confirmation(kind.frame(false), Some(name), kind.count(client))
+            })
+            .collect()
+    }
+
+    /// Sends a message to a channel, returning how many subscribers got it
+    pub fn publish(&self, channel: &Bytes, message: &Bytes) -> i64 {
+        self.pubsub.lock().unwrap().publish(channel, message) as i64
+    }
+
+    /// Channels with subscribers, optionally only those matching `pattern`
+    pub fn pubsub_channels(&self, pattern: Option<&Bytes>) -> Vec<Bytes> {
+        matching(&self.pubsub.lock().unwrap().channels, pattern)
+    }
+
+    /// How many clients are subscribed to each of `channels`
+    pub fn pubsub_numsub(&self, channels: &[Bytes]) -> Vec<(Bytes, i64)> {
+        counts(&self.pubsub.lock().unwrap().channels, channels)
+    }
+
+    /// How many distinct patterns clients are subscribed to
+    pub fn pubsub_numpat(&self) -> i64 {
+        self.pubsub.lock().unwrap().patterns.len() as i64
+    }
+
+    /// Sends a message to a shard channel, returning how many subscribers got it
+    pub fn spublish(&self, channel: &Bytes, message: &Bytes) -> i64 {
+        self.pubsub.lock().unwrap().spublish(channel, message) as i64
+    }
+
+    /// Shard channels with subscribers, optionally only those matching `pattern`
+    pub fn pubsub_shardchannels(&self, pattern: Option<&Bytes>) -> Vec<Bytes> {
+        matching(&self.pubsub.lock().unwrap().shard_channels, pattern)
+    }
+
+    /// How many clients are subscribed to each of the shard `channels`
+    pub fn pubsub_shardnumsub(&self, channels: &[Bytes]) -> Vec<(Bytes, i64)> {
+        counts(&self.pubsub.lock().unwrap().shard_channels, channels)
+    }
+}
+
+/// Whether every channel belongs to the same slot
+fn same_slot(channels: &[Bytes]) -> bool {
+    channels.windows(2).all(|pair| key_slot(&pair[0]) == key_slot(&pair[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscriptions() {
+        let server = RedisServer::new(&[]);
+        let mut client = ClientState::new(1);
+        let channels = vec![Bytes::from("a"), Bytes::from("b")];
+        let replies = server.subscribe(&mut client, Subscription::Channel, &channels);
+        assert_eq!(replies[1], confirmation("subscribe", Some(Bytes::from("b")), 2));
+        server.subscribe(&mut client, Subscription::Pattern, &[Bytes::from("*")]);
+        assert_eq!(client.subscriptions(), 3);
+        assert_eq!(server.pubsub_numpat(), 1);
+        assert_eq!(server.pubsub_numsub(&channels), vec![(Bytes::from("a"), 1), (Bytes::from("b"), 1)]);
+        assert_eq!(server.publish(&Bytes::from("a"), &Bytes::from("hi")), 2);
+
+        // With no channels given, every one is dropped
+        let replies = server.unsubscribe(&mut client, Subscription::Channel, &[]);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(server.pubsub_channels(None), Vec::<Bytes>::new());
+        assert_eq!(
+            server.unsubscribe(&mut client, Subscription::Channel, &[]),
+            vec![confirmation("unsubscribe", None, 1)]
+        );
+        server.disconnect(&client);
+        assert_eq!(server.pubsub_numpat(), 0);
+    }
+
+    #[test]
+    fn test_shard_subscriptions() {
+        let server = RedisServer::new(&[]);
+        let mut client = ClientState::new(1);
+        server.subscribe(&mut client, Subscription::Channel, &[Bytes::from("a")]);
+        let command = Command::SSubscribe(vec![Bytes::from("{user}.a"), Bytes::from("{user}.b")]);
+        let replies = server.subscription_command(&mut client, &command);
+        // Counted apart from the channel subscription
+        assert_eq!(replies[1], confirmation("ssubscribe", Some(Bytes::from("{user}.b")), 2));
+        let command = Command::SSubscribe(vec![Bytes::from("a"), Bytes::from("b")]);
+        assert!(matches!(
+            &server.subscription_command(&mut client, &command)[..],
+            [RedisValue::Error(e)] if e.starts_with("CROSSSLOT")
+        ));
+
+        assert_eq!(server.spublish(&Bytes::from("{user}.a"), &Bytes::from("hi")), 1);
+        assert_eq!(server.spublish(&Bytes::from("a"), &Bytes::from("hi")), 0);
+        assert_eq!(server.pubsub_channels(None), vec![Bytes::from("a")]);
+        assert_eq!(server.pubsub_shardchannels(Some(&Bytes::from("*.b"))), vec![Bytes::from("{user}.b")]);
+        let replies = server.subscription_command(&mut client, &Command::SUnsubscribe(vec![]));
+        assert_eq!(replies.len(), 2);
+        assert_eq!(server.pubsub_shardnumsub(&[Bytes::from("{user}.a")]), vec![(Bytes::from("{user}.a"), 0)]);
+        assert!(client.is_subscribed());
+    }
+}
@@ -0,0 +1,660 @@
+//! EVAL, FCALL and the SCRIPT and FUNCTION commands
+//!
+//! A script or function runs on a thread of its own, holding `exec_lock` like EXEC does, so
+//! no other client's command lands between the ones it calls. Its writes are
+//! propagated rather than the script itself, wrapped in MULTI and EXEC, so
+//! replicas and the AOF don't depend on the script cache or on the script
+//! doing the same thing twice.
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{format_double, RedisServer, RedisValue, SERVER_VERSION};
+use crate::backlog::Backlog;
+use crate::glob::glob_match;
+use crate::log::Logger;
+use crate::lua::{Host, LuaError, Runtime, Table, Value};
+use crate::parser::{Command, EvalScript, Parser, RESPError, RestorePolicy};
+use crate::rdb;
+use crate::scripting::{on_script_thread, register_functions, KillError, Library, RunningFunction, Script};
+
+const BUSY: &str = "BUSY Redis is busy running a script. You can only call SCRIPT KILL or FUNCTION KILL.";
+
+/// What a script reaches of the server through redis.call
+struct ScriptHost<'a> {
+    server: &'a RedisServer,
+    logger: &'a Logger,
+    tx: &'a Option<Arc<Backlog>>,
+    read_only: bool,
+    /// Set for a function rather than a script
+    function: bool,
+    /// Set when the script runs inside EXEC, whose MULTI and EXEC already wrap
+    /// whatever the script writes
+    in_transaction: bool,
+    /// Whether the MULTI ahead of the script's writes went out
+    wrapped: bool,
+}
+
+impl ScriptHost<'_> {
+    fn dispatch(&mut self, args: Vec<Bytes>) -> RedisValue {
+        let command = match Parser::parse_command(&args) {
+            Ok(command) => command,
+            Err(RESPError::UnknownCommand(..)) => {
+                return RedisValue::Error("ERR Unknown Redis command called from script".to_string())
+            }
+            Err(RESPError::WrongNumberOfArguments(_)) => {
+                return RedisValue::Error("ERR Wrong number of args calling Redis command from script".to_string())
+            }
+            Err(e) => return RedisValue::Error(e.to_string()),
+        };
+        match &command {
+            Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch(_)
+            | Command::Unwatch
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::SSubscribe(_)
+            | Command::SUnsubscribe(_)
+            | Command::Eval { .. }
+            | Command::ScriptLoad(_)
+            | Command::ScriptExists(_)
+            | Command::ScriptFlush
+            | Command::ScriptKill
+            | Command::FCall { .. }
+            | Command::FunctionLoad(..)
+            | Command::FunctionDelete(_)
+            | Command::FunctionList { .. }
+            | Command::FunctionDump
+            | Command::FunctionRestore(..)
+            | Command::FunctionFlush
+            | Command::FunctionStats
+            | Command::FunctionKill
+            | Command::Save
+            | Command::Psync
+            | Command::ReplConf(_)
+            | Command::Auth { .. }
+            | Command::Hello { .. } => {
+                RedisValue::Error("ERR This Redis command is not allowed from script".to_string())
+            }
+            write if write.is_write() || matches!(write, Command::Block { .. }) => {
+                if self.read_only {
+                    return RedisValue::Error("ERR Write commands are not allowed from read-only scripts.".to_string());
+                }
+                self.server.script.wrote();
+                if !self.in_transaction && !self.wrapped {
+                    self.server.propagate_multi(self.logger, self.tx);
+                    self.wrapped = true;
+                }
+                match write {
+                    // Scripts never wait
+                    Command::Block { keys, op, .. } => self.server.pop_now(self.logger, keys, op, self.tx),
+                    _ => {
+                        let raw = RedisValue::Array(args.into_iter().map(RedisValue::BulkString).collect());
+                        self.server.execute_write(self.logger, write, &raw.to_response(), self.tx)
+                    }
+                }
+            }
+            other => self.server.execute(self.logger, other),
+        }
+    }
+}
+
+impl Host for ScriptHost<'_> {
+    fn call(&mut self, args: Vec<Bytes>) -> Value {
+        to_lua(self.dispatch(args))
+    }
+
+    fn log(&mut self, level: i64, message: &str) {
+        self.logger.log(&format!("Script log (level {}): {}", level, message));
+    }
+
+    fn check(&mut self) -> Result<(), LuaError> {
+        if self.server.script.killed() {
+            let command = if self.function { "FUNCTION" } else { "SCRIPT" };
+            return Err(LuaError::Interrupted(format!("ERR Script killed by user with {} KILL...", command)));
+        }
+        Ok(())
+    }
+}
+
+/// A command's reply as redis.call hands it to the script
+fn to_lua(reply: RedisValue) -> Value {
+    let mut table = Table::default();
+    match reply {
+        RedisValue::Int(i) => return Value::Number(i as f64),
+        RedisValue::BulkString(s) | RedisValue::Verbatim(_, s) => return Value::String(s),
+        RedisValue::BigNumber(n) => return Value::str(&n),
+        RedisValue::Double(d) => return Value::str(&format_double(d)),
+        RedisValue::Boolean(b) => return Value::Number(b as i64 as f64),
+        RedisValue::Null | RedisValue::NullArray => return Value::Boolean(false),
+        RedisValue::String(status) => table.set_str("ok", Value::str(&status)),
+        RedisValue::Error(e) => table.set_str("err", Value::str(&e)),
+        RedisValue::Array(items) | RedisValue::Set(items) | RedisValue::Push(items) => {
+            table = Table::from_array(items.into_iter().map(to_lua).collect());
+        }
+        RedisValue::Map(pairs) => {
+            let items = pairs.into_iter().flat_map(|(k, v)| [to_lua(k), to_lua(v)]);
+            table = Table::from_array(items.collect());
+        }
+    }
+    Value::table(table)
+}
+
+/// What a script returned, as the reply to send
+fn to_resp(value: &Value) -> RedisValue {
+    match value {
+        Value::Number(n) => RedisValue::Int(*n as i64),
+        Value::String(s) => RedisValue::BulkString(s.clone()),
+        Value::Boolean(true) => RedisValue::Int(1),
+        Value::Table(table) => {
+            let table = table.borrow();
+            if let Value::String(e) = table.get_str("err") {
+                return RedisValue::Error(String::from_utf8_lossy(&e).into_owned());
+            }
+            if let Value::String(status) = table.get_str("ok") {
+                return RedisValue::String(String::from_utf8_lossy(&status).into_owned());
+            }
+            RedisValue::Array(table.sequence().iter().map(to_resp).collect())
+        }
+        _ => RedisValue::Null,
+    }
+}
+
+/// The reply for a script that failed at `line` of `chunk`. Scripts are named by
+/// their digest, functions by their name.
+fn error_reply(error: LuaError, name: &str, chunk: &str, line: u32) -> RedisValue {
+    let message = match error {
+        LuaError::Interrupted(message) => message,
+        LuaError::Error(Value::Table(table)) => match table.borrow().get_str("err") {
+            Value::String(e) => String::from_utf8_lossy(&e).into_owned(),
+            _ => "ERR Error running script".to_string(),
+        },
+        LuaError::Error(other) => {
+            let text = other.to_bytes().unwrap_or_else(|| Bytes::from(format!("({})", other.type_name())));
+            format!("ERR {}", String::from_utf8_lossy(&text))
+        }
+    };
+    RedisValue::Error(format!("{} script: {}, on @{}:{}.", message, name, chunk, line))
+}
+
+/// KEYS, ARGV and the tables FCALL passes a function
+fn strings(words: &[Bytes]) -> Value {
+    let values = words.iter().cloned().map(Value::String).collect();
+    Value::table(Table::from_array(values))
+}
+
+impl RedisServer {
+    /// Runs a script. `in_transaction` is set when EXEC runs it; otherwise the
+    /// caller holds `exec_lock` for it.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn eval(
+        &self,
+        logger: &Logger,
+        script: &EvalScript,
+        keys: &[Bytes],
+        args: &[Bytes],
+        read_only: bool,
+        tx: &Option<Arc<Backlog>>,
+        in_transaction: bool,
+    ) -> RedisValue {
+        let (sha, script) = match script {
+            EvalScript::Sha(sha) => match self.scripts.lock().unwrap().get(sha) {
+                Some(script) => (sha.clone(), script),
+                None => return RedisValue::Error("NOSCRIPT No matching script. Please use EVAL.".to_string()),
+            },
+            EvalScript::Body(body) => match self.load_script(body) {
+                Ok(sha) => {
+                    let script = self.scripts.lock().unwrap().get(&sha).expect("script just loaded");
+                    (sha, script)
+                }
+                Err(e) => return e,
+            },
+        };
+        let read_only = read_only || script.no_writes;
+        self.run_script(logger, tx, read_only, in_transaction, None, |runtime| {
+            runtime.set_global("KEYS", strings(keys));
+            runtime.set_global("ARGV", strings(args));
+            match runtime.run(&script.proto) {
+                Ok(values) => to_resp(&values.into_iter().next().unwrap_or_default()),
+                Err(e) => error_reply(e, &sha, "user_script", runtime.line()),
+            }
+        })
+    }
+
+    /// Calls a function. `in_transaction` is set when EXEC runs it; otherwise the
+    /// caller holds `exec_lock` for it.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn fcall(
+        &self,
+        logger: &Logger,
+        name: &Bytes,
+        keys: &[Bytes],
+        args: &[Bytes],
+        read_only: bool,
+        tx: &Option<Arc<Backlog>>,
+        in_transaction: bool,
+    ) -> RedisValue {
+        let name = String::from_utf8_lossy(name).into_owned();
+        let (library, function) = match self.functions.lock().unwrap().function(&name) {
+            Some(found) => found,
+            None => return RedisValue::Error("ERR Function not found".to_string()),
+        };
+        if read_only && !function.no_writes() {
+            return RedisValue::Error("ERR Can not execute a script with write flag using *_ro command.".to_string());
+        }
+        let mut command = vec![
+            Bytes::from(if read_only { "FCALL_RO" } else { "FCALL" }),
+            Bytes::from(name.clone()),
+            Bytes::from(keys.len().to_string()),
+        ];
+        command.extend(keys.iter().chain(args).cloned());
+        let running = RunningFunction {
+            name: name.clone(),
+            command,
+        };
+        let read_only = read_only || function.no_writes();
+        self.run_script(logger, tx, read_only, in_transaction, Some(running), |runtime| {
+            let call = register_functions(runtime, &library.proto).and_then(|registered| {
+                let callback = registered.into_iter().find(|registration| registration.name == name);
+                let callback = callback.map(|registration| registration.callback).unwrap_or_default();
+                runtime.call(&callback, vec![strings(keys), strings(args)])
+            });
+            match call {
+                Ok(values) => to_resp(&values.into_iter().next().unwrap_or_default()),
+                Err(e) => error_reply(e, &name, "user_function", runtime.line()),
+            }
+        })
+    }
+
+    /// Runs a script or function on the script thread. `run` gets a runtime with
+    /// redis.call wired to the server, and must convert what it returns while the
+    /// runtime is still around.
+    fn run_script(
+        &self,
+        logger: &Logger,
+        tx: &Option<Arc<Backlog>>,
+        read_only: bool,
+        in_transaction: bool,
+        function: Option<RunningFunction>,
+        run: impl FnOnce(&mut Runtime) -> RedisValue + Send,
+    ) -> RedisValue {
+        let chunk = if function.is_some() { "user_function" } else { "user_script" };
+        let mut host = ScriptHost {
+            server: self,
+            logger,
+            tx,
+            read_only,
+            function: function.is_some(),
+            in_transaction,
+            wrapped: false,
+        };
+        self.script.start(function);
+        let reply = on_script_thread(|| {
+            let mut runtime = Runtime::new(chunk, &mut host);
+            if let Value::Table(redis) = runtime.global("redis") {
+                let mut redis = redis.borrow_mut();
+                redis.set_str("REDIS_VERSION", Value::str(SERVER_VERSION));
+                redis.set_str("REDIS_VERSION_NUM", Value::Number(version_number() as f64));
+            }
+            run(&mut runtime)
+        });
+        if host.wrapped {
+            self.propagate_exec(logger, tx);
+        }
+        self.script.finish();
+        reply
+    }
+
+    /// Compiles a script into the cache, returning its digest, or the error to reply with
+    fn load_script(&self, body: &Bytes) -> Result<String, RedisValue> {
+        let sha = crate::sha1::sha1_hex(body);
+        if self.scripts.lock().unwrap().get(&sha).is_some() {
+            return Ok(sha);
+        }
+        let script = Script::compile(body).map_err(RedisValue::Error)?;
+        Ok(self.scripts.lock().unwrap().insert(script))
+    }
+
+    pub(super) fn script_load(&self, body: &Bytes) -> RedisValue {
+        match self.load_script(body) {
+            Ok(sha) => RedisValue::BulkString(Bytes::from(sha)),
+            Err(e) => e,
+        }
+    }
+
+    pub(super) fn script_exists(&self, shas: &[Bytes]) -> RedisValue {
+        let scripts = self.scripts.lock().unwrap();
+        RedisValue::Array(shas.iter().map(|sha| RedisValue::Int(scripts.contains(sha) as i64)).collect())
+    }
+
+    pub(super) fn script_flush(&self) -> RedisValue {
+        self.scripts.lock().unwrap().clear();
+        RedisValue::String("OK".to_string())
+    }
+
+    pub(super) fn script_kill(&self) -> RedisValue {
+        self.kill_script(false)
+    }
+
+    /// Stops the script running, or the function running with `function` set
+    fn kill_script(&self, function: bool) -> RedisValue {
+        match self.script.kill(function) {
+            Ok(()) => RedisValue::String("OK".to_string()),
+            Err(KillError::NotBusy) => RedisValue::Error("NOTBUSY No scripts in execution right now.".to_string()),
+            Err(KillError::Unkillable) => RedisValue::Error(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either \
+                 wait the script termination or kill the server in a hard way."
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// Loads a library, returning its name and 1 change, or the error to reply with
+    pub(super) fn function_load(&self, code: &Bytes, replace: bool) -> (RedisValue, u64) {
+        let library = match Library::load(code) {
+            Ok(library) => library,
+            Err(e) => return (RedisValue::Error(e), 0),
+        };
+        let name = library.name.clone();
+        match self.functions.lock().unwrap().add(library, replace) {
+            Ok(()) => (RedisValue::BulkString(Bytes::from(name)), 1),
+            Err(e) => (RedisValue::Error(e), 0),
+        }
+    }
+
+    pub(super) fn function_delete(&self, name: &Bytes) -> (RedisValue, u64) {
+        match self.functions.lock().unwrap().remove(&String::from_utf8_lossy(name)) {
+            true => (RedisValue::String("OK".to_string()), 1),
+            false => (RedisValue::Error("ERR Library not found".to_string()), 0),
+        }
+    }
+
+    pub(super) fn function_list(&self, pattern: &Option<Bytes>, with_code: bool) -> RedisValue {
+        let text = |s: &str| RedisValue::BulkString(Bytes::from(s.to_string()));
+        let functions = self.functions.lock().unwrap();
+        let libraries = functions
+            .iter()
+            .filter(|library| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, library.name.as_bytes(), false))
+            })
+            .map(|library| {
+                let functions = library.functions.iter().map(|function| {
+                    RedisValue::Map(vec![
+                        (text("name"), text(&function.name)),
+                        (text("description"), function.description.as_deref().map_or(RedisValue::Null, text)),
+                        (text("flags"), RedisValue::Set(function.flags.iter().map(|flag| text(flag)).collect())),
+                    ])
+                });
+                let mut fields = vec![
+                    (text("library_name"), text(&library.name)),
+                    (text("engine"), text("LUA")),
+                    (text("functions"), RedisValue::Array(functions.collect())),
+                ];
+                if with_code {
+                    fields.push((text("library_code"), RedisValue::BulkString(library.code.clone())));
+                }
+                RedisValue::Map(fields)
+            });
+        RedisValue::Array(libraries.collect())
+    }
+
+    /// The code of every library, ordered by library name
+    pub(super) fn function_codes(&self) -> Vec<Bytes> {
+        self.functions.lock().unwrap().iter().map(|library| library.code.clone()).collect()
+    }
+
+    pub(super) fn function_dump(&self) -> RedisValue {
+        RedisValue::BulkString(Bytes::from(rdb::encode_function_dump(&self.function_codes())))
+    }
+
+    /// Loads the libraries in a FUNCTION DUMP payload. Either they all load or
+    /// none do.
+    pub(super) fn function_restore(&self, payload: &Bytes, policy: RestorePolicy) -> (RedisValue, u64) {
+        let codes = match rdb::parse_function_dump(payload) {
+            Ok(codes) => codes,
+            Err(_) => return (RedisValue::Error("ERR payload version or checksum are wrong".to_string()), 0),
+        };
+        let mut loaded = Vec::new();
+        for code in &codes {
+            match Library::load(code) {
+                Ok(library) => loaded.push(library),
+                Err(e) => return (RedisValue::Error(e), 0),
+            }
+        }
+        let mut functions = self.functions.lock().unwrap();
+        let mut restored = match policy {
+            RestorePolicy::Flush => Default::default(),
+            _ => functions.clone(),
+        };
+        for library in loaded {
+            if let Err(e) = restored.add(library, policy == RestorePolicy::Replace) {
+                return (RedisValue::Error(e), 0);
+            }
+        }
+        *functions = restored;
+        (RedisValue::String("OK".to_string()), 1)
+    }
+
+    pub(super) fn function_flush(&self) -> (RedisValue, u64) {
+        self.functions.lock().unwrap().clear();
+        (RedisValue::String("OK".to_string()), 1)
+    }
+
+    pub(super) fn function_stats(&self) -> RedisValue {
+        let text = |s: &str| RedisValue::BulkString(Bytes::from(s.to_string()));
+        let running = match self.script.function() {
+            Some((function, elapsed)) => RedisValue::Map(vec![
+                (text("name"), text(&function.name)),
+                (text("command"), RedisValue::Array(function.command.into_iter().map(RedisValue::BulkString).collect())),
+                (text("duration_ms"), RedisValue::Int(elapsed.as_millis() as i64)),
+            ]),
+            None => RedisValue::Null,
+        };
+        let functions = self.functions.lock().unwrap();
+        let lua = RedisValue::Map(vec![
+            (text("libraries_count"), RedisValue::Int(functions.len() as i64)),
+            (text("functions_count"), RedisValue::Int(functions.function_count() as i64)),
+        ]);
+        RedisValue::Map(vec![
+            (text("running_script"), running),
+            (text("engines"), RedisValue::Map(vec![(text("LUA"), lua)])),
+        ])
+    }
+
+    pub(super) fn function_kill(&self) -> RedisValue {
+        self.kill_script(true)
+    }
+
+    /// Waits for a script another client is running to finish. Gives up once it's
+    /// been running for longer than the busy threshold, returning the BUSY error.
+    pub(super) async fn wait_for_script(&self) -> Option<RedisValue> {
+        let threshold = Duration::from_millis(self.config.busy_reply_threshold);
+        match self.script.wait(threshold).await {
+            true => None,
+            false => Some(RedisValue::Error(BUSY.to_string())),
+        }
+    }
+}
+
+/// The server version as a number, e.g. 0x070200 for 7.2.0
+fn version_number() -> i64 {
+    SERVER_VERSION
+        .split('.')
+        .map(|part| part.parse::<i64>().unwrap_or(0))
+        .fold(0, |number, part| (number << 8) | part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<Bytes> {
+        line.split(' ').map(|w| Bytes::from(w.to_string())).collect()
+    }
+
+    fn eval(server: &RedisServer, script: &str, keys: &str, args: &str) -> RedisValue {
+        let split = |s: &str| if s.is_empty() { vec![] } else { words(s) };
+        let script = EvalScript::Body(Bytes::from(script.to_string()));
+        server.eval(&Logger::new(), &script, &split(keys), &split(args), false, &None, false)
+    }
+
+    fn bulk(s: &str) -> RedisValue {
+        RedisValue::BulkString(Bytes::from(s.to_string()))
+    }
+
+    #[test]
+    fn test_eval() {
+        let server = RedisServer::new(&[]);
+        assert_eq!(
+            eval(&server, "return redis.call('set', KEYS[1], ARGV[1])", "k", "v"),
+            RedisValue::String("OK".to_string())
+        );
+        assert_eq!(eval(&server, "return redis.call('get', KEYS[1])", "k", ""), bulk("v"));
+        assert_eq!(
+            eval(&server, "return {1, 'a', 2.9, true, false, {ok = 'fine'}, nil, 3}", "", ""),
+            RedisValue::Array(vec![
+                RedisValue::Int(1),
+                bulk("a"),
+                RedisValue::Int(2),
+                RedisValue::Int(1),
+                RedisValue::Null,
+                RedisValue::String("fine".to_string()),
+            ])
+        );
+        // Replies come back as Lua values
+        let script = "redis.call('rpush', 'l', 'a', 'b') \
+                      local r = redis.call('lrange', 'l', 0, -1) \
+                      return {#r, type(redis.call('get', 'missing')), redis.call('type', 'l').ok}";
+        assert_eq!(
+            eval(&server, script, "", ""),
+            RedisValue::Array(vec![RedisValue::Int(2), bulk("boolean"), bulk("list")])
+        );
+        assert_eq!(eval(&server, "return redis.REDIS_VERSION_NUM", "", ""), RedisValue::Int(0x070200));
+
+        // EVAL caches what it runs
+        let sha = crate::sha1::sha1_hex(b"return 1");
+        eval(&server, "return 1", "", "");
+        let cached = EvalScript::Sha(sha.clone());
+        let reply = server.eval(&Logger::new(), &cached, &[], &[], false, &None, false);
+        assert_eq!(reply, RedisValue::Int(1));
+        assert_eq!(
+            server.script_exists(&[Bytes::from(sha.to_uppercase()), Bytes::from("nope")]),
+            RedisValue::Array(vec![RedisValue::Int(1), RedisValue::Int(0)])
+        );
+        server.script_flush();
+        let reply = server.eval(&Logger::new(), &cached, &[], &[], false, &None, false);
+        assert!(matches!(reply, RedisValue::Error(e) if e.starts_with("NOSCRIPT")));
+        assert_eq!(server.script_load(&Bytes::from("return 1")), bulk(&sha));
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let server = RedisServer::new(&[]);
+        let error = |script: &str| match eval(&server, script, "", "") {
+            RedisValue::Error(e) => e.replace(&crate::sha1::sha1_hex(script.as_bytes()), "sha"),
+            other => panic!("expected an error, got {:?}", other),
+        };
+        assert_eq!(
+            error("return redis.call('nosuch')"),
+            "ERR Unknown Redis command called from script script: sha, on @user_script:1."
+        );
+        assert_eq!(
+            error("redis.call('set', 'k', 'v')\nreturn redis.call('incr', 'k')"),
+            "ERR value is not an integer or out of range script: sha, on @user_script:2."
+        );
+        assert_eq!(error("error('boom')"), "ERR user_script:1: boom script: sha, on @user_script:1.");
+        assert_eq!(
+            error("return redis.call('multi')"),
+            "ERR This Redis command is not allowed from script script: sha, on @user_script:1."
+        );
+        assert_eq!(error("return redis.error_reply('MY failure')"), "MY failure");
+        assert!(error("return +").starts_with("ERR Error compiling script (new function): user_script:1:"));
+
+        // Read-only scripts can still read
+        let script = EvalScript::Body(Bytes::from("return redis.call('del', 'k')"));
+        let reply = server.eval(&Logger::new(), &script, &[], &[], true, &None, false);
+        assert!(matches!(reply, RedisValue::Error(e) if e.starts_with("ERR Write commands are not allowed")));
+        assert_eq!(eval(&server, "#!lua flags=no-writes\nreturn redis.call('exists', 'k')", "", ""), RedisValue::Int(1));
+        let reply = eval(&server, "return redis.pcall('set', 'k').err", "", "");
+        assert_eq!(reply, bulk("ERR Wrong number of args calling Redis command from script"));
+    }
+
+    #[test]
+    fn test_functions() {
+        let server = RedisServer::new(&[]);
+        let code = "#!lua name=lib\n\
+                    redis.register_function('setget', function(keys, args)\n\
+                      redis.call('set', keys[1], args[1])\n\
+                      return redis.call('get', keys[1])\n\
+                    end)\n\
+                    redis.register_function{function_name = 'fail', callback = function() error('boom') end, \
+                    flags = {'no-writes'}}";
+        let code = Bytes::from(code);
+        assert_eq!(server.function_load(&code, false), (bulk("lib"), 1));
+        let (reply, _) = server.function_load(&code, false);
+        assert_eq!(reply, RedisValue::Error("ERR Library 'lib' already exists".to_string()));
+        assert_eq!(server.function_load(&code, true), (bulk("lib"), 1));
+
+        let fcall = |name: &str, keys: &str, args: &str, read_only: bool| {
+            let split = |s: &str| if s.is_empty() { vec![] } else { words(s) };
+            let name = Bytes::from(name.to_string());
+            server.fcall(&Logger::new(), &name, &split(keys), &split(args), read_only, &None, false)
+        };
+        assert_eq!(fcall("setget", "k", "v", false), bulk("v"));
+        assert_eq!(
+            fcall("setget", "k", "v", true),
+            RedisValue::Error("ERR Can not execute a script with write flag using *_ro command.".to_string())
+        );
+        assert_eq!(
+            fcall("fail", "", "", true),
+            RedisValue::Error("ERR user_function:6: boom script: fail, on @user_function:6.".to_string())
+        );
+        assert_eq!(fcall("nope", "", "", false), RedisValue::Error("ERR Function not found".to_string()));
+
+        // A dump restores to the same libraries
+        let payload = match server.function_dump() {
+            RedisValue::BulkString(payload) => payload,
+            other => panic!("unexpected {:?}", other),
+        };
+        server.function_flush();
+        assert_eq!(fcall("setget", "k", "v", false), RedisValue::Error("ERR Function not found".to_string()));
+        assert_eq!(server.function_restore(&payload, RestorePolicy::Append).1, 1);
+        let (reply, _) = server.function_restore(&payload, RestorePolicy::Append);
+        assert_eq!(reply, RedisValue::Error("ERR Library 'lib' already exists".to_string()));
+        assert_eq!(server.function_restore(&payload, RestorePolicy::Replace).1, 1);
+        let (reply, _) = server.function_restore(&Bytes::from("garbage!!!!"), RestorePolicy::Flush);
+        assert_eq!(reply, RedisValue::Error("ERR payload version or checksum are wrong".to_string()));
+
+        assert_eq!(server.function_list(&Some(Bytes::from("x*")), false), RedisValue::Array(vec![]));
+        match server.function_list(&None, true) {
+            RedisValue::Array(libraries) => assert_eq!(libraries.len(), 1),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(server.function_delete(&Bytes::from("lib")).1, 1);
+        let (reply, _) = server.function_delete(&Bytes::from("lib"));
+        assert_eq!(reply, RedisValue::Error("ERR Library not found".to_string()));
+        assert_eq!(
+            server.function_kill(),
+            RedisValue::Error("NOTBUSY No scripts in execution right now.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_functions_in_snapshot() {
+        let server = RedisServer::new(&[]);
+        let code = Bytes::from("#!lua name=lib\nredis.register_function('one', function() return 1 end)");
+        server.function_load(&code, false);
+        let replica = RedisServer::new(&[]);
+        assert_eq!(replica.load_rdb_bytes(&server.rdb_dump()), Ok(0));
+        let reply = replica.fcall(&Logger::new(), &Bytes::from("one"), &[], &[], true, &None, false);
+        assert_eq!(reply, RedisValue::Error("ERR Can not execute a script with write flag using *_ro command.".to_string()));
+        let reply = replica.fcall(&Logger::new(), &Bytes::from("one"), &[], &[], false, &None, false);
+        assert_eq!(reply, RedisValue::Int(1));
+    }
+}
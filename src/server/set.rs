@@ -0,0 +1,284 @@
+//! Set commands
+use bytes::Bytes;
+
+use super::{check_random_count, random_u64, RandomPicks, RedisServer, RedisValue, WRONGTYPE};
+use crate::db::{Db, Value};
+use crate::glob::glob_match;
+use crate::parser::{ScanOptions, SetOperation};
+use crate::set::Set;
+
+impl RedisServer {
+    /// The set stored at `key`, or a WRONGTYPE error for other types
+    fn get_set<'a>(db: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut Set>, RedisValue> {
+        match db.get_mut(key) {
+            Some((Value::Set(set), _)) => Ok(Some(set)),
+            Some(_) => Err(RedisValue::Error(WRONGTYPE.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// The set at `key`, created empty if there's none
+    fn get_or_create_set<'a>(db: &'a mut Db, key: &[u8]) -> Result<&'a mut Set, RedisValue> {
+        if RedisServer::get_set(db, key)?.is_none() {
+            db.insert(Bytes::copy_from_slice(key), Value::Set(Set::default()), None);
+        }
+        Ok(RedisServer::get_set(db, key)?.unwrap())
+    }
+
+    /// Adds the given members, returning how many were new
+    pub fn sadd(&self, key: &[u8], members: &[Bytes]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let set = RedisServer::get_or_create_set(&mut db, key)?;
+        Ok(members.iter().filter(|member| set.insert(member)).count() as i64)
+    }
+
+    /// Removes the given members, deleting the key once it's empty. Returns how
+    /// many were there.
+    pub fn srem(&self, key: &[u8], members: &[Bytes]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let set = match RedisServer::get_set(&mut db, key)? {
+            Some(set) => set,
+            None => return Ok(0),
+        };
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+        if set.is_empty() {
+            db.remove(key);
+        }
+        Ok(removed as i64)
+    }
+
+    /// Whether each of `members` is in the set
+    pub fn smismember(&self, key: &[u8], members: &[Bytes]) -> Result<Vec<bool>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let set = RedisServer::get_set(&mut db, key)?;
+        Ok(members
+            .iter()
+            .map(|member| set.as_ref().is_some_and(|set| set.contains(member)))
+            .collect())
+    }
+
+    /// Every member, empty if there's no such key
+    pub fn smembers(&self, key: &[u8]) -> Result<Vec<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_set(&mut db, key)?.map_or_else(Vec::new, |set| set.members()))
+    }
+
+    pub fn scard(&self, key: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_set(&mut db, key)?.map_or(0, |set| set.len() as i64))
+    }
+
+    /// Removes up to `count` random members and returns them, deleting the key once
+    /// it's empty
+    pub fn spop(&self, key: &[u8], count: usize) -> Result<Vec<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let set = match RedisServer::get_set(&mut db, key)? {
+            Some(set) => set,
+            None => return Ok(Vec::new()),
+        };
+        let popped = pick_distinct(set.members(), count);
+        for member in &popped {
+            set.remove(member);
+        }
+        if set.is_empty() {
+            db.remove(key);
+        }
+        Ok(popped)
+    }
+
+    /// Random members: `count` distinct ones, or when negative that many with
+    /// repeats allowed. None if there's no such key.
+    pub fn srandmember(&self, key: &[u8], count: i64) -> Result<Option<RandomPicks<Bytes>>, RedisValue> {
+        check_random_count(count)?;
+        let mut db = self.db.lock().unwrap();
+        let members = match RedisServer::get_set(&mut db, key)? {
+            Some(set) => set.members(),
+            None => return Ok(None),
+        };
+        if count < 0 {
+            return Ok(Some(RandomPicks::Repeated(members, count.unsigned_abs())));
+        }
+        Ok(Some(RandomPicks::Distinct(pick_distinct(members, count as usize))))
+    }
+
+    /// Moves `member` from one set to another, returning whether it was in the
+    /// source. Both keys must hold sets, even when there's nothing to move.
+    pub fn smove(&self, source: &[u8], destination: &[u8], member: &Bytes) -> Result<bool, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        RedisServer::get_set(&mut db, destination)?;
+        let set = match RedisServer::get_set(&mut db, source)? {
+            Some(set) => set,
+            None => return Ok(false),
+        };
+        if source == destination {
+            return Ok(set.contains(member));
+        }
+        if !set.remove(member) {
+            return Ok(false);
+        }
+        if set.is_empty() {
+            db.remove(source);
+        }
+        RedisServer::get_or_create_set(&mut db, destination)?.insert(member);
+        Ok(true)
+    }
+
+    /// The intersection, union or difference of the sets at `keys`. Missing keys
+    /// count as empty sets, but every key must hold a set.
+    fn combine(db: &mut Db, op: SetOperation, keys: &[Bytes]) -> Result<Set, RedisValue> {
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            sets.push(RedisServer::get_set(db, key)?.map(|set| set.clone()));
+        }
+        let result = match op {
+            SetOperation::Inter => {
+                if sets.iter().any(Option::is_none) {
+                    return Ok(Set::default());
+                }
+                let mut sets: Vec<Set> = sets.into_iter().flatten().collect();
+                // Only the smallest set needs walking
+                sets.sort_by_key(Set::len);
+                let (smallest, others) = sets.split_first().unwrap();
+                smallest
+                    .members()
+                    .into_iter()
+                    .filter(|member| others.iter().all(|set| set.contains(member)))
+                    .collect()
+            }
+            SetOperation::Union => sets.into_iter().flatten().flat_map(|set| set.members()).collect(),
+            SetOperation::Diff => {
+                let (first, others) = sets.split_first().unwrap();
+                first.as_ref().map_or_else(Set::default, |first| {
+                    first
+                        .members()
+                        .into_iter()
+                        .filter(|member| others.iter().flatten().all(|set| !set.contains(member)))
+                        .collect()
+                })
+            }
+        };
+        Ok(result)
+    }
+
+    /// SINTER, SUNION and SDIFF
+    pub fn set_operation(&self, op: SetOperation, keys: &[Bytes]) -> Result<Vec<Bytes>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::combine(&mut db, op, keys)?.members())
+    }
+
+    /// SINTERSTORE, SUNIONSTORE and SDIFFSTORE: replaces `destination` with the
+    /// result, or deletes it if that's empty. Returns the size of the result and
+    /// whether the keyspace changed.
+    pub fn set_operation_store(&self, op: SetOperation, destination: &Bytes, keys: &[Bytes]) -> Result<(i64, bool), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let result = RedisServer::combine(&mut db, op, keys)?;
+        let len = result.len() as i64;
+        if result.is_empty() {
+            return Ok((0, db.remove(destination).is_some()));
+        }
+        db.insert(destination.clone(), Value::Set(result), None);
+        Ok((len, true))
+    }
+
+    /// Size of the intersection, counting no further than `limit` unless it's 0
+    pub fn sintercard(&self, keys: &[Bytes], limit: usize) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let count = RedisServer::combine(&mut db, SetOperation::Inter, keys)?.len();
+        Ok(if limit > 0 { count.min(limit) } else { count } as i64)
+    }
+
+    /// One step of an SSCAN iteration: the next cursor and the matching members
+    pub fn sscan(&self, key: &[u8], cursor: u64, options: &ScanOptions) -> Result<(u64, Vec<Bytes>), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let (next, batch) = match RedisServer::get_set(&mut db, key)? {
+            Some(set) => set.scan(cursor, options.count),
+            None => return Ok((0, Vec::new())),
+        };
+        let members = batch
+            .into_iter()
+            .filter(|member| {
+                options
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, member, false))
+            })
+            .collect();
+        Ok((next, members))
+    }
+}
+
+/// Up to `count` of `members`, picked at random by a partial shuffle
+fn pick_distinct(mut members: Vec<Bytes>, count: usize) -> Vec<Bytes> {
+    let count = count.min(members.len());
+    for i in 0..count {
+        let j = i + random_u64() as usize % (members.len() - i);
+        members.swap(i, j);
+    }
+    members.truncate(count);
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &str) -> Vec<Bytes> {
+        words.split_whitespace().map(|word| Bytes::from(word.to_string())).collect()
+    }
+
+    fn sorted(mut members: Vec<Bytes>) -> Vec<Bytes> {
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let server = RedisServer::new(&[]);
+        assert_eq!(server.sadd(b"a", &words("1 2 3 x")), Ok(4));
+        assert_eq!(server.sadd(b"a", &words("1 y")), Ok(1));
+        assert_eq!(server.sadd(b"b", &words("2 3 4")), Ok(3));
+        assert_eq!(server.key_type(b"a"), "set");
+        assert_eq!(server.smismember(b"a", &words("1 4")), Ok(vec![true, false]));
+
+        assert_eq!(
+            sorted(server.set_operation(SetOperation::Inter, &words("a b")).unwrap()),
+            words("2 3")
+        );
+        assert_eq!(
+            sorted(server.set_operation(SetOperation::Diff, &words("a b nope")).unwrap()),
+            words("1 x y")
+        );
+        assert_eq!(server.set_operation(SetOperation::Inter, &words("a nope")), Ok(vec![]));
+        assert_eq!(server.sintercard(&words("a b"), 1), Ok(1));
+
+        assert_eq!(server.set_operation_store(SetOperation::Union, &Bytes::from("u"), &words("a b")), Ok((6, true)));
+        assert_eq!(server.set_operation_store(SetOperation::Inter, &Bytes::from("u"), &words("a nope")), Ok((0, true)));
+        assert_eq!(server.key_type(b"u"), "none");
+
+        server.set(Bytes::from("s"), Bytes::from("v"), &Default::default());
+        assert_eq!(
+            server.set_operation(SetOperation::Union, &words("a s")),
+            Err(RedisValue::Error(WRONGTYPE.to_string()))
+        );
+        assert_eq!(
+            server.smove(b"a", b"s", &Bytes::from("1")),
+            Err(RedisValue::Error(WRONGTYPE.to_string()))
+        );
+        assert_eq!(server.smove(b"a", b"c", &Bytes::from("1")), Ok(true));
+        assert_eq!(server.smembers(b"c"), Ok(words("1")));
+
+        assert_eq!(server.srandmember(b"b", 10).unwrap().unwrap().iter().count(), 3);
+        assert_eq!(server.srandmember(b"b", -10).unwrap().unwrap().iter().count(), 10);
+        assert_eq!(server.srandmember(b"nope", 1), Ok(None));
+        // Repeats are drawn as they're read, and a count that could never be met is refused
+        let huge = server.srandmember(b"b", -(i64::MAX / 2)).unwrap().unwrap();
+        assert_eq!(huge.len(), (i64::MAX / 2) as u64);
+        assert_eq!(huge.iter().take(5).count(), 5);
+        let out_of_range = Err(RedisValue::Error("ERR value is out of range".to_string()));
+        assert_eq!(server.srandmember(b"b", -i64::MAX), out_of_range);
+        assert_eq!(server.srandmember(b"b", i64::MAX), out_of_range);
+        assert_eq!(server.spop(b"b", 2).unwrap().len(), 2);
+        assert_eq!(server.spop(b"b", 2).unwrap().len(), 1);
+        assert_eq!(server.key_type(b"b"), "none");
+    }
+}
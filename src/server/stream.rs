@@ -0,0 +1,953 @@
+//! Stream commands, consumer groups included. XREAD BLOCK and XREADGROUP BLOCK
+//! wait with the machinery in `list`.
+//!
+//! Group reads and claims depend on the clock and on what's pending, so rather
+//! than themselves they're propagated as the changes they made to the group:
+//! an XCLAIM for each entry handed out, and XGROUP commands for the rest.
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{unix_time_ms, RedisServer, RedisValue, WRONGTYPE};
+use crate::aof::Aof;
+use crate::backlog::Backlog;
+use crate::db::{Db, Value};
+use crate::log::Logger;
+use crate::parser::{BlockingOp, GroupReadId, StreamTrim, XAddId, XClaimOptions, XPendingRange, XReadId};
+use crate::stream::{ConsumerGroup, Fields, Reads, Stream, StreamId};
+
+/// A reply along with the commands that redo its changes elsewhere
+type WithEffects = (RedisValue, Vec<Vec<Bytes>>);
+
+fn id_reply(id: StreamId) -> RedisValue {
+    RedisValue::BulkString(Bytes::from(id.to_string()))
+}
+
+/// An entry as Redis replies with it: its ID and its fields and values, which
+/// are null for an entry deleted since it was delivered
+fn entry_reply(id: StreamId, fields: Option<Fields>) -> RedisValue {
+    let fields = match fields {
+        Some(fields) => RedisValue::Array(
+            fields
+                .into_iter()
+                .flat_map(|(field, value)| [RedisValue::BulkString(field), RedisValue::BulkString(value)])
+                .collect(),
+        ),
+        None => RedisValue::NullArray,
+    };
+    RedisValue::Array(vec![id_reply(id), fields])
+}
+
+pub(super) fn entries_reply(entries: Vec<(StreamId, Fields)>) -> RedisValue {
+    RedisValue::Array(entries.into_iter().map(|(id, fields)| entry_reply(id, Some(fields))).collect())
+}
+
+/// The XREAD reply: the entries read from each key that had any, or a null
+/// array if none did
+pub(super) fn read_reply(read: Reads) -> RedisValue {
+    if read.is_empty() {
+        return RedisValue::NullArray;
+    }
+    let streams = read
+        .into_iter()
+        .map(|(key, entries)| RedisValue::Array(vec![RedisValue::BulkString(key), entries_reply(entries)]));
+    RedisValue::Array(streams.collect())
+}
+
+fn lossy(b: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(b)
+}
+
+/// The error for a missing key or group from XREADGROUP, XCLAIM and friends
+fn no_key_or_group(key: &[u8], group: &[u8], suffix: &str) -> RedisValue {
+    RedisValue::Error(format!(
+        "NOGROUP No such key '{}' or consumer group '{}'{}",
+        lossy(key),
+        lossy(group),
+        suffix
+    ))
+}
+
+/// The error for a missing group from XGROUP and XINFO
+fn no_group(key: &[u8], group: &[u8]) -> RedisValue {
+    RedisValue::Error(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        lossy(group),
+        lossy(key)
+    ))
+}
+
+const XGROUP_NO_KEY: &str = "ERR The XGROUP subcommand requires the key to exist. \
+    Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+/// The XCLAIM that makes `id` pending on a replica just as it is here
+fn claim_args(key: &Bytes, name: &Bytes, group: &ConsumerGroup, id: StreamId) -> Vec<Bytes> {
+    let entry = &group.pending[&id];
+    vec![
+        Bytes::from("XCLAIM"),
+        key.clone(),
+        name.clone(),
+        entry.consumer.clone(),
+        Bytes::from("0"),
+        Bytes::from(id.to_string()),
+        Bytes::from("TIME"),
+        Bytes::from(entry.delivery_time.to_string()),
+        Bytes::from("RETRYCOUNT"),
+        Bytes::from(entry.delivery_count.to_string()),
+        Bytes::from("FORCE"),
+        Bytes::from("JUSTID"),
+        Bytes::from("LASTID"),
+        Bytes::from(group.last_id.to_string()),
+    ]
+}
+
+/// The XGROUP SETID that moves a group on a replica to where it is here
+fn set_id_args(key: &Bytes, name: &Bytes, group: &ConsumerGroup) -> Vec<Bytes> {
+    let entries_read = group.entries_read.map_or(-1, |read| read as i64);
+    vec![
+        Bytes::from("XGROUP"),
+        Bytes::from("SETID"),
+        key.clone(),
+        name.clone(),
+        Bytes::from(group.last_id.to_string()),
+        Bytes::from("ENTRIESREAD"),
+        Bytes::from(entries_read.to_string()),
+    ]
+}
+
+fn create_consumer_args(key: &Bytes, name: &Bytes, consumer: &Bytes) -> Vec<Bytes> {
+    vec![
+        Bytes::from("XGROUP"),
+        Bytes::from("CREATECONSUMER"),
+        key.clone(),
+        name.clone(),
+        consumer.clone(),
+    ]
+}
+
+fn ack_args(key: &Bytes, name: &Bytes, id: StreamId) -> Vec<Bytes> {
+    vec![Bytes::from("XACK"), key.clone(), name.clone(), Bytes::from(id.to_string())]
+}
+
+fn map_reply(pairs: Vec<(&str, RedisValue)>) -> RedisValue {
+    RedisValue::Map(
+        pairs
+            .into_iter()
+            .map(|(name, value)| (RedisValue::BulkString(Bytes::copy_from_slice(name.as_bytes())), value))
+            .collect(),
+    )
+}
+
+impl RedisServer {
+    /// The stream stored at `key`, or a WRONGTYPE error for other types
+    fn get_stream<'a>(db: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut Stream>, RedisValue> {
+        match db.get_mut(key) {
+            Some((Value::Stream(stream), _)) => Ok(Some(stream)),
+            Some(_) => Err(RedisValue::Error(WRONGTYPE.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// The stream at `key`, created empty if there's none
+    fn get_or_create_stream<'a>(db: &'a mut Db, key: &[u8]) -> Result<&'a mut Stream, RedisValue> {
+        if RedisServer::get_stream(db, key)?.is_none() {
+            db.insert(Bytes::copy_from_slice(key), Value::Stream(Stream::default()), None);
+        }
+        Ok(RedisServer::get_stream(db, key)?.unwrap())
+    }
+
+    /// Where XREAD starts reading each key: past the given ID, or for `$` past
+    /// the stream's last one. Every key must hold a stream or be missing.
+    fn read_positions(db: &mut Db, keys: &[Bytes], ids: &[XReadId]) -> Result<Vec<StreamId>, RedisValue> {
+        let mut positions = Vec::with_capacity(keys.len());
+        for (key, id) in keys.iter().zip(ids) {
+            let stream = RedisServer::get_stream(db, key)?;
+            positions.push(match id {
+                XReadId::Last => stream.map_or(StreamId::MIN, |stream| stream.last_id),
+                XReadId::After(id) => *id,
+            });
+        }
+        Ok(positions)
+    }
+
+    /// Up to `count` entries past each position, for the keys that have any
+    fn read_streams(db: &mut Db, keys: &[Bytes], positions: &[StreamId], count: Option<usize>) -> Result<Reads, RedisValue> {
+        let mut read = Vec::new();
+        for (key, after) in keys.iter().zip(positions) {
+            let entries = match RedisServer::get_stream(db, key)? {
+                Some(stream) => stream.read_after(*after, count),
+                None => continue,
+            };
+            if !entries.is_empty() {
+                read.push((key.clone(), entries));
+            }
+        }
+        Ok(read)
+    }
+
+    /// What a client blocked in XREAD gets once `key` has entries past `after`,
+    /// or None if it has none yet
+    pub(super) fn xread_from(
+        db: &mut Db,
+        key: &Bytes,
+        after: StreamId,
+        count: Option<usize>,
+    ) -> Result<Option<RedisValue>, RedisValue> {
+        let read = RedisServer::read_streams(db, std::slice::from_ref(key), &[after], count)?;
+        Ok((!read.is_empty()).then(|| read_reply(read)))
+    }
+
+    /// Appends an entry, then trims the stream if asked to. Returns the new
+    /// entry's ID, or None when `nomkstream` is set and there's no stream.
+    pub fn xadd(
+        &self,
+        key: &Bytes,
+        nomkstream: bool,
+        trim: Option<&StreamTrim>,
+        id: XAddId,
+        fields: &[(Bytes, Bytes)],
+    ) -> Result<Option<StreamId>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let (ms, seq) = match id {
+            XAddId::Auto => (None, None),
+            XAddId::Partial(ms) => (Some(ms), None),
+            XAddId::Explicit(id) => (Some(id.ms), Some(id.seq)),
+        };
+        let id = match RedisServer::get_stream(&mut db, key)? {
+            Some(stream) => stream.next_id(ms, seq, unix_time_ms()),
+            None if nomkstream => return Ok(None),
+            None => Stream::default().next_id(ms, seq, unix_time_ms()),
+        }
+        .map_err(|e| RedisValue::Error(e.to_string()))?;
+        let stream = RedisServer::get_or_create_stream(&mut db, key)?;
+        stream.add(id, fields.to_vec());
+        if let Some(trim) = trim {
+            stream.trim(&trim.strategy, trim.limit);
+        }
+        db.blocked.signal_ready(key);
+        Ok(Some(id))
+    }
+
+    pub fn xlen(&self, key: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_stream(&mut db, key)?.map_or(0, |stream| stream.len() as i64))
+    }
+
+    /// XRANGE and XREVRANGE, between the inclusive IDs `start` and `end`
+    pub fn xrange(
+        &self,
+        key: &[u8],
+        start: StreamId,
+        end: StreamId,
+        rev: bool,
+        count: Option<usize>,
+    ) -> Result<Vec<(StreamId, Fields)>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_stream(&mut db, key)?
+            .map_or_else(Vec::new, |stream| stream.range(start, end, rev, count)))
+    }
+
+    /// Deletes the given entries, returning how many were there. The stream stays
+    /// even once it's empty.
+    pub fn xdel(&self, key: &[u8], ids: &[StreamId]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = match RedisServer::get_stream(&mut db, key)? {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        Ok(ids.iter().filter(|id| stream.delete(**id)).count() as i64)
+    }
+
+    /// Removes old entries, returning how many went
+    pub fn xtrim(&self, key: &[u8], trim: &StreamTrim) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = match RedisServer::get_stream(&mut db, key)? {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        Ok(stream.trim(&trim.strategy, trim.limit) as i64)
+    }
+
+    /// XREAD without BLOCK: up to `count` entries past the given ID of each key
+    pub fn xread(&self, keys: &[Bytes], ids: &[XReadId], count: Option<usize>) -> Result<Reads, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let positions = RedisServer::read_positions(&mut db, keys, ids)?;
+        RedisServer::read_streams(&mut db, keys, &positions, count)
+    }
+
+    /// XREAD BLOCK. Entries already there are returned right away; otherwise the
+    /// client waits for the first XADD past its IDs, `$` meaning the last entry
+    /// as of now.
+    pub async fn xread_block(
+        &self,
+        logger: &Logger,
+        keys: &[Bytes],
+        ids: &[XReadId],
+        count: Option<usize>,
+        timeout: Option<Duration>,
+        stream: &tokio::net::TcpStream,
+    ) -> RedisValue {
+        let (id, rx) = {
+            let _shared = self.exec_lock.read().unwrap();
+            let mut db = self.db.lock().unwrap();
+            let positions = match RedisServer::read_positions(&mut db, keys, ids) {
+                Ok(positions) => positions,
+                Err(e) => return e,
+            };
+            match RedisServer::read_streams(&mut db, keys, &positions, count) {
+                Ok(read) if !read.is_empty() => return read_reply(read),
+                Ok(_) => {}
+                Err(e) => return e,
+            }
+            let op = BlockingOp::XRead {
+                ids: keys.iter().cloned().zip(positions).collect(),
+                count,
+            };
+            db.blocked.block(keys.to_vec(), op)
+        };
+        self.wait_blocked(logger, id, rx, timeout, RedisValue::NullArray, stream).await
+    }
+}
+
+impl RedisServer {
+    /// The stream at `key` if it has the named group
+    fn get_stream_with_group<'a>(
+        db: &'a mut Db,
+        key: &[u8],
+        group: &[u8],
+    ) -> Result<Option<&'a mut Stream>, RedisValue> {
+        Ok(RedisServer::get_stream(db, key)?.filter(|stream| stream.groups.contains_key(group)))
+    }
+
+    /// The stream XINFO looks at, which must exist
+    fn get_existing_stream<'a>(db: &'a mut Db, key: &[u8]) -> Result<&'a mut Stream, RedisValue> {
+        RedisServer::get_stream(db, key)?.ok_or_else(|| RedisValue::Error("ERR no such key".to_string()))
+    }
+
+    /// The stream XGROUP works on, which must exist
+    fn get_stream_for_xgroup<'a>(db: &'a mut Db, key: &[u8]) -> Result<&'a mut Stream, RedisValue> {
+        RedisServer::get_stream(db, key)?.ok_or_else(|| RedisValue::Error(XGROUP_NO_KEY.to_string()))
+    }
+
+    /// XGROUP CREATE, starting the group past `id`
+    pub fn xgroup_create(
+        &self,
+        key: &Bytes,
+        group: &Bytes,
+        id: XReadId,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    ) -> Result<(), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = match RedisServer::get_stream(&mut db, key)? {
+            Some(stream) => stream,
+            None if mkstream => RedisServer::get_or_create_stream(&mut db, key)?,
+            None => return Err(RedisValue::Error(XGROUP_NO_KEY.to_string())),
+        };
+        if stream.groups.contains_key(group) {
+            return Err(RedisValue::Error("BUSYGROUP Consumer Group name already exists".to_string()));
+        }
+        let last_id = match id {
+            XReadId::Last => stream.last_id,
+            XReadId::After(id) => id,
+        };
+        stream.groups.insert(group.clone(), ConsumerGroup::new(last_id, entries_read));
+        Ok(())
+    }
+
+    /// XGROUP SETID: where the group goes on reading from
+    pub fn xgroup_setid(
+        &self,
+        key: &[u8],
+        group: &[u8],
+        id: XReadId,
+        entries_read: Option<u64>,
+    ) -> Result<(), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_stream_for_xgroup(&mut db, key)?;
+        let last_id = match id {
+            XReadId::Last => stream.last_id,
+            XReadId::After(id) => id,
+        };
+        let group_state = stream.groups.get_mut(group).ok_or_else(|| no_group(key, group))?;
+        group_state.last_id = last_id;
+        group_state.entries_read = entries_read;
+        Ok(())
+    }
+
+    /// Deletes a group, returning whether it existed
+    pub fn xgroup_destroy(&self, key: &[u8], group: &[u8]) -> Result<bool, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_stream_for_xgroup(&mut db, key)?.groups.remove(group).is_some())
+    }
+
+    /// Adds a consumer to a group, returning whether it's new
+    pub fn xgroup_createconsumer(&self, key: &[u8], group: &[u8], consumer: &Bytes) -> Result<bool, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_stream_for_xgroup(&mut db, key)?;
+        let group_state = stream.groups.get_mut(group).ok_or_else(|| no_group(key, group))?;
+        Ok(group_state.create_consumer(consumer, unix_time_ms()))
+    }
+
+    /// Removes a consumer and its pending entries, returning how many it had,
+    /// or None if there was no such consumer
+    pub fn xgroup_delconsumer(&self, key: &[u8], group: &[u8], consumer: &[u8]) -> Result<Option<i64>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_stream_for_xgroup(&mut db, key)?;
+        let group_state = stream.groups.get_mut(group).ok_or_else(|| no_group(key, group))?;
+        Ok(group_state.delete_consumer(consumer).map(|pending| pending as i64))
+    }
+
+    /// XREADGROUP on one key: the entries read, or None when reading new ones
+    /// and there are none, along with the changes to propagate
+    #[allow(clippy::too_many_arguments)]
+    fn read_group(
+        db: &mut Db,
+        key: &Bytes,
+        name: &Bytes,
+        consumer: &Bytes,
+        id: GroupReadId,
+        count: Option<usize>,
+        noack: bool,
+        now_ms: u64,
+    ) -> Result<(Option<RedisValue>, Vec<Vec<Bytes>>), RedisValue> {
+        let stream = RedisServer::get_stream_with_group(db, key, name)?
+            .ok_or_else(|| no_key_or_group(key, name, " in XREADGROUP with GROUP option"))?;
+        let mut effects = Vec::new();
+        let group = stream.groups.get_mut(name).unwrap();
+        if group.consumer(consumer, now_ms).1 {
+            effects.push(create_consumer_args(key, name, consumer));
+        }
+        let after = match id {
+            GroupReadId::New => {
+                let entries = stream.read_group(name, consumer, count, noack, now_ms).unwrap();
+                if entries.is_empty() {
+                    return Ok((None, effects));
+                }
+                let group = &stream.groups[name];
+                if !noack {
+                    effects.extend(entries.iter().map(|(id, _)| claim_args(key, name, group, *id)));
+                }
+                effects.push(set_id_args(key, name, group));
+                return Ok((Some(entries_reply(entries)), effects));
+            }
+            GroupReadId::Pending(after) => after,
+        };
+        // The consumer's own history, delivered once more
+        let ids: Vec<StreamId> = group.consumers[consumer]
+            .pending
+            .range((std::ops::Bound::Excluded(after), std::ops::Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .copied()
+            .collect();
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let entry = group.pending.get_mut(&id).unwrap();
+            entry.delivery_time = now_ms;
+            entry.delivery_count += 1;
+            effects.push(claim_args(key, name, group, id));
+            entries.push(entry_reply(id, stream.entries.get(&id).cloned()));
+        }
+        Ok((Some(RedisValue::Array(entries)), effects))
+    }
+
+    /// XREADGROUP on every key, replying for the keys read from
+    #[allow(clippy::too_many_arguments)]
+    fn read_groups(
+        db: &mut Db,
+        group: &Bytes,
+        consumer: &Bytes,
+        keys: &[Bytes],
+        ids: &[GroupReadId],
+        count: Option<usize>,
+        noack: bool,
+    ) -> Result<(Vec<RedisValue>, Vec<Vec<Bytes>>), RedisValue> {
+        let now_ms = unix_time_ms();
+        let mut read = Vec::new();
+        let mut effects = Vec::new();
+        for (key, id) in keys.iter().zip(ids) {
+            let (entries, changes) = RedisServer::read_group(db, key, group, consumer, *id, count, noack, now_ms)?;
+            effects.extend(changes);
+            if let Some(entries) = entries {
+                read.push(RedisValue::Array(vec![RedisValue::BulkString(key.clone()), entries]));
+            }
+        }
+        Ok((read, effects))
+    }
+
+    /// What a client blocked in XREADGROUP gets once `key` has new entries, or
+    /// None if it has none yet
+    pub(super) fn xreadgroup_from(
+        db: &mut Db,
+        key: &Bytes,
+        group: &Bytes,
+        consumer: &Bytes,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Result<Option<WithEffects>, RedisValue> {
+        let (read, effects) =
+            RedisServer::read_groups(db, group, consumer, std::slice::from_ref(key), &[GroupReadId::New], count, noack)?;
+        Ok((!read.is_empty()).then(|| (RedisValue::Array(read), effects)))
+    }
+
+    /// XREADGROUP without BLOCK
+    pub fn xreadgroup(
+        &self,
+        group: &Bytes,
+        consumer: &Bytes,
+        keys: &[Bytes],
+        ids: &[GroupReadId],
+        count: Option<usize>,
+        noack: bool,
+    ) -> Result<WithEffects, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let (read, effects) = RedisServer::read_groups(&mut db, group, consumer, keys, ids, count, noack)?;
+        let reply = if read.is_empty() { RedisValue::NullArray } else { RedisValue::Array(read) };
+        Ok((reply, effects))
+    }
+
+    /// XREADGROUP BLOCK. Like XREAD BLOCK, but only `>` waits: a read of the
+    /// consumer's history replies right away.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn xreadgroup_block(
+        &self,
+        logger: &Logger,
+        group: &Bytes,
+        consumer: &Bytes,
+        keys: &[Bytes],
+        ids: &[GroupReadId],
+        count: Option<usize>,
+        noack: bool,
+        timeout: Option<Duration>,
+        stream: &tokio::net::TcpStream,
+        tx: &Option<Arc<Backlog>>,
+    ) -> RedisValue {
+        let (id, rx) = {
+            let _shared = self.exec_lock.read().unwrap();
+            let mut aof = self.aof.as_deref().map(Aof::lock);
+            let mut db = self.db.lock().unwrap();
+            let (read, effects) = match RedisServer::read_groups(&mut db, group, consumer, keys, ids, count, noack) {
+                Ok(read) => read,
+                Err(e) => return e,
+            };
+            if !effects.is_empty() {
+                for key in keys {
+                    db.touch(key);
+                }
+            }
+            let blocked = read.is_empty().then(|| {
+                let op = BlockingOp::XReadGroup {
+                    group: group.clone(),
+                    consumer: consumer.clone(),
+                    count,
+                    noack,
+                };
+                db.blocked.block(keys.to_vec(), op)
+            });
+            drop(db);
+            for args in effects {
+                self.propagate_args(logger, &mut aof, tx, args);
+            }
+            match blocked {
+                Some(blocked) => blocked,
+                None => return RedisValue::Array(read),
+            }
+        };
+        self.wait_blocked(logger, id, rx, timeout, RedisValue::NullArray, stream).await
+    }
+
+    /// Acknowledges entries, returning how many were pending
+    pub fn xack(&self, key: &[u8], group: &[u8], ids: &[StreamId]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = match RedisServer::get_stream_with_group(&mut db, key, group)? {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        let group = stream.groups.get_mut(group).unwrap();
+        Ok(ids.iter().filter(|id| group.ack(**id)).count() as i64)
+    }
+
+    /// XPENDING: with a range, the pending entries in it; otherwise how many
+    /// there are, their lowest and highest IDs and how many each consumer has
+    pub fn xpending(&self, key: &[u8], group: &[u8], range: Option<&XPendingRange>) -> Result<RedisValue, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_stream_with_group(&mut db, key, group)?
+            .ok_or_else(|| no_key_or_group(key, group, ""))?;
+        let group = &stream.groups[group];
+        let range = match range {
+            Some(range) => range,
+            None if group.pending.is_empty() => {
+                return Ok(RedisValue::Array(vec![
+                    RedisValue::Int(0),
+                    RedisValue::Null,
+                    RedisValue::Null,
+                    RedisValue::NullArray,
+                ]))
+            }
+            None => {
+                let consumers = group
+                    .consumers
+                    .iter()
+                    .filter(|(_, consumer)| !consumer.pending.is_empty())
+                    .map(|(name, consumer)| {
+                        RedisValue::Array(vec![
+                            RedisValue::BulkString(name.clone()),
+                            RedisValue::BulkString(Bytes::from(consumer.pending.len().to_string())),
+                        ])
+                    });
+                return Ok(RedisValue::Array(vec![
+                    RedisValue::Int(group.pending.len() as i64),
+                    id_reply(*group.pending.keys().next().unwrap()),
+                    id_reply(*group.pending.keys().next_back().unwrap()),
+                    RedisValue::Array(consumers.collect()),
+                ]));
+            }
+        };
+        if range.start > range.end {
+            return Ok(RedisValue::Array(vec![]));
+        }
+        let now_ms = unix_time_ms();
+        let entries = group
+            .pending
+            .range(range.start..=range.end)
+            .filter(|(_, entry)| range.consumer.as_ref().is_none_or(|consumer| entry.consumer == consumer))
+            .map(|(id, entry)| (id, entry, now_ms.saturating_sub(entry.delivery_time)))
+            .filter(|(_, _, idle)| range.idle.is_none_or(|min| *idle >= min))
+            .take(range.count)
+            .map(|(id, entry, idle)| {
+                RedisValue::Array(vec![
+                    id_reply(*id),
+                    RedisValue::BulkString(entry.consumer.clone()),
+                    RedisValue::Int(idle as i64),
+                    RedisValue::Int(entry.delivery_count as i64),
+                ])
+            });
+        Ok(RedisValue::Array(entries.collect()))
+    }
+
+    /// Hands pending entries idle for at least `min_idle` milliseconds to
+    /// `consumer`, replying with them
+    #[allow(clippy::too_many_arguments)]
+    pub fn xclaim(
+        &self,
+        key: &Bytes,
+        name: &Bytes,
+        consumer: &Bytes,
+        min_idle: u64,
+        ids: &[StreamId],
+        options: &XClaimOptions,
+    ) -> Result<WithEffects, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let now_ms = unix_time_ms();
+        let stream = RedisServer::get_stream_with_group(&mut db, key, name)?
+            .ok_or_else(|| no_key_or_group(key, name, ""))?;
+        let group = stream.groups.get_mut(name).unwrap();
+        let mut effects = Vec::new();
+        if group.consumer(consumer, now_ms).1 {
+            effects.push(create_consumer_args(key, name, consumer));
+        }
+        let moved = options.last_id.filter(|last_id| *last_id > group.last_id);
+        if let Some(last_id) = moved {
+            group.last_id = last_id;
+        }
+        let delivery_time = match (options.idle, options.time) {
+            (Some(idle), _) => now_ms.saturating_sub(idle),
+            (None, Some(time)) => time,
+            (None, None) => now_ms,
+        };
+        let mut claimed = Vec::new();
+        for id in ids {
+            let forced = !group.pending.contains_key(id);
+            if forced && !(options.force && stream.entries.contains_key(id)) {
+                continue;
+            }
+            let (idle, delivery_count) = match group.pending.get(id) {
+                Some(entry) => (now_ms.saturating_sub(entry.delivery_time), entry.delivery_count),
+                None => (0, 1),
+            };
+            if !forced && idle < min_idle {
+                continue;
+            }
+            if !stream.entries.contains_key(id) {
+                group.ack(*id);
+                effects.push(ack_args(key, name, *id));
+                continue;
+            }
+            let delivery_count = options
+                .retry_count
+                .unwrap_or(delivery_count + if options.justid { 0 } else { 1 });
+            group.deliver(*id, consumer, now_ms, delivery_count);
+            group.pending.get_mut(id).unwrap().delivery_time = delivery_time;
+            effects.push(claim_args(key, name, group, *id));
+            claimed.push(*id);
+        }
+        if moved.is_some() {
+            effects.push(set_id_args(key, name, group));
+        }
+        let reply = claimed
+            .into_iter()
+            .map(|id| match options.justid {
+                true => id_reply(id),
+                false => entry_reply(id, stream.entries.get(&id).cloned()),
+            })
+            .collect();
+        Ok((RedisValue::Array(reply), effects))
+    }
+
+    /// XAUTOCLAIM: XCLAIM on up to `count` idle entries from `start` on. Replies
+    /// with where to carry on from, the entries claimed, and the IDs of pending
+    /// entries no longer in the stream, which are dropped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn xautoclaim(
+        &self,
+        key: &Bytes,
+        name: &Bytes,
+        consumer: &Bytes,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        justid: bool,
+    ) -> Result<WithEffects, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let now_ms = unix_time_ms();
+        let stream = RedisServer::get_stream_with_group(&mut db, key, name)?
+            .ok_or_else(|| no_key_or_group(key, name, ""))?;
+        let group = stream.groups.get_mut(name).unwrap();
+        let mut effects = Vec::new();
+        if group.consumer(consumer, now_ms).1 {
+            effects.push(create_consumer_args(key, name, consumer));
+        }
+        // Like Redis, look at no more than ten entries per one to claim
+        let candidates: Vec<StreamId> = group
+            .pending
+            .range(start..)
+            .map(|(id, _)| *id)
+            .take(count.saturating_mul(10))
+            .collect();
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut examined = 0;
+        for id in &candidates {
+            if claimed.len() == count {
+                break;
+            }
+            examined += 1;
+            let entry = &group.pending[id];
+            if now_ms.saturating_sub(entry.delivery_time) < min_idle {
+                continue;
+            }
+            if !stream.entries.contains_key(id) {
+                group.ack(*id);
+                effects.push(ack_args(key, name, *id));
+                deleted.push(id_reply(*id));
+                continue;
+            }
+            let delivery_count = entry.delivery_count + if justid { 0 } else { 1 };
+            group.deliver(*id, consumer, now_ms, delivery_count);
+            effects.push(claim_args(key, name, group, *id));
+            claimed.push(match justid {
+                true => id_reply(*id),
+                false => entry_reply(*id, stream.entries.get(id).cloned()),
+            });
+        }
+        let next = candidates
+            .get(examined)
+            .copied()
+            .or_else(|| {
+                let last = candidates.last()?;
+                group.pending.range(last.next()?..).map(|(id, _)| *id).next()
+            })
+            .unwrap_or(StreamId::MIN);
+        let reply = RedisValue::Array(vec![id_reply(next), RedisValue::Array(claimed), RedisValue::Array(deleted)]);
+        Ok((reply, effects))
+    }
+
+    /// XINFO STREAM
+    pub fn xinfo_stream(&self, key: &[u8]) -> Result<RedisValue, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_existing_stream(&mut db, key)?;
+        let entry = |entry: Option<(&StreamId, &Fields)>| {
+            entry.map_or(RedisValue::Null, |(id, fields)| entry_reply(*id, Some(fields.clone())))
+        };
+        // Redis keeps up to 100 entries in each node of its radix tree
+        let nodes = stream.len().div_ceil(100) as i64;
+        Ok(map_reply(vec![
+            ("length", RedisValue::Int(stream.len() as i64)),
+            ("radix-tree-keys", RedisValue::Int(nodes)),
+            ("radix-tree-nodes", RedisValue::Int(nodes + 1)),
+            ("last-generated-id", id_reply(stream.last_id)),
+            ("max-deleted-entry-id", id_reply(stream.max_deleted_id)),
+            ("entries-added", RedisValue::Int(stream.entries_added as i64)),
+            ("recorded-first-entry-id", id_reply(stream.first_id())),
+            ("groups", RedisValue::Int(stream.groups.len() as i64)),
+            ("first-entry", entry(stream.entries.iter().next())),
+            ("last-entry", entry(stream.entries.iter().next_back())),
+        ]))
+    }
+
+    /// XINFO GROUPS
+    pub fn xinfo_groups(&self, key: &[u8]) -> Result<RedisValue, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_existing_stream(&mut db, key)?;
+        let optional = |n: Option<u64>| n.map_or(RedisValue::Null, |n| RedisValue::Int(n as i64));
+        let groups = stream.groups.iter().map(|(name, group)| {
+            map_reply(vec![
+                ("name", RedisValue::BulkString(name.clone())),
+                ("consumers", RedisValue::Int(group.consumers.len() as i64)),
+                ("pending", RedisValue::Int(group.pending.len() as i64)),
+                ("last-delivered-id", id_reply(group.last_id)),
+                ("entries-read", optional(group.entries_read)),
+                ("lag", optional(stream.lag(group))),
+            ])
+        });
+        Ok(RedisValue::Array(groups.collect()))
+    }
+
+    /// XINFO CONSUMERS
+    pub fn xinfo_consumers(&self, key: &[u8], group: &[u8]) -> Result<RedisValue, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let stream = RedisServer::get_existing_stream(&mut db, key)?;
+        let group = stream.groups.get(group).ok_or_else(|| no_group(key, group))?;
+        let now_ms = unix_time_ms();
+        let consumers = group.consumers.iter().map(|(name, consumer)| {
+            let inactive = consumer.active_time.map_or(-1, |active| now_ms.saturating_sub(active) as i64);
+            map_reply(vec![
+                ("name", RedisValue::BulkString(name.clone())),
+                ("pending", RedisValue::Int(consumer.pending.len() as i64)),
+                ("idle", RedisValue::Int(now_ms.saturating_sub(consumer.seen_time) as i64)),
+                ("inactive", RedisValue::Int(inactive)),
+            ])
+        });
+        Ok(RedisValue::Array(consumers.collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::TrimStrategy;
+
+    fn fields(n: u64) -> Vec<(Bytes, Bytes)> {
+        vec![(Bytes::from("n"), Bytes::from(n.to_string()))]
+    }
+
+    #[test]
+    fn test_stream_operations() {
+        let server = RedisServer::new(&[]);
+        let key = Bytes::from("s");
+        let id = |ms, seq| StreamId { ms, seq };
+        assert_eq!(server.xadd(&key, true, None, XAddId::Auto, &fields(0)), Ok(None));
+        assert_eq!(server.key_type(b"s"), "none");
+        for ms in 1..=5 {
+            let added = server.xadd(&key, false, None, XAddId::Partial(ms), &fields(ms));
+            assert_eq!(added, Ok(Some(id(ms, 0))));
+        }
+        assert_eq!(server.key_type(b"s"), "stream");
+        assert_eq!(
+            server.xadd(&key, false, None, XAddId::Explicit(id(5, 0)), &fields(0)),
+            Err(RedisValue::Error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()
+            ))
+        );
+
+        let trim = StreamTrim {
+            strategy: TrimStrategy::MaxLen(4),
+            approximate: false,
+            limit: None,
+        };
+        assert_eq!(server.xtrim(b"s", &trim), Ok(1));
+        assert_eq!(server.xdel(b"s", &[id(3, 0), id(9, 0)]), Ok(1));
+        assert_eq!(server.xlen(b"s"), Ok(3));
+        let ids = server
+            .xrange(b"s", StreamId::MIN, StreamId::MAX, true, Some(2))
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id.ms)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![5, 4]);
+
+        let keys = vec![key.clone(), Bytes::from("nope")];
+        let read = server.xread(&keys, &[XReadId::After(id(4, 0)), XReadId::Last], None).unwrap();
+        assert_eq!(read, vec![(key.clone(), vec![(id(5, 0), fields(5))])]);
+        assert_eq!(server.xread(&keys, &[XReadId::Last, XReadId::Last], None), Ok(vec![]));
+
+        // Emptied streams are kept, unlike other types
+        assert_eq!(server.xdel(b"s", &[id(2, 0), id(4, 0), id(5, 0)]), Ok(3));
+        assert_eq!(server.key_type(b"s"), "stream");
+    }
+
+    #[test]
+    fn test_consumer_groups() {
+        let server = RedisServer::new(&[]);
+        let (key, group) = (Bytes::from("s"), Bytes::from("g"));
+        let (alice, bob) = (Bytes::from("alice"), Bytes::from("bob"));
+        let id = |ms, seq| StreamId { ms, seq };
+        assert_eq!(
+            server.xgroup_create(&key, &group, XReadId::Last, false, None),
+            Err(RedisValue::Error(XGROUP_NO_KEY.to_string()))
+        );
+        assert_eq!(server.xgroup_create(&key, &group, XReadId::Last, true, None), Ok(()));
+        assert!(server.xgroup_create(&key, &group, XReadId::Last, false, None).is_err());
+        for ms in 1..=3 {
+            server.xadd(&key, false, None, XAddId::Partial(ms), &fields(ms)).unwrap();
+        }
+
+        let keys = std::slice::from_ref(&key);
+        let (reply, effects) = server.xreadgroup(&group, &alice, keys, &[GroupReadId::New], Some(2), false).unwrap();
+        let expected = RedisValue::Array(vec![RedisValue::Array(vec![
+            RedisValue::BulkString(key.clone()),
+            entries_reply(vec![(id(1, 0), fields(1)), (id(2, 0), fields(2))]),
+        ])]);
+        assert_eq!(reply, expected);
+        // The new consumer, a claim per entry and where the group got to
+        assert_eq!(effects.len(), 4);
+        assert_eq!(&effects[3][..4], &[Bytes::from("XGROUP"), Bytes::from("SETID"), key.clone(), group.clone()]);
+
+        // Rereading the history delivers the entries again
+        let history = server.xreadgroup(&group, &alice, keys, &[GroupReadId::Pending(id(1, 0))], None, false);
+        assert_eq!(history.unwrap().1.len(), 1);
+        let pending = server.xpending(b"s", b"g", None).unwrap();
+        assert_eq!(
+            pending,
+            RedisValue::Array(vec![
+                RedisValue::Int(2),
+                RedisValue::BulkString(Bytes::from("1-0")),
+                RedisValue::BulkString(Bytes::from("2-0")),
+                RedisValue::Array(vec![RedisValue::Array(vec![
+                    RedisValue::BulkString(alice.clone()),
+                    RedisValue::BulkString(Bytes::from("2")),
+                ])]),
+            ])
+        );
+
+        // Too recently delivered to claim, unless the idle time is low enough
+        let options = XClaimOptions::default();
+        let (reply, _) = server.xclaim(&key, &group, &bob, 60_000, &[id(1, 0)], &options).unwrap();
+        assert_eq!(reply, RedisValue::Array(vec![]));
+        let (reply, _) = server.xclaim(&key, &group, &bob, 0, &[id(1, 0)], &options).unwrap();
+        assert_eq!(reply, entries_reply(vec![(id(1, 0), fields(1))]));
+
+        // Entries deleted while pending are dropped by XAUTOCLAIM
+        server.xdel(b"s", &[id(2, 0)]).unwrap();
+        let (reply, _) = server.xautoclaim(&key, &group, &bob, 0, StreamId::MIN, 10, true).unwrap();
+        assert_eq!(
+            reply,
+            RedisValue::Array(vec![
+                RedisValue::BulkString(Bytes::from("0-0")),
+                RedisValue::Array(vec![RedisValue::BulkString(Bytes::from("1-0"))]),
+                RedisValue::Array(vec![RedisValue::BulkString(Bytes::from("2-0"))]),
+            ])
+        );
+        assert_eq!(server.xack(b"s", b"g", &[id(1, 0), id(2, 0)]), Ok(1));
+        assert_eq!(server.xgroup_delconsumer(b"s", b"g", &alice), Ok(Some(0)));
+        assert_eq!(server.xgroup_delconsumer(b"s", b"g", &alice), Ok(None));
+        assert_eq!(
+            server.xreadgroup(&Bytes::from("nope"), &alice, keys, &[GroupReadId::New], None, false),
+            Err(RedisValue::Error(
+                "NOGROUP No such key 's' or consumer group 'nope' in XREADGROUP with GROUP option".to_string()
+            ))
+        );
+        assert_eq!(server.xgroup_destroy(b"s", b"g"), Ok(true));
+        assert_eq!(server.xgroup_destroy(b"s", b"g"), Ok(false));
+    }
+}
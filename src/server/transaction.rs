@@ -0,0 +1,290 @@
+//! MULTI/EXEC transactions and WATCH
+//!
+//! Commands lock the dataset for each step they take, so EXEC can't simply hold
+//! that lock while the queue runs. Instead everything a client runs shares
+//! `exec_lock`, and EXEC takes it for itself, which keeps other clients from
+//! seeing or changing anything until the transaction is done.
+use bytes::Bytes;
+use std::sync::Arc;
+
+use super::{RedisServer, RedisValue};
+use crate::aof::Aof;
+use crate::backlog::Backlog;
+use crate::client::{ClientState, Transaction};
+use crate::log::Logger;
+use crate::parser::Command;
+
+impl RedisServer {
+    pub(super) fn multi(&self, client: &mut ClientState) -> RedisValue {
+        if client.transaction.is_some() {
+            return RedisValue::Error("ERR MULTI calls can not be nested".to_string());
+        }
+        client.transaction = Some(Transaction::default());
+        RedisValue::String("OK".to_string())
+    }
+
+    /// Queues a command sent after MULTI. One that can't run, or can't run inside
+    /// a transaction, is refused and dooms the transaction.
+    pub(super) fn queue(&self, client: &mut ClientState, command: &Command, raw: &[u8]) -> RedisValue {
+        let transaction = client.transaction.as_mut().expect("queueing outside a transaction");
+        let error = match command {
+            Command::Invalid(e) => e.to_string(),
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::SSubscribe(_)
+            | Command::SUnsubscribe(_)
+            | Command::Psync
+            | Command::Auth { .. }
+            | Command::Hello { .. } => "ERR Command not allowed inside a transaction".to_string(),
+            _ => {
+                transaction.queued.push((command.clone(), Bytes::copy_from_slice(raw)));
+                return RedisValue::String("QUEUED".to_string());
+            }
+        };
+        transaction.aborted = true;
+        RedisValue::Error(error)
+    }
+
+    /// Runs the queued commands with nothing from other clients in between,
+    /// replying with their replies. Replies nil instead if a watched key changed,
+    /// and an error if a command couldn't be queued.
+    ///
+    /// Writes are propagated wrapped in MULTI and EXEC, so replicas and the AOF
+    /// apply them all or not at all.
+    pub(super) fn exec(
+        &self,
+        logger: &Logger,
+        client: &mut ClientState,
+        tx: &Option<Arc<Backlog>>,
+    ) -> RedisValue {
+        let transaction = match client.transaction.take() {
+            Some(transaction) => transaction,
+            None => return RedisValue::Error("ERR EXEC without MULTI".to_string()),
+        };
+        let _exclusive = self.exec_lock.write().unwrap();
+        let changed = self.release_watches(&client.watched);
+        client.watched.clear();
+        if transaction.aborted {
+            return RedisValue::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+        }
+        if changed {
+            return RedisValue::NullArray;
+        }
+
+        let writes = transaction
+            .queued
+            .iter()
+            .any(|(command, _)| {
+                command.is_write()
+                    || matches!(
+                        command,
+                        Command::Block { .. } | Command::Eval { read_only: false, .. } | Command::FCall { read_only: false, .. }
+                    )
+            });
+        if writes {
+            self.propagate_multi(logger, tx);
+        }
+        let replies = transaction
+            .queued
+            .iter()
+            .map(|(command, raw)| match command {
+                // There's no waiting inside a transaction
+                Command::Block { keys, op, .. } => self.pop_now(logger, keys, op, tx),
+                Command::Unwatch => RedisValue::String("OK".to_string()),
+                Command::Eval {
+                    script,
+                    keys,
+                    args,
+                    read_only,
+                } => self.eval(logger, script, keys, args, *read_only, tx, true),
+                Command::FCall {
+                    function,
+                    keys,
+                    args,
+                    read_only,
+                } => self.fcall(logger, function, keys, args, *read_only, tx, true),
+                write if write.is_write() => self.execute_write(logger, write, raw, tx),
+                other => self.execute(logger, other),
+            })
+            .collect();
+        if writes {
+            self.propagate_exec(logger, tx);
+        }
+        RedisValue::Array(replies)
+    }
+
+    pub(super) fn discard(&self, client: &mut ClientState) -> RedisValue {
+        if client.transaction.take().is_none() {
+            return RedisValue::Error("ERR DISCARD without MULTI".to_string());
+        }
+        self.unwatch(client)
+    }
+
+    /// Makes the client's next EXEC fail if any of `keys` changes before it
+    pub(super) fn watch(&self, client: &mut ClientState, keys: &[Bytes]) -> RedisValue {
+        if client.transaction.is_some() {
+            return RedisValue::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        }
+        let mut db = self.db.lock().unwrap();
+        for key in keys {
+            if !client.watched.iter().any(|(watched, _)| watched == key) {
+                let version = db.watch(key);
+                client.watched.push((key.clone(), version));
+            }
+        }
+        RedisValue::String("OK".to_string())
+    }
+
+    pub(super) fn unwatch(&self, client: &mut ClientState) -> RedisValue {
+        self.release_watches(&client.watched);
+        client.watched.clear();
+        RedisValue::String("OK".to_string())
+    }
+
+    /// Stops watching keys, returning whether any of them changed meanwhile
+    pub(super) fn release_watches(&self, watched: &[(Bytes, u64)]) -> bool {
+        if watched.is_empty() {
+            return false;
+        }
+        let mut db = self.db.lock().unwrap();
+        let mut changed = false;
+        for (key, version) in watched {
+            changed |= db.version(key) != *version;
+            db.unwatch(key);
+        }
+        changed
+    }
+
+    /// Propagates the MULTI before a transaction's writes. Replicas get nothing
+    /// more until the EXEC, then the whole transaction at once.
+    pub(super) fn propagate_multi(&self, logger: &Logger, tx: &Option<Arc<Backlog>>) {
+        if let Some(tx) = tx {
+            tx.hold();
+        }
+        self.propagate_marker(logger, tx, "MULTI");
+    }
+
+    /// Propagates the EXEC after a transaction's writes, sending them to replicas
+    pub(super) fn propagate_exec(&self, logger: &Logger, tx: &Option<Arc<Backlog>>) {
+        self.propagate_marker(logger, tx, "EXEC");
+        if let Some(tx) = tx {
+            tx.release();
+        }
+    }
+
+    fn propagate_marker(&self, logger: &Logger, tx: &Option<Arc<Backlog>>, name: &'static str) {
+        let mut aof = self.aof.as_deref().map(Aof::lock);
+        let encoded = RedisValue::Array(vec![RedisValue::BulkString(Bytes::from(name))]);
+        self.propagate(logger, &mut aof, tx, &encoded.to_response());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(line: &str) -> Command {
+        let words: Vec<Bytes> = line.split(' ').map(|w| Bytes::from(w.to_string())).collect();
+        crate::parser::Parser::parse_command(&words).unwrap_or_else(Command::Invalid)
+    }
+
+    /// Queues each command, then runs EXEC
+    fn transaction(server: &RedisServer, client: &mut ClientState, lines: &[&str]) -> RedisValue {
+        let logger = Logger::new();
+        assert_eq!(server.multi(client), RedisValue::String("OK".to_string()));
+        for line in lines {
+            server.queue(client, &parsed(line), line.as_bytes());
+        }
+        server.exec(&logger, client, &None)
+    }
+
+    #[test]
+    fn test_exec_and_discard() {
+        let server = RedisServer::new(&[]);
+        let mut client = ClientState::new(1);
+        assert_eq!(
+            transaction(&server, &mut client, &["SET a 1", "INCR a", "GET a"]),
+            RedisValue::Array(vec![
+                RedisValue::String("OK".to_string()),
+                RedisValue::Int(2),
+                RedisValue::BulkString(Bytes::from("2")),
+            ])
+        );
+        assert!(client.transaction.is_none());
+
+        // A blocking pop doesn't wait
+        assert_eq!(
+            transaction(&server, &mut client, &["BLPOP list 0"]),
+            RedisValue::Array(vec![RedisValue::NullArray])
+        );
+
+        // A command that doesn't parse throws away the whole queue
+        let reply = transaction(&server, &mut client, &["SET a 3", "SET a"]);
+        assert!(matches!(reply, RedisValue::Error(e) if e.starts_with("EXECABORT")));
+        assert_eq!(server.get(b"a"), Ok(Some(Bytes::from("2"))));
+
+        server.multi(&mut client);
+        assert!(matches!(server.multi(&mut client), RedisValue::Error(_)));
+        assert!(matches!(server.watch(&mut client, &[Bytes::from("a")]), RedisValue::Error(_)));
+        server.queue(&mut client, &parsed("SET a 4"), b"");
+        assert_eq!(server.discard(&mut client), RedisValue::String("OK".to_string()));
+        assert!(matches!(server.discard(&mut client), RedisValue::Error(_)));
+        assert!(matches!(server.exec(&Logger::new(), &mut client, &None), RedisValue::Error(_)));
+        assert_eq!(server.get(b"a"), Ok(Some(Bytes::from("2"))));
+    }
+
+    #[tokio::test]
+    async fn test_replicated_transaction() {
+        let server = RedisServer::new(&[]);
+        let mut client = ClientState::new(1);
+        let encoded = |line: &str| {
+            RedisValue::Array(line.split(' ').map(|w| RedisValue::BulkString(Bytes::from(w.to_string()))).collect())
+                .to_response()
+        };
+        // Bigger than the backlog, yet read whole
+        let tx = Some(Arc::new(Backlog::new(64)));
+        let mut replica = tx.as_ref().unwrap().subscribe();
+        server.multi(&mut client);
+        for _ in 0..50 {
+            server.queue(&mut client, &parsed("INCR n"), &encoded("INCR n"));
+        }
+        server.queue(&mut client, &parsed("GET n"), &encoded("GET n"));
+        let replies = server.exec(&Logger::new(), &mut client, &tx);
+        assert!(matches!(replies, RedisValue::Array(replies) if replies.len() == 51));
+
+        let mut expected = encoded("MULTI");
+        for _ in 0..50 {
+            expected.extend(encoded("INCR n"));
+        }
+        expected.extend(encoded("EXEC"));
+        assert_eq!(replica.recv().await, Ok(Bytes::from(expected)));
+    }
+
+    #[test]
+    fn test_watch() {
+        let server = RedisServer::new(&[]);
+        let logger = Logger::new();
+        let mut client = ClientState::new(1);
+        server.watch(&mut client, &[Bytes::from("a"), Bytes::from("b")]);
+        server.execute(&logger, &parsed("SET b 1"));
+        assert_eq!(transaction(&server, &mut client, &["SET a 1"]), RedisValue::NullArray);
+        assert_eq!(server.get(b"a"), Ok(None));
+
+        // EXEC forgets the watches either way
+        assert_eq!(server.db.lock().unwrap().version(b"a"), 0);
+        server.watch(&mut client, &[Bytes::from("b")]);
+        assert!(matches!(transaction(&server, &mut client, &["SET a 1"]), RedisValue::Array(_)));
+
+        // A change in place counts too
+        server.watch(&mut client, &[Bytes::from("a")]);
+        server.execute_write(&logger, &parsed("APPEND a 2"), b"", &None);
+        assert_eq!(transaction(&server, &mut client, &["GET a"]), RedisValue::NullArray);
+
+        server.watch(&mut client, &[Bytes::from("a")]);
+        server.unwatch(&mut client);
+        server.execute_write(&logger, &parsed("DEL a"), b"", &None);
+        assert!(matches!(transaction(&server, &mut client, &["GET a"]), RedisValue::Array(_)));
+    }
+}
@@ -0,0 +1,335 @@
+//! Sorted set commands. The blocking pops share the machinery in `list`.
+use bytes::Bytes;
+use std::collections::HashMap;
+
+use super::list::list_range;
+use super::{RedisServer, RedisValue, WRONGTYPE};
+use crate::db::{Db, Value};
+use crate::glob::glob_match;
+use crate::parser::{Aggregate, LexBound, ScanOptions, ScoreBound, SetOperation, ZAddOptions, ZRange, ZRangeBy};
+use crate::zset::ZSet;
+
+/// The members a range selects, in the order it asks for
+fn select(zset: &ZSet, range: &ZRange) -> Vec<(Bytes, f64)> {
+    let (offset, count) = match range.limit {
+        Some((offset, _)) if offset < 0 => return Vec::new(),
+        Some((offset, count)) => (offset as usize, (count >= 0).then_some(count as usize)),
+        None => (0, None),
+    };
+    match &range.by {
+        ZRangeBy::Rank(start, stop) => match list_range(zset.len(), *start, *stop) {
+            Some((start, stop)) => zset.range_by_rank(start, stop, range.rev),
+            None => Vec::new(),
+        },
+        ZRangeBy::Score(min, max) => zset.range_by_score(min, max, range.rev, offset, count),
+        ZRangeBy::Lex(min, max) => zset.range_by_lex(min, max, range.rev, offset, count),
+    }
+}
+
+/// A score times its weight, where the infinities times 0 count as 0
+fn weighted(score: f64, weight: f64) -> f64 {
+    let score = score * weight;
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+impl RedisServer {
+    /// The sorted set stored at `key`, or a WRONGTYPE error for other types
+    fn get_zset<'a>(db: &'a mut Db, key: &[u8]) -> Result<Option<&'a mut ZSet>, RedisValue> {
+        match db.get_mut(key) {
+            Some((Value::ZSet(zset), _)) => Ok(Some(zset)),
+            Some(_) => Err(RedisValue::Error(WRONGTYPE.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// The sorted set at `key`, created empty if there's none
+    fn get_or_create_zset<'a>(db: &'a mut Db, key: &[u8]) -> Result<&'a mut ZSet, RedisValue> {
+        if RedisServer::get_zset(db, key)?.is_none() {
+            db.insert(Bytes::copy_from_slice(key), Value::ZSet(ZSet::default()), None);
+        }
+        Ok(RedisServer::get_zset(db, key)?.unwrap())
+    }
+
+    /// Replaces `destination` with the given members, or deletes it if there are
+    /// none. Returns how many were stored and whether the keyspace changed.
+    fn store_zset(db: &mut Db, destination: &Bytes, members: ZSet) -> (i64, bool) {
+        if members.is_empty() {
+            return (0, db.remove(destination).is_some());
+        }
+        let len = members.len() as i64;
+        db.insert(destination.clone(), Value::ZSet(members), None);
+        (len, true)
+    }
+
+    /// Removes up to `count` of the lowest or, with `max`, highest scoring
+    /// members, deleting the key once it's empty
+    pub(super) fn zpop_from(db: &mut Db, key: &[u8], max: bool, count: usize) -> Result<Vec<(Bytes, f64)>, RedisValue> {
+        let zset = match RedisServer::get_zset(db, key)? {
+            Some(zset) => zset,
+            None => return Ok(Vec::new()),
+        };
+        let popped = zset.pop(max, count);
+        if zset.is_empty() {
+            db.remove(key);
+        }
+        Ok(popped)
+    }
+
+    /// Sets the scores of the given members as far as the options allow. Returns
+    /// how many members were added, how many had their score changed, and the
+    /// score of the last member if it was set.
+    pub fn zadd(&self, key: &[u8], options: &ZAddOptions, pairs: &[(f64, Bytes)]) -> Result<(usize, usize, Option<f64>), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let (mut added, mut changed, mut last) = (0, 0, None);
+        for (score, member) in pairs {
+            let current = RedisServer::get_zset(&mut db, key)?.and_then(|zset| zset.score(member));
+            let score = match current {
+                Some(current) if options.incr => current + score,
+                _ => *score,
+            };
+            if score.is_nan() {
+                return Err(RedisValue::Error("ERR resulting score is not a number (NaN)".to_string()));
+            }
+            last = None;
+            match current {
+                None if options.xx => continue,
+                Some(_) if options.nx => continue,
+                Some(current) if (options.gt && score <= current) || (options.lt && score >= current) => continue,
+                _ => {}
+            }
+            match RedisServer::get_or_create_zset(&mut db, key)?.insert(member.clone(), score) {
+                None => added += 1,
+                Some(old) if old != score => changed += 1,
+                Some(_) => {}
+            }
+            last = Some(score);
+        }
+        if added > 0 {
+            db.blocked.signal_ready(&Bytes::copy_from_slice(key));
+        }
+        Ok((added, changed, last))
+    }
+
+    pub fn zincrby(&self, key: &[u8], delta: f64, member: &Bytes) -> Result<f64, RedisValue> {
+        let options = ZAddOptions {
+            incr: true,
+            ..Default::default()
+        };
+        let (_, _, score) = self.zadd(key, &options, &[(delta, member.clone())])?;
+        Ok(score.expect("ZINCRBY has no conditions"))
+    }
+
+    /// Removes the given members, deleting the key once it's empty. Returns how
+    /// many were there.
+    pub fn zrem(&self, key: &[u8], members: &[Bytes]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let zset = match RedisServer::get_zset(&mut db, key)? {
+            Some(zset) => zset,
+            None => return Ok(0),
+        };
+        let removed = members.iter().filter(|member| zset.remove(member)).count();
+        if zset.is_empty() {
+            db.remove(key);
+        }
+        Ok(removed as i64)
+    }
+
+    pub fn zcard(&self, key: &[u8]) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_zset(&mut db, key)?.map_or(0, |zset| zset.len() as i64))
+    }
+
+    /// The score of each of `members`, if it's in the sorted set
+    pub fn zmscore(&self, key: &[u8], members: &[Bytes]) -> Result<Vec<Option<f64>>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let zset = RedisServer::get_zset(&mut db, key)?;
+        Ok(members
+            .iter()
+            .map(|member| zset.as_ref().and_then(|zset| zset.score(member)))
+            .collect())
+    }
+
+    /// The rank of `member` and its score
+    pub fn zrank(&self, key: &[u8], member: &[u8], rev: bool) -> Result<Option<(usize, f64)>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_zset(&mut db, key)?
+            .and_then(|zset| Some((zset.rank(member, rev)?, zset.score(member)?))))
+    }
+
+    pub fn zrange(&self, key: &[u8], range: &ZRange) -> Result<Vec<(Bytes, f64)>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_zset(&mut db, key)?.map_or_else(Vec::new, |zset| select(zset, range)))
+    }
+
+    /// Stores the members a range selects in `destination`. Returns how many
+    /// there were and whether the keyspace changed.
+    pub fn zrangestore(&self, destination: &Bytes, key: &[u8], range: &ZRange) -> Result<(i64, bool), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let members = RedisServer::get_zset(&mut db, key)?.map_or_else(Vec::new, |zset| select(zset, range));
+        Ok(RedisServer::store_zset(&mut db, destination, members.into_iter().collect()))
+    }
+
+    pub fn zcount(&self, key: &[u8], min: &ScoreBound, max: &ScoreBound) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_zset(&mut db, key)?.map_or(0, |zset| zset.count_by_score(min, max) as i64))
+    }
+
+    pub fn zlexcount(&self, key: &[u8], min: &LexBound, max: &LexBound) -> Result<i64, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        Ok(RedisServer::get_zset(&mut db, key)?.map_or(0, |zset| zset.count_by_lex(min, max) as i64))
+    }
+
+    pub fn zpop(&self, key: &[u8], max: bool, count: usize) -> Result<Vec<(Bytes, f64)>, RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        RedisServer::zpop_from(&mut db, key, max, count)
+    }
+
+    /// ZUNIONSTORE and ZINTERSTORE. Plain sets count as sorted sets whose scores
+    /// are all 1, and missing keys as empty ones. Returns the size of the result
+    /// and whether the keyspace changed.
+    pub fn zset_operation_store(
+        &self,
+        op: SetOperation,
+        destination: &Bytes,
+        keys: &[Bytes],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> Result<(i64, bool), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let mut inputs = Vec::with_capacity(keys.len());
+        for (key, weight) in keys.iter().zip(weights) {
+            let pairs: Option<HashMap<Bytes, f64>> = match db.get_mut(key) {
+                Some((Value::ZSet(zset), _)) => Some(zset.iter().map(|(member, score)| (member, weighted(score, *weight))).collect()),
+                Some((Value::Set(set), _)) => Some(set.members().into_iter().map(|member| (member, weighted(1.0, *weight))).collect()),
+                Some(_) => return Err(RedisValue::Error(WRONGTYPE.to_string())),
+                None => None,
+            };
+            inputs.push(pairs);
+        }
+        let combine = |a: f64, b: f64| match aggregate {
+            Aggregate::Sum => weighted(a + b, 1.0),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        };
+        let result: HashMap<Bytes, f64> = if op == SetOperation::Union {
+            let mut result = HashMap::new();
+            for (member, score) in inputs.into_iter().flatten().flatten() {
+                result
+                    .entry(member)
+                    .and_modify(|total| *total = combine(*total, score))
+                    .or_insert(score);
+            }
+            result
+        } else if inputs.iter().any(Option::is_none) {
+            HashMap::new()
+        } else {
+            let mut inputs = inputs.into_iter().flatten();
+            let mut result = inputs.next().unwrap_or_default();
+            for input in inputs {
+                result.retain(|member, total| match input.get(member) {
+                    Some(score) => {
+                        *total = combine(*total, *score);
+                        true
+                    }
+                    None => false,
+                });
+            }
+            result
+        };
+        Ok(RedisServer::store_zset(&mut db, destination, result.into_iter().collect()))
+    }
+
+    /// One step of a ZSCAN iteration: the next cursor and the matching members
+    /// with their scores
+    pub fn zscan(&self, key: &[u8], cursor: u64, options: &ScanOptions) -> Result<(u64, Vec<(Bytes, f64)>), RedisValue> {
+        let mut db = self.db.lock().unwrap();
+        let (next, batch) = match RedisServer::get_zset(&mut db, key)? {
+            Some(zset) => zset.scan(cursor, options.count),
+            None => return Ok((0, Vec::new())),
+        };
+        let pairs = batch
+            .into_iter()
+            .filter(|(member, _)| {
+                options
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, member, false))
+            })
+            .collect();
+        Ok((next, pairs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(words: &str) -> Vec<(f64, Bytes)> {
+        let words: Vec<&str> = words.split_whitespace().collect();
+        words
+            .chunks(2)
+            .map(|pair| (pair[0].parse().unwrap(), Bytes::from(pair[1].to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_sorted_set_operations() {
+        let server = RedisServer::new(&[]);
+        let plain = ZAddOptions::default();
+        assert_eq!(server.zadd(b"z", &plain, &pairs("1 a 2 b 3 c")), Ok((3, 0, Some(3.0))));
+        let gt = ZAddOptions {
+            gt: true,
+            ..Default::default()
+        };
+        assert_eq!(server.zadd(b"z", &gt, &pairs("0 a 5 b 4 d")), Ok((1, 1, Some(4.0))));
+        let xx_incr = ZAddOptions {
+            xx: true,
+            incr: true,
+            ..Default::default()
+        };
+        assert_eq!(server.zadd(b"z", &xx_incr, &pairs("1 nope")), Ok((0, 0, None)));
+        assert_eq!(server.zadd(b"nope", &xx_incr, &pairs("1 a")), Ok((0, 0, None)));
+        assert_eq!(server.key_type(b"nope"), "none");
+        assert_eq!(server.zincrby(b"z", 0.5, &Bytes::from("a")), Ok(1.5));
+        assert_eq!(server.zadd(b"z", &plain, &pairs("inf x")), Ok((1, 0, Some(f64::INFINITY))));
+        assert_eq!(
+            server.zincrby(b"z", f64::NEG_INFINITY, &Bytes::from("x")),
+            Err(RedisValue::Error("ERR resulting score is not a number (NaN)".to_string()))
+        );
+        assert_eq!(server.zrank(b"z", b"b", true), Ok(Some((1, 5.0))));
+        assert_eq!(server.zcard(b"z"), Ok(5));
+
+        let top = ZRange {
+            by: ZRangeBy::Score(
+                ScoreBound { value: 2.0, exclusive: true },
+                ScoreBound { value: f64::INFINITY, exclusive: true },
+            ),
+            rev: true,
+            limit: Some((0, 2)),
+        };
+        assert_eq!(
+            server.zrange(b"z", &top),
+            Ok(vec![(Bytes::from("b"), 5.0), (Bytes::from("d"), 4.0)])
+        );
+        assert_eq!(server.zrangestore(&Bytes::from("top"), b"z", &top), Ok((2, true)));
+
+        server.sadd(b"s", &[Bytes::from("a"), Bytes::from("b")]).unwrap();
+        assert_eq!(
+            server.zset_operation_store(SetOperation::Inter, &Bytes::from("i"), &[Bytes::from("z"), Bytes::from("s")], &[2.0, 10.0], Aggregate::Sum),
+            Ok((2, true))
+        );
+        assert_eq!(server.zmscore(b"i", &[Bytes::from("a"), Bytes::from("b")]), Ok(vec![Some(13.0), Some(20.0)]));
+        assert_eq!(
+            server.zset_operation_store(SetOperation::Union, &Bytes::from("u"), &[Bytes::from("top"), Bytes::from("nope")], &[1.0, 1.0], Aggregate::Max),
+            Ok((2, true))
+        );
+
+        assert_eq!(server.zpop(b"z", false, 2), Ok(vec![(Bytes::from("a"), 1.5), (Bytes::from("c"), 3.0)]));
+        assert_eq!(server.zrem(b"z", &[Bytes::from("b"), Bytes::from("d"), Bytes::from("x")]), Ok(3));
+        assert_eq!(server.key_type(b"z"), "none");
+    }
+}
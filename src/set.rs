@@ -0,0 +1,171 @@
+//! The set type
+//!
+//! Like Redis, a set whose members are all integers is kept as a sorted array of
+//! them, an intset, until it outgrows `MAX_INTSET_ENTRIES` or gets a member that
+//! isn't an integer. It's then converted to a hash set for good.
+use bytes::Bytes;
+use std::collections::HashSet;
+
+use crate::parser::parse_integer;
+use crate::scan::ScanOrder;
+
+/// Most members an intset may hold, as in Redis' set-max-intset-entries
+pub const MAX_INTSET_ENTRIES: usize = 512;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Set {
+    /// Sorted, without duplicates
+    Ints(Vec<i64>),
+    /// The members, and the order SSCAN visits them in
+    Members(HashSet<Bytes>, ScanOrder),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Ints(Vec::new())
+    }
+}
+
+impl Set {
+    /// Adds `member`, returning whether it's new
+    pub fn insert(&mut self, member: &Bytes) -> bool {
+        if let Set::Ints(ints) = self {
+            match parse_integer(member) {
+                Some(n) => match ints.binary_search(&n) {
+                    Ok(_) => return false,
+                    Err(_) if ints.len() >= MAX_INTSET_ENTRIES => self.convert(),
+                    Err(position) => {
+                        ints.insert(position, n);
+                        return true;
+                    }
+                },
+                None => self.convert(),
+            }
+        }
+        match self {
+            Set::Members(members, order) => {
+                let added = members.insert(member.clone());
+                if added {
+                    order.insert(member);
+                }
+                added
+            }
+            Set::Ints(_) => unreachable!("converted above"),
+        }
+    }
+
+    /// Removes `member`, returning whether it was there
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => match parse_integer(member).map(|n| ints.binary_search(&n)) {
+                Some(Ok(position)) => {
+                    ints.remove(position);
+                    true
+                }
+                _ => false,
+            },
+            Set::Members(members, order) => {
+                let removed = members.remove(member);
+                if removed {
+                    order.remove(member);
+                }
+                removed
+            }
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => parse_integer(member).is_some_and(|n| ints.binary_search(&n).is_ok()),
+            Set::Members(members, _) => members.contains(member),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Members(members, _) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every member; integers come back in order and in their decimal form
+    pub fn members(&self) -> Vec<Bytes> {
+        match self {
+            Set::Ints(ints) => ints.iter().map(|n| Bytes::from(n.to_string())).collect(),
+            Set::Members(members, _) => members.iter().cloned().collect(),
+        }
+    }
+
+    /// One step of an SSCAN iteration: up to about `count` members, and the
+    /// cursor to continue from. An intset is small enough to return whole, as
+    /// Redis does.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        match self {
+            Set::Ints(_) => (0, self.members()),
+            Set::Members(_, order) => {
+                let (next, members) = order.scan(cursor, count);
+                (next, members.into_iter().cloned().collect())
+            }
+        }
+    }
+
+    /// The name Redis gives the encoding, as in OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Set::Ints(_) => "intset",
+            Set::Members(..) => "hashtable",
+        }
+    }
+
+    fn convert(&mut self) {
+        if let Set::Ints(ints) = self {
+            let members: HashSet<Bytes> = ints.iter().map(|n| Bytes::from(n.to_string())).collect();
+            let mut order = ScanOrder::default();
+            for member in &members {
+                order.insert(member);
+            }
+            *self = Set::Members(members, order);
+        }
+    }
+}
+
+impl FromIterator<Bytes> for Set {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(members: I) -> Self {
+        let mut set = Set::default();
+        for member in members {
+            set.insert(&member);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intset_converts_when_needed() {
+        let mut set: Set = ["3", "1", "2", "1"].map(Bytes::from).into_iter().collect();
+        assert_eq!(set, Set::Ints(vec![1, 2, 3]));
+        assert!(set.contains(b"2"));
+        // Not the canonical form of 2, so not a member
+        assert!(!set.contains(b"02"));
+        assert!(set.remove(b"2"));
+        assert!(!set.remove(b"2"));
+
+        assert!(set.insert(&Bytes::from("x")));
+        assert_eq!(set.encoding(), "hashtable");
+        assert!(set.contains(b"1") && set.contains(b"x"));
+
+        let mut big: Set = (0..MAX_INTSET_ENTRIES).map(|n| Bytes::from(n.to_string())).collect();
+        assert_eq!(big.encoding(), "intset");
+        assert!(!big.insert(&Bytes::from("0")));
+        assert!(big.insert(&Bytes::from("-1")));
+        assert_eq!(big.encoding(), "hashtable");
+        assert_eq!(big.len(), MAX_INTSET_ENTRIES + 1);
+    }
+}
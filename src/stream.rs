@@ -0,0 +1,413 @@
+//! The stream type
+//!
+//! Entries are kept in a B-tree keyed by their ID, which gives ordered range
+//! scans and cheap trimming from the front. A stream remembers the last ID it
+//! handed out even once that entry is deleted, so IDs never go backwards.
+//!
+//! Consumer groups hang off the stream they read: each tracks the last ID it
+//! handed out and the entries its consumers haven't acknowledged yet.
+use bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::ops::Bound;
+
+/// An entry ID: milliseconds then a sequence number within that millisecond
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// The smallest ID after this one
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    /// The largest ID before this one
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+
+    /// Parses `<ms>-<seq>`, or a bare `<ms>` with `missing_seq` as its sequence
+    pub fn parse(s: &[u8], missing_seq: u64) -> Option<StreamId> {
+        let s = std::str::from_utf8(s).ok()?;
+        let number = |n: &str| n.bytes().all(|b| b.is_ascii_digit()).then(|| n.parse().ok()).flatten();
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId {
+                ms: number(ms)?,
+                seq: number(seq)?,
+            }),
+            None => Some(StreamId {
+                ms: number(s)?,
+                seq: missing_seq,
+            }),
+        }
+    }
+}
+
+impl Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Field and value pairs, in the order they were added
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+/// Entries read from several streams, by key
+pub type Reads = Vec<(Bytes, Vec<(StreamId, Fields)>)>;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Fields>,
+    /// The ID of the newest entry ever added, deleted or not
+    pub last_id: StreamId,
+    /// The largest ID deleted by XDEL or trimming
+    pub max_deleted_id: StreamId,
+    /// How many entries were ever added
+    pub entries_added: u64,
+    pub groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn first_id(&self) -> StreamId {
+        self.entries.keys().next().copied().unwrap_or_default()
+    }
+
+    /// The ID for a new entry: `ms` and `seq` where given, and otherwise the
+    /// current time and the next free sequence number. Fails with the Redis error
+    /// for an ID that isn't past the last one.
+    pub fn next_id(&self, ms: Option<u64>, seq: Option<u64>, now_ms: u64) -> Result<StreamId, &'static str> {
+        let id = match (ms, seq) {
+            (Some(ms), Some(seq)) => StreamId { ms, seq },
+            (Some(ms), None) if ms == self.last_id.ms => match self.last_id.seq.checked_add(1) {
+                Some(seq) => StreamId { ms, seq },
+                None => return Err(SMALLER_ID),
+            },
+            (Some(ms), None) => StreamId { ms, seq: 0 },
+            (None, _) if now_ms > self.last_id.ms => StreamId { ms: now_ms, seq: 0 },
+            (None, _) => self.last_id.next().ok_or(EXHAUSTED)?,
+        };
+        if id <= self.last_id {
+            return Err(SMALLER_ID);
+        }
+        Ok(id)
+    }
+
+    /// Appends an entry, whose ID the caller got from `next_id`
+    pub fn add(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Deletes an entry, returning whether it was there
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        if self.entries.remove(&id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Removes the oldest entries as the strategy asks, no more than `limit` of
+    /// them if given. Returns how many were removed.
+    pub fn trim(&mut self, strategy: &TrimStrategy, limit: Option<usize>) -> usize {
+        let len = self.entries.len();
+        let mut removed = 0;
+        while let Some(entry) = self.entries.first_entry() {
+            let over = match strategy {
+                TrimStrategy::MaxLen(max) => len - removed > *max,
+                TrimStrategy::MinId(min) => entry.key() < min,
+            };
+            if !over || limit.is_some_and(|limit| removed >= limit) {
+                break;
+            }
+            let (id, _) = entry.remove_entry();
+            self.max_deleted_id = self.max_deleted_id.max(id);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Entries from `start` to `end` inclusive, newest first with `rev`, and no
+    /// more than `count` of them
+    pub fn range(&self, start: StreamId, end: StreamId, rev: bool, count: Option<usize>) -> Vec<(StreamId, Fields)> {
+        if start > end {
+            return Vec::new();
+        }
+        let range = self.entries.range(start..=end).map(|(id, fields)| (*id, fields.clone()));
+        let count = count.unwrap_or(usize::MAX);
+        if rev {
+            range.rev().take(count).collect()
+        } else {
+            range.take(count).collect()
+        }
+    }
+
+    /// Entries with IDs past `after`, oldest first
+    pub fn read_after(&self, after: StreamId, count: Option<usize>) -> Vec<(StreamId, Fields)> {
+        self.entries
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect()
+    }
+
+    /// Whether an entry from `start` on may have been deleted
+    fn has_tombstones_from(&self, start: StreamId) -> bool {
+        !self.is_empty() && self.max_deleted_id != StreamId::MIN && start <= self.max_deleted_id
+    }
+
+    /// How many entries were added up to and including `id`, if that can be
+    /// told without counting
+    fn entries_added_until(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 || (self.is_empty() && id <= self.last_id) || id == self.last_id {
+            return Some(self.entries_added);
+        }
+        if id > self.last_id {
+            return None;
+        }
+        let first = self.first_id();
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first {
+            // Nothing was deleted past the first entry
+            if id < first {
+                return Some(self.entries_added - self.len() as u64);
+            } else if id == first {
+                return Some(self.entries_added - self.len() as u64 + 1);
+            }
+        }
+        None
+    }
+
+    /// How many entries a group has yet to read, if that can be told
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let read = match group.entries_read {
+            Some(read) if !self.has_tombstones_from(group.last_id) => Some(read),
+            _ => self.entries_added_until(group.last_id),
+        };
+        read.map(|read| self.entries_added.saturating_sub(read))
+    }
+
+    /// Hands a group the entries past its last ID, moving the ID along. Unless
+    /// `noack`, they become pending for `consumer`. None if there's no such group.
+    pub fn read_group(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        count: Option<usize>,
+        noack: bool,
+        now_ms: u64,
+    ) -> Option<Vec<(StreamId, Fields)>> {
+        let entries = self.read_after(self.groups.get(group)?.last_id, count);
+        for (id, _) in &entries {
+            let tombstones = self.has_tombstones_from(*id);
+            let estimate = self.entries_added_until(*id);
+            let added = self.entries_added;
+            let group = self.groups.get_mut(group).unwrap();
+            group.entries_read = match group.entries_read {
+                Some(read) if !tombstones => Some(read + 1),
+                _ if added > 0 => estimate,
+                read => read,
+            };
+            group.last_id = *id;
+            if !noack {
+                group.deliver(*id, consumer, now_ms, 1);
+            }
+        }
+        Some(entries)
+    }
+}
+
+/// A consumer group: how far it has read, and what its consumers were handed
+/// but haven't acknowledged
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ConsumerGroup {
+    /// The last entry handed to any of its consumers
+    pub last_id: StreamId,
+    /// How many entries the group has read, when that's known
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<Bytes, Consumer>,
+}
+
+/// An entry delivered to a consumer but not yet acknowledged
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    /// When it was last delivered, in unix milliseconds
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Consumer {
+    /// When it last read or claimed, in unix milliseconds
+    pub seen_time: u64,
+    /// When it last got any entries, if ever
+    pub active_time: Option<u64>,
+    /// The IDs of its entries in the group's pending list
+    pub pending: BTreeSet<StreamId>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> ConsumerGroup {
+        ConsumerGroup {
+            last_id,
+            entries_read,
+            ..Default::default()
+        }
+    }
+
+    /// The named consumer, marked as seen now. Returns whether it's new.
+    pub fn consumer(&mut self, name: &Bytes, now_ms: u64) -> (&mut Consumer, bool) {
+        let created = !self.consumers.contains_key(name);
+        let consumer = self.consumers.entry(name.clone()).or_default();
+        consumer.seen_time = now_ms;
+        (consumer, created)
+    }
+
+    /// Adds a consumer that has never been seen, returning false if it exists
+    pub fn create_consumer(&mut self, name: &Bytes, now_ms: u64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumer(name, now_ms);
+        true
+    }
+
+    /// Removes a consumer along with its pending entries, returning how many
+    /// it had, or None if there's no such consumer
+    pub fn delete_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Makes `id` pending for `consumer`, taking it from any other consumer
+    pub fn deliver(&mut self, id: StreamId, consumer: &Bytes, now_ms: u64, delivery_count: u64) {
+        let previous = self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.clone(),
+                delivery_time: now_ms,
+                delivery_count,
+            },
+        );
+        if let Some(previous) = previous {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        let (consumer, _) = self.consumer(consumer, now_ms);
+        consumer.active_time = Some(now_ms);
+        consumer.pending.insert(id);
+    }
+
+    /// Acknowledges an entry, returning whether it was pending
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let entry = match self.pending.remove(&id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+}
+
+const SMALLER_ID: &str = "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+const EXHAUSTED: &str = "ERR The stream has exhausted the last possible ID, unable to add more items";
+
+/// Which entries XADD and XTRIM keep
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrimStrategy {
+    /// At most this many entries
+    MaxLen(usize),
+    /// Only entries with at least this ID
+    MinId(StreamId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse(b"5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse(b"5-", 0), None);
+        assert_eq!(StreamId::parse(b"-1", 0), None);
+        assert_eq!(StreamId::parse(b"+5", 0), None);
+        assert_eq!(id(1, u64::MAX).next(), Some(id(2, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(id(2, 0).prev(), Some(id(1, u64::MAX)));
+
+        let mut stream = Stream::default();
+        assert_eq!(stream.next_id(Some(0), None, 100), Ok(id(0, 1)));
+        assert_eq!(stream.next_id(None, None, 100), Ok(id(100, 0)));
+        stream.add(id(100, 0), vec![]);
+        assert_eq!(stream.next_id(None, None, 50), Ok(id(100, 1)));
+        assert_eq!(stream.next_id(Some(100), None, 0), Ok(id(100, 1)));
+        assert_eq!(stream.next_id(Some(100), Some(0), 0), Err(SMALLER_ID));
+        assert_eq!(stream.next_id(Some(99), None, 0), Err(SMALLER_ID));
+    }
+
+    #[test]
+    fn test_trim_and_ranges() {
+        let mut stream = Stream::default();
+        for ms in 1..=10 {
+            stream.add(id(ms, 0), vec![(Bytes::from("n"), Bytes::from(ms.to_string()))]);
+        }
+        assert_eq!(stream.trim(&TrimStrategy::MaxLen(8), None), 2);
+        assert_eq!(stream.trim(&TrimStrategy::MinId(id(6, 0)), Some(2)), 2);
+        assert_eq!(stream.first_id(), id(5, 0));
+        assert_eq!(stream.max_deleted_id, id(4, 0));
+        assert!(stream.delete(id(7, 0)));
+        assert!(!stream.delete(id(7, 0)));
+
+        let ids = |entries: Vec<(StreamId, Fields)>| entries.into_iter().map(|(id, _)| id.ms).collect::<Vec<_>>();
+        assert_eq!(ids(stream.range(id(6, 0), StreamId::MAX, false, None)), vec![6, 8, 9, 10]);
+        assert_eq!(ids(stream.range(StreamId::MIN, id(9, 0), true, Some(2))), vec![9, 8]);
+        assert_eq!(ids(stream.read_after(id(8, 0), None)), vec![9, 10]);
+        assert_eq!(stream.len(), 5);
+        assert_eq!(stream.entries_added, 10);
+    }
+}
@@ -0,0 +1,508 @@
+//! The sorted set type
+//!
+//! As in Redis, members are kept in a skiplist ordered by score and then by
+//! member, which answers range and rank queries in logarithmic time, next to a
+//! hash map from member to score for direct lookups. Every link in the skiplist
+//! records how many nodes it skips, its span, so ranks can be summed on the way
+//! down.
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::parser::{LexBound, ScoreBound};
+use crate::scan::ScanOrder;
+
+const MAX_LEVEL: usize = 32;
+
+/// Most members, and longest member, a sorted set may have to be reported with
+/// the listpack encoding, as in Redis' zset-max-listpack-entries and -value
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    next: Option<usize>,
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    links: Vec<Link>,
+    prev: Option<usize>,
+}
+
+impl Node {
+    /// Where the node sorts relative to `score` and `member`
+    fn cmp(&self, score: f64, member: &[u8]) -> Ordering {
+        self.score
+            .partial_cmp(&score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.member[..].cmp(member))
+    }
+}
+
+/// Nodes live in a vector and refer to each other by index. Slot 0 is the head,
+/// which holds no member and has a link on every level.
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    seed: u64,
+}
+
+const HEAD: usize = 0;
+
+impl SkipList {
+    fn new() -> Self {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            links: vec![Link { next: None, span: 0 }; MAX_LEVEL],
+            prev: None,
+        };
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            seed: 0x2545f4914f6cdd1d,
+        }
+    }
+
+    /// A level for a new node: each level is a quarter as likely as the one below
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        loop {
+            // xorshift64
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            if self.seed & 3 != 0 || level == MAX_LEVEL {
+                return level;
+            }
+            level += 1;
+        }
+    }
+
+    fn next(&self, id: usize) -> Option<usize> {
+        self.nodes[id].links[0].next
+    }
+
+    /// For each level, the last node sorting before `score` and `member`, and its rank
+    fn predecessors(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].links[i].next {
+                if self.nodes[next].cmp(score, member) != Ordering::Less {
+                    break;
+                }
+                rank[i] += self.nodes[x].links[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    /// Adds a member, which the caller makes sure isn't in the list yet
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].links[i].span = self.len;
+            }
+            self.level = level;
+        }
+        let node = Node {
+            member,
+            score,
+            links: vec![Link { next: None, span: 0 }; level],
+            prev: (update[0] != HEAD).then_some(update[0]),
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let before = self.nodes[update[i]].links[i];
+            self.nodes[id].links[i] = Link {
+                next: before.next,
+                span: before.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].links[i] = Link {
+                next: Some(id),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &before) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[before].links[i].span += 1;
+        }
+        if let Some(next) = self.next(id) {
+            self.nodes[next].prev = Some(id);
+        }
+        self.len += 1;
+    }
+
+    /// Removes a member, returning whether it was there with that score
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.predecessors(score, member);
+        let id = match self.next(update[0]) {
+            Some(id) if self.nodes[id].cmp(score, member) == Ordering::Equal => id,
+            _ => return false,
+        };
+        for (i, &before) in update.iter().enumerate().take(self.level) {
+            let removed = self.nodes[id].links.get(i).copied();
+            let link = &mut self.nodes[before].links[i];
+            match removed {
+                Some(removed) if link.next == Some(id) => {
+                    link.span = link.span + removed.span - 1;
+                    link.next = removed.next;
+                }
+                _ => link.span -= 1,
+            }
+        }
+        if let Some(next) = self.next(id) {
+            self.nodes[next].prev = self.nodes[id].prev;
+        }
+        while self.level > 1 && self.nodes[HEAD].links[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+        self.nodes[id].member = Bytes::new();
+        self.nodes[id].links.clear();
+        self.free.push(id);
+        self.len -= 1;
+        true
+    }
+
+    /// The 0-based rank of a member
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut x = HEAD;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if self.nodes[next].cmp(score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += self.nodes[x].links[i].span;
+                x = next;
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// The node at a 0-based rank
+    fn by_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut x = HEAD;
+        let mut traversed = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if traversed + self.nodes[x].links[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].links[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// The first node for which `reached` holds, and its rank, given that it holds
+    /// for every node after it too
+    fn first_where(&self, reached: impl Fn(&Node) -> bool) -> Option<(usize, usize)> {
+        let mut x = HEAD;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if reached(&self.nodes[next]) {
+                    break;
+                }
+                rank += self.nodes[x].links[i].span;
+                x = next;
+            }
+        }
+        self.next(x).map(|next| (next, rank))
+    }
+
+    /// The last node for which `within` holds, and its rank, given that it holds
+    /// for every node before it too
+    fn last_where(&self, within: impl Fn(&Node) -> bool) -> Option<(usize, usize)> {
+        let mut x = HEAD;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if !within(&self.nodes[next]) {
+                    break;
+                }
+                rank += self.nodes[x].links[i].span;
+                x = next;
+            }
+        }
+        (x != HEAD).then(|| (x, rank - 1))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+    /// The order ZSCAN visits members in
+    order: ScanOrder,
+}
+
+impl Default for ZSet {
+    fn default() -> Self {
+        ZSet {
+            scores: HashMap::new(),
+            list: SkipList::new(),
+            order: ScanOrder::default(),
+        }
+    }
+}
+
+impl PartialEq for ZSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl ZSet {
+    pub fn len(&self) -> usize {
+        self.list.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.len == 0
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` or changes its score, returning the score it had. The score
+    /// must not be NaN.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        match old {
+            Some(old) if old == score => {}
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member);
+            }
+            None => {
+                self.order.insert(&member);
+                self.list.insert(score, member);
+            }
+        }
+        old
+    }
+
+    /// Removes `member`, returning whether it was there
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.order.remove(member);
+                self.list.remove(score, member)
+            }
+            None => false,
+        }
+    }
+
+    /// The 0-based position of `member`, counting from the highest score with `rev`
+    pub fn rank(&self, member: &[u8], rev: bool) -> Option<usize> {
+        let rank = self.list.rank(self.score(member)?, member)?;
+        Some(if rev { self.len() - 1 - rank } else { rank })
+    }
+
+    /// Members from `start` to `stop` inclusive, which must be valid ranks. With
+    /// `rev` ranks count from the highest score and members come highest first.
+    pub fn range_by_rank(&self, start: usize, stop: usize, rev: bool) -> Vec<(Bytes, f64)> {
+        let first = if rev { self.len() - 1 - start } else { start };
+        let node = self.list.by_rank(first);
+        self.walk(node, rev).take(stop + 1 - start).collect()
+    }
+
+    /// Members with scores between `min` and `max`, lowest first or with `rev`
+    /// highest first, after skipping `offset` and at most `count` of them
+    pub fn range_by_score(&self, min: &ScoreBound, max: &ScoreBound, rev: bool, offset: usize, count: Option<usize>) -> Vec<(Bytes, f64)> {
+        self.range_where(|node| min.admits_above(node.score), |node| max.admits_below(node.score), rev, offset, count)
+    }
+
+    /// Like `range_by_score`, by member for a sorted set whose scores are all equal
+    pub fn range_by_lex(&self, min: &LexBound, max: &LexBound, rev: bool, offset: usize, count: Option<usize>) -> Vec<(Bytes, f64)> {
+        self.range_where(|node| min.admits_above(&node.member), |node| max.admits_below(&node.member), rev, offset, count)
+    }
+
+    pub fn count_by_score(&self, min: &ScoreBound, max: &ScoreBound) -> usize {
+        self.count_where(|node| min.admits_above(node.score), |node| max.admits_below(node.score))
+    }
+
+    pub fn count_by_lex(&self, min: &LexBound, max: &LexBound) -> usize {
+        self.count_where(|node| min.admits_above(&node.member), |node| max.admits_below(&node.member))
+    }
+
+    /// Removes up to `count` members with the lowest scores, or the highest with
+    /// `max`, and returns them in that order
+    pub fn pop(&mut self, max: bool, count: usize) -> Vec<(Bytes, f64)> {
+        if self.is_empty() || count == 0 {
+            return Vec::new();
+        }
+        let popped = self.range_by_rank(0, count.min(self.len()) - 1, max);
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+
+    /// Every member and score, lowest score first
+    pub fn iter(&self) -> impl Iterator<Item = (Bytes, f64)> + '_ {
+        self.walk(self.list.next(HEAD), false)
+    }
+
+    /// One step of a ZSCAN iteration: up to about `count` members with their
+    /// scores, and the cursor to continue from
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(Bytes, f64)>) {
+        let (next, members) = self.order.scan(cursor, count);
+        let pairs = members.into_iter().map(|member| (member.clone(), self.scores[member])).collect();
+        (next, pairs)
+    }
+
+    /// The name Redis gives the encoding, as in OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        if self.len() <= MAX_LISTPACK_ENTRIES && self.scores.keys().all(|member| member.len() <= MAX_LISTPACK_VALUE) {
+            "listpack"
+        } else {
+            "skiplist"
+        }
+    }
+
+    /// Members from node `from` on, going towards lower scores with `rev`
+    fn walk(&self, from: Option<usize>, rev: bool) -> impl Iterator<Item = (Bytes, f64)> + '_ {
+        std::iter::successors(from, move |&id| {
+            if rev {
+                self.list.nodes[id].prev
+            } else {
+                self.list.next(id)
+            }
+        })
+        .map(|id| (self.list.nodes[id].member.clone(), self.list.nodes[id].score))
+    }
+
+    fn range_where(
+        &self,
+        above_min: impl Fn(&Node) -> bool,
+        below_max: impl Fn(&Node) -> bool,
+        rev: bool,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Vec<(Bytes, f64)> {
+        let (from, inside): (_, &dyn Fn(&Node) -> bool) = if rev {
+            (self.list.last_where(&below_max), &above_min)
+        } else {
+            (self.list.first_where(&above_min), &below_max)
+        };
+        let mut ids = Vec::new();
+        let mut id = from.map(|(id, _)| id);
+        while let Some(current) = id {
+            if !inside(&self.list.nodes[current]) || count.is_some_and(|count| ids.len() >= offset + count) {
+                break;
+            }
+            ids.push(current);
+            id = if rev { self.list.nodes[current].prev } else { self.list.next(current) };
+        }
+        ids.into_iter()
+            .skip(offset)
+            .map(|id| (self.list.nodes[id].member.clone(), self.list.nodes[id].score))
+            .collect()
+    }
+
+    fn count_where(&self, above_min: impl Fn(&Node) -> bool, below_max: impl Fn(&Node) -> bool) -> usize {
+        match (self.list.first_where(above_min), self.list.last_where(below_max)) {
+            (Some((_, first)), Some((_, last))) if last >= first => last - first + 1,
+            _ => 0,
+        }
+    }
+}
+
+impl FromIterator<(Bytes, f64)> for ZSet {
+    fn from_iter<I: IntoIterator<Item = (Bytes, f64)>>(pairs: I) -> Self {
+        let mut zset = ZSet::default();
+        for (member, score) in pairs {
+            zset.insert(member, score);
+        }
+        zset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(pairs: Vec<(Bytes, f64)>) -> Vec<Bytes> {
+        pairs.into_iter().map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn test_ranks_and_ranges() {
+        let mut zset: ZSet = (0..1000).rev().map(|i| (Bytes::from(format!("m{:04}", i)), i as f64)).collect();
+        assert_eq!(zset.len(), 1000);
+        assert_eq!(zset.rank(b"m0500", false), Some(500));
+        assert_eq!(zset.rank(b"m0500", true), Some(499));
+        assert_eq!(zset.rank(b"nope", false), None);
+        assert_eq!(members(zset.range_by_rank(2, 3, true)), vec![Bytes::from("m0997"), Bytes::from("m0996")]);
+
+        for i in (0..1000).step_by(2) {
+            assert!(zset.remove(format!("m{:04}", i).as_bytes()));
+        }
+        assert!(!zset.remove(b"m0000"));
+        assert_eq!(zset.rank(b"m0501", false), Some(250));
+
+        let min = ScoreBound { value: 10.0, exclusive: true };
+        let max = ScoreBound { value: 15.0, exclusive: false };
+        assert_eq!(zset.count_by_score(&min, &max), 3);
+        assert_eq!(members(zset.range_by_score(&min, &max, true, 1, Some(1))), vec![Bytes::from("m0013")]);
+        assert_eq!(zset.count_by_score(&max, &min), 0);
+
+        // Moving a member keeps the ranks consistent
+        assert_eq!(zset.insert(Bytes::from("m0001"), 2000.0), Some(1.0));
+        assert_eq!(zset.rank(b"m0001", false), Some(499));
+        assert_eq!(zset.rank(b"m0003", false), Some(0));
+        assert_eq!(zset.pop(true, 1), vec![(Bytes::from("m0001"), 2000.0)]);
+        assert_eq!(zset.pop(false, 2).len(), 2);
+        assert_eq!(zset.len(), 497);
+        assert_eq!(zset.iter().count(), 497);
+    }
+
+    #[test]
+    fn test_lex_ranges() {
+        let zset: ZSet = ["a", "b", "c", "d"].map(|m| (Bytes::from(m), 0.0)).into_iter().collect();
+        let b = LexBound::Inclusive(Bytes::from("b"));
+        let d = LexBound::Exclusive(Bytes::from("d"));
+        assert_eq!(members(zset.range_by_lex(&b, &d, false, 0, None)), vec![Bytes::from("b"), Bytes::from("c")]);
+        assert_eq!(zset.count_by_lex(&LexBound::Min, &LexBound::Max), 4);
+        assert_eq!(members(zset.range_by_lex(&LexBound::Min, &b, true, 0, None)), vec![Bytes::from("b"), Bytes::from("a")]);
+        assert_eq!(zset.encoding(), "listpack");
+    }
+}